@@ -0,0 +1,421 @@
+use crate::DecodeOptions;
+use crate::DecodingError;
+use crate::ErrorKind;
+use crate::Image;
+use crate::png_decoder::PNGDatastream;
+use crate::png_decoder::PNGImage;
+use crate::png_decoder::PNG_SIGNATURE;
+use crate::png_decoder::decode_chunk;
+use crate::png_decoder::decode_subimage;
+use crate::pixel_format;
+use crate::pixel_format::PixelFormat;
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum DisposeOp {
+    None,
+    Background,
+    Previous,
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum BlendOp {
+    Source,
+    Over,
+}
+
+/// Fully composited animation frame. `image` always covers the whole canvas and has 4 channels (RGBA)
+/// with the bit depth of the file (8 or 16), unless `output_format` is set.
+pub struct ApngFrame {
+    pub image: Image,
+    pub delay_num: u16,
+    pub delay_den: u16,
+}
+
+impl ApngFrame {
+    /// Frame delay in seconds
+    pub fn delay(&self) -> f32 {
+        let den = if self.delay_den == 0 { 100 } else { self.delay_den };
+        self.delay_num as f32 / den as f32
+    }
+}
+
+struct FrameControl<'a> {
+    w: u32,
+    h: u32,
+    x: u32,
+    y: u32,
+    delay_num: u16,
+    delay_den: u16,
+    dispose_op: DisposeOp,
+    blend_op: BlendOp,
+    data: Vec<&'a [u8]>, // zlib stream, split across IDAT or fdAT chunks
+}
+
+/// Iterator over the frames of an animated PNG. A PNG without `acTL` is treated as a single frame animation.
+pub struct ApngDecoder<'a> {
+    png_image: PNGImage,
+    num_plays: u32,
+    frames: Vec<FrameControl<'a>>,
+    next_frame: usize,
+    canvas: Vec<u16>, // RGBA samples
+    // how to clean up after the previous frame: its index and, for DisposeOp::Previous, the saved region
+    pending_dispose: Option<(usize, Vec<u16>)>,
+    verify_checksums: bool, // Adler-32 of the frames
+    output_format: Option<PixelFormat>,
+    lenient: bool,
+}
+
+fn decode_actl(stream: &mut PNGDatastream) -> Result<(u32, u32), DecodingError> {
+    if stream.len() != 8 {
+        return Err(ErrorKind::InvalidChunkLength.into());
+    }
+    let num_frames = stream.read_u32()?;
+    let num_plays = stream.read_u32()?;
+    if num_frames == 0 {
+        return Err(ErrorKind::InvalidChunkData.into());
+    }
+    Ok((num_frames, num_plays))
+}
+
+fn decode_fctl<'a>(stream: &mut PNGDatastream, png_image: &PNGImage) -> Result<FrameControl<'a>, DecodingError> {
+    if stream.len() != 22 {
+        return Err(ErrorKind::InvalidChunkLength.into());
+    }
+    let w = stream.read_u32()?;
+    let h = stream.read_u32()?;
+    let x = stream.read_u32()?;
+    let y = stream.read_u32()?;
+    let delay_num = stream.read_u16()?;
+    let delay_den = stream.read_u16()?;
+    let dispose_op = match stream.read_u8()? {
+        0 => DisposeOp::None,
+        1 => DisposeOp::Background,
+        2 => DisposeOp::Previous,
+        _ => return Err(ErrorKind::InvalidChunkData.into()),
+    };
+    let blend_op = match stream.read_u8()? {
+        0 => BlendOp::Source,
+        1 => BlendOp::Over,
+        _ => return Err(ErrorKind::InvalidChunkData.into()),
+    };
+    if w == 0 || h == 0
+        || x as u64 + w as u64 > png_image.image.w as u64
+        || y as u64 + h as u64 > png_image.image.h as u64
+    {
+        return Err(ErrorKind::InvalidChunkData.into());
+    }
+    Ok(FrameControl { w, h, x, y, delay_num, delay_den, dispose_op, blend_op, data: vec![] })
+}
+
+pub fn decode(buf: &[u8]) -> Result<ApngDecoder<'_>, DecodingError> {
+    decode_with_options(buf, &DecodeOptions::default())
+}
+
+/// Like `decode`, only the limits, `verify_checksums`, `output_format` and `lenient` of `options` apply to
+/// animations. The frames are converted to `output_format` after they are composited. With `lenient`,
+/// broken frames keep the rows decoded before the error, and an error after the first frame data ends the
+/// animation after the frames with data. The errors end up in `warnings`.
+pub fn decode_with_options<'a>(buf: &'a [u8], options: &DecodeOptions) -> Result<ApngDecoder<'a>, DecodingError> {
+    let mut stream = PNGDatastream::new(buf);
+    stream.check_crc = options.verify_checksums;
+    let mut chunk = None;
+    let mut decoder = ApngDecoder {
+        png_image: PNGImage::new(),
+        num_plays: 0,
+        frames: vec![],
+        next_frame: 0,
+        canvas: vec![],
+        pending_dispose: None,
+        verify_checksums: options.verify_checksums,
+        output_format: options.output_format,
+        lenient: options.lenient,
+    };
+    let result = decode_chunks(&mut stream, &mut chunk, &mut decoder, options).map_err(|err| err.at(chunk, stream.cursor));
+    match result {
+        // the animation ends before the first frame without data
+        Err(err) if options.lenient && decoder.frames.first().is_some_and(|frame| !frame.data.is_empty()) => {
+            let complete = decoder.frames.iter().take_while(|frame| !frame.data.is_empty()).count();
+            decoder.frames.truncate(complete);
+            decoder.png_image.warnings.push(err);
+        },
+        result => result?,
+    }
+
+    // the canvas has 4 samples of 2 bytes per pixel
+    let image = &decoder.png_image.image;
+    options.limits.check_alloc((image.w as u64 * image.h as u64).saturating_mul(8))?;
+    decoder.canvas = vec![0; image.w as usize * image.h as usize * 4];
+    Ok(decoder)
+}
+
+// Reads the frames into `decoder`. `chunk` is the chunk being decoded, errors that don't know their offset
+// happened at the cursor of `stream`.
+fn decode_chunks<'a>(stream: &mut PNGDatastream<'a>, chunk: &mut Option<[u8; 4]>, decoder: &mut ApngDecoder<'a>, options: &DecodeOptions) -> Result<(), DecodingError> {
+    let limits = &options.limits;
+    let buf = stream.buf;
+    stream.consume(PNG_SIGNATURE)?;
+
+    let png_image = &mut decoder.png_image;
+    let frames = &mut decoder.frames;
+    let mut num_frames = None;
+    let mut next_sequence_number = 0;
+    let mut fdat_seen = false;
+
+    loop {
+        *chunk = None;
+        let len = stream.read_u32()? as usize;
+        stream.reset_crc();
+        let chunk_name = stream.read_chunk_name()?;
+        *chunk = Some(chunk_name);
+        if png_image.misplaced_ihdr(&chunk_name) {
+            return Err(ErrorKind::ChunkOrder.into());
+        }
+        if len > limits.max_chunk_len {
+            return Err(ErrorKind::LimitsExceeded.into());
+        }
+        if stream.cursor + len >= buf.len() {
+            return Err(ErrorKind::InvalidChunkLength.into());
+        }
+        let data = &buf[stream.cursor..stream.cursor + len];
+        let mut chunk_stream = PNGDatastream::new(data);
+
+        match &chunk_name {
+            b"acTL" => {
+                if png_image.idat_seen || num_frames.is_some() {
+                    return Err(ErrorKind::ChunkOrder.into());
+                }
+                let (n, plays) = decode_actl(&mut chunk_stream)?;
+                num_frames = Some(n);
+                decoder.num_plays = plays;
+            },
+            b"fcTL" => {
+                if num_frames.is_none() {
+                    return Err(ErrorKind::ChunkOrder.into());
+                }
+                if chunk_stream.read_u32()? != next_sequence_number {
+                    return Err(ErrorKind::ChunkOrder.into());
+                }
+                // every fcTL is followed by the data of its frame before the next one
+                if frames.last().is_some_and(|frame| frame.data.is_empty()) {
+                    return Err(ErrorKind::ChunkOrder.into());
+                }
+                next_sequence_number += 1;
+                let mut frame = PNGDatastream::new(&data[4..]);
+                let frame = decode_fctl(&mut frame, png_image)?;
+                // fcTL before IDAT makes the default image the first frame, which must cover the whole canvas
+                if !png_image.idat_seen && (frame.x, frame.y, frame.w, frame.h) != (0, 0, png_image.image.w, png_image.image.h) {
+                    return Err(ErrorKind::InvalidChunkData.into());
+                }
+                frames.push(frame);
+            },
+            b"fdAT" => {
+                if !png_image.idat_seen || chunk_stream.read_u32()? != next_sequence_number {
+                    return Err(ErrorKind::ChunkOrder.into());
+                }
+                next_sequence_number += 1;
+                fdat_seen = true;
+                let Some(frame) = frames.last_mut() else {
+                    return Err(ErrorKind::ChunkOrder.into());
+                };
+                frame.data.push(&data[4..]);
+            },
+            b"IDAT" => {
+                if fdat_seen || png_image.palette_missing() {
+                    return Err(ErrorKind::ChunkOrder.into());
+                }
+                // without acTL the default image is the only frame
+                if num_frames.is_none() && !png_image.idat_seen {
+                    frames.push(FrameControl {
+                        w: png_image.image.w,
+                        h: png_image.image.h,
+                        x: 0,
+                        y: 0,
+                        delay_num: 0,
+                        delay_den: 0,
+                        dispose_op: DisposeOp::None,
+                        blend_op: BlendOp::Source,
+                        data: vec![],
+                    });
+                }
+                png_image.idat_seen = true;
+                if let Some(frame) = frames.last_mut() {
+                    frame.data.push(data);
+                }
+            },
+            b"IEND" => {},
+            _ => decode_chunk(&chunk_name, &mut chunk_stream, png_image, limits, stream.cursor)?,
+        }
+        stream.skip(len)?;
+        stream.consume_crc()?;
+        if chunk_name == *b"IEND" {
+            break;
+        }
+    }
+
+    *chunk = None;
+    if !png_image.idat_seen {
+        return Err(ErrorKind::ChunkOrder.into());
+    }
+    if !stream.eof() {
+        return Err(ErrorKind::TooMuchData.into());
+    }
+
+    if let Some(num_frames) = num_frames && (frames.len() != num_frames as usize || frames.iter().any(|frame| frame.data.is_empty())) {
+        return Err(ErrorKind::ChunkOrder.into());
+    }
+    Ok(())
+}
+
+// Converts a decoded frame to RGBA samples
+fn to_rgba(image: &Image) -> Vec<u16> {
+    let max = if image.depth == 16 { 0xffff } else { 0xff };
+    let samples: Vec<u16> =
+        if image.depth == 16 {
+            image.buf.chunks_exact(2).map(|s| u16::from_le_bytes([s[0], s[1]])).collect()
+        } else {
+            image.buf.iter().map(|s| *s as u16).collect()
+        };
+    let mut rgba = Vec::with_capacity(image.w as usize * image.h as usize * 4);
+    for pix in samples.chunks_exact(image.channels as usize) {
+        match pix.len() {
+            1 => rgba.extend_from_slice(&[pix[0], pix[0], pix[0], max]),
+            2 => rgba.extend_from_slice(&[pix[0], pix[0], pix[0], pix[1]]),
+            3 => rgba.extend_from_slice(&[pix[0], pix[1], pix[2], max]),
+            _ => rgba.extend_from_slice(pix),
+        }
+    }
+    rgba
+}
+
+// Non-premultiplied "over" operator from the APNG spec
+fn blend_over(src: &[u16], dst: &mut [u16], max: u64) {
+    let src_a = src[3] as u64;
+    if src_a == max {
+        dst.copy_from_slice(src);
+    } else if src_a != 0 {
+        let dst_a = dst[3] as u64 * (max - src_a) / max;
+        let out_a = src_a + dst_a;
+        for c in 0..3 {
+            dst[c] = ((src[c] as u64 * src_a + dst[c] as u64 * dst_a + out_a / 2) / out_a) as u16;
+        }
+        dst[3] = out_a as u16;
+    }
+}
+
+impl ApngDecoder<'_> {
+    pub fn num_frames(&self) -> u32 {
+        self.frames.len() as u32
+    }
+
+    /// 0 means infinite looping
+    pub fn num_plays(&self) -> u32 {
+        self.num_plays
+    }
+
+    /// Broken ancillary chunks that were left out and, with `lenient`, the errors decoding got past
+    pub fn warnings(&self) -> &[DecodingError] {
+        &self.png_image.warnings
+    }
+
+    fn region_rows(&self, frame: &FrameControl) -> impl Iterator<Item = std::ops::Range<usize>> + use<> {
+        let canvas_w = self.png_image.image.w as usize;
+        let (x, y, w, h) = (frame.x as usize, frame.y as usize, frame.w as usize, frame.h as usize);
+        (y..y + h).map(move |row| (row * canvas_w + x) * 4..(row * canvas_w + x + w) * 4)
+    }
+
+    fn dispose_previous_frame(&mut self) {
+        let Some((idx, saved)) = self.pending_dispose.take() else {
+            return;
+        };
+        let frame = &self.frames[idx];
+        let rows: Vec<_> = self.region_rows(frame).collect();
+        // DisposeOp::Previous on the first frame is treated as DisposeOp::Background
+        let dispose_op = if frame.dispose_op == DisposeOp::Previous && idx == 0 { DisposeOp::Background } else { frame.dispose_op };
+        match dispose_op {
+            DisposeOp::None => {},
+            DisposeOp::Background => rows.into_iter().for_each(|row| self.canvas[row].fill(0)),
+            DisposeOp::Previous => {
+                for (row, saved_row) in rows.into_iter().zip(saved.chunks_exact(frame.w as usize * 4)) {
+                    self.canvas[row].copy_from_slice(saved_row);
+                }
+            },
+        }
+    }
+
+    fn decode_frame(&mut self) -> Result<ApngFrame, DecodingError> {
+        self.dispose_previous_frame();
+
+        let idx = self.next_frame;
+        self.next_frame += 1;
+        let frame = &self.frames[idx];
+        let sub_image = decode_subimage(&self.png_image, frame.w, frame.h, &frame.data, self.verify_checksums, self.lenient)?;
+        self.png_image.warnings.extend(sub_image.warnings);
+        let src = to_rgba(&sub_image.image);
+
+        let rows: Vec<_> = self.region_rows(frame).collect();
+        let saved =
+            if frame.dispose_op == DisposeOp::Previous {
+                rows.iter().flat_map(|row| self.canvas[row.clone()].iter().copied()).collect()
+            } else {
+                vec![]
+            };
+
+        let max = if self.png_image.image.depth == 16 { 0xffff } else { 0xff };
+        for (row, src_row) in rows.into_iter().zip(src.chunks_exact(frame.w as usize * 4)) {
+            let dst_row = &mut self.canvas[row];
+            match frame.blend_op {
+                BlendOp::Source => dst_row.copy_from_slice(src_row),
+                BlendOp::Over => {
+                    for (src_pix, dst_pix) in src_row.chunks_exact(4).zip(dst_row.chunks_exact_mut(4)) {
+                        blend_over(src_pix, dst_pix, max);
+                    }
+                },
+            }
+        }
+        let (delay_num, delay_den) = (frame.delay_num, frame.delay_den);
+        self.pending_dispose = Some((idx, saved));
+
+        let buf =
+            if self.png_image.image.depth == 16 {
+                self.canvas.iter().flat_map(|s| s.to_le_bytes()).collect()
+            } else {
+                self.canvas.iter().map(|s| *s as u8).collect()
+            };
+        let mut image = Image {
+            w: self.png_image.image.w,
+            h: self.png_image.image.h,
+            channels: 4,
+            buf,
+            depth: self.png_image.image.depth,
+        };
+        if let Some(format) = self.output_format {
+            pixel_format::convert(&mut image, format);
+        }
+        Ok(ApngFrame {
+            image,
+            delay_num,
+            delay_den,
+        })
+    }
+}
+
+impl Iterator for ApngDecoder<'_> {
+    type Item = Result<ApngFrame, DecodingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_frame >= self.frames.len() {
+            return None;
+        }
+        let frame = self.decode_frame();
+        if frame.is_err() {
+            self.next_frame = self.frames.len(); // stop after the first error
+        }
+        Some(frame)
+    }
+}
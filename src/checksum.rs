@@ -0,0 +1,159 @@
+use crate::png_decoder::CRC_TABLE;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+// CRC_TABLES[k][i] is the CRC of byte i followed by k zero bytes, which lets crc32_update process 8 bytes at once
+const CRC_TABLES: [[u32; 256]; 8] = build_crc_tables();
+
+const fn build_crc_tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0; 256]; 8];
+    tables[0] = CRC_TABLE;
+    let mut k = 1;
+    while k < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev >> 8) ^ CRC_TABLE[(prev & 0xff) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+}
+
+// Updates the CRC register (without the initial and final inversion) with `buf`
+pub(crate) fn crc32_update(crc: u32, buf: &[u8]) -> u32 {
+    let t = &CRC_TABLES;
+    let mut crc = crc;
+    let mut chunks = buf.chunks_exact(8);
+    for chunk in &mut chunks {
+        let lo = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) ^ crc;
+        let hi = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        crc = t[7][(lo & 0xff) as usize] ^ t[6][((lo >> 8) & 0xff) as usize] ^ t[5][((lo >> 16) & 0xff) as usize] ^ t[4][(lo >> 24) as usize]
+            ^ t[3][(hi & 0xff) as usize] ^ t[2][((hi >> 8) & 0xff) as usize] ^ t[1][((hi >> 16) & 0xff) as usize] ^ t[0][(hi >> 24) as usize];
+    }
+    for byte in chunks.remainder() {
+        crc = CRC_TABLE[(crc as u8 ^ byte) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+pub(crate) fn adler32_update(adler: u32, buf: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        adler32_update_sse2(adler, buf)
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        adler32_update_scalar(adler, buf)
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn adler32_update_scalar(adler: u32, buf: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let mut a = adler & 0xffff;
+    let mut b = adler >> 16;
+    for block in buf.chunks(5552) { // largest block that can't overflow u32 before the modulo
+        for byte in block {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    b << 16 | a
+}
+
+// 16 bytes at a time: a byte adds to b once for itself and once for every byte after it, so b gets
+// the sums of the previous vectors 16 times and each byte of a vector weighted by its distance to the end
+#[cfg(target_arch = "x86_64")]
+fn adler32_update_sse2(adler: u32, buf: &[u8]) -> u32 {
+    const MOD: u64 = 65521;
+    let mut a = (adler & 0xffff) as u64;
+    let mut b = (adler >> 16) as u64;
+    for block in buf.chunks(5552 / 16 * 16) { // no lane overflows u32 before the modulo
+        let mut vectors = block.chunks_exact(16);
+        unsafe {
+            let zero = _mm_setzero_si128();
+            let weights_lo = _mm_setr_epi16(16, 15, 14, 13, 12, 11, 10, 9);
+            let weights_hi = _mm_setr_epi16(8, 7, 6, 5, 4, 3, 2, 1);
+            let mut sums = zero; // of the bytes
+            let mut prefix_sums = zero; // of `sums` before each vector
+            let mut weighted = zero;
+            for vector in &mut vectors {
+                let x = _mm_loadu_si128(vector.as_ptr() as *const __m128i);
+                prefix_sums = _mm_add_epi32(prefix_sums, sums);
+                sums = _mm_add_epi32(sums, _mm_sad_epu8(x, zero));
+                weighted = _mm_add_epi32(weighted, _mm_madd_epi16(_mm_unpacklo_epi8(x, zero), weights_lo));
+                weighted = _mm_add_epi32(weighted, _mm_madd_epi16(_mm_unpackhi_epi8(x, zero), weights_hi));
+            }
+            let hsum = |x: __m128i| std::mem::transmute::<__m128i, [u32; 4]>(x).iter().map(|lane| *lane as u64).sum::<u64>();
+            let len = (block.len() - vectors.remainder().len()) as u64;
+            b += len * a + 16 * hsum(prefix_sums) + hsum(weighted);
+            a += hsum(sums);
+        }
+        for byte in vectors.remainder() {
+            a += *byte as u64;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16 | a) as u32
+}
+
+// Adler-32 of two buffers from the Adler-32 of each and the length of the second one
+pub(crate) fn adler32_combine(adler1: u32, adler2: u32, len2: usize) -> u32 {
+    const MOD: u64 = 65521;
+    let rem = len2 as u64 % MOD;
+    let a1 = (adler1 & 0xffff) as u64;
+    let b1 = (adler1 >> 16) as u64;
+    let a2 = (adler2 & 0xffff) as u64;
+    let b2 = (adler2 >> 16) as u64;
+    let a = (a1 + a2 + MOD - 1) % MOD;
+    let b = (b1 + b2 + rem * a1 + MOD - rem) % MOD;
+    (b << 16 | a) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::adler32_combine;
+    use crate::checksum::adler32_update;
+    use crate::checksum::crc32_update;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32_update(0xffffffff, b"") ^ 0xffffffff, 0);
+        assert_eq!(crc32_update(0xffffffff, b"123456789") ^ 0xffffffff, 0xcbf43926);
+        let data: Vec<u8> = (0..1000u32).map(|i| ((i * i) >> 3) as u8).collect();
+        for split in [0, 1, 7, 8, 9, 500, 999] {
+            let crc = crc32_update(crc32_update(0xffffffff, &data[..split]), &data[split..]);
+            assert_eq!(crc, crc32_update(0xffffffff, &data));
+        }
+        let bytewise = data.iter().fold(0xffffffff, |crc, byte| crc32_update(crc, &[*byte]));
+        assert_eq!(bytewise, crc32_update(0xffffffff, &data));
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32_update(1, b"Wikipedia"), 0x11e60398);
+        assert_eq!(adler32_update(adler32_update(1, b"Wiki"), b"pedia"), 0x11e60398);
+        assert_eq!(adler32_combine(adler32_update(1, b"Wiki"), adler32_update(1, b"pedia"), 5), 0x11e60398);
+        assert_eq!(adler32_combine(adler32_update(1, b"Wikipedia"), 1, 0), 0x11e60398);
+        let data: Vec<u8> = (0..100000u32).map(|i| (i.wrapping_mul(i) >> 5) as u8).collect();
+        assert_eq!(adler32_combine(adler32_update(1, &data[..70000]), adler32_update(1, &data[70000..]), 30000), adler32_update(1, &data));
+        // against the definition, a byte at a time
+        let bytewise = |data: &[u8]| data.iter().fold((1u32, 0u32), |(a, b), byte| {
+            let a = (a + *byte as u32) % 65521;
+            (a, (b + a) % 65521)
+        });
+        for len in [0, 1, 15, 16, 17, 5535, 5536, 5537, 100000] {
+            let (a, b) = bytewise(&data[..len]);
+            assert_eq!(adler32_update(1, &data[..len]), b << 16 | a);
+        }
+        let ones = vec![0xff; 20000];
+        let (a, b) = bytewise(&ones);
+        assert_eq!(adler32_update(adler32_update(1, &ones[..7]), &ones[7..]), b << 16 | a);
+    }
+}
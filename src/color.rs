@@ -0,0 +1,379 @@
+use crate::DecodingError;
+use crate::ErrorKind;
+use crate::Image;
+use crate::png_decoder::PNGImage;
+
+pub mod icc;
+
+pub(crate) type Matrix = [[f64; 3]; 3];
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum TargetSpace {
+    Srgb,
+    LinearSrgb, // sRGB primaries and white point, linear transfer function
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum RenderingIntent {
+    Perceptual,
+    RelativeColorimetric,
+    Saturation,
+    AbsoluteColorimetric,
+}
+
+/// CIE xy chromaticities of the white point and the primaries, from cHRM
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Chromaticities {
+    pub white: (f64, f64),
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+}
+
+/// Coding-independent code points (ITU-T H.273) from cICP
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Cicp {
+    pub color_primaries: u8,
+    pub transfer_function: u8,
+    pub matrix_coefficients: u8,
+    pub full_range: bool,
+}
+
+pub const SRGB_CHROMATICITIES: Chromaticities = Chromaticities {
+    white: (0.3127, 0.3290),
+    red: (0.64, 0.33),
+    green: (0.30, 0.60),
+    blue: (0.15, 0.06),
+};
+
+const BT2020_CHROMATICITIES: Chromaticities = Chromaticities {
+    white: (0.3127, 0.3290),
+    red: (0.708, 0.292),
+    green: (0.170, 0.797),
+    blue: (0.131, 0.046),
+};
+
+const DCI_P3_CHROMATICITIES: Chromaticities = Chromaticities {
+    white: (0.314, 0.351),
+    red: (0.680, 0.320),
+    green: (0.265, 0.690),
+    blue: (0.150, 0.060),
+};
+
+const DISPLAY_P3_CHROMATICITIES: Chromaticities = Chromaticities {
+    white: (0.3127, 0.3290),
+    ..DCI_P3_CHROMATICITIES
+};
+
+const PQ_REFERENCE_WHITE: f64 = 203.0; // cd/m², maps to 1.0 (ITU-R BT.2408)
+const HLG_REFERENCE_WHITE: f64 = 0.26496256; // scene light of the 75% HLG signal, maps to 1.0
+
+// Encoded value in 0..=1 to linear light
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub(crate) enum TransferFunction {
+    Srgb,
+    Linear,
+    Gamma(f64), // linear = encoded ^ gamma
+    Bt709,
+    Pq,
+    Hlg,
+}
+
+impl TransferFunction {
+    pub(crate) fn of(target: TargetSpace) -> TransferFunction {
+        match target {
+            TargetSpace::Srgb => TransferFunction::Srgb,
+            TargetSpace::LinearSrgb => TransferFunction::Linear,
+        }
+    }
+
+    fn decode(self, v: f64) -> f64 {
+        match self {
+            TransferFunction::Srgb => if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) },
+            TransferFunction::Linear => v,
+            TransferFunction::Gamma(gamma) => v.powf(gamma),
+            TransferFunction::Bt709 => if v < 0.081 { v / 4.5 } else { ((v + 0.099) / 1.099).powf(1.0 / 0.45) },
+            TransferFunction::Pq => {
+                const M1: f64 = 2610.0 / 16384.0;
+                const M2: f64 = 2523.0 / 4096.0 * 128.0;
+                const C1: f64 = 3424.0 / 4096.0;
+                const C2: f64 = 2413.0 / 4096.0 * 32.0;
+                const C3: f64 = 2392.0 / 4096.0 * 32.0;
+                let p = v.powf(1.0 / M2);
+                let nits = ((p - C1).max(0.0) / (C2 - C3 * p)).powf(1.0 / M1) * 10000.0;
+                nits / PQ_REFERENCE_WHITE
+            },
+            TransferFunction::Hlg => {
+                const A: f64 = 0.17883277;
+                const B: f64 = 0.28466892;
+                const C: f64 = 0.55991073;
+                let scene = if v <= 0.5 { v * v / 3.0 } else { (((v - C) / A).exp() + B) / 12.0 };
+                scene / HLG_REFERENCE_WHITE
+            },
+        }
+    }
+
+    fn encode(self, v: f64) -> f64 {
+        match self {
+            TransferFunction::Srgb => if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 },
+            TransferFunction::Linear => v,
+            _ => unreachable!("only sRGB and linear are target transfer functions"),
+        }
+    }
+}
+
+// Color space of the decoded samples
+struct SourceSpace {
+    chromaticities: Chromaticities,
+    transfer: TransferFunction,
+    full_range: bool,
+}
+
+impl SourceSpace {
+    // cICP takes precedence over sRGB, which takes precedence over gAMA and cHRM.
+    // Images without any of them are assumed to be sRGB.
+    fn of(png_image: &PNGImage) -> Result<SourceSpace, DecodingError> {
+        if let Some(cicp) = png_image.cicp {
+            return SourceSpace::from_cicp(&cicp);
+        }
+        if png_image.srgb_intent.is_some() {
+            return Ok(SourceSpace { chromaticities: SRGB_CHROMATICITIES, transfer: TransferFunction::Srgb, full_range: true });
+        }
+        Ok(SourceSpace {
+            chromaticities: png_image.chromaticities.unwrap_or(SRGB_CHROMATICITIES),
+            transfer: match png_image.gamma() {
+                Some(gamma) => TransferFunction::Gamma(1.0 / gamma as f64),
+                None => TransferFunction::Srgb,
+            },
+            full_range: true,
+        })
+    }
+
+    fn from_cicp(cicp: &Cicp) -> Result<SourceSpace, DecodingError> {
+        if cicp.matrix_coefficients != 0 {
+            return Err(ErrorKind::InvalidChunkData.into()); // PNG only allows RGB
+        }
+        let chromaticities = match cicp.color_primaries {
+            1 => SRGB_CHROMATICITIES,
+            9 => BT2020_CHROMATICITIES,
+            11 => DCI_P3_CHROMATICITIES,
+            12 => DISPLAY_P3_CHROMATICITIES,
+            _ => return Err(ErrorKind::NotImplemented.into()),
+        };
+        let transfer = match cicp.transfer_function {
+            1 | 6 | 14 | 15 => TransferFunction::Bt709,
+            4 => TransferFunction::Gamma(2.2),
+            5 => TransferFunction::Gamma(2.8),
+            8 => TransferFunction::Linear,
+            13 => TransferFunction::Srgb,
+            16 => TransferFunction::Pq,
+            18 => TransferFunction::Hlg,
+            _ => return Err(ErrorKind::NotImplemented.into()),
+        };
+        Ok(SourceSpace { chromaticities, transfer, full_range: cicp.full_range })
+    }
+}
+
+pub(crate) fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    core::array::from_fn(|i| core::array::from_fn(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+pub(crate) fn mul_vec(a: &Matrix, v: [f64; 3]) -> [f64; 3] {
+    core::array::from_fn(|i| (0..3).map(|k| a[i][k] * v[k]).sum())
+}
+
+pub(crate) fn invert(m: &Matrix) -> Matrix {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    [
+        [cofactor(1, 2, 1, 2) / det, -cofactor(0, 2, 1, 2) / det, cofactor(0, 1, 1, 2) / det],
+        [-cofactor(1, 2, 0, 2) / det, cofactor(0, 2, 0, 2) / det, -cofactor(0, 1, 0, 2) / det],
+        [cofactor(1, 2, 0, 1) / det, -cofactor(0, 2, 0, 1) / det, cofactor(0, 1, 0, 1) / det],
+    ]
+}
+
+pub(crate) fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+// RGB to CIE XYZ, the white point maps to Y = 1
+pub(crate) fn rgb_to_xyz(chromaticities: &Chromaticities) -> Matrix {
+    let [r, g, b] = [chromaticities.red, chromaticities.green, chromaticities.blue].map(xy_to_xyz);
+    let primaries = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+    let scale = mul_vec(&invert(&primaries), xy_to_xyz(chromaticities.white));
+    core::array::from_fn(|i| core::array::from_fn(|j| primaries[i][j] * scale[j]))
+}
+
+// Bradford chromatic adaptation of XYZ values from one white point to another
+pub(crate) fn adaptation(from: [f64; 3], to: [f64; 3]) -> Matrix {
+    const BRADFORD: Matrix = [
+        [0.8951, 0.2664, -0.1614],
+        [-0.7502, 1.7135, 0.0367],
+        [0.0389, -0.0685, 1.0296],
+    ];
+    let from = mul_vec(&BRADFORD, from);
+    let to = mul_vec(&BRADFORD, to);
+    let scale = [[to[0] / from[0], 0.0, 0.0], [0.0, to[1] / from[1], 0.0], [0.0, 0.0, to[2] / from[2]]];
+    mul(&invert(&BRADFORD), &mul(&scale, &BRADFORD))
+}
+
+// Source RGB to linear sRGB
+fn rgb_to_srgb(chromaticities: &Chromaticities, intent: RenderingIntent) -> Matrix {
+    let src_white = xy_to_xyz(chromaticities.white);
+    let dst_white = xy_to_xyz(SRGB_CHROMATICITIES.white);
+    let to_xyz =
+        if intent == RenderingIntent::AbsoluteColorimetric {
+            rgb_to_xyz(chromaticities)
+        } else {
+            mul(&adaptation(src_white, dst_white), &rgb_to_xyz(chromaticities))
+        };
+    mul(&invert(&rgb_to_xyz(&SRGB_CHROMATICITIES)), &to_xyz)
+}
+
+// Per-pixel conversion: decode each color channel to linear light, mix with a matrix, encode for the target.
+// Alpha is left as is.
+pub(crate) struct Transform {
+    pub(crate) curves: Vec<Box<dyn Fn(f64) -> f64>>, // decoding of normalized samples, one for all channels or one per channel
+    pub(crate) matrix: Option<Matrix>, // linear RGB to linear sRGB, None for the identity
+    pub(crate) target: TransferFunction,
+}
+
+impl Transform {
+    fn new(source: &SourceSpace, intent: RenderingIntent, target: TargetSpace) -> Transform {
+        let transfer = source.transfer;
+        let full_range = source.full_range;
+        let to_linear = move |v: f64| {
+            let v = if full_range { v } else { ((v * 255.0 - 16.0) / 219.0).clamp(0.0, 1.0) };
+            transfer.decode(v)
+        };
+        Transform {
+            curves: vec![Box::new(to_linear)],
+            matrix: Transform::non_identity(rgb_to_srgb(&source.chromaticities, intent)),
+            target: TransferFunction::of(target),
+        }
+    }
+
+    pub(crate) fn non_identity(matrix: Matrix) -> Option<Matrix> {
+        let is_identity = (0..3).all(|i| (0..3).all(|j| (matrix[i][j] - (i == j) as u8 as f64).abs() < 1e-6));
+        if is_identity { None } else { Some(matrix) }
+    }
+
+    pub(crate) fn apply(&self, image: &mut Image) {
+        let channels = image.channels as usize;
+        let color_channels = if channels <= 2 { 1 } else { 3 };
+        let max_val = if image.depth == 16 { 65535 } else { 255 };
+        let decode_luts: Vec<Vec<f64>> = self.curves.iter()
+            .map(|curve| (0..=max_val).map(|v| curve(v as f64 / max_val as f64)).collect())
+            .collect();
+        let decode_lut = |c: usize| &decode_luts[std::cmp::min(c, decode_luts.len() - 1)];
+
+        if image.depth == 8 {
+            // linear values are quantized to 14 bits, which is plenty for 8-bit output
+            const ENCODE_LUT_SIZE: usize = 1 << 14;
+            let encode_lut: Vec<u8> = (0..ENCODE_LUT_SIZE).map(|i| {
+                (self.target.encode(i as f64 / (ENCODE_LUT_SIZE - 1) as f64) * 255.0).round() as u8
+            }).collect();
+            let encode = |v: f64| encode_lut[(v.clamp(0.0, 1.0) * (ENCODE_LUT_SIZE - 1) as f64).round() as usize];
+            match &self.matrix {
+                Some(matrix) if color_channels == 3 => {
+                    for pixel in image.buf.chunks_exact_mut(channels) {
+                        let rgb = mul_vec(matrix, [0, 1, 2].map(|c| decode_lut(c)[pixel[c] as usize]));
+                        (0..3).for_each(|c| pixel[c] = encode(rgb[c]));
+                    }
+                },
+                _ => {
+                    let luts: Vec<Vec<u8>> = (0..color_channels).map(|c| decode_lut(c).iter().map(|v| encode(*v)).collect()).collect();
+                    for pixel in image.buf.chunks_exact_mut(channels) {
+                        (0..color_channels).for_each(|c| pixel[c] = luts[c][pixel[c] as usize]);
+                    }
+                },
+            }
+        } else {
+            let encode = |v: f64| (self.target.encode(v.clamp(0.0, 1.0)) * 65535.0).round() as u16;
+            for pixel in image.buf.chunks_exact_mut(channels * 2) {
+                let mut samples = [0f64; 3];
+                for c in 0..color_channels {
+                    samples[c] = decode_lut(c)[u16::from_le_bytes([pixel[2 * c], pixel[2 * c + 1]]) as usize];
+                }
+                if color_channels == 3 && let Some(matrix) = &self.matrix {
+                    samples = mul_vec(matrix, samples);
+                }
+                for c in 0..color_channels {
+                    pixel[2 * c..2 * c + 2].copy_from_slice(&encode(samples[c]).to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Converts the decoded pixels from the color space described by cICP, iCCP, sRGB, gAMA and cHRM to `target`.
+/// iCCP is used if it's a matrix/TRC or gray TRC profile matching the image, other profiles fall back to the other chunks.
+/// The color chunks of `png_image` are updated to describe the result, so converting twice is harmless.
+pub fn convert(png_image: &mut PNGImage, target: TargetSpace) -> Result<(), DecodingError> {
+    let icc_transform = match (&png_image.cicp, &png_image.icc_profile) {
+        (None, Some(icc_profile)) => icc::Profile::parse(&icc_profile.data)
+            .and_then(|profile| profile.transform(png_image.image.channels, target))
+            .ok(),
+        _ => None,
+    };
+    let transform = match icc_transform {
+        Some(transform) => transform,
+        None => {
+            let source = SourceSpace::of(png_image)?;
+            let intent = png_image.srgb_intent.unwrap_or(RenderingIntent::RelativeColorimetric);
+            Transform::new(&source, intent, target)
+        },
+    };
+    transform.apply(&mut png_image.image);
+    png_image.set_color_space(target);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::SRGB_CHROMATICITIES;
+    use crate::color::RenderingIntent;
+    use crate::color::TransferFunction;
+    use crate::color::rgb_to_srgb;
+    use crate::color::rgb_to_xyz;
+
+    #[test]
+    fn test_srgb_matrix() {
+        // well-known sRGB to XYZ matrix
+        let expected = [[0.4124, 0.3576, 0.1805], [0.2126, 0.7152, 0.0722], [0.0193, 0.1192, 0.9505]];
+        let m = rgb_to_xyz(&SRGB_CHROMATICITIES);
+        (0..3).for_each(|i| (0..3).for_each(|j| assert!((m[i][j] - expected[i][j]).abs() < 1e-3)));
+
+        let identity = rgb_to_srgb(&SRGB_CHROMATICITIES, RenderingIntent::Perceptual);
+        (0..3).for_each(|i| (0..3).for_each(|j| assert!((identity[i][j] - (i == j) as u8 as f64).abs() < 1e-9)));
+    }
+
+    #[test]
+    fn test_transfer_functions() {
+        for tf in [TransferFunction::Srgb, TransferFunction::Linear] {
+            for i in 0..=100 {
+                let v = i as f64 / 100.0;
+                assert!((tf.encode(tf.decode(v)) - v).abs() < 1e-9);
+            }
+        }
+        assert!((TransferFunction::Pq.decode(0.58069) - 1.0).abs() < 1e-3); // 203 cd/m²
+        assert!((TransferFunction::Hlg.decode(0.75) - 1.0).abs() < 1e-6);
+    }
+}
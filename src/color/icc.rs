@@ -0,0 +1,343 @@
+use crate::Image;
+use crate::color::SRGB_CHROMATICITIES;
+use crate::color::TargetSpace;
+use crate::color::Transform;
+use crate::color::TransferFunction;
+use crate::color::adaptation;
+use crate::color::invert;
+use crate::color::mul;
+use crate::color::rgb_to_xyz;
+use crate::color::xy_to_xyz;
+
+const HEADER_SIZE: usize = 128;
+const PCS_WHITE: [f64; 3] = [0.9642, 1.0, 0.8249]; // D50
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum IccError {
+    Malformed,
+    LutBased, // A2B/B2A lookup tables without matrix/TRC tags
+    Unsupported, // version, color space or PCS that can't be converted, or one that doesn't match the image
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct TagEntry {
+    pub signature: [u8; 4],
+    offset: usize,
+    size: usize,
+}
+
+/// ICC profile header and tag table; tag data is parsed on demand
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Profile {
+    pub version: (u8, u8), // major, minor
+    pub device_class: [u8; 4], // e.g. b"mntr"
+    pub color_space: [u8; 4], // e.g. b"RGB " or b"GRAY"
+    pub pcs: [u8; 4], // b"XYZ " or b"Lab "
+    pub rendering_intent: u32,
+    pub tags: Vec<TagEntry>,
+    data: Vec<u8>,
+}
+
+/// Tone reproduction curve from a `curv` or `para` tag, maps device values in 0..=1 to linear PCS values
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Curve {
+    Identity,
+    Gamma(f64),
+    Sampled(Vec<f64>), // equally spaced over 0..=1
+    Parametric { function_type: u16, params: [f64; 7] }, // g, a, b, c, d, e, f
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, IccError> {
+    data.get(pos..pos + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])).ok_or(IccError::Malformed)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, IccError> {
+    data.get(pos..pos + 4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).ok_or(IccError::Malformed)
+}
+
+fn read_s15_fixed16(data: &[u8], pos: usize) -> Result<f64, IccError> {
+    Ok(read_u32(data, pos)? as i32 as f64 / 65536.0)
+}
+
+fn read_signature(data: &[u8], pos: usize) -> Result<[u8; 4], IccError> {
+    Ok(read_u32(data, pos)?.to_be_bytes())
+}
+
+impl Curve {
+    fn parse(tag: &[u8]) -> Result<Curve, IccError> {
+        match &read_signature(tag, 0)? {
+            b"curv" => {
+                let count = read_u32(tag, 8)? as usize;
+                match count {
+                    0 => Ok(Curve::Identity),
+                    1 => Ok(Curve::Gamma(read_u16(tag, 12)? as f64 / 256.0)),
+                    _ => {
+                        let samples = (0..count).map(|i| Ok(read_u16(tag, 12 + 2 * i)? as f64 / 65535.0));
+                        Ok(Curve::Sampled(samples.collect::<Result<_, IccError>>()?))
+                    },
+                }
+            },
+            b"para" => {
+                const PARAM_COUNTS: [usize; 5] = [1, 3, 4, 5, 7];
+                let function_type = read_u16(tag, 8)?;
+                let count = *PARAM_COUNTS.get(function_type as usize).ok_or(IccError::Malformed)?;
+                let mut params = [0.0; 7];
+                for (i, param) in params.iter_mut().enumerate().take(count) {
+                    *param = read_s15_fixed16(tag, 12 + 4 * i)?;
+                }
+                Ok(Curve::Parametric { function_type, params })
+            },
+            _ => Err(IccError::Malformed),
+        }
+    }
+
+    pub fn eval(&self, x: f64) -> f64 {
+        match self {
+            Curve::Identity => x,
+            Curve::Gamma(gamma) => x.powf(*gamma),
+            Curve::Sampled(samples) => {
+                let pos = x.clamp(0.0, 1.0) * (samples.len() - 1) as f64;
+                let i = std::cmp::min(pos as usize, samples.len() - 2);
+                let t = pos - i as f64;
+                samples[i] * (1.0 - t) + samples[i + 1] * t
+            },
+            Curve::Parametric { function_type, params } => {
+                let [g, a, b, c, d, e, f] = *params;
+                let pow = |x: f64| if x > 0.0 { x.powf(g) } else { 0.0 };
+                match function_type {
+                    0 => pow(x),
+                    1 => if x >= -b / a { pow(a * x + b) } else { 0.0 },
+                    2 => if x >= -b / a { pow(a * x + b) + c } else { c },
+                    3 => if x >= d { pow(a * x + b) } else { c * x },
+                    _ => if x >= d { pow(a * x + b) + e } else { c * x + f },
+                }
+            },
+        }
+    }
+}
+
+impl Profile {
+    pub fn parse(data: &[u8]) -> Result<Profile, IccError> {
+        let size = read_u32(data, 0)? as usize;
+        if size < HEADER_SIZE + 4 || size > data.len() || &data[36..40] != b"acsp" {
+            return Err(IccError::Malformed);
+        }
+        let data = &data[..size];
+        let tag_count = read_u32(data, HEADER_SIZE)? as usize;
+        let mut tags = Vec::with_capacity(std::cmp::min(tag_count, size / 12));
+        for i in 0..tag_count {
+            let pos = HEADER_SIZE + 4 + 12 * i;
+            let entry = TagEntry {
+                signature: read_signature(data, pos)?,
+                offset: read_u32(data, pos + 4)? as usize,
+                size: read_u32(data, pos + 8)? as usize,
+            };
+            if entry.offset.checked_add(entry.size).is_none_or(|end| end > size) {
+                return Err(IccError::Malformed);
+            }
+            tags.push(entry);
+        }
+        Ok(Profile {
+            version: (data[8], data[9] >> 4),
+            device_class: read_signature(data, 12)?,
+            color_space: read_signature(data, 16)?,
+            pcs: read_signature(data, 20)?,
+            rendering_intent: read_u32(data, 64)?,
+            tags,
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn tag(&self, signature: &[u8; 4]) -> Option<&[u8]> {
+        self.tags.iter()
+            .find(|entry| &entry.signature == signature)
+            .map(|entry| &self.data[entry.offset..entry.offset + entry.size])
+    }
+
+    pub fn curve(&self, signature: &[u8; 4]) -> Result<Curve, IccError> {
+        Curve::parse(self.tag(signature).ok_or(self.missing_tag_error())?)
+    }
+
+    fn xyz(&self, signature: &[u8; 4]) -> Result<[f64; 3], IccError> {
+        let tag = self.tag(signature).ok_or(self.missing_tag_error())?;
+        if &read_signature(tag, 0)? != b"XYZ " {
+            return Err(IccError::Malformed);
+        }
+        Ok([read_s15_fixed16(tag, 8)?, read_s15_fixed16(tag, 12)?, read_s15_fixed16(tag, 16)?])
+    }
+
+    // Profiles without matrix/TRC tags are usually LUT-based
+    fn missing_tag_error(&self) -> IccError {
+        if [b"A2B0", b"A2B1", b"A2B2", b"B2A0"].iter().any(|signature| self.tag(signature).is_some()) {
+            IccError::LutBased
+        } else {
+            IccError::Malformed
+        }
+    }
+
+    pub(crate) fn transform(&self, channels: u32, target: TargetSpace) -> Result<Transform, IccError> {
+        if !(2..=4).contains(&self.version.0) {
+            return Err(IccError::Unsupported);
+        }
+        let is_gray_image = channels <= 2;
+        let curves: Vec<Curve>;
+        let mut matrix = None;
+        match &self.color_space {
+            b"RGB " if !is_gray_image => {
+                if &self.pcs != b"XYZ " {
+                    return Err(if self.missing_tag_error() == IccError::LutBased { IccError::LutBased } else { IccError::Unsupported });
+                }
+                curves = vec![self.curve(b"rTRC")?, self.curve(b"gTRC")?, self.curve(b"bTRC")?];
+                let [r, g, b] = [self.xyz(b"rXYZ")?, self.xyz(b"gXYZ")?, self.xyz(b"bXYZ")?];
+                let colorants = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+                let pcs_to_srgb = mul(&invert(&rgb_to_xyz(&SRGB_CHROMATICITIES)), &adaptation(PCS_WHITE, xy_to_xyz(SRGB_CHROMATICITIES.white)));
+                matrix = Transform::non_identity(mul(&pcs_to_srgb, &colorants));
+            },
+            b"GRAY" if is_gray_image => curves = vec![self.curve(b"kTRC")?],
+            _ => return Err(IccError::Unsupported),
+        }
+        Ok(Transform {
+            curves: curves.into_iter().map(|curve| Box::new(move |x| curve.eval(x)) as Box<dyn Fn(f64) -> f64>).collect(),
+            matrix,
+            target: TransferFunction::of(target),
+        })
+    }
+
+    /// Converts `image` (in the color space of this profile) to `target`
+    pub fn convert(&self, image: &mut Image, target: TargetSpace) -> Result<(), IccError> {
+        self.transform(image.channels, target)?.apply(image);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Image;
+    use crate::color::TargetSpace;
+    use crate::color::icc::Curve;
+    use crate::color::icc::IccError;
+    use crate::color::icc::Profile;
+
+    fn s15_fixed16(val: f64) -> [u8; 4] {
+        ((val * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+        [b"XYZ \0\0\0\0".to_vec(), xyz.iter().flat_map(|v| s15_fixed16(*v)).collect()].concat()
+    }
+
+    fn curv_tag(samples: &[u16]) -> Vec<u8> {
+        let mut tag = b"curv\0\0\0\0".to_vec();
+        tag.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+        samples.iter().for_each(|sample| tag.extend_from_slice(&sample.to_be_bytes()));
+        tag
+    }
+
+    fn para_tag(function_type: u16, params: &[f64]) -> Vec<u8> {
+        let mut tag = b"para\0\0\0\0".to_vec();
+        tag.extend_from_slice(&function_type.to_be_bytes());
+        tag.extend_from_slice(&[0, 0]);
+        params.iter().for_each(|param| tag.extend_from_slice(&s15_fixed16(*param)));
+        tag
+    }
+
+    fn build_profile(version: u8, color_space: &[u8; 4], tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut header = vec![0; 128];
+        header[8] = version;
+        header[12..16].copy_from_slice(b"mntr");
+        header[16..20].copy_from_slice(color_space);
+        header[20..24].copy_from_slice(b"XYZ ");
+        header[36..40].copy_from_slice(b"acsp");
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = vec![];
+        let data_start = 128 + 4 + 12 * tags.len();
+        for (signature, tag) in tags {
+            table.extend_from_slice(*signature);
+            table.extend_from_slice(&((data_start + data.len()) as u32).to_be_bytes());
+            table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+        let mut profile = [header, table, data].concat();
+        let size = profile.len() as u32;
+        profile[..4].copy_from_slice(&size.to_be_bytes());
+        profile
+    }
+
+    // sRGB colorants adapted to D50, as found in the common sRGB profiles
+    fn srgb_matrix_tags(trc: Vec<u8>) -> Vec<(&'static [u8; 4], Vec<u8>)> {
+        vec![
+            (b"rXYZ", xyz_tag([0.4361, 0.2225, 0.0139])),
+            (b"gXYZ", xyz_tag([0.3851, 0.7169, 0.0971])),
+            (b"bXYZ", xyz_tag([0.1431, 0.0606, 0.7141])),
+            (b"rTRC", trc.clone()),
+            (b"gTRC", trc.clone()),
+            (b"bTRC", trc),
+        ]
+    }
+
+    fn rgb_ramp() -> Image {
+        let buf = (0..=255u8).flat_map(|v| [v, v / 2, 255 - v]).collect();
+        Image { w: 256, h: 1, channels: 3, buf, depth: 8 }
+    }
+
+    #[test]
+    fn test_srgb_v4_parametric() {
+        let srgb_curve = para_tag(3, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045]);
+        let data = build_profile(4, b"RGB ", &srgb_matrix_tags(srgb_curve));
+        let profile = Profile::parse(&data).unwrap();
+        assert_eq!(profile.version, (4, 0));
+        assert_eq!(&profile.color_space, b"RGB ");
+        assert_eq!(profile.tags.len(), 6);
+
+        let mut image = rgb_ramp();
+        profile.convert(&mut image, TargetSpace::Srgb).unwrap();
+        for (a, b) in image.buf.iter().zip(&rgb_ramp().buf) {
+            assert!(a.abs_diff(*b) <= 1);
+        }
+    }
+
+    #[test]
+    fn test_v2_sampled_and_gamma_curves() {
+        let linear = build_profile(2, b"RGB ", &srgb_matrix_tags(curv_tag(&[0, 65535])));
+        let mut image = rgb_ramp();
+        Profile::parse(&linear).unwrap().convert(&mut image, TargetSpace::LinearSrgb).unwrap();
+        for (a, b) in image.buf.iter().zip(&rgb_ramp().buf) {
+            assert!(a.abs_diff(*b) <= 1);
+        }
+
+        let gray = build_profile(2, b"GRAY", &[(b"kTRC", curv_tag(&[512]))]); // gamma 2.0
+        let profile = Profile::parse(&gray).unwrap();
+        assert_eq!(profile.curve(b"kTRC").unwrap(), Curve::Gamma(2.0));
+        let mut image = Image { w: 3, h: 1, channels: 1, buf: vec![0, 128, 255], depth: 8 };
+        profile.convert(&mut image, TargetSpace::LinearSrgb).unwrap();
+        assert_eq!(image.buf, vec![0, 64, 255]);
+
+        // gray profile doesn't fit an RGB image
+        assert_eq!(profile.convert(&mut rgb_ramp(), TargetSpace::Srgb), Err(IccError::Unsupported));
+    }
+
+    #[test]
+    fn test_errors() {
+        let lut = build_profile(2, b"RGB ", &[(b"A2B0", b"mft2\0\0\0\0".to_vec())]);
+        assert_eq!(Profile::parse(&lut).unwrap().convert(&mut rgb_ramp(), TargetSpace::Srgb), Err(IccError::LutBased));
+
+        let cmyk = build_profile(2, b"CMYK", &[]);
+        assert_eq!(Profile::parse(&cmyk).unwrap().convert(&mut rgb_ramp(), TargetSpace::Srgb), Err(IccError::Unsupported));
+
+        let mut bad_signature = build_profile(2, b"RGB ", &[]);
+        bad_signature[36] = b'x';
+        assert_eq!(Profile::parse(&bad_signature).err(), Some(IccError::Malformed));
+
+        let truncated = build_profile(2, b"RGB ", &srgb_matrix_tags(curv_tag(&[])));
+        assert_eq!(Profile::parse(&truncated[..truncated.len() - 1]).err(), Some(IccError::Malformed));
+
+        let missing_trc = build_profile(2, b"RGB ", &srgb_matrix_tags(curv_tag(&[]))[..5]);
+        assert_eq!(Profile::parse(&missing_trc).unwrap().convert(&mut rgb_ramp(), TargetSpace::Srgb), Err(IccError::Malformed));
+    }
+}
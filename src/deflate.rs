@@ -0,0 +1,556 @@
+use crate::checksum;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+pub const NO_COMPRESSION: u8 = 0;
+pub const BEST_SPEED: u8 = 1;
+pub const DEFAULT_COMPRESSION: u8 = 6;
+pub const BEST_COMPRESSION: u8 = 9;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const WINDOW_SIZE: usize = 1 << 15;
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+const HASH_BITS: u32 = 15;
+const NO_POS: u32 = u32::MAX;
+const MAX_STORED_LEN: usize = 65535;
+const BLOCK_TOKENS: usize = 1 << 14; // tokens per Huffman block
+
+const LEN_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LEN_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+const CL_INDEX_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// Match finder parameters of a compression level, the same trade-offs zlib makes
+struct LevelParams {
+    max_chain: u32, // hash chain entries to visit
+    good_len: usize, // a match at least this long cuts the search of the next one to a quarter
+    nice_len: usize, // stop searching once a match is at least this long
+    max_lazy: usize, // try a match at the next position if the current one is shorter; 0 means greedy matching
+}
+
+const LEVELS: [LevelParams; 9] = [
+    LevelParams { max_chain: 4, good_len: 4, nice_len: 8, max_lazy: 0 },
+    LevelParams { max_chain: 8, good_len: 4, nice_len: 16, max_lazy: 0 },
+    LevelParams { max_chain: 32, good_len: 4, nice_len: 32, max_lazy: 0 },
+    LevelParams { max_chain: 16, good_len: 4, nice_len: 16, max_lazy: 4 },
+    LevelParams { max_chain: 32, good_len: 8, nice_len: 32, max_lazy: 16 },
+    LevelParams { max_chain: 128, good_len: 8, nice_len: 128, max_lazy: 16 },
+    LevelParams { max_chain: 256, good_len: 8, nice_len: 128, max_lazy: 32 },
+    LevelParams { max_chain: 1024, good_len: 32, nice_len: 258, max_lazy: 128 },
+    LevelParams { max_chain: 4096, good_len: 32, nice_len: 258, max_lazy: 258 },
+];
+
+#[derive(Clone)]
+#[derive(Copy)]
+enum Token {
+    Literal(u8),
+    Match { len: u16, dist: u16 },
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    buf: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, bits: u32, count: u32) {
+        self.buf |= (bits as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.buf as u8);
+            self.buf >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn align_to_byte(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+}
+
+struct MatchFinder {
+    head: Vec<u32>, // last position with the given hash
+    prev: Vec<u32>, // previous position with the same hash, indexed by position modulo the window size
+}
+
+impl MatchFinder {
+    fn new() -> MatchFinder {
+        MatchFinder {
+            head: vec![NO_POS; 1 << HASH_BITS],
+            prev: vec![NO_POS; WINDOW_SIZE],
+        }
+    }
+
+    fn hash(data: &[u8], pos: usize) -> usize {
+        let val = (data[pos] as u32) | (data[pos + 1] as u32) << 8 | (data[pos + 2] as u32) << 16;
+        (val.wrapping_mul(0x9e3779b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    // Adds `pos` to its hash chain and returns the previous head of the chain
+    fn insert(&mut self, data: &[u8], pos: usize) -> u32 {
+        if pos + MIN_MATCH > data.len() {
+            return NO_POS;
+        }
+        let hash = MatchFinder::hash(data, pos);
+        let candidate = self.head[hash];
+        self.prev[pos & WINDOW_MASK] = candidate;
+        self.head[hash] = pos as u32;
+        candidate
+    }
+
+    // Longest match for `pos` among the chain starting at `candidate`, returns (length, distance)
+    fn longest_match(&self, data: &[u8], pos: usize, candidate: u32, prev_len: usize, params: &LevelParams) -> (usize, usize) {
+        let max_len = std::cmp::min(MAX_MATCH, data.len() - pos);
+        let mut chain = if prev_len >= params.good_len { params.max_chain / 4 } else { params.max_chain };
+        let mut best = (0, 0);
+        let mut candidate = candidate;
+        while candidate != NO_POS && chain > 0 {
+            let cand = candidate as usize;
+            if pos - cand > WINDOW_SIZE {
+                break;
+            }
+            let best_len = best.0;
+            if best_len < max_len && data[cand + best_len] == data[pos + best_len] {
+                let len = data[cand..cand + max_len].iter().zip(&data[pos..pos + max_len]).take_while(|(a, b)| a == b).count();
+                if len > best_len {
+                    best = (len, pos - cand);
+                    if len >= params.nice_len || len == max_len {
+                        break;
+                    }
+                }
+            }
+            chain -= 1;
+            let next = self.prev[cand & WINDOW_MASK];
+            if next == NO_POS || next as usize >= cand {
+                break; // the slot was reused by a newer position
+            }
+            candidate = next;
+        }
+        if best.0 < MIN_MATCH { (0, 0) } else { best }
+    }
+}
+
+fn tokenize(data: &[u8], params: &LevelParams) -> Vec<Token> {
+    let mut tokens = Vec::with_capacity(data.len() / 2);
+    let mut finder = MatchFinder::new();
+    let mut pos = 0;
+    let mut pending: Option<(usize, usize)> = None; // match found at pos - 1, deferred by lazy matching
+    while pos < data.len() {
+        let candidate = finder.insert(data, pos);
+        let prev_len = pending.map_or(0, |(len, _)| len);
+        let (len, dist) =
+            if pos + MIN_MATCH <= data.len() {
+                finder.longest_match(data, pos, candidate, prev_len, params)
+            } else {
+                (0, 0)
+            };
+        if let Some((prev_len, prev_dist)) = pending {
+            if len > prev_len {
+                tokens.push(Token::Literal(data[pos - 1]));
+                pending = Some((len, dist));
+                pos += 1;
+            } else {
+                tokens.push(Token::Match { len: prev_len as u16, dist: prev_dist as u16 });
+                let end = pos - 1 + prev_len;
+                for p in pos + 1..end {
+                    finder.insert(data, p);
+                }
+                pending = None;
+                pos = end;
+            }
+            continue;
+        }
+        if len >= MIN_MATCH {
+            if len < params.max_lazy && pos + 1 < data.len() {
+                pending = Some((len, dist));
+                pos += 1;
+                continue;
+            }
+            tokens.push(Token::Match { len: len as u16, dist: dist as u16 });
+            for p in pos + 1..pos + len {
+                finder.insert(data, p);
+            }
+            pos += len;
+        } else {
+            tokens.push(Token::Literal(data[pos]));
+            pos += 1;
+        }
+    }
+    if let Some((len, dist)) = pending {
+        tokens.push(Token::Match { len: len as u16, dist: dist as u16 });
+    }
+    tokens
+}
+
+fn len_code(len: usize) -> usize {
+    LEN_BASE.partition_point(|base| *base as usize <= len) - 1
+}
+
+fn dist_code(dist: usize) -> usize {
+    DIST_BASE.partition_point(|base| *base as usize <= dist) - 1
+}
+
+// Huffman code lengths for the given symbol frequencies, no longer than `max_len` bits.
+// At least two symbols get a code so that the code is always complete.
+fn code_lengths(freqs: &[u32], max_len: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    let mut used = freqs.iter().filter(|freq| **freq != 0).count();
+    for freq in freqs.iter_mut() {
+        if used >= 2 {
+            break;
+        }
+        if *freq == 0 {
+            *freq = 1;
+            used += 1;
+        }
+    }
+    loop {
+        let lens = huffman_code_lengths(&freqs);
+        if lens.iter().all(|len| *len <= max_len) {
+            return lens;
+        }
+        // flatten the distribution until the tree is shallow enough
+        freqs.iter_mut().filter(|freq| **freq != 0).for_each(|freq| *freq = freq.div_ceil(2));
+    }
+}
+
+fn huffman_code_lengths(freqs: &[u32]) -> Vec<u8> {
+    let mut parent: Vec<usize> = vec![usize::MAX; freqs.len()];
+    let mut heap = BinaryHeap::new();
+    for (sym, freq) in freqs.iter().enumerate() {
+        if *freq != 0 {
+            heap.push(Reverse((*freq as u64, sym)));
+        }
+    }
+    while heap.len() > 1 {
+        let Reverse((freq_a, a)) = heap.pop().unwrap();
+        let Reverse((freq_b, b)) = heap.pop().unwrap();
+        let node = parent.len();
+        parent.push(usize::MAX);
+        parent[a] = node;
+        parent[b] = node;
+        heap.push(Reverse((freq_a + freq_b, node)));
+    }
+    let mut depths = vec![0u8; parent.len()];
+    for node in (0..parent.len()).rev() { // parents are created after their children
+        if parent[node] != usize::MAX {
+            depths[node] = depths[parent[node]] + 1;
+        }
+    }
+    depths.truncate(freqs.len());
+    depths
+}
+
+// Canonical codes for the given lengths, bit-reversed since deflate writes them starting from the MSB
+fn canonical_codes(lens: &[u8]) -> Vec<u16> {
+    let mut bl_count = [0u16; 16];
+    lens.iter().filter(|len| **len != 0).for_each(|len| bl_count[*len as usize] += 1);
+    let mut next_code = [0u16; 16];
+    let mut code = 0;
+    for bits in 1..16 {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+    lens.iter().map(|len| {
+        if *len == 0 {
+            return 0;
+        }
+        let code = next_code[*len as usize];
+        next_code[*len as usize] += 1;
+        code.reverse_bits() >> (16 - len)
+    }).collect()
+}
+
+// Code lengths of the literal/length and distance alphabets, run-length encoded with symbols 16, 17 and 18.
+// Returns (symbol, extra bits value) pairs.
+fn rle_code_lengths(lens: &[u8]) -> Vec<(u8, u8)> {
+    let mut res = vec![];
+    let mut i = 0;
+    while i < lens.len() {
+        let len = lens[i];
+        let mut run = lens[i..].iter().take_while(|l| **l == len).count();
+        i += run;
+        if len == 0 {
+            while run >= 11 {
+                let reps = std::cmp::min(run, 138);
+                res.push((18, (reps - 11) as u8));
+                run -= reps;
+            }
+            if run >= 3 {
+                res.push((17, (run - 3) as u8));
+                run = 0;
+            }
+        } else {
+            res.push((len, 0));
+            run -= 1;
+            while run >= 3 {
+                let reps = std::cmp::min(run, 6);
+                res.push((16, (reps - 3) as u8));
+                run -= reps;
+            }
+        }
+        res.extend(std::iter::repeat_n((len, 0), run));
+    }
+    res
+}
+
+fn cl_extra_bits(sym: u8) -> u32 {
+    match sym {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+struct DynamicHeader {
+    lit_lens: Vec<u8>,
+    dist_lens: Vec<u8>,
+    cl_lens: Vec<u8>,
+    rle: Vec<(u8, u8)>,
+    hclen: usize,
+}
+
+impl DynamicHeader {
+    fn new(lit_freqs: &[u32; 286], dist_freqs: &[u32; 30]) -> DynamicHeader {
+        let mut lit_lens = code_lengths(lit_freqs, 15);
+        let mut dist_lens = code_lengths(dist_freqs, 15);
+        let hlit = std::cmp::max(lit_lens.iter().rposition(|len| *len != 0).unwrap_or(0) + 1, 257);
+        let hdist = std::cmp::max(dist_lens.iter().rposition(|len| *len != 0).unwrap_or(0) + 1, 1);
+        lit_lens.truncate(hlit);
+        dist_lens.truncate(hdist);
+
+        let rle = rle_code_lengths(&[lit_lens.as_slice(), dist_lens.as_slice()].concat());
+        let mut cl_freqs = [0u32; 19];
+        rle.iter().for_each(|(sym, _)| cl_freqs[*sym as usize] += 1);
+        let cl_lens = code_lengths(&cl_freqs, 7);
+        let hclen = std::cmp::max(CL_INDEX_ORDER.iter().rposition(|sym| cl_lens[*sym] != 0).unwrap_or(0) + 1, 4);
+        DynamicHeader { lit_lens, dist_lens, cl_lens, rle, hclen }
+    }
+
+    fn bits(&self) -> u64 {
+        let rle_bits: u64 = self.rle.iter().map(|(sym, _)| (self.cl_lens[*sym as usize] as u32 + cl_extra_bits(*sym)) as u64).sum();
+        5 + 5 + 4 + 3 * self.hclen as u64 + rle_bits
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write((self.lit_lens.len() - 257) as u32, 5);
+        writer.write((self.dist_lens.len() - 1) as u32, 5);
+        writer.write((self.hclen - 4) as u32, 4);
+        for sym in &CL_INDEX_ORDER[..self.hclen] {
+            writer.write(self.cl_lens[*sym] as u32, 3);
+        }
+        let cl_codes = canonical_codes(&self.cl_lens);
+        for (sym, extra) in &self.rle {
+            writer.write(cl_codes[*sym as usize] as u32, self.cl_lens[*sym as usize] as u32);
+            writer.write(*extra as u32, cl_extra_bits(*sym));
+        }
+    }
+}
+
+fn fixed_lit_lens() -> Vec<u8> {
+    (0..288).map(|i| {
+        match i {
+            _ if i <= 143 => 8,
+            _ if i <= 255 => 9,
+            _ if i <= 279 => 7,
+            _ => 8,
+        }
+    }).collect()
+}
+
+// Size in bits of the Huffman-coded tokens, not counting the block header
+fn tokens_bits(lit_freqs: &[u32; 286], dist_freqs: &[u32; 30], lit_lens: &[u8], dist_lens: &[u8]) -> u64 {
+    let lit_bits: u64 = lit_freqs.iter().enumerate().map(|(sym, freq)| {
+        let extra = if sym > 256 { LEN_EXTRA[sym - 257] as u64 } else { 0 };
+        *freq as u64 * (lit_lens.get(sym).copied().unwrap_or(0) as u64 + extra)
+    }).sum();
+    let dist_bits: u64 = dist_freqs.iter().enumerate().map(|(sym, freq)| {
+        *freq as u64 * (dist_lens.get(sym).copied().unwrap_or(0) as u64 + DIST_EXTRA[sym] as u64)
+    }).sum();
+    lit_bits + dist_bits
+}
+
+fn write_tokens(writer: &mut BitWriter, tokens: &[Token], lit_lens: &[u8], dist_lens: &[u8]) {
+    let lit_codes = canonical_codes(lit_lens);
+    let dist_codes = canonical_codes(dist_lens);
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => writer.write(lit_codes[byte as usize] as u32, lit_lens[byte as usize] as u32),
+            Token::Match { len, dist } => {
+                let lc = len_code(len as usize);
+                writer.write(lit_codes[257 + lc] as u32, lit_lens[257 + lc] as u32);
+                writer.write((len - LEN_BASE[lc]) as u32, LEN_EXTRA[lc] as u32);
+                let dc = dist_code(dist as usize);
+                writer.write(dist_codes[dc] as u32, dist_lens[dc] as u32);
+                writer.write((dist - DIST_BASE[dc]) as u32, DIST_EXTRA[dc] as u32);
+            },
+        }
+    }
+    writer.write(lit_codes[256] as u32, lit_lens[256] as u32);
+}
+
+fn write_stored(writer: &mut BitWriter, data: &[u8], last: bool) {
+    let blocks: Vec<&[u8]> = if data.is_empty() { vec![&[]] } else { data.chunks(MAX_STORED_LEN).collect() };
+    for (i, block) in blocks.iter().enumerate() {
+        writer.write((last && i + 1 == blocks.len()) as u32, 3); // BTYPE = 0
+        writer.align_to_byte();
+        writer.out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        writer.out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        writer.out.extend_from_slice(block);
+    }
+}
+
+// Writes the tokens covering `data` as the cheapest of stored, fixed Huffman and dynamic Huffman blocks
+fn write_block(writer: &mut BitWriter, tokens: &[Token], data: &[u8], last: bool) {
+    let mut lit_freqs = [0u32; 286];
+    let mut dist_freqs = [0u32; 30];
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => lit_freqs[byte as usize] += 1,
+            Token::Match { len, dist } => {
+                lit_freqs[257 + len_code(len as usize)] += 1;
+                dist_freqs[dist_code(dist as usize)] += 1;
+            },
+        }
+    }
+    lit_freqs[256] = 1;
+
+    let header = DynamicHeader::new(&lit_freqs, &dist_freqs);
+    let dynamic_bits = header.bits() + tokens_bits(&lit_freqs, &dist_freqs, &header.lit_lens, &header.dist_lens);
+    let fixed_lens = fixed_lit_lens();
+    let fixed_dist_lens = [5u8; 30];
+    let fixed_bits = tokens_bits(&lit_freqs, &dist_freqs, &fixed_lens, &fixed_dist_lens);
+    let stored_bits = (data.len() as u64 + 5 * data.len().div_ceil(MAX_STORED_LEN).max(1) as u64) * 8;
+
+    if stored_bits <= fixed_bits.min(dynamic_bits) {
+        write_stored(writer, data, last);
+    } else if fixed_bits <= dynamic_bits {
+        writer.write(last as u32 | 1 << 1, 3);
+        write_tokens(writer, tokens, &fixed_lens, &fixed_dist_lens);
+    } else {
+        writer.write(last as u32 | 2 << 1, 3);
+        header.write(writer);
+        write_tokens(writer, tokens, &header.lit_lens, &header.dist_lens);
+    }
+}
+
+/// Compresses `data` into a raw DEFLATE stream.
+/// `level` goes from `NO_COMPRESSION` (stored blocks only) to `BEST_COMPRESSION`, larger values are treated as 9.
+pub fn compress(data: &[u8], level: u8) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: Vec::with_capacity(data.len() / 2 + 64),
+        buf: 0,
+        count: 0,
+    };
+    if level == NO_COMPRESSION {
+        write_stored(&mut writer, data, true);
+        return writer.out;
+    }
+
+    let params = &LEVELS[std::cmp::min(level, BEST_COMPRESSION) as usize - 1];
+    let tokens = tokenize(data, params);
+    let blocks: Vec<&[Token]> = if tokens.is_empty() { vec![&[]] } else { tokens.chunks(BLOCK_TOKENS).collect() };
+    let mut data_pos = 0;
+    for (i, block) in blocks.iter().enumerate() {
+        let block_len: usize = block.iter().map(|token| match token {
+            Token::Literal(_) => 1,
+            Token::Match { len, .. } => *len as usize,
+        }).sum();
+        write_block(&mut writer, block, &data[data_pos..data_pos + block_len], i + 1 == blocks.len());
+        data_pos += block_len;
+    }
+    writer.align_to_byte();
+    writer.out
+}
+
+/// Compresses `data` into a zlib stream, see `compress` for the meaning of `level`
+pub fn zlib_compress(data: &[u8], level: u8) -> Vec<u8> {
+    let cmf: u8 = 0x78; // deflate with a 32K window
+    let flevel: u8 = match level {
+        0 | 1 => 0,
+        2..=5 => 1,
+        6 => 2,
+        _ => 3,
+    };
+    let flg = flevel << 6;
+    let flg = flg + (31 - ((cmf as u16) << 8 | flg as u16) % 31) as u8 % 31;
+    let mut out = vec![cmf, flg];
+    out.extend_from_slice(&compress(data, level));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    checksum::adler32_update(1, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deflate::adler32;
+    use crate::deflate::code_lengths;
+    use crate::deflate::compress;
+    use crate::deflate::zlib_compress;
+    use crate::inflate::inflate;
+    use crate::inflate::zlib_decompress;
+
+    fn test_inputs() -> Vec<Vec<u8>> {
+        let mut state: u32 = 1;
+        let mut noise = || {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        };
+        let text = b"the quick brown fox jumps over the lazy dog; ".repeat(2000);
+        let random: Vec<u8> = (0..100000).map(|_| noise()).collect();
+        let mixed: Vec<u8> = (0..200000).map(|i| if i % 1000 < 700 { (i % 7) as u8 } else { noise() & 3 }).collect();
+        vec![vec![], vec![42], vec![0; 100000], b"abcabcabcabcabca".to_vec(), text, random, mixed]
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn test_round_trip_all_levels() {
+        for data in test_inputs() {
+            for level in 0..=10 {
+                let compressed = compress(&data, level);
+                assert_eq!(inflate(&compressed).unwrap(), data, "level {}, {} bytes", level, data.len());
+                let zlib = zlib_compress(&data, level);
+                assert_eq!(((zlib[0] as u16) << 8 | zlib[1] as u16) % 31, 0);
+                assert_eq!(zlib_decompress(&zlib).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_levels_compress() {
+        let text = b"the quick brown fox jumps over the lazy dog; ".repeat(2000);
+        let stored = compress(&text, 0).len();
+        let fast = compress(&text, 1).len();
+        let best = compress(&text, 9).len();
+        assert!(stored > text.len());
+        assert!(fast < text.len() / 20);
+        assert!(best <= fast);
+    }
+
+    #[test]
+    fn test_code_lengths_are_limited() {
+        // Fibonacci frequencies produce a maximally deep Huffman tree
+        let mut freqs = vec![1u32, 1];
+        while freqs.len() < 30 {
+            freqs.push(freqs[freqs.len() - 1] + freqs[freqs.len() - 2]);
+        }
+        let lens = code_lengths(&freqs, 15);
+        assert!(lens.iter().all(|len| *len >= 1 && *len <= 15));
+        let kraft: f64 = lens.iter().map(|len| 0.5f64.powi(*len as i32)).sum();
+        assert!(kraft <= 1.0);
+    }
+}
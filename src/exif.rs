@@ -0,0 +1,375 @@
+use crate::DecodingError;
+use crate::ErrorKind;
+
+// IFD0 tags
+pub const MAKE: u16 = 0x010f;
+pub const MODEL: u16 = 0x0110;
+pub const ORIENTATION: u16 = 0x0112;
+pub const X_RESOLUTION: u16 = 0x011a;
+pub const Y_RESOLUTION: u16 = 0x011b;
+pub const RESOLUTION_UNIT: u16 = 0x0128;
+pub const SOFTWARE: u16 = 0x0131;
+pub const DATE_TIME: u16 = 0x0132;
+pub const ARTIST: u16 = 0x013b;
+pub const COPYRIGHT: u16 = 0x8298;
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const GPS_IFD_POINTER: u16 = 0x8825;
+
+// EXIF IFD tags
+pub const EXPOSURE_TIME: u16 = 0x829a;
+pub const F_NUMBER: u16 = 0x829d;
+pub const ISO_SPEED: u16 = 0x8827;
+pub const DATE_TIME_ORIGINAL: u16 = 0x9003;
+pub const FOCAL_LENGTH: u16 = 0x920a;
+pub const COLOR_SPACE: u16 = 0xa001;
+pub const PIXEL_X_DIMENSION: u16 = 0xa002;
+pub const PIXEL_Y_DIMENSION: u16 = 0xa003;
+const INTEROP_IFD_POINTER: u16 = 0xa005;
+
+// GPS IFD tags
+pub const GPS_LATITUDE_REF: u16 = 0x0001;
+pub const GPS_LATITUDE: u16 = 0x0002;
+pub const GPS_LONGITUDE_REF: u16 = 0x0003;
+pub const GPS_LONGITUDE: u16 = 0x0004;
+pub const GPS_ALTITUDE_REF: u16 = 0x0005;
+pub const GPS_ALTITUDE: u16 = 0x0006;
+
+const MAX_IFDS: usize = 16;
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum ByteOrder {
+    LittleEndian, // "II"
+    BigEndian, // "MM"
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum Ifd {
+    Primary, // IFD0, describes the main image
+    Thumbnail, // IFD1
+    Exif,
+    Gps,
+    Interop,
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Rational {
+    pub num: u32,
+    pub den: u32,
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct SRational {
+    pub num: i32,
+    pub den: i32,
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum Value {
+    Byte(Vec<u8>),
+    Ascii(String), // up to the first NUL
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<Rational>),
+    SByte(Vec<i8>),
+    Undefined(Vec<u8>),
+    SShort(Vec<i16>),
+    SLong(Vec<i32>),
+    SRational(Vec<SRational>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Field {
+    pub ifd: Ifd,
+    pub tag: u16,
+    pub value: Value,
+}
+
+/// Tags of a TIFF structure as embedded by PNG eXIf, JPEG APP1, WebP EXIF and others
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Exif {
+    pub byte_order: ByteOrder,
+    pub fields: Vec<Field>, // in file order, sub-IFD pointers are not included
+}
+
+impl Value {
+    /// Unsigned integer value at `index` of a BYTE, SHORT or LONG field
+    pub fn as_u32(&self, index: usize) -> Option<u32> {
+        match self {
+            Value::Byte(values) => values.get(index).map(|v| *v as u32),
+            Value::Short(values) => values.get(index).map(|v| *v as u32),
+            Value::Long(values) => values.get(index).copied(),
+            _ => None,
+        }
+    }
+
+    /// Numeric value at `index`, rationals are divided out
+    pub fn as_f64(&self, index: usize) -> Option<f64> {
+        match self {
+            Value::Rational(values) => values.get(index).map(|v| v.num as f64 / v.den as f64),
+            Value::SRational(values) => values.get(index).map(|v| v.num as f64 / v.den as f64),
+            Value::SByte(values) => values.get(index).map(|v| *v as f64),
+            Value::SShort(values) => values.get(index).map(|v| *v as f64),
+            Value::SLong(values) => values.get(index).map(|v| *v as f64),
+            Value::Float(values) => values.get(index).map(|v| *v as f64),
+            Value::Double(values) => values.get(index).copied(),
+            _ => self.as_u32(index).map(|v| v as f64),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Ascii(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+struct TiffReader<'a> {
+    data: &'a [u8],
+    byte_order: ByteOrder,
+}
+
+impl TiffReader<'_> {
+    fn bytes<const N: usize>(&self, pos: usize) -> Result<[u8; N], DecodingError> {
+        let bytes: [u8; N] = self.data.get(pos..pos + N).ok_or(ErrorKind::InvalidChunkData)?.try_into().unwrap();
+        Ok(match self.byte_order {
+            ByteOrder::LittleEndian => bytes,
+            ByteOrder::BigEndian => {
+                let mut bytes = bytes;
+                bytes.reverse();
+                bytes
+            },
+        })
+    }
+
+    fn u16(&self, pos: usize) -> Result<u16, DecodingError> {
+        Ok(u16::from_le_bytes(self.bytes(pos)?))
+    }
+
+    fn u32(&self, pos: usize) -> Result<u32, DecodingError> {
+        Ok(u32::from_le_bytes(self.bytes(pos)?))
+    }
+
+    fn u64(&self, pos: usize) -> Result<u64, DecodingError> {
+        Ok(u64::from_le_bytes(self.bytes(pos)?))
+    }
+
+    // Reads `count` values of `size` bytes each, stored inline when they fit into 4 bytes
+    fn values<T>(&self, entry: usize, count: usize, size: usize, read: impl Fn(usize) -> Result<T, DecodingError>) -> Result<Vec<T>, DecodingError> {
+        let len = count.checked_mul(size).ok_or(ErrorKind::InvalidChunkData)?;
+        let start = if len <= 4 { entry + 8 } else { self.u32(entry + 8)? as usize };
+        if start.checked_add(len).is_none_or(|end| end > self.data.len()) {
+            return Err(ErrorKind::InvalidChunkData.into());
+        }
+        (0..count).map(|i| read(start + i * size)).collect()
+    }
+
+    // None for unknown field types, which readers are supposed to skip
+    fn value(&self, entry: usize) -> Result<Option<Value>, DecodingError> {
+        let count = self.u32(entry + 4)? as usize;
+        let byte = |pos: usize| Ok(self.data[pos]);
+        let rational = |pos: usize| Ok(Rational { num: self.u32(pos)?, den: self.u32(pos + 4)? });
+        let srational = |pos: usize| Ok(SRational { num: self.u32(pos)? as i32, den: self.u32(pos + 4)? as i32 });
+        Ok(Some(match self.u16(entry + 2)? {
+            1 => Value::Byte(self.values(entry, count, 1, byte)?),
+            2 => {
+                let bytes = self.values(entry, count, 1, byte)?;
+                let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+                Value::Ascii(String::from_utf8_lossy(&bytes[..end]).into_owned())
+            },
+            3 => Value::Short(self.values(entry, count, 2, |pos| self.u16(pos))?),
+            4 => Value::Long(self.values(entry, count, 4, |pos| self.u32(pos))?),
+            5 => Value::Rational(self.values(entry, count, 8, rational)?),
+            6 => Value::SByte(self.values(entry, count, 1, |pos| Ok(self.data[pos] as i8))?),
+            7 => Value::Undefined(self.values(entry, count, 1, byte)?),
+            8 => Value::SShort(self.values(entry, count, 2, |pos| Ok(self.u16(pos)? as i16))?),
+            9 => Value::SLong(self.values(entry, count, 4, |pos| Ok(self.u32(pos)? as i32))?),
+            10 => Value::SRational(self.values(entry, count, 8, srational)?),
+            11 => Value::Float(self.values(entry, count, 4, |pos| Ok(f32::from_bits(self.u32(pos)?)))?),
+            12 => Value::Double(self.values(entry, count, 8, |pos| Ok(f64::from_bits(self.u64(pos)?)))?),
+            _ => return Ok(None),
+        }))
+    }
+}
+
+impl Exif {
+    /// Parses a TIFF header and its IFDs, an "Exif\0\0" prefix as used by JPEG APP1 is skipped
+    pub fn parse(data: &[u8]) -> Result<Exif, DecodingError> {
+        let data = data.strip_prefix(b"Exif\0\0").unwrap_or(data);
+        let byte_order = match data.get(..4) {
+            Some(b"II*\0") => ByteOrder::LittleEndian,
+            Some(b"MM\0*") => ByteOrder::BigEndian,
+            _ => return Err(ErrorKind::InvalidChunkData.into()),
+        };
+        let reader = TiffReader { data, byte_order };
+        let mut exif = Exif { byte_order, fields: vec![] };
+        let mut pending = vec![(Ifd::Primary, reader.u32(4)? as usize)];
+        let mut visited = vec![];
+        while let Some((ifd, offset)) = pending.pop() {
+            if visited.contains(&offset) || visited.len() == MAX_IFDS {
+                return Err(ErrorKind::InvalidChunkData.into()); // loops between IFDs
+            }
+            visited.push(offset);
+            let entry_count = reader.u16(offset)? as usize;
+            for i in 0..entry_count {
+                let entry = offset + 2 + 12 * i;
+                let tag = reader.u16(entry)?;
+                let sub_ifd = match (ifd, tag) {
+                    (Ifd::Primary, EXIF_IFD_POINTER) => Some(Ifd::Exif),
+                    (Ifd::Primary, GPS_IFD_POINTER) => Some(Ifd::Gps),
+                    (Ifd::Exif, INTEROP_IFD_POINTER) => Some(Ifd::Interop),
+                    _ => None,
+                };
+                let Some(value) = reader.value(entry)? else {
+                    continue;
+                };
+                match sub_ifd {
+                    Some(sub_ifd) => pending.push((sub_ifd, value.as_u32(0).ok_or(ErrorKind::InvalidChunkData)? as usize)),
+                    None => exif.fields.push(Field { ifd, tag, value }),
+                }
+            }
+            let next = reader.u32(offset + 2 + 12 * entry_count)? as usize;
+            if ifd == Ifd::Primary && next != 0 {
+                pending.push((Ifd::Thumbnail, next));
+            }
+        }
+        Ok(exif)
+    }
+
+    pub fn get(&self, ifd: Ifd, tag: u16) -> Option<&Value> {
+        self.fields.iter().find(|field| field.ifd == ifd && field.tag == tag).map(|field| &field.value)
+    }
+
+    /// Orientation of the primary image, 1..=8 as in TIFF: 1 is upright, 6 needs a clockwise rotation by 90°
+    pub fn orientation(&self) -> Option<u16> {
+        let orientation = self.get(Ifd::Primary, ORIENTATION)?.as_u32(0)?;
+        (1..=8).contains(&orientation).then_some(orientation as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ErrorKind;
+    use crate::exif;
+    use crate::exif::ByteOrder;
+    use crate::exif::Exif;
+    use crate::exif::Ifd;
+    use crate::exif::Rational;
+    use crate::exif::Value;
+
+    struct TiffWriter {
+        byte_order: ByteOrder,
+    }
+
+    impl TiffWriter {
+        fn u16(&self, v: u16) -> Vec<u8> {
+            match self.byte_order {
+                ByteOrder::LittleEndian => v.to_le_bytes().to_vec(),
+                ByteOrder::BigEndian => v.to_be_bytes().to_vec(),
+            }
+        }
+
+        fn u32(&self, v: u32) -> Vec<u8> {
+            match self.byte_order {
+                ByteOrder::LittleEndian => v.to_le_bytes().to_vec(),
+                ByteOrder::BigEndian => v.to_be_bytes().to_vec(),
+            }
+        }
+
+        // IFD at `start` followed by the values that don't fit into the entries
+        fn ifd(&self, start: usize, entries: &[(u16, u16, u32, Vec<u8>)], next: u32) -> Vec<u8> {
+            let mut ifd = self.u16(entries.len() as u16);
+            let mut data = vec![];
+            let data_start = start + 2 + 12 * entries.len() + 4;
+            for (tag, field_type, count, value) in entries {
+                ifd.extend(self.u16(*tag));
+                ifd.extend(self.u16(*field_type));
+                ifd.extend(self.u32(*count));
+                if value.len() <= 4 {
+                    ifd.extend(value);
+                    ifd.resize(ifd.len() + 4 - value.len(), 0);
+                } else {
+                    ifd.extend(self.u32((data_start + data.len()) as u32));
+                    data.extend(value);
+                }
+            }
+            ifd.extend(self.u32(next));
+            [ifd, data].concat()
+        }
+
+        fn tiff(&self) -> Vec<u8> {
+            let header = match self.byte_order {
+                ByteOrder::LittleEndian => b"II*\0".to_vec(),
+                ByteOrder::BigEndian => b"MM\0*".to_vec(),
+            };
+            let rational = |num, den| [self.u32(num), self.u32(den)].concat();
+            let ifd0 = |exif_offset: u32, gps_offset: u32| self.ifd(8, &[
+                (exif::MAKE, 2, 6, b"Canon\0".to_vec()),
+                (exif::ORIENTATION, 3, 1, self.u16(6)),
+                (exif::EXIF_IFD_POINTER, 4, 1, self.u32(exif_offset)),
+                (exif::GPS_IFD_POINTER, 4, 1, self.u32(gps_offset)),
+                (0x1234, 99, 1, vec![1, 2, 3, 4]), // unknown type
+            ], 0);
+            let exif_offset = 8 + ifd0(0, 0).len();
+            let exif_ifd = self.ifd(exif_offset, &[
+                (exif::EXPOSURE_TIME, 5, 1, rational(1, 250)),
+                (exif::ISO_SPEED, 3, 1, self.u16(400)),
+            ], 0);
+            let gps_offset = exif_offset + exif_ifd.len();
+            let gps_ifd = self.ifd(gps_offset, &[
+                (exif::GPS_LATITUDE_REF, 2, 2, b"N\0".to_vec()),
+                (exif::GPS_LATITUDE, 5, 3, [rational(52, 1), rational(31, 1), rational(1234, 100)].concat()),
+            ], 0);
+            [header, self.u32(8), ifd0(exif_offset as u32, gps_offset as u32), exif_ifd, gps_ifd].concat()
+        }
+    }
+
+    #[test]
+    fn test_byte_orders() {
+        for byte_order in [ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let exif = Exif::parse(&TiffWriter { byte_order }.tiff()).unwrap();
+            assert_eq!(exif.byte_order, byte_order);
+            assert_eq!(exif.fields.len(), 6);
+            assert_eq!(exif.get(Ifd::Primary, exif::MAKE).unwrap().as_str(), Some("Canon"));
+            assert_eq!(exif.orientation(), Some(6));
+            assert_eq!(exif.get(Ifd::Exif, exif::EXPOSURE_TIME), Some(&Value::Rational(vec![Rational { num: 1, den: 250 }])));
+            assert_eq!(exif.get(Ifd::Exif, exif::ISO_SPEED).unwrap().as_u32(0), Some(400));
+            assert_eq!(exif.get(Ifd::Primary, exif::ISO_SPEED), None);
+            assert_eq!(exif.get(Ifd::Gps, exif::GPS_LATITUDE_REF).unwrap().as_str(), Some("N"));
+            assert_eq!(exif.get(Ifd::Gps, exif::GPS_LATITUDE).unwrap().as_f64(2), Some(12.34));
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        let writer = TiffWriter { byte_order: ByteOrder::BigEndian };
+        let tiff = writer.tiff();
+        assert_eq!(Exif::parse(&[b"Exif\0\0".as_slice(), &tiff].concat()), Exif::parse(&tiff));
+        assert_eq!(Exif::parse(&tiff[..tiff.len() - 1]).map_err(|err| err.kind), Err(ErrorKind::InvalidChunkData));
+        assert_eq!(Exif::parse(b"MM\0+\0\0\0\x08").map_err(|err| err.kind), Err(ErrorKind::InvalidChunkData));
+
+        // IFD1 pointing back at IFD0
+        let looped = [b"MM\0*".to_vec(), writer.u32(8), writer.ifd(8, &[(exif::ORIENTATION, 3, 1, writer.u16(1))], 8)].concat();
+        assert_eq!(Exif::parse(&looped).map_err(|err| err.kind), Err(ErrorKind::InvalidChunkData));
+    }
+}
@@ -0,0 +1,1064 @@
+use crate::DecodingError;
+use crate::ErrorKind;
+use crate::checksum;
+
+fn build_initiabl_codes_by_cls<const N: usize>(cls: &[u8]) -> Result<[u16; N], DecodingError> {
+    let mut bl_count: [u16; N] = [0; N];
+    cls.iter()
+        .for_each(|val| bl_count[*val as usize] += 1);
+    bl_count[0] = 0;
+    let mut next_code: [u16; N] = [0; N];
+    for bits in 1..N {
+        next_code[bits] = (next_code[bits - 1] + bl_count[bits - 1] as u16) << 1;
+        if next_code[bits] + bl_count[bits] > (1 << bits) {
+            return Err(ErrorKind::InvalidHuffmanTable.into()); // oversubscribed code
+        }
+    }
+    Ok(next_code)
+}
+
+const HUFF_FAST_BITS: u8 = 9;
+struct HuffmanTables {
+    fast: [u16; 1 << HUFF_FAST_BITS],
+    slow: [u16; 32768],
+}
+
+impl HuffmanTables {
+    fn new() -> HuffmanTables {
+        HuffmanTables {
+            fast: [0; 1 << HUFF_FAST_BITS],
+            slow: [0; 32768]
+        }
+    }
+
+    fn update(&mut self, cls: &[u8]) -> Result<(), DecodingError> {
+        let mut next_code = build_initiabl_codes_by_cls::<16>(cls)?;
+        let mut slow_table_ids = [0xffffu16; 1 << HUFF_FAST_BITS];
+        let mut n_slow_tables = 0;
+        // codes of an earlier block that this one doesn't have mustn't be found
+        self.fast.fill(0xffff);
+        self.slow.fill(0);
+        for n in 0..cls.len() {
+            let len = cls[n];
+            if len != 0 {
+                let code = (next_code[len as usize].reverse_bits() >> (16 - len)) as usize;
+                let sym_len = n as u16 | ((len as u16) << 9);
+
+                if len <= HUFF_FAST_BITS {
+                    for c in (code..1 << HUFF_FAST_BITS).step_by(1 << len) {
+                        self.fast[c] = sym_len;
+                    }
+                } else {
+                    let lo = code & ((1 << HUFF_FAST_BITS) - 1);
+                    let hi = code >> HUFF_FAST_BITS;
+                    let mut slow_table_id = slow_table_ids[lo];
+                    if slow_table_id == 0xffff {
+                        slow_table_id = n_slow_tables;
+                        slow_table_ids[lo] = slow_table_id;
+                        n_slow_tables += 1;
+                    }
+                    self.fast[lo] = slow_table_id;
+                    let slow_table_offset = slow_table_id * (1 << (15 - HUFF_FAST_BITS));
+                    for c in (hi..1 << (15 - HUFF_FAST_BITS)).step_by(1 << (len - HUFF_FAST_BITS)) {
+                        self.slow[slow_table_offset as usize + c] = sym_len;
+                    }
+                }
+
+                next_code[len as usize] += 1;
+            }
+        }
+        // println!("n_slow_tables: {n_slow_tables}");
+        // the rest of the codes lead to an empty slow table, where the length is 0
+        for packed in self.fast.iter_mut().filter(|packed| **packed == 0xffff) {
+            *packed = n_slow_tables;
+        }
+
+        // sym/small_table_id: 9 bits
+        // code_len: 1..15 (4 bits), 0 - slow path
+        Ok(())
+    }
+
+    fn lookup_sym_len(&self, code: u16) -> (u16, u8) {
+        let packed = self.fast[code as usize & ((1 << HUFF_FAST_BITS) - 1)];
+        let (sym, len) = unpack_sym_len(packed);
+        if len != 0 {
+            (sym, len)
+        } else {
+            let hi = code >> HUFF_FAST_BITS;
+            let slow_table_offset = packed * (1 << (15 - HUFF_FAST_BITS));
+            unpack_sym_len(self.slow[slow_table_offset as usize + hi as usize])
+        }
+    }
+}
+
+fn build_huffman_lut<const N: usize, const M: usize>(cls: &[u8]) -> Result<[u16; M], DecodingError> {
+    assert_eq!(M, 1 << (N - 1)); // TODO: wait for #![feature(generic_const_exprs)]
+    let mut next_code = build_initiabl_codes_by_cls::<N>(cls)?;
+    let mut huff = [0; M];
+    for n in 0..cls.len() {
+        let len = cls[n];
+        if len != 0 {
+            let code = (next_code[len as usize].reverse_bits() >> (16 - len)) as usize;
+            let sym_len = n as u16 | ((len as u16) << 9);
+
+            for c in (code..M).step_by(1 << len) {
+                huff[c] = sym_len;
+            }
+
+            next_code[len as usize] += 1;
+        }
+    }
+
+    // sym: 9 bits
+    // code_len: 1..15 (4 bits)
+
+    Ok(huff)
+}
+
+fn unpack_sym_len(packed: u16) -> (u16, u8) {
+    (packed & 0x1ff, (packed >> 9) as u8)
+}
+
+const MAX_HLIT: usize = 286;
+const LEN_OFFSETS: [u8; 20] = [11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227];
+const DIST_OFFSETS: [u16; 26] = [5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const MAX_HDIST: usize = 32;
+const CLS_MAX: usize = (MAX_HLIT + 257) + (MAX_HDIST + 1);
+
+
+// Decodes code lengths of the literal/length and distance alphabets of a dynamic block
+fn decode_cls<I: InflateInput>(bits: &mut BitBuf, input: &mut I, huff: &[u16; 128], max_symbol: usize) -> Result<[u8; CLS_MAX], DecodingError> {
+    let mut cls: [u8; CLS_MAX] = [0; CLS_MAX];
+    const OFFSETS: [usize; 3] = [3, 3, 11];
+    const EXTRA_BITS: [u32; 3] = [2, 3, 7];
+    let mut i: usize = 0;
+    while i < max_symbol {
+        input.refill(bits)?;
+        let code = bits.peek(7);
+        let (cl, len) = unpack_sym_len(huff[code as usize]);
+        if len == 0 {
+            return Err(ErrorKind::InvalidHuffmanTable.into()); // code is not in the table
+        }
+        bits.skip(len as u32)?;
+        if cl <= 15 {
+            cls[i] = cl as u8;
+            i += 1;
+        } else {
+            let idx = cl as usize - 16;
+            let reps = bits.read(EXTRA_BITS[idx])? as usize + OFFSETS[idx];
+            let till = i + reps;
+            if till > max_symbol {
+                return Err(ErrorKind::InvalidHuffmanTable.into());
+            }
+            let val =
+                if cl == 16 {
+                    if i == 0 {
+                        return Err(ErrorKind::InvalidHuffmanTable.into());
+                    }
+                    cls[i - 1]
+                } else { 0 };
+            cls[i..till].fill(val);
+            i = till;
+        }
+    }
+
+    Ok(cls)
+}
+
+const WINDOW_SIZE: usize = 1 << 15;
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum Format {
+    Raw, // bare DEFLATE stream
+    Zlib,
+    Gzip,
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+enum InflateState {
+    Header,
+    BlockHeader,
+    Stored { len_left: u16 },
+    Huffman,
+    Trailer,
+    Done,
+}
+
+// Why `InflateCore::run` returned
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub(crate) enum Status {
+    NeedInput, // the input ended in the middle of the stream, decoding resumes with more of it
+    Stopped, // the output doesn't need more bytes
+    Done, // the end of the stream and its trailer were decoded
+}
+
+enum Symbol {
+    Literal(u8),
+    EndOfBlock,
+    Match { len: usize, dist: usize },
+}
+
+// Bits read ahead from the input, the next one is the lowest
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Default)]
+pub(crate) struct BitBuf {
+    buf: u64,
+    bits_left: u32,
+}
+
+impl BitBuf {
+    // Appends the lowest `count` bytes of `data`, there must be room for them
+    #[inline(always)]
+    pub(crate) fn append(&mut self, data: u64, count: u32) {
+        let mask =
+            if count == 0 {
+                0
+            } else {
+                0xffffffffffffffff >> (64 - count * 8)
+            };
+        self.buf |= (data & mask) << self.bits_left;
+        self.bits_left += count * 8;
+    }
+
+    pub(crate) fn bits_left(&self) -> u32 {
+        self.bits_left
+    }
+
+    #[inline(always)]
+    fn read(&mut self, count: u32) -> Result<u64, DecodingError> {
+        if self.bits_left < count {
+            Err(ErrorKind::Truncated.into())
+        } else {
+            self.bits_left -= count;
+            let res = self.buf & ((1 << count) - 1); // generates BZHI instruction with enabled bmi2
+            self.buf >>= count;
+            Ok(res)
+        }
+    }
+
+    #[inline(always)]
+    fn peek(&self, count: u32) -> u64 {
+        self.buf & ((1 << count) - 1)
+    }
+
+    #[inline(always)]
+    fn skip(&mut self, count: u32) -> Result<(), DecodingError> {
+        if self.bits_left < count {
+            Err(ErrorKind::Truncated.into())
+        } else {
+            self.bits_left -= count;
+            self.buf >>= count;
+            Ok(())
+        }
+    }
+
+    fn align_to_byte(&mut self) {
+        let skip = self.bits_left % 8;
+        self.buf >>= skip;
+        self.bits_left -= skip;
+    }
+}
+
+// Where the compressed bytes of `InflateCore` come from
+pub(crate) trait InflateInput {
+    type Checkpoint: Copy;
+
+    // Appends whole bytes to `bits` until it has at least 57 bits or the input runs out
+    fn refill(&mut self, bits: &mut BitBuf) -> Result<(), DecodingError>;
+    // Whether more input can follow once this runs out. The core stops at the last complete symbol or header
+    // then, instead of failing with `ErrorKind::Truncated`.
+    fn resumable(&self) -> bool;
+    // Position that a cut off header is read again from
+    fn checkpoint(&self) -> Self::Checkpoint;
+    fn rollback(&mut self, checkpoint: Self::Checkpoint);
+}
+
+/// Where the inflated bytes of `InflateCore` go: the core writes them to the buffer from the cursor to the end
+/// and gets the next buffer once it's full.
+///
+/// # Safety
+/// The buffers must be valid for writes from the cursor to the end, which must be after the cursor.
+pub(crate) unsafe trait InflateOutput {
+    // Cursor and end of the current buffer
+    fn buf(&mut self) -> (*mut u8, *const u8);
+    // Takes the full buffer, returns cursor and end of the next one
+    fn next(&mut self) -> Result<(*mut u8, *const u8), DecodingError>;
+    // Keeps the cursor of the current buffer when the core returns
+    fn pause(&mut self, cursor: *mut u8);
+    // True once no more bytes are needed
+    fn stopped(&self) -> bool;
+}
+
+// Compressed data that arrives in slices, see `Leftover`
+pub(crate) struct SliceInput<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl SliceInput<'_> {
+    // True if all the bytes are read
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+impl InflateInput for SliceInput<'_> {
+    type Checkpoint = usize;
+
+    #[inline(always)]
+    fn refill(&mut self, bits: &mut BitBuf) -> Result<(), DecodingError> {
+        if bits.bits_left >= 57 {
+            return Ok(());
+        }
+        let count = std::cmp::min((64 - bits.bits_left) / 8, (self.data.len() - self.pos) as u32);
+        let data = if self.pos + 8 <= self.data.len() {
+            u64::from_le_bytes(self.data[self.pos..self.pos + 8].try_into().unwrap())
+        } else {
+            let mut bytes = [0; 8];
+            let tail = &self.data[self.pos..];
+            bytes[..tail.len()].copy_from_slice(tail);
+            u64::from_le_bytes(bytes)
+        };
+        bits.append(data, count);
+        self.pos += count as usize;
+        Ok(())
+    }
+
+    fn resumable(&self) -> bool {
+        true
+    }
+
+    fn checkpoint(&self) -> usize {
+        self.pos
+    }
+
+    fn rollback(&mut self, checkpoint: usize) {
+        self.pos = checkpoint;
+    }
+}
+
+// Bytes of the last slice that the core reads again with the next one, after a header was cut off
+#[derive(Default)]
+pub(crate) struct Leftover {
+    bytes: Vec<u8>,
+}
+
+impl Leftover {
+    // Runs `inflate` on the leftover bytes followed by `data` and keeps the bytes it didn't read
+    pub(crate) fn feed<R>(&mut self, data: &[u8], inflate: impl FnOnce(&mut SliceInput) -> R) -> R {
+        let mut bytes = std::mem::take(&mut self.bytes);
+        let data =
+            if bytes.is_empty() {
+                data
+            } else {
+                bytes.extend_from_slice(data);
+                &bytes[..]
+            };
+        let mut input = SliceInput { data, pos: 0 };
+        let result = inflate(&mut input);
+        self.bytes = input.data[input.pos..].to_vec();
+        result
+    }
+}
+
+#[inline(always)]
+fn read_bits<I: InflateInput>(bits: &mut BitBuf, input: &mut I, count: u32) -> Result<u64, DecodingError> {
+    if bits.bits_left < count {
+        input.refill(bits)?;
+    }
+    bits.read(count)
+}
+
+// Writes a byte to the output and moves on to the next buffer when it's full
+#[inline(always)]
+fn put<O: InflateOutput>(output: &mut O, cursor: &mut *mut u8, end: &mut *const u8, byte: u8) -> Result<(), DecodingError> {
+    unsafe {
+        **cursor = byte;
+        *cursor = cursor.add(1);
+    }
+    if std::ptr::eq(*cursor, *end) {
+        (*cursor, *end) = output.next()?;
+    }
+    Ok(())
+}
+
+// DEFLATE decoder behind `Inflater` and the PNG image data. It keeps its state between `run` calls, so the input
+// can arrive in pieces, and writes the inflated bytes straight to an `InflateOutput`. Checking the checksum in
+// the trailer is up to the caller.
+pub(crate) struct InflateCore {
+    format: Format,
+    state: InflateState,
+    bfinal: bool,
+    bits: BitBuf,
+    huff_lit_len: Box<HuffmanTables>,
+    huff_dist: Box<HuffmanTables>,
+    window: Box<[u8; WINDOW_SIZE]>,
+    window_pos: usize,
+    produced: usize, // bytes inflated so far, matches can't reach before the first one
+    trailer: [u32; 2], // Adler-32 of zlib, CRC-32 and size of gzip
+}
+
+impl InflateCore {
+    pub(crate) fn new(format: Format) -> InflateCore {
+        InflateCore {
+            format,
+            state: if format == Format::Raw { InflateState::BlockHeader } else { InflateState::Header },
+            bfinal: false,
+            bits: BitBuf::default(),
+            huff_lit_len: Box::new(HuffmanTables::new()),
+            huff_dist: Box::new(HuffmanTables::new()),
+            window: Box::new([0; WINDOW_SIZE]),
+            window_pos: 0,
+            produced: 0,
+            trailer: [0; 2],
+        }
+    }
+
+    // Starts at the first block, for a zlib stream that continues one before it
+    pub(crate) fn skip_header(&mut self) {
+        self.state = InflateState::BlockHeader;
+    }
+
+    // Starts over with the next member of a gzip file
+    fn next_member(&mut self) {
+        self.state = InflateState::Header;
+        self.produced = 0;
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.state == InflateState::Done
+    }
+
+    // The words after the end of the stream, valid once it's done
+    pub(crate) fn trailer(&self) -> [u32; 2] {
+        self.trailer
+    }
+
+    // Bits read ahead from the input, after the trailer they belong to whatever follows the stream
+    pub(crate) fn bits_left(&self) -> u32 {
+        self.bits.bits_left
+    }
+
+    // Inflates until the input runs out, the output stops or the stream ends
+    #[inline(always)]
+    pub(crate) fn run<I: InflateInput, O: InflateOutput>(&mut self, input: &mut I, output: &mut O) -> Result<Status, DecodingError> {
+        let mut bits = self.bits;
+        let (mut cursor, mut end) = output.buf();
+        let mut window_pos = self.window_pos & (WINDOW_SIZE - 1); // masked, so indexing the window needs no bounds checks
+        let mut produced = self.produced;
+        let status = 'run: loop {
+            if output.stopped() && self.state != InflateState::Done {
+                break Status::Stopped;
+            }
+            match self.state {
+                InflateState::Header => {
+                    let (checkpoint, saved) = (input.checkpoint(), bits);
+                    match decode_header(self.format, &mut bits, input) {
+                        Err(err) if err.kind == ErrorKind::Truncated && input.resumable() => {
+                            input.rollback(checkpoint);
+                            bits = saved;
+                            break Status::NeedInput;
+                        },
+                        result => result?,
+                    }
+                    self.state = InflateState::BlockHeader;
+                },
+                InflateState::BlockHeader => {
+                    let (checkpoint, saved) = (input.checkpoint(), bits);
+                    match decode_block_header(&mut bits, input, &mut self.huff_lit_len, &mut self.huff_dist) {
+                        Err(err) if err.kind == ErrorKind::Truncated && input.resumable() => {
+                            input.rollback(checkpoint);
+                            bits = saved;
+                            break Status::NeedInput;
+                        },
+                        result => (self.bfinal, self.state) = result?,
+                    }
+                },
+                InflateState::Stored { len_left } => {
+                    let mut len_left = len_left;
+                    while len_left > 0 {
+                        if output.stopped() {
+                            self.state = InflateState::Stored { len_left };
+                            break 'run Status::Stopped;
+                        }
+                        input.refill(&mut bits)?;
+                        if bits.bits_left < 8 {
+                            if !input.resumable() {
+                                return Err(ErrorKind::Truncated.into());
+                            }
+                            self.state = InflateState::Stored { len_left };
+                            break 'run Status::NeedInput;
+                        }
+                        while bits.bits_left >= 8 && len_left > 0 {
+                            let byte = bits.buf as u8;
+                            bits.buf >>= 8;
+                            bits.bits_left -= 8;
+                            put(output, &mut cursor, &mut end, byte)?;
+                            // later blocks can copy from stored bytes too
+                            self.window[window_pos] = byte;
+                            window_pos = (window_pos + 1) & (WINDOW_SIZE - 1);
+                            produced += 1;
+                            len_left -= 1;
+                        }
+                    }
+                    self.state = if self.bfinal { InflateState::Trailer } else { InflateState::BlockHeader };
+                },
+                InflateState::Huffman => {
+                    let (huff_lit_len, huff_dist, window) = (&*self.huff_lit_len, &*self.huff_dist, &mut *self.window);
+                    loop {
+                        if output.stopped() {
+                            break 'run Status::Stopped;
+                        }
+                        input.refill(&mut bits)?;
+                        // a symbol fits into the refilled bits, so one that is cut off waits for more input
+                        let saved = bits;
+                        let (len, dist) = match decode_symbol(&mut bits, huff_lit_len, huff_dist) {
+                            Ok(Symbol::Literal(byte)) => {
+                                put(output, &mut cursor, &mut end, byte)?;
+                                window[window_pos] = byte;
+                                window_pos = (window_pos + 1) & (WINDOW_SIZE - 1);
+                                produced += 1;
+                                continue;
+                            },
+                            Ok(Symbol::EndOfBlock) => break,
+                            Ok(Symbol::Match { len, dist }) => (len, dist),
+                            Err(err) if err.kind == ErrorKind::Truncated && input.resumable() => {
+                                bits = saved;
+                                break 'run Status::NeedInput;
+                            },
+                            Err(err) => return Err(err),
+                        };
+                        if dist > produced {
+                            return Err(ErrorKind::InvalidDeflateData.into());
+                        }
+                        produced += len;
+                        let mut len = len;
+                        let mut p = (window_pos + WINDOW_SIZE - dist) & (WINDOW_SIZE - 1);
+                        while len > 0 {
+                            let room = unsafe { end.offset_from(cursor) } as usize;
+                            let cnt = std::cmp::min(len, room);
+                            if dist >= cnt && p + cnt <= WINDOW_SIZE && window_pos + cnt <= WINDOW_SIZE {
+                                // the bytes are all there already and don't wrap around
+                                window.copy_within(p..p + cnt, window_pos);
+                                unsafe {
+                                    std::ptr::copy_nonoverlapping(window.as_ptr().add(window_pos), cursor, cnt);
+                                }
+                                window_pos = (window_pos + cnt) & (WINDOW_SIZE - 1);
+                                p = (p + cnt) & (WINDOW_SIZE - 1);
+                            } else {
+                                for out_cursor in 0..cnt {
+                                    let byte = window[p];
+                                    window[window_pos] = byte;
+                                    window_pos = (window_pos + 1) & (WINDOW_SIZE - 1);
+                                    p = (p + 1) & (WINDOW_SIZE - 1);
+                                    unsafe {
+                                        *cursor.add(out_cursor) = byte;
+                                    }
+                                }
+                            }
+                            len -= cnt;
+                            if cnt == room {
+                                (cursor, end) = output.next()?;
+                            } else {
+                                cursor = unsafe { cursor.add(cnt) };
+                            }
+                        }
+                    }
+                    self.state = if self.bfinal { InflateState::Trailer } else { InflateState::BlockHeader };
+                },
+                InflateState::Trailer => {
+                    bits.align_to_byte();
+                    input.refill(&mut bits)?;
+                    let saved = bits;
+                    match read_trailer(self.format, &mut bits) {
+                        Err(err) if err.kind == ErrorKind::Truncated && input.resumable() => {
+                            bits = saved;
+                            break Status::NeedInput;
+                        },
+                        result => self.trailer = result?,
+                    }
+                    self.state = InflateState::Done;
+                },
+                InflateState::Done => break Status::Done,
+            }
+        };
+        self.bits = bits;
+        self.window_pos = window_pos;
+        self.produced = produced;
+        output.pause(cursor);
+        Ok(status)
+    }
+}
+
+// Reads the zlib or gzip header
+fn decode_header<I: InflateInput>(format: Format, bits: &mut BitBuf, input: &mut I) -> Result<(), DecodingError> {
+    match format {
+        Format::Raw => {},
+        Format::Zlib => {
+            let cmf = read_bits(bits, input, 8)?;
+            let flg = read_bits(bits, input, 8)?;
+            let cm = cmf & 0xf;
+            let cinfo = cmf >> 4;
+            if cm != 8 || cinfo > 7 {
+                return Err(ErrorKind::InvalidZlibHeader.into());
+            }
+            if (cmf << 8 | flg) % 31 != 0 || flg & 0x20 != 0 {
+                return Err(ErrorKind::InvalidZlibHeader.into());
+            }
+        },
+        Format::Gzip => {
+            const FHCRC: u64 = 2;
+            const FEXTRA: u64 = 4;
+            const FNAME: u64 = 8;
+            const FCOMMENT: u64 = 16;
+            let magic = read_bits(bits, input, 16)?;
+            let cm = read_bits(bits, input, 8)?;
+            let flg = read_bits(bits, input, 8)?;
+            if magic != 0x8b1f || cm != 8 || flg & 0xe0 != 0 {
+                return Err(ErrorKind::InvalidZlibHeader.into());
+            }
+            read_bits(bits, input, 32)?; // MTIME
+            read_bits(bits, input, 16)?; // XFL, OS
+            if flg & FEXTRA != 0 {
+                let xlen = read_bits(bits, input, 16)?;
+                for _ in 0..xlen {
+                    read_bits(bits, input, 8)?;
+                }
+            }
+            for flag in [FNAME, FCOMMENT] {
+                if flg & flag != 0 {
+                    while read_bits(bits, input, 8)? != 0 {} // zero-terminated string
+                }
+            }
+            if flg & FHCRC != 0 {
+                read_bits(bits, input, 16)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+// Reads a block header and the code lengths of a dynamic block, returns BFINAL and the state of the block
+fn decode_block_header<I: InflateInput>(bits: &mut BitBuf, input: &mut I, huff_lit_len: &mut HuffmanTables, huff_dist: &mut HuffmanTables) -> Result<(bool, InflateState), DecodingError> {
+    let header = read_bits(bits, input, 3)?;
+    let bfinal = header & 1 != 0;
+    let state = match header >> 1 {
+        0 => {
+            bits.align_to_byte();
+            let len_nlen = read_bits(bits, input, 32)?;
+            let len = len_nlen as u16;
+            let nlen = (len_nlen >> 16) as u16;
+            if nlen != !len {
+                return Err(ErrorKind::InvalidDeflateData.into());
+            }
+            InflateState::Stored { len_left: len }
+        },
+        1 => {
+            let cls_all = fixed_code_lengths();
+            huff_lit_len.update(&cls_all[..288])?;
+            huff_dist.update(&cls_all[288..288 + 32])?;
+            InflateState::Huffman
+        },
+        2 => {
+            let hlit = read_bits(bits, input, 5)?;
+            let hdist = read_bits(bits, input, 5)?;
+            let hclen = read_bits(bits, input, 4)?;
+
+            let mut cls_of_cls: [u8; 19] = [0; 19];
+            const INDEX_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+            for i in 0..hclen as usize + 4 {
+                cls_of_cls[INDEX_ORDER[i]] = read_bits(bits, input, 3)? as u8;
+            }
+            let huff: [u16; 128] = build_huffman_lut::<8, 128>(&cls_of_cls)?;
+
+            let n_hlit = hlit as usize + 257;
+            let n_hdist = hdist as usize + 1;
+            let cls = decode_cls(bits, input, &huff, n_hlit + n_hdist)?;
+            if cls[256] == 0 {
+                return Err(ErrorKind::InvalidHuffmanTable.into()); // no end of block code
+            }
+            huff_lit_len.update(&cls[..n_hlit])?;
+            huff_dist.update(&cls[n_hlit..n_hlit + n_hdist])?;
+            InflateState::Huffman
+        },
+        _ => return Err(ErrorKind::InvalidDeflateData.into()),
+    };
+    Ok((bfinal, state))
+}
+
+// Decodes a literal/length symbol and the distance of a match from the refilled bits
+#[inline(always)]
+fn decode_symbol(bits: &mut BitBuf, huff_lit_len: &HuffmanTables, huff_dist: &HuffmanTables) -> Result<Symbol, DecodingError> {
+    let (sym, code_len) = huff_lit_len.lookup_sym_len(bits.peek(15) as u16);
+    if code_len == 0 {
+        return Err(ErrorKind::InvalidDeflateData.into()); // code is not in the table
+    }
+    bits.skip(code_len as u32)?;
+    if sym < 256 {
+        return Ok(Symbol::Literal(sym as u8));
+    }
+    if sym == 256 {
+        return Ok(Symbol::EndOfBlock);
+    }
+    if sym > 285 {
+        return Err(ErrorKind::InvalidDeflateData.into());
+    }
+    let len =
+        if sym < 265 {
+            sym as usize - 254
+        } else if sym == 285 {
+            258 // max
+        } else {
+            let extra_bits = (sym as u32 - 261) / 4; // 1..5 bits
+            bits.read(extra_bits)? as usize + LEN_OFFSETS[sym as usize - 265] as usize
+        };
+    let (dist_code, code_len) = huff_dist.lookup_sym_len(bits.peek(15) as u16);
+    if code_len == 0 {
+        return Err(ErrorKind::InvalidDeflateData.into());
+    }
+    bits.skip(code_len as u32)?;
+    if dist_code > 29 {
+        return Err(ErrorKind::InvalidDeflateData.into());
+    }
+    let dist =
+        if dist_code < 4 {
+            dist_code as usize + 1
+        } else {
+            let extra_bits = (dist_code as u32 - 2) / 2; // 1..13 bits
+            bits.read(extra_bits)? as usize + DIST_OFFSETS[dist_code as usize - 4] as usize
+        };
+    Ok(Symbol::Match { len, dist })
+}
+
+fn read_trailer(format: Format, bits: &mut BitBuf) -> Result<[u32; 2], DecodingError> {
+    match format {
+        Format::Raw => Ok([0; 2]),
+        Format::Zlib => Ok([(bits.read(32)? as u32).swap_bytes(), 0]), // big-endian
+        Format::Gzip => Ok([bits.read(32)? as u32, bits.read(32)? as u32]), // CRC-32, ISIZE
+    }
+}
+
+// Collects the inflated bytes for the sink of `Inflater::feed` and updates the checksum over them
+struct SinkOutput<'a, F> {
+    buf: &'a mut [u8],
+    filled: usize,
+    sink: &'a mut F,
+    format: Format,
+    verify_checksum: bool,
+    checksum: &'a mut u32,
+    size: &'a mut u32,
+}
+
+impl<F> SinkOutput<'_, F>
+    where F: FnMut(&[u8]) -> Result<(), DecodingError>
+{
+    fn flush(&mut self) -> Result<(), DecodingError> {
+        let bytes = &self.buf[..self.filled];
+        self.filled = 0;
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        if self.verify_checksum {
+            *self.checksum = match self.format {
+                Format::Raw => 0,
+                Format::Zlib => checksum::adler32_update(*self.checksum, bytes),
+                Format::Gzip => checksum::crc32_update(*self.checksum, bytes),
+            };
+            *self.size = self.size.wrapping_add(bytes.len() as u32);
+        }
+        (self.sink)(bytes)
+    }
+}
+
+unsafe impl<F> InflateOutput for SinkOutput<'_, F>
+    where F: FnMut(&[u8]) -> Result<(), DecodingError>
+{
+    fn buf(&mut self) -> (*mut u8, *const u8) {
+        let range = self.buf[self.filled..].as_mut_ptr_range();
+        (range.start, range.end)
+    }
+
+    fn next(&mut self) -> Result<(*mut u8, *const u8), DecodingError> {
+        self.filled = self.buf.len();
+        self.flush()?;
+        Ok(self.buf())
+    }
+
+    fn pause(&mut self, cursor: *mut u8) {
+        self.filled = unsafe { cursor.offset_from(self.buf.as_ptr()) } as usize;
+    }
+
+    fn stopped(&self) -> bool {
+        false
+    }
+}
+
+/// Resumable DEFLATE decoder: it doesn't need the whole stream at once, and keeps the bit reader,
+/// Huffman tables and the LZ77 window between `feed` calls.
+pub struct Inflater {
+    core: InflateCore,
+    leftover: Leftover,
+    buf: Vec<u8>, // decoded bytes on their way to the sink
+    verify_checksum: bool,
+    checksum: u32, // Adler-32 for zlib, CRC-32 register for gzip
+    size: u32, // decompressed size of the current gzip member modulo 2^32
+}
+
+impl Inflater {
+    pub fn new(format: Format) -> Inflater {
+        Inflater {
+            core: InflateCore::new(format),
+            leftover: Leftover::default(),
+            buf: vec![0; WINDOW_SIZE],
+            verify_checksum: true,
+            checksum: initial_checksum(format),
+            size: 0,
+        }
+    }
+
+    /// Check the zlib Adler-32 or the gzip CRC-32 and size against the decompressed data, on by default
+    pub fn verify_checksum(mut self, verify_checksum: bool) -> Inflater {
+        self.verify_checksum = verify_checksum;
+        self
+    }
+
+    /// True once the end of the stream (including the zlib/gzip trailer) has been decoded
+    pub fn is_done(&self) -> bool {
+        self.core.is_done()
+    }
+
+    /// Decodes as much of `data` (appended to the not yet consumed input) as possible,
+    /// passing every decoded byte to `sink` exactly once, in order.
+    /// Data after the end of the stream is an error, except for further members of a gzip file.
+    pub fn feed<F>(&mut self, data: &[u8], sink: &mut F) -> Result<(), DecodingError>
+        where F: FnMut(&[u8]) -> Result<(), DecodingError>
+    {
+        let Inflater { core, leftover, buf, verify_checksum, checksum, size } = self;
+        let format = core.format;
+        let mut output = SinkOutput { buf, filled: 0, sink, format, verify_checksum: *verify_checksum, checksum, size };
+        leftover.feed(data, |input| {
+            loop {
+                if core.is_done() {
+                    if core.bits_left() < 8 && input.is_empty() {
+                        break;
+                    }
+                    if format != Format::Gzip {
+                        return Err(ErrorKind::TooMuchData.into()); // data after the end of the stream
+                    }
+                    core.next_member();
+                    (*output.checksum, *output.size) = (initial_checksum(format), 0);
+                }
+                if run_sink(core, input, &mut output)? != Status::Done {
+                    break;
+                }
+                output.flush()?;
+                let trailer = core.trailer();
+                let valid = match format {
+                    Format::Raw => true,
+                    Format::Zlib => trailer[0] == *output.checksum,
+                    Format::Gzip => trailer[0] == *output.checksum ^ 0xffffffff && trailer[1] == *output.size,
+                };
+                if output.verify_checksum && !valid {
+                    let kind = if format == Format::Zlib { ErrorKind::AdlerMismatch } else { ErrorKind::CrcMismatch };
+                    return Err(kind.into());
+                }
+            }
+            output.flush()
+        })
+    }
+}
+
+fn run_sink<F>(core: &mut InflateCore, input: &mut SliceInput, output: &mut SinkOutput<F>) -> Result<Status, DecodingError>
+    where F: FnMut(&[u8]) -> Result<(), DecodingError>
+{
+    #[cfg(target_arch = "x86_64")]
+    if crate::CpuFeatures::detect().bmi2 {
+        return unsafe { run_sink_bmi2(core, input, output) };
+    }
+    core.run(input, output)
+}
+
+// The bit reader masks compile to BZHI here
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+#[target_feature(enable = "bmi2")]
+fn run_sink_bmi2<F>(core: &mut InflateCore, input: &mut SliceInput, output: &mut SinkOutput<F>) -> Result<Status, DecodingError>
+    where F: FnMut(&[u8]) -> Result<(), DecodingError>
+{
+    core.run(input, output)
+}
+
+fn initial_checksum(format: Format) -> u32 {
+    if format == Format::Gzip { 0xffffffff } else { 1 }
+}
+fn fixed_code_lengths() -> [u8; CLS_MAX] {
+    core::array::from_fn(|i| {
+        match i {
+            _ if i <= 143 => 8,
+            _ if i <= 255 => 9,
+            _ if i <= 279 => 7,
+            _ if i <= 287 => 8,
+            _ => 5,
+        }
+    })
+}
+
+/// Decodes a whole stream of the given format into a new `Vec`
+pub fn decompress(data: &[u8], format: Format) -> Result<Vec<u8>, DecodingError> {
+    decompress_limited(data, format, usize::MAX)
+}
+
+// Like `decompress`, fails as soon as the output would be longer than `max_len`
+pub(crate) fn decompress_limited(data: &[u8], format: Format, max_len: usize) -> Result<Vec<u8>, DecodingError> {
+    let mut out = vec![];
+    let mut inflater = Inflater::new(format);
+    inflater.feed(data, &mut |bytes: &[u8]| {
+        if bytes.len() > max_len - out.len() {
+            return Err(ErrorKind::LimitsExceeded.into());
+        }
+        out.extend_from_slice(bytes);
+        Ok(())
+    })?;
+    if !inflater.is_done() {
+        return Err(ErrorKind::Truncated.into()); // truncated stream
+    }
+    Ok(out)
+}
+
+/// Decodes a whole stream of the given format into `out`, returns the decompressed size.
+/// Fails if `out` is too small.
+pub fn decompress_into(data: &[u8], format: Format, out: &mut [u8]) -> Result<usize, DecodingError> {
+    let mut len = 0;
+    let mut inflater = Inflater::new(format);
+    inflater.feed(data, &mut |bytes: &[u8]| {
+        if len + bytes.len() > out.len() {
+            return Err(ErrorKind::BufferTooSmall.into());
+        }
+        out[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+        Ok(())
+    })?;
+    if !inflater.is_done() {
+        return Err(ErrorKind::Truncated.into()); // truncated stream
+    }
+    Ok(len)
+}
+
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, DecodingError> {
+    decompress(data, Format::Raw)
+}
+
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, DecodingError> {
+    decompress(data, Format::Zlib)
+}
+
+pub fn gzip_decompress(data: &[u8]) -> Result<Vec<u8>, DecodingError> {
+    decompress(data, Format::Gzip)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ErrorKind;
+    use crate::deflate::zlib_compress;
+    use crate::inflate::Format;
+    use crate::inflate::Inflater;
+    use crate::inflate::decompress;
+    use crate::inflate::decompress_into;
+    use crate::inflate::gzip_decompress;
+    use crate::inflate::inflate;
+    use crate::inflate::zlib_decompress;
+
+    // "hello hello hello hello\n" compressed with fixed Huffman codes and back references
+    const RAW: &[u8] = &[0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00];
+    const EXPECTED: &[u8] = b"hello hello hello hello\n";
+
+    #[test]
+    fn test_front_ends() {
+        assert_eq!(inflate(RAW).unwrap(), EXPECTED);
+
+        let mut zlib = vec![0x78, 0x9c];
+        zlib.extend_from_slice(RAW);
+        zlib.extend_from_slice(&[0x70, 0xbe, 0x08, 0xbb]); // Adler-32
+        assert_eq!(zlib_decompress(&zlib).unwrap(), EXPECTED);
+
+        // gzip with FNAME, twice: concatenated members decode as one stream
+        let mut gzip = vec![0x1f, 0x8b, 0x08, 0x08, 0, 0, 0, 0, 0, 0x03, b'a', 0];
+        gzip.extend_from_slice(RAW);
+        gzip.extend_from_slice(&[0x00, 0x88, 0x59, 0x0b, EXPECTED.len() as u8, 0, 0, 0]); // CRC-32, ISIZE
+        let gzip = gzip.repeat(2);
+        assert_eq!(gzip_decompress(&gzip).unwrap(), EXPECTED.repeat(2));
+
+        let mut out = [0; 64];
+        assert_eq!(decompress_into(RAW, Format::Raw, &mut out), Ok(EXPECTED.len()));
+        assert_eq!(&out[..EXPECTED.len()], EXPECTED);
+        assert!(decompress_into(RAW, Format::Raw, &mut out[..10]).is_err());
+
+        assert!(inflate(&RAW[..RAW.len() - 1]).is_err());
+
+        let mut bad_zlib = zlib.clone();
+        bad_zlib[zlib.len() - 1] ^= 1;
+        assert_eq!(zlib_decompress(&bad_zlib).map_err(|err| err.kind), Err(ErrorKind::AdlerMismatch));
+        let mut unchecked = Vec::new();
+        Inflater::new(Format::Zlib).verify_checksum(false).feed(&bad_zlib, &mut |bytes: &[u8]| {
+            unchecked.extend_from_slice(bytes);
+            Ok(())
+        }).unwrap();
+        assert_eq!(unchecked, EXPECTED);
+        let mut bad_gzip = gzip.clone();
+        bad_gzip[12 + RAW.len() + 4] += 1; // ISIZE of the first member
+        assert_eq!(gzip_decompress(&bad_gzip).map_err(|err| err.kind), Err(ErrorKind::CrcMismatch));
+        assert!(decompress(&zlib[..zlib.len() - 1], Format::Zlib).is_err());
+    }
+
+    #[test]
+    fn test_stored_blocks_byte_by_byte() {
+        // two stored blocks, the second is the final one
+        let data: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+        let mut raw = vec![0x00];
+        raw.extend_from_slice(&200u16.to_le_bytes());
+        raw.extend_from_slice(&(!200u16).to_le_bytes());
+        raw.extend_from_slice(&data[..200]);
+        raw.push(0x01);
+        raw.extend_from_slice(&100u16.to_le_bytes());
+        raw.extend_from_slice(&(!100u16).to_le_bytes());
+        raw.extend_from_slice(&data[200..]);
+
+        let mut out = vec![];
+        let mut inflater = Inflater::new(Format::Raw);
+        for byte in &raw {
+            inflater.feed(&[*byte], &mut |bytes: &[u8]| {
+                out.extend_from_slice(bytes);
+                Ok(())
+            }).unwrap();
+        }
+        assert!(inflater.is_done());
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_huffman_blocks_byte_by_byte() {
+        // the input ends in the middle of headers, code lengths, symbols and the trailer
+        let data: Vec<u8> = (0..100000u64).map(|i| (i * i % 251 ^ i / 1000) as u8).collect();
+        let zlib = zlib_compress(&data, 6);
+        let mut out = vec![];
+        let mut inflater = Inflater::new(Format::Zlib);
+        for byte in &zlib {
+            inflater.feed(&[*byte], &mut |bytes: &[u8]| {
+                out.extend_from_slice(bytes);
+                Ok(())
+            }).unwrap();
+        }
+        assert!(inflater.is_done());
+        assert_eq!(out, data);
+        assert_eq!(zlib_decompress(&zlib).unwrap(), data);
+    }
+}
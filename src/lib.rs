@@ -1,13 +1,291 @@
 
 pub mod png_decoder;
 pub mod tga_decoder;
+pub mod apng_decoder;
+pub mod png_encoder;
+pub mod deflate;
+pub mod inflate;
+pub mod color;
+pub mod exif;
+pub mod pixel_format;
+mod checksum;
+
+use std::fmt;
+
+/// Why decoding failed, with the PNG chunk and the position in the file if they are known
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Clone)]
+pub struct DecodingError {
+    pub kind: ErrorKind,
+    pub chunk: Option<[u8; 4]>, // name of the PNG chunk being decoded
+    pub offset: Option<usize>, // byte offset in the file where decoding stopped
+}
 
 #[derive(Debug)]
 #[derive(PartialEq)]
-pub enum DecodingError {
+#[derive(Clone)]
+#[derive(Copy)]
+pub enum ErrorKind {
     UnknownFormat,
-    MalformedImage,
-    NotImplemented,
+    NotImplemented, // valid file that uses a feature this crate doesn't support
+    BufferTooSmall, // destination buffer or row stride of decode_into is too small for the image
+    Truncated, // the data ends early
+    InvalidSignature,
+    InvalidHeader, // IHDR or TGA header field out of range, e.g. a zero width
+    InvalidColorType, // color type and bit depth combination that isn't allowed
+    ChunkOrder, // chunk is missing, repeated or in the wrong place
+    InvalidChunkLength,
+    InvalidChunkData, // contents of an ancillary chunk that break its rules
+    CrcMismatch, // chunk CRC or gzip CRC-32 doesn't match the data
+    AdlerMismatch, // zlib Adler-32 doesn't match the data
+    InvalidZlibHeader, // zlib or gzip header
+    InvalidHuffmanTable, // code lengths that don't form a prefix code
+    InvalidDeflateData, // block type, stored block length, length or distance code
+    InvalidFilter, // scanline filter type isn't 0..=4
+    TooMuchData, // data after the last scanline, the end of the zlib stream or IEND
+    LimitsExceeded, // the image needs more than `DecodeOptions::limits` allow
+}
+
+impl DecodingError {
+    /// Adds where the error happened, unless it's known already
+    pub(crate) fn at(mut self, chunk: Option<[u8; 4]>, offset: usize) -> DecodingError {
+        if self.chunk.is_none() && self.offset.is_none() {
+            self.chunk = chunk;
+            self.offset = Some(offset);
+        }
+        self
+    }
+}
+
+impl From<ErrorKind> for DecodingError {
+    fn from(kind: ErrorKind) -> DecodingError {
+        DecodingError { kind, chunk: None, offset: None }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            ErrorKind::UnknownFormat => "unknown image format",
+            ErrorKind::NotImplemented => "unsupported image feature",
+            ErrorKind::BufferTooSmall => "destination buffer too small",
+            ErrorKind::Truncated => "truncated data",
+            ErrorKind::InvalidSignature => "invalid signature",
+            ErrorKind::InvalidHeader => "invalid header",
+            ErrorKind::InvalidColorType => "invalid color type and bit depth combination",
+            ErrorKind::ChunkOrder => "missing, repeated or misplaced chunk",
+            ErrorKind::InvalidChunkLength => "invalid chunk length",
+            ErrorKind::InvalidChunkData => "invalid chunk data",
+            ErrorKind::CrcMismatch => "CRC mismatch",
+            ErrorKind::AdlerMismatch => "Adler-32 mismatch",
+            ErrorKind::InvalidZlibHeader => "invalid zlib header",
+            ErrorKind::InvalidHuffmanTable => "invalid Huffman table",
+            ErrorKind::InvalidDeflateData => "invalid deflate data",
+            ErrorKind::InvalidFilter => "invalid filter type",
+            ErrorKind::TooMuchData => "unexpected data after the end",
+            ErrorKind::LimitsExceeded => "decoding limits exceeded",
+        };
+        f.write_str(description)
+    }
+}
+
+impl fmt::Display for DecodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(chunk) = self.chunk {
+            write!(f, " in {} chunk", String::from_utf8_lossy(&chunk))?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {offset}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DecodingError {}
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum EncodingError {
+    InvalidParameters, // unsupported combination of encoder settings
+    InvalidImage, // image doesn't match the encoder settings
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct DecodeOptions {
+    pub(crate) verify_checksums: bool,
+    pub(crate) color_space: Option<color::TargetSpace>,
+    pub(crate) significant_bits: Option<png_decoder::SignificantBits>,
+    pub(crate) composite_over: Option<png_decoder::CompositeOver>,
+    pub(crate) output_format: Option<pixel_format::PixelFormat>,
+    pub(crate) adam7_passes: Option<usize>,
+    pub(crate) cpu_features: CpuFeatures,
+    pub(crate) limits: Limits,
+    pub(crate) lenient: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions::new()
+    }
+}
+
+impl DecodeOptions {
+    pub fn new() -> DecodeOptions {
+        DecodeOptions {
+            verify_checksums: true,
+            color_space: None,
+            significant_bits: None,
+            composite_over: None,
+            output_format: None,
+            adam7_passes: None,
+            cpu_features: CpuFeatures::detect(),
+            limits: Limits::default(),
+            lenient: false,
+        }
+    }
+
+    /// Check chunk CRCs and the zlib Adler-32, on by default. Costs about 5% of the PNG decoding time
+    pub fn verify_checksums(mut self, verify_checksums: bool) -> DecodeOptions {
+        self.verify_checksums = verify_checksums;
+        self
+    }
+
+    /// Convert PNG pixels to `color_space` using the cICP, sRGB, gAMA and cHRM chunks, off by default
+    pub fn color_space(mut self, color_space: color::TargetSpace) -> DecodeOptions {
+        self.color_space = Some(color_space);
+        self
+    }
+
+    /// Adjust samples of PNGs with an sBIT chunk, off by default.
+    /// `Rescale` is applied after the color space conversion, `Replicate` before it. `Rescale` can't be
+    /// combined with `output_format`, decoding fails with `ErrorKind::NotImplemented`.
+    pub fn significant_bits(mut self, mode: png_decoder::SignificantBits) -> DecodeOptions {
+        self.significant_bits = Some(mode);
+        self
+    }
+
+    /// Composite PNGs with alpha or tRNS over a background color and drop the alpha channel, off by default
+    pub fn composite_over(mut self, over: png_decoder::CompositeOver) -> DecodeOptions {
+        self.composite_over = Some(over);
+        self
+    }
+
+    /// Pixel layout of the decoded image, the layout of the file by default
+    pub fn output_format(mut self, format: pixel_format::PixelFormat) -> DecodeOptions {
+        self.output_format = Some(format);
+        self
+    }
+
+    /// Decode only the first `passes` (1..=7) Adam7 passes of interlaced PNGs, all of them by default.
+    /// The image is 1/8 of the size with 1 pass, 1/4 with 3 and 1/2 with 5, inflate stops once the last pass
+    /// is complete. Non-interlaced PNGs are decoded whole and reduced to the same size.
+    pub fn adam7_passes(mut self, passes: u8) -> DecodeOptions {
+        self.adam7_passes = Some(passes.clamp(1, 7) as usize);
+        self
+    }
+
+    /// Use only these instruction set extensions, e.g. to compare the SIMD and the scalar code paths.
+    /// Extensions the CPU doesn't have are never used, all the others are by default.
+    pub fn cpu_features(mut self, features: CpuFeatures) -> DecodeOptions {
+        let detected = CpuFeatures::detect();
+        self.cpu_features = CpuFeatures {
+            sse41: features.sse41 && detected.sse41,
+            bmi2: features.bmi2 && detected.bmi2,
+        };
+        self
+    }
+
+    /// Reject files that need more memory than `limits` allow, `Limits::default()` by default
+    pub fn limits(mut self, limits: Limits) -> DecodeOptions {
+        self.limits = limits;
+        self
+    }
+
+    /// Return what could be decoded of broken files instead of failing, off by default. Errors in or after
+    /// the pixel data end up in `warnings` of the decoded image and the pixels that weren't decoded are 0,
+    /// `rows_valid` tells how many rows are complete. Errors before the pixel data still fail.
+    pub fn lenient(mut self, lenient: bool) -> DecodeOptions {
+        self.lenient = lenient;
+        self
+    }
+}
+
+/// Resource limits against decompression bombs. They are checked before anything is allocated
+/// and exceeding any of them fails with `ErrorKind::LimitsExceeded`.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    pub max_alloc_bytes: usize, // largest image buffer or decompressed ICC profile
+    pub max_chunk_len: usize, // PNG chunks, the data of longer ones is never read
+    pub max_text_bytes: usize, // decompressed text of all PNG text chunks together
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_width: 1 << 24,
+            max_height: 1 << 24,
+            max_pixels: 1 << 28,
+            max_alloc_bytes: 1 << 30,
+            max_chunk_len: 1 << 28,
+            max_text_bytes: 1 << 24,
+        }
+    }
+}
+
+impl Limits {
+    pub(crate) fn check_size(&self, w: u32, h: u32) -> Result<(), DecodingError> {
+        if w > self.max_width || h > self.max_height || w as u64 * h as u64 > self.max_pixels {
+            Err(ErrorKind::LimitsExceeded.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    // `bytes` saturates instead of overflowing
+    pub(crate) fn check_alloc(&self, bytes: u64) -> Result<(), DecodingError> {
+        if bytes > self.max_alloc_bytes as u64 {
+            Err(ErrorKind::LimitsExceeded.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Instruction set extensions with faster code paths, the default is none of them
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Default)]
+#[derive(Debug)]
+pub struct CpuFeatures {
+    pub sse41: bool, // PNG defiltering of 3 and 4 byte pixels
+    pub bmi2: bool, // PNG inflate loop
+}
+
+impl CpuFeatures {
+    /// Extensions of the CPU the code is running on, checked at runtime
+    pub fn detect() -> CpuFeatures {
+        #[cfg(target_arch = "x86_64")]
+        {
+            CpuFeatures {
+                sse41: is_x86_feature_detected!("sse4.1"),
+                bmi2: is_x86_feature_detected!("bmi2"),
+            }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            CpuFeatures::default()
+        }
+    }
 }
 
 pub struct Image {
@@ -18,14 +296,125 @@ pub struct Image {
     pub depth: u8,
 }
 
+/// Dimensions and pixel layout of a decoded image, for sizing the buffer of `decode_into`
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub struct BufferLayout {
+    pub w: u32,
+    pub h: u32,
+    pub channels: u32,
+    pub depth: u8,
+}
+
+impl BufferLayout {
+    /// Size of a row without padding, the smallest allowed stride
+    pub fn row_bytes(&self) -> usize {
+        self.w as usize * self.channels as usize * (self.depth / 8) as usize
+    }
+
+    /// Buffer size for rows `stride` bytes apart, the last row doesn't need padding
+    pub fn required_size(&self, stride: usize) -> usize {
+        if self.h == 0 { 0 } else { (self.h as usize - 1) * stride + self.row_bytes() }
+    }
+
+    pub(crate) fn check_buffer(&self, dst: &[u8], stride: usize) -> Result<(), DecodingError> {
+        if stride < self.row_bytes() || dst.len() < self.required_size(stride) {
+            Err(ErrorKind::BufferTooSmall.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// What `Image::probe` finds out about an image without decoding its pixels
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct ImageInfo {
+    pub w: u32,
+    pub h: u32,
+    pub channels: u32, // of the decoded image, including alpha from transparency
+    pub depth: u8, // bits per sample in the file
+    pub interlaced: bool,
+    pub palette_size: Option<usize>, // number of palette entries of indexed images
+    pub has_transparency: bool, // alpha channel or tRNS
+}
+
+/// Receives the rows of an image as they are decoded, in the layout `decode_into` would write.
+/// Rows arrive in file order, which is bottom-up for most TGA images.
+pub trait RowSink {
+    fn row(&mut self, y: u32, row: &[u8]);
+}
+
+impl<F: FnMut(u32, &[u8])> RowSink for F {
+    fn row(&mut self, y: u32, row: &[u8]) {
+        self(y, row)
+    }
+}
+
+// Where the decoders put the final image
+#[derive(Default)]
+pub(crate) enum Destination<'a> {
+    #[default]
+    Image,
+    Buffer(&'a mut [u8], usize), // caller buffer and its row stride
+    Rows(&'a mut dyn RowSink),
+}
+
 impl Image {
+    pub(crate) fn layout(&self) -> BufferLayout {
+        BufferLayout { w: self.w, h: self.h, channels: self.channels, depth: self.depth }
+    }
+
+    // Copies the rows to `dst`, `stride` bytes apart
+    pub(crate) fn copy_into(&self, dst: &mut [u8], stride: usize) -> Result<(), DecodingError> {
+        let layout = self.layout();
+        layout.check_buffer(dst, stride)?;
+        let row_bytes = layout.row_bytes();
+        if row_bytes == 0 {
+            return Ok(());
+        }
+        for (row, dst_row) in self.buf.chunks_exact(row_bytes).zip(dst.chunks_mut(stride)) {
+            dst_row[..row_bytes].copy_from_slice(row);
+        }
+        Ok(())
+    }
+
+    // Hands the rows to `sink` top-down and empties the buffer
+    pub(crate) fn send_rows(&mut self, sink: &mut dyn RowSink) {
+        let row_bytes = self.layout().row_bytes();
+        if row_bytes != 0 {
+            for (y, row) in self.buf.chunks_exact(row_bytes).enumerate() {
+                sink.row(y as u32, row);
+            }
+        }
+        self.buf = vec![];
+    }
+
     pub fn new(buf: &[u8]) -> Result<Image, DecodingError> {
+        Image::new_with_options(buf, &DecodeOptions::default())
+    }
+
+    /// Reads only the header and the metadata before the pixel data, no pixel data is inflated
+    pub fn probe(buf: &[u8]) -> Result<ImageInfo, DecodingError> {
+        if buf.starts_with(png_decoder::PNG_SIGNATURE) {
+            png_decoder::probe(buf)
+        } else if tga_decoder::is_tga(buf) {
+            tga_decoder::probe(buf)
+        } else {
+            Err(ErrorKind::UnknownFormat.into())
+        }
+    }
+
+    pub fn new_with_options(buf: &[u8], options: &DecodeOptions) -> Result<Image, DecodingError> {
         if buf.starts_with(png_decoder::PNG_SIGNATURE) {
-            png_decoder::decode(buf).map(|png_image| png_image.image)
+            png_decoder::decode_with_options(buf, options).map(|png_image| png_image.image)
         } else if tga_decoder::is_tga(buf) {
-            tga_decoder::decode(buf).map(|tga_image| tga_image.image)
+            tga_decoder::decode_with_options(buf, options).map(|tga_image| tga_image.image)
         } else {
-            Err(DecodingError::UnknownFormat)
+            Err(ErrorKind::UnknownFormat.into())
         }
     }
 }
@@ -0,0 +1,141 @@
+use crate::Image;
+
+/// Pixel layout of decoded images. 16-bit samples are little-endian.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum PixelFormat {
+    L8,
+    La8,
+    Rgb8,
+    Rgba8,
+    Bgr8,
+    Bgra8,
+    L16,
+    La16,
+    Rgb16,
+    Rgba16,
+}
+
+impl PixelFormat {
+    pub fn channels(self) -> u32 {
+        match self {
+            PixelFormat::L8 | PixelFormat::L16 => 1,
+            PixelFormat::La8 | PixelFormat::La16 => 2,
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 | PixelFormat::Rgb16 => 3,
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 | PixelFormat::Rgba16 => 4,
+        }
+    }
+
+    pub fn depth(self) -> u8 {
+        match self {
+            PixelFormat::L16 | PixelFormat::La16 | PixelFormat::Rgb16 | PixelFormat::Rgba16 => 16,
+            _ => 8,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        self.channels() as usize * self.depth() as usize / 8
+    }
+
+    fn is_bgr(self) -> bool {
+        self == PixelFormat::Bgr8 || self == PixelFormat::Bgra8
+    }
+}
+
+// Converts a sample between 8 and 16 bits, rounding to the nearest value
+fn rescale(val: u32, from_depth: u8, to_depth: u8) -> u32 {
+    match (from_depth, to_depth) {
+        (16, 8) => (val * 255 + 32767) / 65535,
+        (8, 16) => val * 257,
+        _ => val,
+    }
+}
+
+/// Converts pixels with `channels` samples (gray, gray + alpha, RGB or RGBA) of `depth` bits each to `format`.
+/// 16-bit input samples are big-endian if `big_endian` is set. Only every `step`-th pixel of `src` is converted
+/// to the same position in `dst`, for the pixels of an Adam7 pass.
+pub(crate) fn convert_row(src: &[u8], channels: u32, depth: u8, big_endian: bool, format: PixelFormat, dst: &mut [u8], step: usize) {
+    let channels = channels as usize;
+    let bpc = depth as usize / 8; // bytes per channel
+    let has_alpha = channels == 2 || channels == 4;
+    let max_val = (1u32 << format.depth()) - 1;
+    let out_channels = format.channels() as usize;
+    let out_bpp = format.bytes_per_pixel();
+    for (pixel, out) in src.chunks(channels * bpc * step).zip(dst.chunks_mut(out_bpp * step)) {
+        let sample = |c: usize| {
+            let val = match (bpc, big_endian) {
+                (1, _) => pixel[c] as u32,
+                (_, true) => u16::from_be_bytes([pixel[2 * c], pixel[2 * c + 1]]) as u32,
+                (_, false) => u16::from_le_bytes([pixel[2 * c], pixel[2 * c + 1]]) as u32,
+            };
+            rescale(val, depth, format.depth())
+        };
+        let mut rgb = if channels <= 2 { [sample(0); 3] } else { [sample(0), sample(1), sample(2)] };
+        if format.is_bgr() {
+            rgb.swap(0, 2);
+        }
+        let color = if out_channels <= 2 {
+            // BT.709 luma for RGB sources
+            let luma = if channels <= 2 { rgb[0] } else { (2126 * rgb[0] + 7152 * rgb[1] + 722 * rgb[2] + 5000) / 10000 };
+            &[luma][..]
+        } else {
+            &rgb[..]
+        };
+        let alpha = if has_alpha { sample(channels - 1) } else { max_val };
+        let samples = color.iter().chain(std::iter::once(&alpha)).take(out_channels);
+        for (i, val) in samples.enumerate() {
+            if format.depth() == 8 {
+                out[i] = *val as u8;
+            } else {
+                out[2 * i..2 * i + 2].copy_from_slice(&(*val as u16).to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Converts a decoded image to `format` in place of its buffer
+pub fn convert(image: &mut Image, format: PixelFormat) {
+    let mut buf = vec![0; image.w as usize * image.h as usize * format.bytes_per_pixel()];
+    convert_row(&image.buf, image.channels, image.depth, false, format, &mut buf, 1);
+    image.buf = buf;
+    image.channels = format.channels();
+    image.depth = format.depth();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Image;
+    use crate::pixel_format::PixelFormat;
+    use crate::pixel_format::convert;
+
+    fn converted(image: &Image, format: PixelFormat) -> Vec<u8> {
+        let mut image = Image { w: image.w, h: image.h, channels: image.channels, buf: image.buf.clone(), depth: image.depth };
+        convert(&mut image, format);
+        assert_eq!(image.channels, format.channels());
+        assert_eq!(image.depth, format.depth());
+        image.buf
+    }
+
+    #[test]
+    fn test_convert() {
+        let rgba = Image { w: 2, h: 1, channels: 4, buf: vec![10, 20, 30, 40, 255, 255, 255, 0], depth: 8 };
+        assert_eq!(converted(&rgba, PixelFormat::Rgba8), rgba.buf);
+        assert_eq!(converted(&rgba, PixelFormat::Rgb8), vec![10, 20, 30, 255, 255, 255]);
+        assert_eq!(converted(&rgba, PixelFormat::Bgra8), vec![30, 20, 10, 40, 255, 255, 255, 0]);
+        assert_eq!(converted(&rgba, PixelFormat::Bgr8), vec![30, 20, 10, 255, 255, 255]);
+        assert_eq!(converted(&rgba, PixelFormat::La8), vec![19, 40, 255, 0]);
+        assert_eq!(converted(&rgba, PixelFormat::Rgb16), vec![10, 10, 20, 20, 30, 30, 255, 255, 255, 255, 255, 255]);
+
+        let gray = Image { w: 2, h: 1, channels: 1, buf: vec![0, 200], depth: 8 };
+        assert_eq!(converted(&gray, PixelFormat::Rgba8), vec![0, 0, 0, 255, 200, 200, 200, 255]);
+        assert_eq!(converted(&gray, PixelFormat::La16), vec![0, 0, 255, 255, 200, 200, 255, 255]);
+
+        // 16 -> 8 rounds to the nearest value
+        let gray_alpha_16: Vec<u8> = [0x0081u16, 0xffff, 0x7f7f, 0x8080].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let gray_alpha_16 = Image { w: 2, h: 1, channels: 2, buf: gray_alpha_16, depth: 16 };
+        assert_eq!(converted(&gray_alpha_16, PixelFormat::La8), vec![1, 255, 127, 128]);
+        assert_eq!(converted(&gray_alpha_16, PixelFormat::L8), vec![1, 127]);
+    }
+}
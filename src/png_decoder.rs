@@ -1,13 +1,41 @@
 
+use crate::BufferLayout;
+use crate::CpuFeatures;
+use crate::DecodeOptions;
+use crate::Destination;
 use crate::DecodingError;
+use crate::ErrorKind;
 use crate::Image;
+use crate::ImageInfo;
+use crate::Limits;
+use crate::RowSink;
+use crate::checksum;
+use crate::color;
+use crate::color::Chromaticities;
+use crate::color::Cicp;
+use crate::color::RenderingIntent;
+use crate::color::TargetSpace;
+use crate::exif::Exif;
+use crate::pixel_format;
+use crate::pixel_format::PixelFormat;
+use crate::pixel_format::convert_row;
+use crate::inflate::BitBuf;
+use crate::inflate::Format;
+use crate::inflate::InflateCore;
+use crate::inflate::InflateInput;
+use crate::inflate::InflateOutput;
+use crate::inflate::Inflater;
+use crate::inflate::Leftover;
+use crate::inflate::Status;
+use crate::inflate::decompress_limited;
 use std::cmp::max;
+use std::ops::Range;
+#[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 
-const CHECK_CRC: bool = false;
 pub const PNG_SIGNATURE: &[u8] = b"\x89\x50\x4e\x47\x0d\x0a\x1a\x0a";
 
-const CRC_TABLE: [u32; 256] = [
+pub(crate) const CRC_TABLE: [u32; 256] = [
     0x00000000, 0x77073096, 0xee0e612c, 0x990951ba, 0x076dc419, 0x706af48f, 0xe963a535, 0x9e6495a3, 
     0x0edb8832, 0x79dcb8a4, 0xe0d5e91e, 0x97d2d988, 0x09b64c2b, 0x7eb17cbd, 0xe7b82d07, 0x90bf1d91, 
     0x1db71064, 0x6ab020f2, 0xf3b97148, 0x84be41de, 0x1adad47d, 0x6ddde4eb, 0xf4d4b551, 0x83d385c7, 
@@ -51,22 +79,98 @@ pub struct PNGImage {
     color_type: u8,
     gama: Option<f32>,
     interlaced: bool,
+    pub text: Vec<TextEntry>, // tEXt, zTXt and iTXt chunks in file order
+    pub icc_profile: Option<IccProfile>,
+    pub chromaticities: Option<Chromaticities>, // cHRM
+    pub srgb_intent: Option<RenderingIntent>, // sRGB
+    pub cicp: Option<Cicp>,
+    pub exif: Option<Exif>, // eXIf
+    pub significant_bits: Option<Vec<u8>>, // sBIT, one entry per channel of the color type (RGB for palette images)
+    pub background: Option<Background>, // bKGD
+    pub(crate) idat_seen: bool,
+    segments: Option<Vec<Segment>>, // iDOT
+    pub warnings: Vec<DecodingError>, // errors the lenient mode got past and broken ancillary chunks that were left out
+    pub rows_valid: u32, // rows of `image` from the top that are completely decoded
 }
 
+// Part of the image data of an image with an iDOT chunk, it can be inflated on its own
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+struct Segment {
+    first_row: u32,
+    rows: u32,
+    offset: usize, // of the first IDAT chunk of the segment in the file
+}
+
+/// How samples of images with an sBIT chunk are adjusted
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum SignificantBits {
+    Rescale, // shift samples down to their original precision, e.g. 0..=31 for 5 significant bits
+    Replicate, // repeat the significant bits to fill the sample, e.g. 5 bits abcde become abcdeabc
+}
+
+/// Background color from the bKGD chunk, gray and RGB values are at the bit depth of the file
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum Background {
+    PaletteIndex(u8),
+    Gray(u16),
+    Rgb([u16; 3]),
+}
+
+/// Color that images with alpha are composited over, in the color space of the image
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum CompositeOver {
+    Bkgd, // images without bKGD keep their alpha
+    Color([u8; 3]), // scaled up for 16-bit images
+}
+
+/// Embedded ICC profile from the iCCP chunk
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct IccProfile {
+    pub name: String,
+    pub data: Vec<u8>, // decompressed profile
+}
+
+/// Keyword/value pair from a tEXt, zTXt or iTXt chunk
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct TextEntry {
+    pub keyword: String,
+    pub text: String,
+    pub compressed: bool, // zTXt or compressed iTXt
+    pub language_tag: Option<String>, // iTXt only, may be empty
+    pub translated_keyword: Option<String>, // iTXt only, may be empty
+}
+
+#[derive(Clone)]
 pub struct Palette {
     values: [[u8; 256]; 4],
     len: usize
 }
 
-struct PNGDatastream<'a> {
-    buf: &'a [u8],
-    cursor: usize,
+pub(crate) struct PNGDatastream<'a> {
+    pub(crate) buf: &'a [u8],
+    pub(crate) cursor: usize,
     crc: u32,
+    pub(crate) check_crc: bool,
 }
 
-struct BitStream {
-    buf: u64,
-    bits_left: u32,
+// Image data in consecutive IDAT chunks, read straight from the datastream
+struct IdatInput<'s, 'b> {
+    stream: &'s mut PNGDatastream<'b>,
     chunk_bytes_left: u32,
     end_of_bytestream: bool,
 }
@@ -74,7 +178,8 @@ struct BitStream {
 #[derive(PartialEq)]
 #[derive(Clone)]
 #[derive(Copy)]
-enum Filter {
+#[derive(Debug)]
+pub enum Filter {
     None,
     Sub,
     Up,
@@ -82,12 +187,123 @@ enum Filter {
     Paeth
 }
 
+type PassCallback<'a> = &'a mut dyn FnMut(usize, &Image);
+type RowCallback<'a> = &'a mut dyn FnMut(u32, &[u8]);
+
 #[derive(Default)]
-struct PNGReconstructor {
+struct PNGReconstructor<'a> {
     y: u32,
     pass_id: usize, // 0: non-interlaced, 1..7: interlaced
     scanline_bufs: [Vec<u8>; 2], // 0 - prev, 1 - cur
     cur_consumable_bytes: usize,
+    cur_filled_bytes: usize, // bytes of the current scanline inflated so far
+    adler: Option<u32>, // Adler-32 of the scanlines so far, if the zlib checksum is verified
+    format: Option<PixelFormat>, // layout of the final image, if it differs from the file
+    converted: Vec<u8>, // final image if it's converted or reduced and there is no caller buffer
+    dst: Option<&'a mut [u8]>, // caller buffer for the final image
+    sink: Option<&'a mut dyn RowSink>, // receiver of the final rows
+    rows_only: bool, // only the current row is kept, it goes to `sink` once reconstructed
+    native_row_only: bool, // only the current row is kept in the layout of the file, the image is in `converted` or `dst`
+    on_pass: Option<PassCallback<'a>>, // receiver of a preview after each Adam7 pass
+    preview_format: Option<PixelFormat>, // layout of the previews if the image is converted after decoding
+    last_pass: usize, // Adam7 passes after it aren't decoded, the image is reduced to the grid of the passes up to it
+    first_row: u32, // first and end row of an iDOT segment, the whole image otherwise
+    end_row: u32,
+    stopped: bool, // set once the last pass or row is complete and there are more, inflate stops then
+    #[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+    cpu: CpuFeatures, // extensions the SIMD code paths may use
+    stride: usize, // row stride of the final image
+    row_buf: Vec<u8>, // little-endian copy of a 16-bit row for the row callbacks
+}
+
+impl PNGImage {
+    pub(crate) fn new() -> PNGImage {
+        PNGImage {
+            image: Image {
+                w: 0,
+                h: 0,
+                channels: 0,
+                buf: vec![],
+                depth: 0,
+            },
+            depth: 0,
+            palette: None,
+            trns_alpha: None,
+            color_type: 0,
+            gama: None,
+            interlaced: false,
+            text: vec![],
+            icc_profile: None,
+            chromaticities: None,
+            srgb_intent: None,
+            cicp: None,
+            exif: None,
+            significant_bits: None,
+            background: None,
+            idat_seen: false,
+            segments: None,
+            warnings: vec![],
+            rows_valid: 0,
+        }
+    }
+
+    // Indexed images need a PLTE chunk before the image data
+    pub(crate) fn palette_missing(&self) -> bool {
+        self.color_type == 3 && self.palette.is_none()
+    }
+
+    // IHDR has to be the first chunk and can't be repeated
+    pub(crate) fn misplaced_ihdr(&self, chunk_name: &[u8; 4]) -> bool {
+        (chunk_name == b"IHDR") != (self.image.w == 0)
+    }
+
+    // Copy of the header fields that the reconstructor needs, for reconstructing a segment on another thread
+    fn segment_image(&self) -> PNGImage {
+        let mut segment_image = PNGImage::new();
+        segment_image.image = Image { w: self.image.w, h: self.image.h, channels: self.image.channels, buf: vec![], depth: self.image.depth };
+        segment_image.depth = self.depth;
+        segment_image.palette = self.palette.clone();
+        segment_image.trns_alpha = self.trns_alpha;
+        segment_image.color_type = self.color_type;
+        segment_image
+    }
+
+    /// Gamma from gAMA, i.e. encoded samples are linear values raised to this power
+    pub fn gamma(&self) -> Option<f32> {
+        self.gama
+    }
+
+    /// Whether the image data is Adam7 interlaced
+    pub fn interlaced(&self) -> bool {
+        self.interlaced
+    }
+
+    /// Color type from IHDR, 3 for palette images
+    pub fn color_type(&self) -> u8 {
+        self.color_type
+    }
+
+    /// Number of PLTE entries of palette images
+    pub fn palette_size(&self) -> Option<usize> {
+        self.palette.as_ref().map(|palette| palette.len)
+    }
+
+    // Makes the color chunks describe `target`
+    pub(crate) fn set_color_space(&mut self, target: TargetSpace) {
+        self.chromaticities = None;
+        self.cicp = None;
+        self.icc_profile = None;
+        match target {
+            TargetSpace::Srgb => {
+                self.srgb_intent = Some(self.srgb_intent.unwrap_or(RenderingIntent::RelativeColorimetric));
+                self.gama = Some(1. / 2.2);
+            },
+            TargetSpace::LinearSrgb => {
+                self.srgb_intent = None;
+                self.gama = Some(1.);
+            },
+        }
+    }
 }
 
 impl Palette {
@@ -100,19 +316,20 @@ impl Palette {
 }
 
 impl<'a> PNGDatastream<'a> {
-    fn new(buf: &'a [u8]) -> PNGDatastream<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> PNGDatastream<'a> {
         PNGDatastream {
             buf,
             cursor: 0,
             crc: 0,
+            check_crc: false,
         }
     }
 
-    fn len(&self) -> usize {
+    pub(crate) fn len(&self) -> usize {
         self.buf.len()
     }
 
-    fn eof(&self) -> bool {
+    pub(crate) fn eof(&self) -> bool {
         self.cursor == self.buf.len()
     }
 
@@ -126,20 +343,20 @@ impl<'a> PNGDatastream<'a> {
             self.cursor += 4;
             Ok(val)
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::Truncated.into())
         }
     }
 
-    fn read_u32(&mut self) -> Result<u32, DecodingError> {
+    pub(crate) fn read_u32(&mut self) -> Result<u32, DecodingError> {
         let val = self.read_u32_unchecked()?;
         if val < 0x80000000 {
             Ok(val)
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::InvalidChunkData.into()) // PNG integers are at most 2^31 - 1
         }
     }
 
-    fn read_u16(&mut self) -> Result<u16, DecodingError> {
+    pub(crate) fn read_u16(&mut self) -> Result<u16, DecodingError> {
         if self.cursor + 2 <= self.buf.len() {
             self.update_crc(&self.buf[self.cursor..self.cursor + 2]);
             let val = u16::from_be_bytes([
@@ -148,7 +365,7 @@ impl<'a> PNGDatastream<'a> {
             self.cursor += 2;
             Ok(val)
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::Truncated.into())
         }
     }
 
@@ -163,37 +380,37 @@ impl<'a> PNGDatastream<'a> {
     //             self.cursor += 4;
     //             Ok(val)
     //         } else {
-    //             Err(DecodingError::MalformedImage)
+    //             Err(ErrorKind::Truncated.into())
     //         }
     //     } else {
-    //         Err(DecodingError::MalformedImage)
+    //         Err(ErrorKind::Truncated.into())
     //     }
     // }
 
-    fn read_u8(&mut self) -> Result<u8, DecodingError> {
+    pub(crate) fn read_u8(&mut self) -> Result<u8, DecodingError> {
         if self.cursor + 1 <= self.buf.len() {
             self.update_crc_by_byte(self.buf[self.cursor]);
             let val = self.buf[self.cursor];
             self.cursor += 1;
             Ok(val)
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::Truncated.into())
         }
     }
 
-    fn consume(&mut self, pattern: &[u8]) -> Result<(), DecodingError> {
+    pub(crate) fn consume(&mut self, pattern: &[u8]) -> Result<(), DecodingError> {
         if self.buf[self.cursor..self.buf.len()].starts_with(pattern) {
             self.update_crc(&self.buf[self.cursor..self.cursor + pattern.len()]);
             self.cursor += pattern.len();
             Ok(())
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::InvalidSignature.into())
         }
     }
 
     #[inline(always)]
-    fn read_chunk_name(&mut self) -> Result<[u8; 4], DecodingError> {
-        if self.cursor + 4 < self.buf.len() {
+    pub(crate) fn read_chunk_name(&mut self) -> Result<[u8; 4], DecodingError> {
+        if self.cursor + 4 <= self.buf.len() {
             self.update_crc(&self.buf[self.cursor..self.cursor + 4]);
             let val = [
                 self.buf[self.cursor + 0],
@@ -204,56 +421,59 @@ impl<'a> PNGDatastream<'a> {
             self.cursor += 4;
             Ok(val)
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::Truncated.into())
         }
     }
 
-    fn skip(&mut self, count: usize) -> Result<(), DecodingError> {
+    // Bytes up to the next zero byte, which is consumed too
+    pub(crate) fn read_null_terminated(&mut self) -> Result<&'a [u8], DecodingError> {
+        let len = self.buf[self.cursor..].iter().position(|byte| *byte == 0).ok_or(ErrorKind::InvalidChunkData)?;
+        let bytes = &self.buf[self.cursor..self.cursor + len];
+        self.skip(len + 1)?;
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_to_end(&mut self) -> &'a [u8] {
+        let bytes = &self.buf[self.cursor..];
+        self.update_crc(bytes);
+        self.cursor = self.buf.len();
+        bytes
+    }
+
+    pub(crate) fn skip(&mut self, count: usize) -> Result<(), DecodingError> {
         if self.cursor + count <= self.buf.len() {
             self.update_crc(&self.buf[self.cursor..self.cursor + count]);
             self.cursor += count;
             Ok(())
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::Truncated.into())
         }
     }
 
-    fn reset_crc(&mut self) {
-        if CHECK_CRC {
+    pub(crate) fn reset_crc(&mut self) {
+        if self.check_crc {
             self.crc = 0xffffffff;
         }
     }
 
     fn update_crc_by_byte(&mut self, byte: u8) {
-        if CHECK_CRC {
+        if self.check_crc {
             self.crc = CRC_TABLE[(self.crc as u8 ^ byte) as usize] ^ (self.crc >> 8);
         }
     }
 
     fn update_crc(&mut self, buf: &[u8]) {
-        if CHECK_CRC {
-            for elem in buf {
-                self.update_crc_by_byte(*elem);
-            }
-            // for byte in buf {
-            //     for i in 0..8 {
-            //         let new_bit = (self.crc as u8 ^ (byte >> i)) & 1 > 0;
-            //         self.crc >>= 1;
-            //         if new_bit {
-            //             self.crc ^= 0xedb88320;
-            //         }
-            //     }
-            // }
+        if self.check_crc {
+            self.crc = checksum::crc32_update(self.crc, buf);
         }
     }
 
     #[inline(always)]
-    fn consume_crc(&mut self) -> Result<(), DecodingError> {
+    pub(crate) fn consume_crc(&mut self) -> Result<(), DecodingError> {
         let crc = self.crc ^ 0xffffffff;
         let crc_check = self.read_u32_unchecked()?;
-        if CHECK_CRC && crc != crc_check {
-            // println!("crc check failed: computed {:08x} != decoded {:08x}", crc, crc_check);
-            Err(DecodingError::MalformedImage)
+        if self.check_crc && crc != crc_check {
+            Err(ErrorKind::CrcMismatch.into())
         } else {
             Ok(())
         }
@@ -281,7 +501,7 @@ fn paeth_predictor_orig(a: u8, b: u8, c: u8) -> u8 {
     }
 }
 
-fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+pub(crate) fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
     // stbi's paeth predictor
     let thresh = c as i32 * 3 - (a as i32 + b as i32);
     let lo = if a < b { a } else { b };
@@ -362,11 +582,25 @@ fn defilter_paeth<const BPP: usize>(prev: &[u8], cur: *const u8, out: *mut u8) {
 }
 
 
+// `prev` can be the row above in the image, so its last pixel is loaded byte by byte
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn load_3(prev: &[u8], i: usize) -> __m128i {
+    unsafe {
+        if i + 4 <= prev.len() {
+            _mm_loadu_si32(prev.as_ptr().add(i))
+        } else {
+            _mm_cvtsi32_si128(i32::from_le_bytes([prev[i], prev[i + 1], prev[i + 2], 0]))
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
 fn defilter_sub_3(len: usize, cur: *const u8, out: *mut u8) {
     unsafe {
         let mut acc = _mm_setzero_si128();
         for i in (0..len).step_by(3) {
-            let x = _mm_loadu_si32(cur.add(i)); // TODO: oob
+            let x = _mm_loadu_si32(cur.add(i));
 
             acc = _mm_add_epi8(acc, x);
 
@@ -378,12 +612,13 @@ fn defilter_sub_3(len: usize, cur: *const u8, out: *mut u8) {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
 fn defilter_avg_3(prev: &[u8], cur: *const u8, out: *mut u8) {
     unsafe {
         let mut a = _mm_setzero_si128();
         for i in (0..prev.len()).step_by(3) {
-            let b = _mm_loadu_si32(prev.as_ptr().add(i)); // TODO: oob
-            let x = _mm_loadu_si32(cur.add(i)); // TODO: oob
+            let b = load_3(prev, i);
+            let x = _mm_loadu_si32(cur.add(i));
 
             // (a + b) / 2 == a + b - (a + b + 1) / 2
             let sum = _mm_add_epi8(a, b);
@@ -399,14 +634,15 @@ fn defilter_avg_3(prev: &[u8], cur: *const u8, out: *mut u8) {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
 #[target_feature(enable = "sse4.1")]
 fn defilter_paeth_3(prev: &[u8], cur: *const u8, out: *mut u8) {
     unsafe {
         let mut a = _mm_setzero_si128();
         let mut c = _mm_setzero_si128();
         for i in (0..prev.len()).step_by(3) {
-            let b = _mm_loadu_si32(prev.as_ptr().add(i)); // TODO: last read is out of bounds
-            let x = _mm_loadu_si32(cur.add(i)); // TODO: out of bounds
+            let b = load_3(prev, i);
+            let x = _mm_loadu_si32(cur.add(i));
 
             // fast 8-bit implementation adapted from https://github.com/veluca93/fpnge/issues/32
             // c >= max(a, b) -> pa saturates to 0; pred = min(a, b)
@@ -437,6 +673,7 @@ fn defilter_paeth_3(prev: &[u8], cur: *const u8, out: *mut u8) {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
 fn defilter_sub_4(len: usize, cur: *const u8, out: *mut u8) {
     unsafe {
         let mut acc = _mm_setzero_si128();
@@ -448,6 +685,7 @@ fn defilter_sub_4(len: usize, cur: *const u8, out: *mut u8) {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
 fn defilter_avg_4(prev: &[u8], cur: *const u8, out: *mut u8) {
     unsafe {
         let mut a = _mm_setzero_si128();
@@ -466,6 +704,7 @@ fn defilter_avg_4(prev: &[u8], cur: *const u8, out: *mut u8) {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
 #[target_feature(enable = "sse4.1")]
 fn defilter_paeth_4(prev: &[u8], cur: *const u8, out: *mut u8) {
     unsafe {
@@ -506,38 +745,131 @@ fn defilter_scanline<const BPP: usize>(filter: Filter, prev: &[u8], cur: *const
     }
 }
 
+// SSE versions for the common RGB and RGBA pixels
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
 fn defilter_scanline_3(filter: Filter, prev: &[u8], cur: *const u8, out: *mut u8) {
     match filter {
         Filter::None => defilter_none(prev.len(), cur, out),
         Filter::Sub => defilter_sub_3(prev.len(), cur, out),
         Filter::Up => defilter_up(prev, cur, out),
         Filter::Average => defilter_avg_3(prev, cur, out),
-        Filter::Paeth => unsafe { defilter_paeth_3(prev, cur, out) },
+        Filter::Paeth => defilter_paeth_3(prev, cur, out),
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
 fn defilter_scanline_4(filter: Filter, prev: &[u8], cur: *const u8, out: *mut u8) {
     match filter {
         Filter::None => defilter_none(prev.len(), cur, out),
         Filter::Sub => defilter_sub_4(prev.len(), cur, out),
         Filter::Up => defilter_up(prev, cur, out),
         Filter::Average => defilter_avg_4(prev, cur, out),
-        Filter::Paeth => unsafe { defilter_paeth_4(prev, cur, out) },
+        Filter::Paeth => defilter_paeth_4(prev, cur, out),
     }
 }
 
 // index 0 is for regular non-interlaced images
 // indices 1..7 are for interlacing passes
-const START_X: [u32; 8] = [ 0, 0, 4, 0, 2, 0, 1, 0 ];
-const START_Y: [u32; 8] = [ 0, 0, 0, 4, 0, 2, 0, 1 ];
-const STEP_X:  [u32; 8] = [ 1, 8, 8, 4, 4, 2, 2, 1 ];
-const STEP_Y:  [u32; 8] = [ 1, 8, 8, 8, 4, 4, 2, 2 ];
+pub(crate) const START_X: [u32; 8] = [ 0, 0, 4, 0, 2, 0, 1, 0 ];
+pub(crate) const START_Y: [u32; 8] = [ 0, 0, 0, 4, 0, 2, 0, 1 ];
+pub(crate) const STEP_X:  [u32; 8] = [ 1, 8, 8, 4, 4, 2, 2, 1 ];
+pub(crate) const STEP_Y:  [u32; 8] = [ 1, 8, 8, 8, 4, 4, 2, 2 ];
+// the SIMD defilters load 4 bytes for each 3-byte pixel of the current scanline
+const SCANLINE_PADDING: usize = 1;
+// size of the blocks that each decoded pixel covers in the preview after a pass
+const BLOCK_W: [usize; 8] = [ 1, 8, 4, 4, 2, 2, 1, 1 ];
+const BLOCK_H: [usize; 8] = [ 1, 8, 8, 4, 4, 2, 2, 1 ];
+
+impl<'a> PNGReconstructor<'a> {
+    // Pixels are converted to `format` scanline by scanline if it's set. The final image goes to `destination`.
+    // Rows of non-interlaced images go to a row sink as soon as they are reconstructed, so only one row is kept.
+    // Interlaced images are kept whole and go to the sink once the last pass is done. Only the pixels of the
+    // passes up to `last_pass` are kept, in an image reduced to their grid if it's less than 7.
+    // Also allocates the buffers of `png_image` that are needed.
+    fn new(png_image: &mut PNGImage, format: Option<PixelFormat>, destination: Destination<'a>, last_pass: usize) -> PNGReconstructor<'a> {
+        let h = png_image.image.h;
+        PNGReconstructor::new_for_rows(png_image, format, destination, 0..h, last_pass)
+    }
+
+    // Reconstructs only `rows` of a non-interlaced image, the buffers hold just these rows.
+    // The first row is reconstructed as if it were the first row of the image. All the CPU features are used
+    // unless the caller restricts `cpu`.
+    fn new_for_rows(png_image: &mut PNGImage, format: Option<PixelFormat>, destination: Destination<'a>, rows: Range<u32>, last_pass: usize) -> PNGReconstructor<'a> {
+        png_image.idat_seen = true;
+        let w = png_image.image.w as usize;
+        let bpp = png_image.image.channels as usize * (png_image.image.depth / 8) as usize;
+        let native_stride = w * bpp;
+        let (reduced_w, _) = reduced_size(&png_image.image, last_pass);
+        let mut reconstructor = PNGReconstructor {
+            y: rows.start,
+            format,
+            stride: reduced_w as usize * format.map_or(bpp, |format| format.bytes_per_pixel()),
+            last_pass,
+            first_row: rows.start,
+            end_row: rows.end,
+            cpu: CpuFeatures::detect(),
+            ..Default::default()
+        };
+        match destination {
+            Destination::Buffer(dst, stride) => {
+                reconstructor.stride = stride;
+                reconstructor.dst = Some(dst);
+            },
+            Destination::Rows(sink) => {
+                reconstructor.sink = Some(sink);
+                reconstructor.rows_only = !png_image.interlaced;
+            },
+            Destination::Image => {},
+        }
+        // a converted or reduced image is put together from the rows of the file, the filters
+        // only look at the row before, which `scanline_bufs` keeps
+        reconstructor.native_row_only = reconstructor.rows_only || format.is_some() || last_pass < 7;
+        let rows = if reconstructor.rows_only { 1 } else { rows.len() };
+        if (format.is_some() || last_pass < 7) && reconstructor.dst.is_none() {
+            reconstructor.converted = vec![0; reconstructor.stride * rows.div_ceil(BLOCK_H[last_pass])];
+        }
+        // native pixels are the history for the filters, they go straight to the caller buffer if there's no conversion
+        png_image.image.buf =
+            if reconstructor.native_row_only {
+                vec![0; native_stride]
+            } else if reconstructor.dst.is_some() {
+                vec![]
+            } else {
+                vec![0; native_stride * rows]
+            };
+        if png_image.interlaced {
+            reconstructor.pass_id = 1;
+        }
+        let bytes_per_scanline = 1 +
+            if png_image.color_type != 3 {
+                (png_image.depth as usize * png_image.image.channels as usize * png_image.image.w as usize + 7) / 8
+            } else {
+                (png_image.depth as usize * png_image.image.w as usize + 7) / 8
+            };
+        reconstructor.scanline_bufs = [ vec![0; bytes_per_scanline + SCANLINE_PADDING], vec![0; bytes_per_scanline + SCANLINE_PADDING] ];
+
+        let png_channels =
+            if png_image.color_type == 3 {
+                1
+            } else {
+                png_image.image.channels as usize - png_image.trns_alpha.is_some() as usize
+            };
+        let scanline_pixels = (png_image.image.w + STEP_X[reconstructor.pass_id] - START_X[reconstructor.pass_id] - 1) / STEP_X[reconstructor.pass_id];
+        reconstructor.cur_consumable_bytes = (scanline_pixels as usize * png_channels * png_image.depth as usize + 7) / 8 + 1;
+        reconstructor
+    }
 
-impl PNGReconstructor {
     #[inline(never)]
     fn process_scanline(&mut self, png_image: &mut PNGImage) -> Result<(*mut u8, *const u8), DecodingError> {
+        if self.stopped {
+            // the bytes decoded before inflate notices are dropped
+            let cur_scanline_cursor = self.scanline_bufs[1].as_mut_ptr();
+            return Ok((cur_scanline_cursor, unsafe { cur_scanline_cursor.add(self.cur_consumable_bytes) }));
+        }
         if self.y >= png_image.image.h || self.pass_id >= 8 {
-            return Err(DecodingError::MalformedImage);
+            return Err(ErrorKind::TooMuchData.into());
         }
 
         let png_channels =
@@ -553,53 +885,68 @@ impl PNGReconstructor {
                 2 => Filter::Up,
                 3 => Filter::Average,
                 4 => Filter::Paeth,
-                _ => return Err(DecodingError::MalformedImage)
+                _ => return Err(ErrorKind::InvalidFilter.into())
             };
+        if self.y == self.first_row && self.first_row != 0 && matches!(filter, Filter::Up | Filter::Average | Filter::Paeth) {
+            return Err(ErrorKind::NotImplemented.into()); // the segment depends on the row above it
+        }
         // println!("filter: {}", byte);
+        if let Some(adler) = self.adler.as_mut() {
+            *adler = checksum::adler32_update(*adler, &self.scanline_bufs[1][..self.cur_consumable_bytes]);
+        }
         
         let bpp_out = (png_image.image.channels * png_image.image.depth as u32 / 8) as usize; // bytes per pixel
-        let bpp_in = if png_image.depth < 8 { 1 } else { bpp_out };
+        let bpp_in = max(png_channels * png_image.depth as usize / 8, 1); // bytes per pixel in the file
         let bpc = (png_image.depth / 8) as usize; // bytes per channel
+        let (native, native_stride) = match &mut self.dst {
+            Some(dst) if !self.native_row_only => (&mut dst[..], self.stride),
+            _ => (&mut png_image.image.buf[..], png_image.image.w as usize * bpp_out),
+        };
+        let row_start = if self.native_row_only { 0 } else { (self.y - self.first_row) as usize * native_stride };
 
         let (prev, cur) = self.scanline_bufs.split_at_mut(1);
         let (prev, cur, out) =
-            if png_image.palette.is_none() && png_image.depth >= 8 && STEP_X[self.pass_id] == 1 && png_image.trns_alpha.is_none() {
-                let idx = self.y as usize * png_image.image.w as usize * bpp_out as usize;
-                let (img_l, img_r) = png_image.image.buf.split_at_mut(idx);
+            if png_image.palette.is_none() && png_image.depth >= 8 && STEP_X[self.pass_id] == 1 && png_image.trns_alpha.is_none() && !self.native_row_only {
+                let idx = row_start;
+                let (img_l, img_r) = native.split_at_mut(idx);
                 let prev =
-                    if self.y == START_Y[self.pass_id] {
+                    if self.y == START_Y[self.pass_id] + self.first_row {
                         &prev[0][1..self.cur_consumable_bytes]
                         // &self.scanline_bufs[0][1..self.cur_consumable_bytes]
                     } else {
-                        let start = idx - (self.cur_consumable_bytes - 1) * STEP_Y[self.pass_id] as usize;
+                        let start = idx - native_stride * STEP_Y[self.pass_id] as usize;
                         &img_l[start..start + self.cur_consumable_bytes - 1]
                     };
-                (prev, cur[0][1..self.cur_consumable_bytes].as_ptr(), img_r[0..self.cur_consumable_bytes - 1].as_mut_ptr())
+                (prev, cur[0][1..].as_ptr(), img_r[0..self.cur_consumable_bytes - 1].as_mut_ptr())
             } else {
-                (&prev[0][1..self.cur_consumable_bytes], cur[0][1..self.cur_consumable_bytes].as_ptr(), cur[0][1..self.cur_consumable_bytes].as_mut_ptr())
+                // in place, `cur` keeps the padding for the SIMD loads
+                let cur = cur[0][1..].as_mut_ptr();
+                (&prev[0][1..self.cur_consumable_bytes], cur as *const u8, cur)
             };
         
         match bpp_in {
             1 => defilter_scanline::<1>(filter, prev, cur, out),
             2 => defilter_scanline::<2>(filter, prev, cur, out),
-            // 3 => defilter_scanline::<3>(filter, prev, cur, out),
-            3 => defilter_scanline_3(filter, prev, cur, out),
-            // 4 => defilter_scanline::<4>(filter, prev, cur, out),
-            4 => defilter_scanline_4(filter, prev, cur, out),
+            #[cfg(target_arch = "x86_64")]
+            3 if self.cpu.sse41 => unsafe { defilter_scanline_3(filter, prev, cur, out) },
+            3 => defilter_scanline::<3>(filter, prev, cur, out),
+            #[cfg(target_arch = "x86_64")]
+            4 if self.cpu.sse41 => unsafe { defilter_scanline_4(filter, prev, cur, out) },
+            4 => defilter_scanline::<4>(filter, prev, cur, out),
             6 => defilter_scanline::<6>(filter, prev, cur, out),
             8 => defilter_scanline::<8>(filter, prev, cur, out),
-            _ => panic!("Unreachable")
+            _ => return Err(ErrorKind::InvalidHeader.into()),
         }
 
         if let Some(palette) = &png_image.palette {
-            let base_idx = (self.y as usize * png_image.image.w as usize + START_X[self.pass_id] as usize) * bpp_out;
+            let base_idx = row_start + START_X[self.pass_id] as usize * bpp_out;
             let mut idx = 0;
             for i in 1..self.cur_consumable_bytes {
                 let byte = self.scanline_bufs[1][i];
                 for j in (0..8).step_by(png_image.depth as usize) {
                     let idx_in_palette = ((byte >> (8 - png_image.depth - j)) & (((1 as u32) << png_image.depth) - 1) as u8) as usize;
                     for c in 0..png_image.image.channels {
-                        png_image.image.buf[base_idx + idx + c as usize] = palette.values[c as usize][idx_in_palette];
+                        native[base_idx + idx + c as usize] = palette.values[c as usize][idx_in_palette];
                     }
                     idx += STEP_X[self.pass_id] as usize * bpp_out;
                     if idx + START_X[self.pass_id] as usize * bpp_out >= png_image.image.w as usize * bpp_out as usize {
@@ -610,16 +957,16 @@ impl PNGReconstructor {
         } else { // no palette
             if png_image.depth < 8 {
                 let max_val = (1 << png_image.depth) - 1;
-                let base_idx = (self.y as usize * png_image.image.w as usize + START_X[self.pass_id] as usize) * bpp_out;
+                let base_idx = row_start + START_X[self.pass_id] as usize * bpp_out;
                 let mut idx = 0;
                 for i in 1..self.cur_consumable_bytes {
                     let byte = self.scanline_bufs[1][i];
                     for j in (0..8).step_by(png_image.depth as usize).rev() {
                         let val = byte >> j & max_val;
-                        png_image.image.buf[base_idx + idx] = val * (255 / max_val) as u8;
+                        native[base_idx + idx] = val * (255 / max_val) as u8;
                         if let Some(trns_alpha) = png_image.trns_alpha {
                             let alpha = if val as u16 == trns_alpha[0] { 0 } else { 255 };
-                            png_image.image.buf[base_idx + idx + 1] = alpha;
+                            native[base_idx + idx + 1] = alpha;
                         }
                         idx += STEP_X[self.pass_id] as usize * bpp_out as usize;
                         if idx + START_X[self.pass_id] as usize * bpp_out >= png_image.image.w as usize * bpp_out as usize {
@@ -628,12 +975,12 @@ impl PNGReconstructor {
                     }
                 }
             } else { // depth >= 8
-                let mut idx = (self.y as usize * png_image.image.w as usize + START_X[self.pass_id] as usize) * bpp_out as usize;
+                let mut idx = row_start + START_X[self.pass_id] as usize * bpp_out;
                 let pix_size = png_channels * bpc;
                 if STEP_X[self.pass_id] != 1 || png_image.trns_alpha.is_some() {
                     // copy pixel by pixel
                     for i in (1..self.cur_consumable_bytes).step_by(pix_size) {
-                        png_image.image.buf[idx..idx + pix_size].copy_from_slice(&self.scanline_bufs[1][i..i + pix_size]);
+                        native[idx..idx + pix_size].copy_from_slice(&self.scanline_bufs[1][i..i + pix_size]);
                         idx += STEP_X[self.pass_id] as usize * bpp_out as usize;
                     }
                     if let Some(trns_alpha) = png_image.trns_alpha {
@@ -641,25 +988,70 @@ impl PNGReconstructor {
                             if png_image.depth == 8 {
                                 std::array::from_fn(|i| if i < 3 { trns_alpha[i] as u8 } else { 0 })
                             } else { // depth == 16
-                                std::array::from_fn(|i| (trns_alpha[i / 2] >> (i % 2 * 8)) as u8)
+                                std::array::from_fn(|i| (trns_alpha[i / 2] >> ((1 - i % 2) * 8)) as u8) // big-endian, like the samples
                             };
-                        let mut idx = (self.y as usize * png_image.image.w as usize + START_X[self.pass_id] as usize) * bpp_out as usize;
+                        let mut idx = row_start + START_X[self.pass_id] as usize * bpp_out;
                         for i in (1..self.cur_consumable_bytes).step_by(pix_size) {
                             let alpha =
                                 if self.scanline_bufs[1][i..i + png_channels * bpc].eq(&trns_alpha[0..png_channels * bpc])
                                     { 0 } else { 255 };
-                            png_image.image.buf[idx + png_channels * bpc..idx + png_channels * bpc + bpc].fill(alpha);
+                            native[idx + png_channels * bpc..idx + png_channels * bpc + bpc].fill(alpha);
                             idx += STEP_X[self.pass_id] as usize * bpp_out as usize;
                         }
                     }
+                } else if self.native_row_only {
+                    // defiltered in place, there is no image to write into directly
+                    native[idx..idx + self.cur_consumable_bytes - 1].copy_from_slice(&self.scanline_bufs[1][1..self.cur_consumable_bytes]);
+                }
+            }
+        }
+
+        // rows off the grid of a reduced image aren't kept
+        let (block_w, block_h) = (BLOCK_W[self.last_pass], BLOCK_H[self.last_pass]);
+        if (self.format.is_some() || self.last_pass < 7) && self.y as usize % block_h == 0 {
+            let (w, _) = reduced_size(&png_image.image, self.last_pass);
+            let (mut start_x, mut step) = (START_X[self.pass_id] as usize, STEP_X[self.pass_id] as usize);
+            let native_row = &mut png_image.image.buf[row_start..];
+            if block_w > 1 {
+                // the pixels on the grid move to their columns in the reduced image, which are never to the right
+                for x in (start_x..png_image.image.w as usize).step_by(step.max(block_w)) {
+                    native_row.copy_within(x * bpp_out..(x + 1) * bpp_out, x / block_w * bpp_out);
                 }
+                (start_x, step) = (start_x / block_w, step.div_ceil(block_w));
+            }
+            let src = &native_row[start_x * bpp_out..w as usize * bpp_out];
+            let out = self.dst.as_deref_mut().unwrap_or(&mut self.converted);
+            let out_start = if self.rows_only { 0 } else { (self.y - self.first_row) as usize / block_h * self.stride };
+            let out_bpp = self.format.map_or(bpp_out, |format| format.bytes_per_pixel());
+            let dst = &mut out[out_start..][start_x * out_bpp..w as usize * out_bpp];
+            match self.format {
+                Some(format) => {
+                    let big_endian = png_image.depth == 16; // samples are swapped in finish
+                    convert_row(src, png_image.image.channels, png_image.image.depth, big_endian, format, dst, step);
+                },
+                None => {
+                    for (pixel, out) in src.chunks(bpp_out * step).zip(dst.chunks_mut(bpp_out * step)) {
+                        out[..bpp_out].copy_from_slice(&pixel[..bpp_out]);
+                    }
+                },
+            }
+        }
+
+        if self.rows_only {
+            let mut row_buf = std::mem::take(&mut self.row_buf);
+            let mut sink = self.sink.take();
+            if let Some(sink) = sink.as_deref_mut() {
+                sink.row(self.y, self.final_row(png_image, self.y as usize, &mut row_buf));
             }
+            self.sink = sink;
+            self.row_buf = row_buf;
         }
 
         self.y += STEP_Y[self.pass_id];
         self.scanline_bufs.swap(0, 1);
 
         if png_image.interlaced {
+            let pass_id = self.pass_id;
             while START_X[self.pass_id] >= png_image.image.w || self.y >= png_image.image.h {
                 self.pass_id += 1;
                 if self.pass_id < 8 {
@@ -673,6 +1065,21 @@ impl PNGReconstructor {
                 self.cur_consumable_bytes = (scanline_pixels as usize * png_channels * png_image.depth as usize + 7) / 8 + 1;
                 self.scanline_bufs[0][0..self.cur_consumable_bytes].fill(0);
             }
+            self.stopped = self.pass_id > self.last_pass && self.pass_id < 8;
+            if self.on_pass.is_some() {
+                // passes without pixels are done along with the one before them
+                for pass in pass_id..self.pass_id.min(self.last_pass + 1) {
+                    let mut preview = self.preview(png_image, pass);
+                    if let Some(format) = self.preview_format {
+                        pixel_format::convert(&mut preview, format);
+                    }
+                    if let Some(on_pass) = self.on_pass.as_mut() {
+                        on_pass(pass, &preview);
+                    }
+                }
+            }
+        } else {
+            self.stopped = self.y == self.end_row && self.end_row < png_image.image.h;
         }
 
         let cur_scanline_cursor = self.scanline_bufs[1].as_mut_ptr();
@@ -681,50 +1088,151 @@ impl PNGReconstructor {
         Ok((cur_scanline_cursor, cur_scanline_end))
     }
 
-    #[inline(always)]
-    fn consume_decoded_byte(&mut self, png_image: &mut PNGImage, byte: u8, cur_scanline_cursor: *mut u8, cur_scanline_end: *const u8) -> Result<(*mut u8, *const u8), DecodingError> {
-        let cur_scanline_cursor = unsafe {
-            *cur_scanline_cursor = byte;
-            cur_scanline_cursor.add(1)
-        };
-        if cur_scanline_cursor as *const u8 == cur_scanline_end {
-            self.process_scanline(png_image)
+    // Like process_scanline, also calls `on_row` with the row index and the row in the final layout
+    // if the scanline is kept
+    fn process_scanline_with_row(&mut self, png_image: &mut PNGImage, on_row: &mut dyn FnMut(u32, &[u8])) -> Result<(*mut u8, *const u8), DecodingError> {
+        let (y, stopped) = (self.y as usize, self.stopped);
+        let next = self.process_scanline(png_image)?;
+        // rows off the grid of a reduced image aren't kept
+        let block_h = BLOCK_H[self.last_pass];
+        if !stopped && y % block_h == 0 {
+            let mut row_buf = std::mem::take(&mut self.row_buf);
+            on_row((y / block_h) as u32, self.final_row(png_image, y, &mut row_buf));
+            self.row_buf = row_buf;
+        }
+        Ok(next)
+    }
+
+    // Full-size image after Adam7 pass `pass` in the final layout. Pixels of later passes are copied
+    // from the decoded pixel above and to the left of them.
+    fn preview(&self, png_image: &PNGImage, pass: usize) -> Image {
+        let image = &png_image.image;
+        let (w, h) = (image.w as usize, image.h as usize);
+        let (channels, depth) = self.format.map_or((image.channels, image.depth), |format| (format.channels(), format.depth()));
+        let bpp = channels as usize * depth as usize / 8;
+        let src = self.final_buf(png_image);
+        let (block_w, block_h) = (BLOCK_W[self.last_pass], BLOCK_H[self.last_pass]);
+        let mut buf = vec![0; w * h * bpp];
+        for y in 0..h {
+            let src_y = y & !(BLOCK_H[pass] - 1);
+            if src_y != y {
+                buf.copy_within(src_y * w * bpp..(src_y + 1) * w * bpp, y * w * bpp);
+                continue;
+            }
+            for x in 0..w {
+                let src_x = x & !(BLOCK_W[pass] - 1);
+                buf[(y * w + x) * bpp..][..bpp].copy_from_slice(&src[y / block_h * self.stride + src_x / block_w * bpp..][..bpp]);
+            }
+        }
+        if self.format.is_none() && png_image.depth == 16 {
+            swap_bytes_16(&mut buf);
+        }
+        Image { w: image.w, h: image.h, channels, buf, depth }
+    }
+
+    // Final image, or the current row of it, unless it goes to the caller buffer
+    fn final_buf<'s>(&'s self, png_image: &'s PNGImage) -> &'s [u8] {
+        if self.format.is_some() || self.last_pass < 7 { &self.converted } else { &png_image.image.buf }
+    }
+
+    // Row `y` of the file in the final layout, which has to be on the grid of a reduced image. 16-bit rows
+    // that aren't converted are still big-endian, so they are copied to `row_buf` and swapped.
+    fn final_row<'s>(&'s self, png_image: &'s PNGImage, y: usize, row_buf: &'s mut Vec<u8>) -> &'s [u8] {
+        let image = &png_image.image;
+        let start = if self.rows_only { 0 } else { (y - self.first_row as usize) / BLOCK_H[self.last_pass] * self.stride };
+        let final_buf = self.dst.as_deref().unwrap_or(self.final_buf(png_image));
+        let (w, _) = reduced_size(image, self.last_pass);
+        let bpp = self.format.map_or(image.channels as usize * image.depth as usize / 8, |format| format.bytes_per_pixel());
+        let row = &final_buf[start..][..w as usize * bpp];
+        if self.format.is_some() {
+            return row;
+        }
+        if png_image.depth == 16 {
+            row_buf.clear();
+            row_buf.extend_from_slice(row);
+            swap_bytes_16(row_buf);
+            row_buf
         } else {
-            Ok((cur_scanline_cursor, cur_scanline_end))
+            row
+        }
+    }
+
+    // Rows from the top that no pass has to touch anymore
+    fn rows_valid(&self, png_image: &PNGImage) -> u32 {
+        let image = &png_image.image;
+        if !png_image.interlaced {
+            return self.y - self.first_row;
+        }
+        if self.pass_id > self.last_pass {
+            return image.h;
+        }
+        let later_passes = (self.pass_id + 1..=self.last_pass).filter(|pass| START_X[*pass] < image.w);
+        later_passes.map(|pass| START_Y[pass]).fold(self.y, u32::min).min(image.h)
+    }
+
+    // Puts the image into its final layout once all scanlines are reconstructed, or as many as there are.
+    // The buffer of `png_image` is left empty if the image went to a caller buffer or a row sink.
+    fn finish(&mut self, png_image: &mut PNGImage) {
+        png_image.rows_valid = self.rows_valid(png_image).div_ceil(BLOCK_H[self.last_pass] as u32);
+        let image = &mut png_image.image;
+        (image.w, image.h) = reduced_size(image, self.last_pass);
+        if self.format.is_some() || self.last_pass < 7 {
+            image.buf = std::mem::take(&mut self.converted);
+        }
+        if let Some(format) = self.format {
+            image.channels = format.channels();
+            image.depth = format.depth();
+        } else if png_image.depth == 16 && !self.rows_only {
+            let row_size = image.w as usize * image.channels as usize * 2;
+            match self.dst.as_deref_mut() {
+                Some(dst) => dst.chunks_mut(self.stride).take(image.h as usize).for_each(|row| swap_bytes_16(&mut row[..row_size])),
+                None => swap_bytes_16(&mut image.buf),
+            }
+        }
+        if let Some(sink) = self.sink.as_deref_mut() {
+            if self.rows_only {
+                image.buf = vec![];
+            } else {
+                image.send_rows(sink);
+            }
         }
     }
 }
 
-impl BitStream {
-    fn new(chunk_bytes_left: u32) -> BitStream {
-        BitStream {
-            buf: 0,
-            bits_left: 0,
+impl<'s, 'b> IdatInput<'s, 'b> {
+    fn new(stream: &'s mut PNGDatastream<'b>, chunk_bytes_left: u32) -> IdatInput<'s, 'b> {
+        let mut input = IdatInput {
+            stream,
             chunk_bytes_left,
             end_of_bytestream: false,
-        }
+        };
+        input.update_chunk_crc();
+        input
+    }
+
+    // Adds the data of the chunk to its CRC all at once, which is much faster than the few bytes of each refill
+    fn update_chunk_crc(&mut self) {
+        let datastream = &mut *self.stream;
+        let buf = datastream.buf;
+        let end = std::cmp::min(datastream.cursor + self.chunk_bytes_left as usize, buf.len());
+        datastream.update_crc(&buf[datastream.cursor..end]);
     }
 
     #[inline(always)]
-    fn ensure_inside_chunk(&mut self, datastream: &mut PNGDatastream, req_bytes: u32) {
+    fn refill_inside_chunk(&mut self, bits: &mut BitBuf, req_bytes: u32) {
         let available_bytes = req_bytes;
+        let datastream = &mut *self.stream;
 
-        let data = unsafe { (datastream.buf.as_ptr().add(datastream.cursor) as *const u64).read_unaligned() };
-        // let slice: [u8; 8] = datastream.buf[datastream.cursor..datastream.cursor + 8].try_into().unwrap();
-        // let slice: [u8; 8] = datastream.buf[datastream.cursor + available_bytes as usize - 8..datastream.cursor + available_bytes as usize].try_into().unwrap();
-        // let data = u64::from_le_bytes(slice);
-        // let mask = (1 << (available_bytes * 8)) - 1;
-        // let mask = if available_bytes >= 8 { 0xffffffffffffffff } else { (1 << (available_bytes * 8)) - 1 };
-        let mask =
-            if available_bytes == 0 {
-                0
-            } else {
-                0xffffffffffffffff >> (64 - available_bytes * 8)
-            };
-        let buf_update = (data & mask) << self.bits_left;
-        self.buf |= buf_update;
-        self.bits_left += available_bytes * 8;
-        datastream.update_crc(&data.to_le_bytes()[0..available_bytes as usize]);
+        let data = if datastream.cursor + 8 <= datastream.len() {
+            unsafe { (datastream.buf.as_ptr().add(datastream.cursor) as *const u64).read_unaligned() }
+        } else {
+            // the last bytes of the file, e.g. of a cut off IDAT chunk
+            let mut bytes = [0; 8];
+            let tail = &datastream.buf[datastream.cursor..];
+            bytes[..tail.len()].copy_from_slice(tail);
+            u64::from_le_bytes(bytes)
+        };
+        bits.append(data, available_bytes);
 
         datastream.cursor += available_bytes as usize;
 
@@ -732,14 +1240,20 @@ impl BitStream {
     }
 
     #[inline(never)]
-    fn ensure_across_chunks(&mut self, datastream: &mut PNGDatastream, req_bytes: u32) -> Result<(), DecodingError> {
+    fn refill_across_chunks(&mut self, bits: &mut BitBuf, req_bytes: u32) -> Result<(), DecodingError> {
         let mut req_bytes = req_bytes;
         loop {
             let available_bytes = std::cmp::min(self.chunk_bytes_left, req_bytes);
-            self.ensure_inside_chunk(datastream, available_bytes);
+            self.refill_inside_chunk(bits, available_bytes);
             if req_bytes == available_bytes {
                 break;
             }
+            let datastream = &mut *self.stream;
+            if datastream.eof() {
+                // the file ends inside the chunk, inflate gets the bits that are left
+                self.end_of_bytestream = true;
+                break;
+            }
             datastream.consume_crc()?;
             self.chunk_bytes_left = datastream.read_u32()?;
             datastream.reset_crc();
@@ -748,72 +1262,112 @@ impl BitStream {
                 datastream.cursor -= 8;
                 self.end_of_bytestream = true;
                 break;
-            } else if datastream.cursor + self.chunk_bytes_left as usize > datastream.len() {
-                return Err(DecodingError::MalformedImage);
             }
+            self.chunk_bytes_left = self.chunk_bytes_left.min((datastream.len() - datastream.cursor) as u32);
+            self.update_chunk_crc();
             req_bytes -= available_bytes;
         }
         Ok(())
     }
 
+    // Skips the rest of the image data, the CRCs of the chunks are still checked
+    fn skip_idat_chunks(&mut self) -> Result<(), DecodingError> {
+        if self.end_of_bytestream {
+            return Ok(());
+        }
+        let datastream = &mut *self.stream;
+        datastream.cursor += self.chunk_bytes_left as usize; // the CRC already has the rest of the chunk
+        datastream.consume_crc()?;
+        loop {
+            let len = datastream.read_u32()? as usize;
+            datastream.reset_crc();
+            if datastream.read_chunk_name()? != *b"IDAT" {
+                datastream.cursor -= 8;
+                return Ok(());
+            }
+            datastream.skip(len)?;
+            datastream.consume_crc()?;
+        }
+    }
+}
+
+impl InflateInput for IdatInput<'_, '_> {
+    type Checkpoint = ();
+
     #[inline(always)]
-    fn ensure(&mut self, datastream: &mut PNGDatastream) -> Result<(), DecodingError> {
-        if self.bits_left >= 57 || self.end_of_bytestream {
+    fn refill(&mut self, bits: &mut BitBuf) -> Result<(), DecodingError> {
+        if bits.bits_left() >= 57 || self.end_of_bytestream {
             return Ok(());
         }
-        let req_bytes = (64 - self.bits_left) / 8;
+        let req_bytes = (64 - bits.bits_left()) / 8;
         if self.chunk_bytes_left >= req_bytes {
-            self.ensure_inside_chunk(datastream, req_bytes);
+            self.refill_inside_chunk(bits, req_bytes);
             Ok(())
         } else {
-            self.ensure_across_chunks(datastream, req_bytes)
+            self.refill_across_chunks(bits, req_bytes)
         }
     }
 
-    #[target_feature(enable = "bmi2")]
-    fn read(&mut self, count: u32) -> Result<u64, DecodingError> {
-        if self.bits_left < count {
-            Err(DecodingError::MalformedImage)
-        } else {
-            self.bits_left -= count;
-            let res = self.buf & ((1 << count) - 1); // generates BZHI instruction with enabled bmi2
-            // let res = self.buf & !(!0u64 << count);
-            // let res = _bzhi_u64(self.buf, count);
-            self.buf >>= count;
-            Ok(res)
-        }
+    // the image data is all there, it just may be cut off
+    fn resumable(&self) -> bool {
+        false
     }
 
-    fn peek(&mut self, count: u32) -> u64 {
-        self.buf & ((1 << count) - 1)
+    fn checkpoint(&self) {}
+
+    fn rollback(&mut self, _checkpoint: ()) {}
+}
+
+// Passes the inflated image data on to the reconstructor a scanline at a time. `on_row` gets the rows like
+// the `on_scanline` callback of `PngStreamDecoder::push`.
+struct ScanlineOutput<'r, 'a> {
+    reconstructor: &'r mut PNGReconstructor<'a>,
+    png_image: &'r mut PNGImage,
+    on_row: Option<RowCallback<'r>>,
+}
+
+unsafe impl InflateOutput for ScanlineOutput<'_, '_> {
+    #[inline(always)]
+    fn buf(&mut self) -> (*mut u8, *const u8) {
+        let reconstructor = &mut *self.reconstructor;
+        let cur_scanline = reconstructor.scanline_bufs[1].as_mut_ptr();
+        unsafe { (cur_scanline.add(reconstructor.cur_filled_bytes), cur_scanline.add(reconstructor.cur_consumable_bytes)) }
     }
 
-    fn skip(&mut self, count: u8) -> Result<(), DecodingError> {
-        if self.bits_left < count as u32 {
-            Err(DecodingError::MalformedImage)
-        } else {
-            self.bits_left -= count as u32;
-            self.buf >>= count;
-            Ok(())
+    #[inline(always)]
+    fn next(&mut self) -> Result<(*mut u8, *const u8), DecodingError> {
+        match self.on_row.as_deref_mut() {
+            Some(on_row) => self.reconstructor.process_scanline_with_row(self.png_image, on_row),
+            None => self.reconstructor.process_scanline(self.png_image),
         }
     }
+
+    fn pause(&mut self, cursor: *mut u8) {
+        self.reconstructor.cur_filled_bytes = unsafe { cursor.offset_from(self.reconstructor.scanline_bufs[1].as_ptr()) } as usize;
+    }
+
+    #[inline(always)]
+    fn stopped(&self) -> bool {
+        self.reconstructor.stopped
+    }
 }
 
-fn decode_ihdr(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(), DecodingError> {
+fn decode_ihdr(stream: &mut PNGDatastream, png_image: &mut PNGImage, limits: &Limits) -> Result<(), DecodingError> {
     if stream.len() != 13 {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidChunkLength.into());
     }
 
     let w = stream.read_u32()?;
     let h = stream.read_u32()?;
     if w == 0 || h == 0 {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidHeader.into());
     }
+    limits.check_size(w, h)?;
     // println!("{w} x {h}");
 
     png_image.depth = stream.read_u8()? as u8;
     if !([1, 2, 4, 8, 16] as [u8; 5]).contains(&png_image.depth) {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidColorType.into());
     }
     // if png_image.depth != 8 && png_image.depth != 16 {
     //     println!("depth == {}", png_image.depth);
@@ -826,7 +1380,7 @@ fn decode_ihdr(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(
         3 => png_image.image.channels = 3, // indexed color, channels might be promoted to 4 later
         4 => png_image.image.channels = 2, // grayscale with alpha
         6 => png_image.image.channels = 4, // rgba
-        _ => return Err(DecodingError::MalformedImage),
+        _ => return Err(ErrorKind::InvalidColorType.into()),
     }
     // println!("chans: {}", png_image.image.channels);
 
@@ -835,23 +1389,23 @@ fn decode_ihdr(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(
         || png_image.color_type == 3 && !([1, 2, 4, 8] as [u8; 4]).contains(&png_image.depth)
         || png_image.color_type != 0 && png_image.color_type != 3 && png_image.depth != 8 && png_image.depth != 16
     {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidColorType.into());
     }
     // println!("color type: {}", png_image.color_type);
 
     let compression_method = stream.read_u8()?;
     if compression_method != 0 {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidHeader.into());
     }
 
     let filter_method = stream.read_u8()?;
     if filter_method != 0 {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidHeader.into());
     }
 
     let interlace = stream.read_u8()?;
     if interlace > 1 {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidHeader.into());
     }
     png_image.interlaced = interlace == 1;
     // if png_image.interlaced {
@@ -865,414 +1419,271 @@ fn decode_ihdr(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(
     Ok(())
 }
 
-fn build_initiabl_codes_by_cls<const N: usize>(cls: &[u8]) -> [u16; N] {
-    let mut bl_count: [u16; N] = [0; N];
-    cls.iter()
-        .for_each(|val| bl_count[*val as usize] += 1);
-    bl_count[0] = 0;
-    let mut next_code: [u16; N] = [0; N];
-    for bits in 1..N {
-        next_code[bits] = (next_code[bits - 1] + bl_count[bits - 1] as u16) << 1;
-        if next_code[bits] + bl_count[bits] > (1 << bits) {
-            panic!("Malformed");
-        }
-    }
-    next_code
+#[inline(never)]
+fn decode_idat<'a>(stream: &mut PNGDatastream, chunk_bytes_left: u32, png_image: &mut PNGImage, options: &DecodeOptions, destination: Destination<'a>, on_pass: Option<PassCallback<'a>>) -> Result<(), DecodingError> {
+    let mut input = IdatInput::new(stream, chunk_bytes_left);
+    // the output format is applied along with the scanlines, unless other options have to go first
+    let (fused_format, preview_format) = if needs_postprocessing(options) { (None, options.output_format) } else { (options.output_format, None) };
+    let mut reconstructor = PNGReconstructor::new(png_image, fused_format, destination, options.adam7_passes.unwrap_or(7));
+    reconstructor.on_pass = on_pass;
+    reconstructor.preview_format = preview_format;
+    reconstructor.cpu = options.cpu_features;
+    if options.verify_checksums {
+        reconstructor.adler = Some(1);
+    }
+    let result = inflate_image_data(&mut input, png_image, &mut reconstructor);
+    // the lenient mode keeps the rows decoded before the error
+    if result.is_ok() || options.lenient {
+        reconstructor.finish(png_image);
+    }
+    result
 }
 
-const HUFF_FAST_BITS: u8 = 9;
-struct HuffmanTables {
-    fast: [u16; 1 << HUFF_FAST_BITS],
-    slow: [u16; 32768],
+fn inflate_image_data(input: &mut IdatInput, png_image: &mut PNGImage, reconstructor: &mut PNGReconstructor) -> Result<(), DecodingError> {
+    let Some(adler) = inflate_idat(input, png_image, reconstructor, true)? else {
+        // stopped after the last needed pass
+        return input.skip_idat_chunks();
+    };
+    if reconstructor.adler.is_some_and(|expected| expected != adler) {
+        return Err(ErrorKind::AdlerMismatch.into());
+    }
+    if reconstructor.rows_valid(png_image) < png_image.image.h {
+        return Err(ErrorKind::Truncated.into()); // the zlib stream ended before the last scanline
+    }
+    if !input.end_of_bytestream {
+        input.stream.consume_crc()?;
+    }
+    Ok(())
 }
 
-impl HuffmanTables {
-    fn new() -> HuffmanTables {
-        HuffmanTables {
-            fast: [0; 1 << HUFF_FAST_BITS],
-            slow: [0; 32768]
-        }
+// Inflates image data into `reconstructor` until the end of the zlib stream and returns the Adler-32 stored
+// after it, or None if the reconstructor stopped before. Segments of iDOT images after the first one
+// start without the zlib header.
+fn inflate_idat(input: &mut IdatInput, png_image: &mut PNGImage, reconstructor: &mut PNGReconstructor, zlib_header: bool) -> Result<Option<u32>, DecodingError> {
+    let mut core = InflateCore::new(Format::Zlib);
+    if !zlib_header {
+        core.skip_header();
     }
+    match inflate_scanlines(&mut core, input, &mut ScanlineOutput { reconstructor, png_image, on_row: None })? {
+        Status::Stopped => Ok(None),
+        Status::Done if core.bits_left() != 0 => Err(ErrorKind::TooMuchData.into()), // data after the end of the zlib stream
+        Status::Done => Ok(Some(core.trailer()[0])),
+        Status::NeedInput => Err(ErrorKind::Truncated.into()),
+    }
+}
 
-    fn update(&mut self, cls: &[u8]) {
-        let mut next_code = build_initiabl_codes_by_cls::<16>(cls);
-        let mut slow_table_ids = [0xffffu16; 1 << HUFF_FAST_BITS];
-        let mut n_slow_tables = 0;
-        for n in 0..cls.len() {
-            let len = cls[n];
-            if len != 0 {
-                let code = (next_code[len as usize].reverse_bits() >> (16 - len)) as usize;
-                let sym_len = n as u16 | ((len as u16) << 9);
-
-                if len <= HUFF_FAST_BITS {
-                    for c in (code..1 << HUFF_FAST_BITS).step_by(1 << len) {
-                        self.fast[c] = sym_len;
-                    }
-                } else {
-                    let lo = code & ((1 << HUFF_FAST_BITS) - 1);
-                    let hi = code >> HUFF_FAST_BITS;
-                    let mut slow_table_id = slow_table_ids[lo];
-                    if slow_table_id == 0xffff {
-                        slow_table_id = n_slow_tables;
-                        slow_table_ids[lo] = slow_table_id;
-                        n_slow_tables += 1;
-                    }
-                    self.fast[lo] = slow_table_id;
-                    let slow_table_offset = slow_table_id * (1 << (15 - HUFF_FAST_BITS));
-                    for c in (hi..1 << (15 - HUFF_FAST_BITS)).step_by(1 << (len - HUFF_FAST_BITS)) {
-                        self.slow[slow_table_offset as usize + c] = sym_len;
-                    }
-                }
-
-                next_code[len as usize] += 1;
+// Inflates `data` after the input left over from the slices before it into the scanlines of `output`.
+// The Adler-32 is checked once the zlib stream ends, if the reconstructor computes it.
+fn inflate_slice(core: &mut InflateCore, leftover: &mut Leftover, data: &[u8], output: &mut ScanlineOutput) -> Result<Status, DecodingError> {
+    leftover.feed(data, |input| {
+        let status = inflate_scanlines(core, input, output)?;
+        if status == Status::Done {
+            if core.bits_left() != 0 || !input.is_empty() {
+                return Err(ErrorKind::TooMuchData.into()); // data after the end of the zlib stream
+            }
+            if output.reconstructor.adler.is_some_and(|adler| adler != core.trailer()[0]) {
+                return Err(ErrorKind::AdlerMismatch.into());
             }
         }
-        // println!("n_slow_tables: {n_slow_tables}");
-
-        // sym/small_table_id: 9 bits
-        // code_len: 1..15 (4 bits), 0 - slow path
-    }
+        Ok(status)
+    })
+}
 
-    fn lookup_sym_len(&self, code: u16) -> (u16, u8) {
-        let packed = self.fast[code as usize & ((1 << HUFF_FAST_BITS) - 1)];
-        let (sym, len) = unpack_sym_len(packed);
-        if len != 0 {
-            (sym, len)
-        } else {
-            let hi = code >> HUFF_FAST_BITS;
-            let slow_table_offset = packed * (1 << (15 - HUFF_FAST_BITS));
-            unpack_sym_len(self.slow[slow_table_offset as usize + hi as usize])
-        }
+fn inflate_scanlines<I: InflateInput>(core: &mut InflateCore, input: &mut I, output: &mut ScanlineOutput) -> Result<Status, DecodingError> {
+    #[cfg(target_arch = "x86_64")]
+    if output.reconstructor.cpu.bmi2 {
+        return unsafe { inflate_scanlines_bmi2(core, input, output) };
     }
+    inflate_scanlines_scalar(core, input, output)
 }
 
-fn build_huffman_lut<const N: usize, const M: usize>(cls: &[u8]) -> [u16; M] {
-    assert_eq!(M, 1 << (N - 1)); // TODO: wait for #![feature(generic_const_exprs)]
-    let mut next_code = build_initiabl_codes_by_cls::<N>(cls);
-    let mut huff = [0; M];
-    for n in 0..cls.len() {
-        let len = cls[n];
-        if len != 0 {
-            let code = (next_code[len as usize].reverse_bits() >> (16 - len)) as usize;
-            let sym_len = n as u16 | ((len as u16) << 9);
+// The bit reader masks compile to BZHI here
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+#[target_feature(enable = "bmi2")]
+fn inflate_scanlines_bmi2<I: InflateInput>(core: &mut InflateCore, input: &mut I, output: &mut ScanlineOutput) -> Result<Status, DecodingError> {
+    core.run(input, output)
+}
 
-            for c in (code..M).step_by(1 << len) {
-                huff[c] = sym_len;
-            }
+#[inline(never)]
+fn inflate_scanlines_scalar<I: InflateInput>(core: &mut InflateCore, input: &mut I, output: &mut ScanlineOutput) -> Result<Status, DecodingError> {
+    core.run(input, output)
+}
 
-            next_code[len as usize] += 1;
+// Apple's iDOT chunk splits the image data into segments of rows that start with a fresh deflate state.
+// Layouts that can't be decoded in parallel are ignored.
+fn decode_idot(stream: &mut PNGDatastream, chunk_start: usize, png_image: &PNGImage) -> Option<Vec<Segment>> {
+    let count = stream.read_u32().ok()? as usize;
+    if count < 2 || stream.len() != 4 + count * 12 || png_image.interlaced {
+        return None;
+    }
+    let mut segments: Vec<Segment> = vec![];
+    for _ in 0..count {
+        let first_row = stream.read_u32().ok()?;
+        let rows = stream.read_u32().ok()?;
+        let offset = chunk_start + stream.read_u32().ok()? as usize; // relative to the start of the iDOT chunk
+        let next_row = segments.last().map_or(0, |prev| prev.first_row + prev.rows);
+        if first_row != next_row || rows == 0 || segments.last().is_some_and(|prev| offset <= prev.offset) {
+            return None;
         }
+        segments.push(Segment { first_row, rows, offset });
     }
-
-    // sym: 9 bits
-    // code_len: 1..15 (4 bits)
-
-    huff
+    (segments[count - 1].first_row + segments[count - 1].rows == png_image.image.h).then_some(segments)
 }
 
-fn unpack_sym_len(packed: u16) -> (u16, u8) {
-    (packed & 0x1ff, (packed >> 9) as u8)
-}
-
-const MAX_HLIT: usize = 286;
-const MAX_HDIST: usize = 32;
-const CLS_MAX: usize = (MAX_HLIT + 257) + (MAX_HDIST + 1);
-
-#[target_feature(enable = "bmi2")]
-fn decode_cls(bs: &mut BitStream, stream: &mut PNGDatastream, huff: &[u16], max_symbol: usize) -> Result<[u8; CLS_MAX], DecodingError> {
-    let mut cls: [u8; CLS_MAX] = [0; CLS_MAX];
-    const OFFSETS: [usize; 3] = [3, 3, 11];
-    const EXTRA_BITS: [u32; 3] = [2, 3, 7];
-    let mut i: usize = 0;
-    while i < max_symbol {
-        bs.ensure(stream)?;
-        let code = bs.peek(7);
-        let (cl, len) = unpack_sym_len(huff[code as usize]);
-        bs.skip(len)?;
-        if cl <= 15 {
-            cls[i as usize] = cl as u8;
-            i += 1;
-        } else {
-            let idx = cl as usize - 16;
-            let offset = OFFSETS[idx];
-            let extra_bits = EXTRA_BITS[idx];
-            let reps = bs.read(extra_bits)? as usize + offset;
-            let till = i + reps;
-            let val =
-                if cl == 16 {
-                    if i == 0 {
-                        return Err(DecodingError::MalformedImage);
-                    }
-                    cls[i - 1]
-                } else { 0 };
-            while i != till {
-                cls[i] = val;
-                i += 1;
-            }
+// Filter type of the first scanline of a segment after the first one, None if it isn't in the first IDAT chunk
+fn first_filter(buf: &[u8], segment: &Segment) -> Option<u8> {
+    let mut stream = PNGDatastream::new(buf);
+    stream.cursor = segment.offset;
+    let len = stream.read_u32().ok()? as usize;
+    if stream.read_chunk_name().ok()? != *b"IDAT" {
+        return None;
+    }
+    let data = buf.get(stream.cursor..stream.cursor + len)?;
+    // only the start of the chunk is inflated
+    let mut inflater = Inflater::new(Format::Raw);
+    let mut filter = None;
+    for piece in data.chunks(64) {
+        inflater.feed(piece, &mut |bytes: &[u8]| {
+            filter = filter.or(bytes.first().copied());
+            Ok(())
+        }).ok()?;
+        if filter.is_some() {
+            break;
         }
     }
-
-    Ok(cls)
+    filter
 }
 
-#[inline(never)]
-#[target_feature(enable = "bmi2")]
-fn decode_idat(stream: &mut PNGDatastream, chunk_bytes_left: u32, png_image: &mut PNGImage) -> Result<(), DecodingError> {
-    let sz = png_image.image.w as usize * png_image.image.h as usize * png_image.image.channels as usize
-        * (png_image.image.depth / 8) as usize;
-    png_image.image.buf = vec![0; sz];
-    let mut bs = BitStream::new(chunk_bytes_left);
-    let mut reconstructor: PNGReconstructor = Default::default();
-    if png_image.interlaced {
-        reconstructor.pass_id = 1;
-    }
-    let bytes_per_scanline = 1 +
-        if png_image.color_type != 3 {
-            (png_image.depth as usize * png_image.image.channels as usize * png_image.image.w as usize + 7) / 8
-        } else {
-            (png_image.depth as usize * png_image.image.w as usize + 7) / 8
-        };
-    reconstructor.scanline_bufs = [ vec![0; bytes_per_scanline], vec![0; bytes_per_scanline] ];
+// Inflates the segments of an iDOT image on separate threads. Returns false if they can't be decoded on their
+// own, because they turn out to depend on each other or don't match the image data, the image data is decoded
+// sequentially then. Segments that start with a row filtered against the row above are found before anything
+// is decoded. Errors in the image data itself are returned.
+fn decode_segments(buf: &[u8], segments: &[Segment], png_image: &mut PNGImage, options: &DecodeOptions, destination: &mut Destination) -> Result<bool, DecodingError> {
+    // reduced images are put together by a single reconstructor
+    if options.adam7_passes.is_some() {
+        return Ok(false);
+    }
+    // of the filters only None and Sub don't look at the row above
+    if segments[1..].iter().any(|segment| !matches!(first_filter(buf, segment), Some(0 | 1))) {
+        return Ok(false);
+    }
+    let format = if needs_postprocessing(options) { None } else { options.output_format };
+    let image = &png_image.image;
+    let row_bytes = image.w as usize * format.map_or(image.channels as usize * image.depth as usize / 8, |format| format.bytes_per_pixel());
+    let mut buffer = vec![];
+    let (mut dst, stride) = match destination {
+        Destination::Buffer(dst, stride) => (&mut dst[..], *stride),
+        Destination::Image => {
+            buffer = vec![0; row_bytes * image.h as usize];
+            (&mut buffer[..], row_bytes)
+        },
+        Destination::Rows(_) => return Ok(false), // would need the whole image
+    };
 
-    let png_channels =
-        if png_image.color_type == 3 {
-            1
-        } else {
-            png_image.image.channels as usize - png_image.trns_alpha.is_some() as usize
+    // every worker decodes a run of consecutive segments
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get()).min(segments.len());
+    let results: Vec<_> = std::thread::scope(|scope| {
+        let mut handles = vec![];
+        for group in segments.chunks(segments.len().div_ceil(workers)) {
+            let rows: usize = group.iter().map(|segment| segment.rows as usize).sum();
+            let (mut group_dst, rest) = dst.split_at_mut((rows * stride).min(dst.len()));
+            dst = rest;
+            let segment_images: Vec<PNGImage> = group.iter().map(|_| png_image.segment_image()).collect();
+            let decode_group = move || {
+                group.iter().zip(segment_images).map(|(segment, mut segment_image)| {
+                    let len = (segment.rows as usize * stride).min(group_dst.len());
+                    let (segment_dst, rest) = std::mem::take(&mut group_dst).split_at_mut(len);
+                    group_dst = rest;
+                    decode_segment(buf, *segment, &mut segment_image, options, format, segment_dst, stride)
+                }).collect::<Vec<_>>()
+            };
+            handles.push(std::thread::Builder::new().spawn_scoped(scope, decode_group));
+        }
+        handles.into_iter().flat_map(|handle| match handle {
+            Ok(handle) => handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+            // no thread to spare, the caller decodes the image sequentially
+            Err(_) => vec![Ok(None)],
+        }).collect()
+    });
+
+    let mut adler = 1;
+    for (i, result) in results.into_iter().enumerate() {
+        let Some((segment_adler, len, stored_adler)) = result? else {
+            return Ok(false);
         };
-    let scanline_pixels = (png_image.image.w + STEP_X[reconstructor.pass_id] - START_X[reconstructor.pass_id] - 1) / STEP_X[reconstructor.pass_id];
-    reconstructor.cur_consumable_bytes = (scanline_pixels as usize * png_channels * png_image.depth as usize + 7) / 8 + 1;
-    // let mut cur_scanline_cursor = reconstructor.cur_scanline_cursor;
-    // let mut cur_scanline_end = reconstructor.cur_scanline_end;
-    let mut cur_scanline_cursor = reconstructor.scanline_bufs[1].as_mut_ptr();
-    let mut cur_scanline_end = unsafe { cur_scanline_cursor.add(reconstructor.cur_consumable_bytes) } as *const u8;
-    // reconstructor.cur_scanline_cursor = reconstructor.scanline_bufs[1].as_mut_ptr();
-    // unsafe { reconstructor.cur_scanline_end = reconstructor.cur_scanline_cursor.add(reconstructor.cur_consumable_bytes) }
-
-    bs.ensure(stream)?;
-    let cmf = bs.read(8)?;
-    let cm = cmf & 0xf;
-    let cinfo = cmf >> 4;
-    if cm != 8 || cinfo > 7 {
-        return Err(DecodingError::MalformedImage);
-    }
-    // let window_size = 1usize << (cinfo + 8);
-    let window_size = 1usize << 15;
-    let flg = bs.read(8)?;
-    if (((cmf as u32) << 8 | flg as u32) & 0x1f) % 31 == 0 || flg & 0x20 != 0 {
-        return Err(DecodingError::MalformedImage);
-    }
-    // let flevel = flg >> 6; // ignore compression level
-
-    // compressed data
-    let mut dec_buf: [u8; 32768] = [0; 32768];
-    let mut dec_cursor = 0;
-    let mut huff_lit_len = HuffmanTables::new();
-    let mut huff_dist = HuffmanTables::new();
-    // let mut d1 = 0;
-    // let mut dnoover = 0;
-    // let mut dtotal = 0;
-    loop {
-        bs.ensure(stream)?;
-        let header = bs.read(3)?;
-        let bfinal = header & 1;
-        let btype = header >> 1;
-        match btype {
-            3 => return Err(DecodingError::MalformedImage),
-            1 | 2 => {
-                // let mut cls_dist: [u8; 32] = [5; 32];
-                let (cls_all, n_hlit, n_hdist) = if btype == 2 {
-                    // parse literal/length codes
-                    bs.ensure(stream)?;
-                    let hlit = bs.read(5)?;
-                    let hdist = bs.read(5)?;
-                    let hclen = bs.read(4)? as u32;
-
-                    // cls is code lengths
-                    bs.ensure(stream)?; // conveniently no more than 57 bits
-                    let mut cls_of_cls: [u8; 19] = [0; 19];
-                    const INDEX_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
-                    for i in 0..hclen + 4 {
-                        cls_of_cls[INDEX_ORDER[i as usize]] = bs.read(3)? as u8;
-                    }
-
-                    let huff: [u16; 128] = build_huffman_lut::<8, 128>(&cls_of_cls);
-                    let n_hlit = hlit as usize + 257;
-                    let n_hdist = hdist as usize + 1;
-                    let cls_all = decode_cls(&mut bs, stream, &huff, n_hlit + n_hdist)?;
-                    (cls_all, n_hlit, n_hdist)
-                } else {
-                    let cls_all = core::array::from_fn(|i| {
-                        match i {
-                            _ if i <= 143 => 8,
-                            _ if i <= 255 => 9,
-                            _ if i <= 279 => 7,
-                            _ if i <= 287 => 8,
-                            _ => 5,
-                        }
-                    });
-                    (cls_all, 288, 32)
-                };
-                let cls_lit_len = &cls_all[..n_hlit];
-                let cls_dist = &cls_all[n_hlit..n_hlit + n_hdist];
-
-                huff_lit_len.update(&cls_lit_len);
-                huff_dist.update(&cls_dist);
-
-                // the actual compressed data starts here
-                const LEN_OFFSETS: [u8; 20] = [11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227];
-                const DIST_OFFSETS: [u16; 26] = [5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
-                loop {
-                    bs.ensure(stream)?;
-                    let code = bs.peek(15) as usize;
-                    let (sym, len) = huff_lit_len.lookup_sym_len(code as u16);
-                    bs.skip(len)?;
-                    if sym < 256 {
-                        let byte = sym as u8;
-                        (cur_scanline_cursor, cur_scanline_end) = reconstructor.consume_decoded_byte(png_image, byte, cur_scanline_cursor, cur_scanline_end)?;
-                        dec_buf[dec_cursor & (window_size - 1)] = byte;
-                        dec_cursor += 1;
-                        dec_cursor &= window_size - 1;
-                        // println!("lit {sym}");
-                    } else if sym == 256 { // end of block
-                        // println!("EOB");
-                        break;
-                    } else {
-                        if sym > 285 {
-                            return Err(DecodingError::MalformedImage);
-                        }
-                        let mut len =
-                            if sym < 265 {
-                                sym - 254
-                            } else if sym == 285 {
-                                258 // max
-                            } else {
-                                let extra_bits = (sym - 261) / 4; // 1..5 bits
-                                let len = bs.read(extra_bits as u32)? as u16;
-                                len + LEN_OFFSETS[(sym - 265) as usize] as u16
-                            };
-                        let code = bs.peek(15) as usize;
-                        let (dist_code, code_len) = huff_dist.lookup_sym_len(code as u16);
-                        bs.skip(code_len)?;
-                        if dist_code > 29 {
-                            return Err(DecodingError::MalformedImage);
-                        }
-                        let dist =
-                            if dist_code < 4 {
-                                dist_code as usize + 1
-                            } else {
-                                let extra_bits = (dist_code - 2) / 2; // 1..13 bits
-                                let len = bs.read(extra_bits as u32)? as u32;
-                                len as usize + DIST_OFFSETS[(dist_code - 4) as usize] as usize
-                            };
-                        let mut p = (dec_cursor + window_size - dist) & (window_size - 1);
-
-                        // if dist == 1 {
-                        //     d1 += 1;
-                        // }
-                        // if dist >= len as usize {
-                        //     dnoover += 1;
-                        // }
-                        // dtotal += 1;
-                        // println!("len {len} dist {dist}");
-
-                        // if (dec_cursor + len as usize) <= p && (p + len as usize) < window_size {
-                        //     let (dst, src) = dec_buf.split_at_mut(p);
-                        //     dst[dec_cursor..dec_cursor + len as usize].copy_from_slice(&src[0..len as usize]);
-                        //     for byte in &src[0..len as usize] {
-                        //         reconstructor.consume_decoded_byte(png_image, *byte, &mut cur_scanline_cursor, &mut cur_scanline_end)?;
-                        //     }
-                        //     dec_cursor += len as usize;
-                        // } else if (p + len as usize) <= dec_cursor && (dec_cursor + len as usize) < window_size {
-                        //     let (src, dst) = dec_buf.split_at_mut(dec_cursor);
-                        //     dst[0..len as usize].copy_from_slice(&src[p..p + len as usize]);
-                        //     for byte in &dst[0..len as usize] {
-                        //         reconstructor.consume_decoded_byte(png_image, *byte, &mut cur_scanline_cursor, &mut cur_scanline_end)?;
-                        //     }
-                        //     dec_cursor += len as usize;
-                        // } else {
-                        // slow path
-                        // let end = ((dec_cursor + len as usize - 1) & (window_size - 1)) + 1;
-                        let end = (dec_cursor + len as usize) & (window_size - 1);
-                        while dec_cursor != end {
-                            let bytes_left = unsafe { cur_scanline_end.offset_from(cur_scanline_cursor) } as usize;
-                            let cnt = std::cmp::min(len as usize, bytes_left);
-                            // let to = std::cmp::min(unsafe { cur_scanline_cursor.add(len as usize) as *const u8 }, cur_scanline_end);
-                            // let n = unsafe { to.offset_from(cur_scanline_cursor) } as u16;
-                            // while cur_scanline_cursor as *const u8 != to {
-                            // while out_cursor < cnt {
-                            for out_cursor in 0..cnt {
-                                p &= window_size - 1;
-                                let byte = dec_buf[p];
-                                dec_buf[dec_cursor] = byte;
-                                dec_cursor += 1;
-                                dec_cursor &= window_size - 1;
-                                p += 1;
-                                // (cur_scanline_cursor, cur_scanline_end) = reconstructor.consume_decoded_byte(png_image, byte, cur_scanline_cursor, cur_scanline_end)?;
-                                unsafe {
-                                    *cur_scanline_cursor.add(out_cursor) = byte;
-                                };
-                            }
-                            if cnt == bytes_left {
-                                (cur_scanline_cursor, cur_scanline_end) = reconstructor.process_scanline(png_image)?;
-                                len -= cnt as u16;
-                            } else {
-                                cur_scanline_cursor = unsafe { cur_scanline_cursor.add(cnt) };
-                            }
-                        }
-                    }
+        adler = checksum::adler32_combine(adler, segment_adler, len);
+        let last = i == segments.len() - 1;
+        match stored_adler {
+            // only the last segment reaches the end of the zlib stream
+            Some(stored_adler) if last => {
+                // without verify_checksums the mismatch is taken as segments that weren't independent
+                if stored_adler != adler {
+                    return if options.verify_checksums { Err(ErrorKind::AdlerMismatch.into()) } else { Ok(false) };
                 }
             },
-            _ => { // 0
-                bs.skip(bs.bits_left as u8 % 8)?;
-                bs.ensure(stream)?;
-                let len_nlen = bs.read(32)? as u32;
-                let mut len = len_nlen as u16;
-                let nlen = (len_nlen >> 16) as u16;
-                if nlen != !len {
-                    return Err(DecodingError::MalformedImage);
-                }
-                while len > 0 {
-                    bs.ensure(stream)?;
-                    let byte = bs.read(8)? as u8;
-                    (cur_scanline_cursor, cur_scanline_end) = reconstructor.consume_decoded_byte(png_image, byte, cur_scanline_cursor, cur_scanline_end)?;
-                    len -= 1;
-                }
-            }
-        }
-        if bfinal != 0 {
-            break;
+            None if !last => {},
+            _ => return Ok(false),
         }
     }
-    // println!("d1: {:.2}%, no_overlap: {:.2}%", d1 as f64 * 100f64 / dtotal as f64, dnoover as f64 * 100f64 / dtotal as f64);
-
-    // skip 4 bytes of zlib's ADLER32 checksum
-    let skip_bits = 32 + bs.bits_left % 8; // ignore bs padding bits in the last byte
-    bs.skip(skip_bits as u8)?;
-    assert_eq!(bs.bits_left, 0); // TODO: is it always true?
 
-    if !bs.end_of_bytestream {
-        stream.consume_crc()?;
+    png_image.idat_seen = true;
+    png_image.rows_valid = png_image.image.h;
+    png_image.image.buf = buffer;
+    if let Some(format) = format {
+        png_image.image.channels = format.channels();
+        png_image.image.depth = format.depth();
     }
+    Ok(true)
+}
 
-    if png_image.depth == 16 {
-        for i in (0..png_image.image.buf.len()).step_by(2) {
-            png_image.image.buf.swap(i, i + 1); // make it little-endian
-        }
+// Decodes the rows of one segment to `dst`. Returns the Adler-32 of its scanlines, their length and
+// the Adler-32 after the end of the zlib stream if the segment reaches it, or None if the segment
+// doesn't start at its offset, ends before its rows or refers back to the data before it.
+fn decode_segment(buf: &[u8], segment: Segment, png_image: &mut PNGImage, options: &DecodeOptions, format: Option<PixelFormat>, dst: &mut [u8], stride: usize) -> Result<Option<(u32, usize, Option<u32>)>, DecodingError> {
+    let mut stream = PNGDatastream::new(buf);
+    stream.check_crc = options.verify_checksums;
+    stream.cursor = segment.offset;
+    let len = stream.read_u32()?;
+    stream.reset_crc();
+    if stream.read_chunk_name()? != *b"IDAT" || stream.cursor + len as usize >= buf.len() {
+        return Ok(None);
+    }
+    let mut input = IdatInput::new(&mut stream, len);
+    let rows = segment.first_row..segment.first_row + segment.rows;
+    let mut reconstructor = PNGReconstructor::new_for_rows(png_image, format, Destination::Buffer(dst, stride), rows, 7);
+    reconstructor.cpu = options.cpu_features;
+    reconstructor.adler = Some(1);
+    let stored_adler = match inflate_idat(&mut input, png_image, &mut reconstructor, segment.first_row == 0) {
+        // a match that reaches before the start of the segment, or a segment that starts in the middle of a block
+        Err(err) if err.kind == ErrorKind::InvalidDeflateData && segment.first_row != 0 => return Ok(None),
+        result => result?,
+    };
+    if reconstructor.rows_valid(png_image) < segment.rows {
+        return Ok(None); // the zlib stream ends before the rows of the segment
     }
+    reconstructor.finish(png_image);
+    Ok(Some((reconstructor.adler.unwrap_or(1), segment.rows as usize * reconstructor.cur_consumable_bytes, stored_adler)))
+}
 
-    Ok(())
+fn swap_bytes_16(buf: &mut [u8]) {
+    for i in (0..buf.len()).step_by(2) {
+        buf.swap(i, i + 1); // make it little-endian
+    }
 }
 
 fn decode_plte(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(), DecodingError> {
+    if png_image.idat_seen || png_image.palette.is_some() {
+        return Err(ErrorKind::ChunkOrder.into()); // only one PLTE is allowed, before IDAT
+    }
     if stream.len() % 3 != 0 {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidChunkLength.into());
     }
 
     if png_image.color_type == 3 {
         let palette_len = stream.len() / 3;
         if palette_len > (1 << png_image.depth) {
-            return Err(DecodingError::MalformedImage);
+            return Err(ErrorKind::InvalidChunkLength.into());
         }
 
         let mut palette = Palette::new(palette_len);
@@ -1292,10 +1703,15 @@ fn decode_plte(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(
 
 fn decode_trns(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(), DecodingError> {
     let len = stream.len();
+    if png_image.idat_seen {
+        return Err(ErrorKind::ChunkOrder.into()); // the pixels are already decoded without alpha
+    }
 
     match png_image.color_type {
         0 | 2 => {
-            assert!(png_image.image.channels == 1 || png_image.image.channels == 3);
+            if png_image.trns_alpha.is_some() {
+                return Err(ErrorKind::ChunkOrder.into()); // second tRNS
+            }
             let mut trns_alpha: [u16; 3] = [0; 3];
             for i in 0..png_image.image.channels {
                 trns_alpha[i as usize] = stream.read_u16()?;
@@ -1305,21 +1721,23 @@ fn decode_trns(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(
             png_image.image.channels += 1; // + alpha
         }
         3 => {
-            assert!(png_image.image.channels == 3);
-            assert!(png_image.palette.is_some());
-            png_image.image.channels = 4;
-
-
-            let palette = png_image.palette.as_mut().unwrap();
+            // tRNS has to follow PLTE and appear once
+            let Some(palette) = png_image.palette.as_mut() else {
+                return Err(ErrorKind::ChunkOrder.into());
+            };
+            if png_image.image.channels != 3 {
+                return Err(ErrorKind::ChunkOrder.into());
+            }
             if len > palette.len {
-                return Err(DecodingError::MalformedImage);
+                return Err(ErrorKind::InvalidChunkLength.into());
             }
+            png_image.image.channels = 4;
 
             for i in 0..len {
                 palette.values[3][i] = stream.read_u8()?;
             }
         },
-        _ => return Err(DecodingError::MalformedImage)
+        _ => return Err(ErrorKind::InvalidChunkData.into())
     }
 
     Ok(())
@@ -1328,7 +1746,7 @@ fn decode_trns(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(
 #[allow(dead_code)]
 fn decode_phys(stream: &mut PNGDatastream) -> Result<(), DecodingError> {
     if stream.len() != 9 {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidChunkLength.into());
     }
 
     let _x = stream.read_u32()?;
@@ -1348,33 +1766,579 @@ fn decode_gama(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(
     Ok(())
 }
 
-pub fn decode(buf: &[u8]) -> Result<PNGImage, DecodingError> {
-    let mut stream = PNGDatastream::new(buf);
-    stream.consume(PNG_SIGNATURE)?;
+fn decode_sbit(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(), DecodingError> {
+    if png_image.significant_bits.is_some() || png_image.idat_seen {
+        return Err(ErrorKind::ChunkOrder.into()); // only one sBIT is allowed, before IDAT
+    }
+    let (channels, sample_depth) = match png_image.color_type {
+        0 => (1, png_image.depth),
+        2 => (3, png_image.depth),
+        3 => (3, 8),
+        4 => (2, png_image.depth),
+        _ => (4, png_image.depth),
+    };
+    let bits = stream.read_to_end();
+    if bits.len() != channels {
+        return Err(ErrorKind::InvalidChunkLength.into());
+    }
+    if bits.iter().any(|bits| *bits == 0 || *bits > sample_depth) {
+        return Err(ErrorKind::InvalidChunkData.into());
+    }
+    png_image.significant_bits = Some(bits.to_vec());
+    Ok(())
+}
+
+// Repeats the `bits` low bits of `sample` until `depth` bits are filled
+fn replicate_bits(sample: u32, bits: u32, depth: u32) -> u32 {
+    let mut out = 0;
+    let mut filled = 0;
+    while filled < depth {
+        out = out << bits | sample;
+        filled += bits;
+    }
+    out >> (filled - depth)
+}
+
+fn apply_significant_bits(png_image: &mut PNGImage, mode: SignificantBits) {
+    let Some(significant_bits) = &png_image.significant_bits else {
+        return;
+    };
+    let image = &mut png_image.image;
+    let depth = image.depth as u32;
+    let channels = image.channels as usize;
+    // alpha added for tRNS keeps its full precision
+    let bits: Vec<u32> = (0..channels).map(|c| significant_bits.get(c).map_or(depth, |bits| *bits as u32)).collect();
+    let adjust = |sample: u32, bits: u32| {
+        let significant = sample >> (depth - bits);
+        match mode {
+            SignificantBits::Rescale => significant,
+            SignificantBits::Replicate => replicate_bits(significant, bits, depth),
+        }
+    };
+    if depth == 8 {
+        let luts: Vec<Vec<u8>> = bits.iter().map(|bits| (0..256).map(|sample| adjust(sample, *bits) as u8).collect()).collect();
+        for pixel in image.buf.chunks_exact_mut(channels) {
+            for (sample, lut) in pixel.iter_mut().zip(&luts) {
+                *sample = lut[*sample as usize];
+            }
+        }
+    } else {
+        for pixel in image.buf.chunks_exact_mut(2 * channels) {
+            for (sample, bits) in pixel.chunks_exact_mut(2).zip(&bits) {
+                let adjusted = adjust(u16::from_le_bytes([sample[0], sample[1]]) as u32, *bits) as u16;
+                sample.copy_from_slice(&adjusted.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn decode_bkgd(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(), DecodingError> {
+    if png_image.background.is_some() || png_image.idat_seen {
+        return Err(ErrorKind::ChunkOrder.into()); // only one bKGD is allowed, before IDAT
+    }
+    let len = match png_image.color_type {
+        3 => 1,
+        0 | 4 => 2,
+        _ => 6,
+    };
+    if stream.len() > len {
+        return Err(ErrorKind::InvalidChunkLength.into());
+    }
+    let max_val = ((1u32 << png_image.depth) - 1) as u16;
+    let background = match png_image.color_type {
+        3 => {
+            let palette_len = png_image.palette.as_ref().ok_or(ErrorKind::ChunkOrder)?.len;
+            let index = stream.read_u8()?;
+            if index as usize >= palette_len {
+                return Err(ErrorKind::InvalidChunkData.into());
+            }
+            Background::PaletteIndex(index)
+        },
+        0 | 4 => Background::Gray(stream.read_u16()?),
+        _ => Background::Rgb([stream.read_u16()?, stream.read_u16()?, stream.read_u16()?]),
+    };
+    let values = match &background {
+        Background::PaletteIndex(_) => &[][..],
+        Background::Gray(val) => std::slice::from_ref(val),
+        Background::Rgb(rgb) => &rgb[..],
+    };
+    if values.iter().any(|val| *val > max_val) {
+        return Err(ErrorKind::InvalidChunkData.into());
+    }
+    png_image.background = Some(background);
+    Ok(())
+}
 
-    let mut png_image = PNGImage {
+impl PNGImage {
+    // bKGD as RGB at the depth of the decoded image
+    fn background_rgb(&self) -> Option<[u32; 3]> {
+        Some(match self.background? {
+            Background::PaletteIndex(i) => {
+                let values = &self.palette.as_ref()?.values;
+                [values[0][i as usize], values[1][i as usize], values[2][i as usize]].map(|v| v as u32)
+            },
+            Background::Gray(val) if self.depth < 8 => [val as u32 * (255 / ((1 << self.depth) - 1)); 3],
+            Background::Gray(val) => [val as u32; 3],
+            Background::Rgb(rgb) => rgb.map(|v| v as u32),
+        })
+    }
+}
+
+// Background color and channel count of the composited image, None if there's nothing to composite.
+// Gray images stay gray over a gray background.
+fn composite_params(png_image: &PNGImage, over: CompositeOver) -> Option<([u64; 3], usize)> {
+    let channels = png_image.image.channels as usize;
+    if channels != 2 && channels != 4 {
+        return None;
+    }
+    let max_val = (1u64 << png_image.image.depth) - 1;
+    let background = match over {
+        CompositeOver::Bkgd => png_image.background_rgb()?.map(|v| v as u64),
+        CompositeOver::Color(rgb) => rgb.map(|v| v as u64 * max_val / 255),
+    };
+    let is_gray = channels == 2;
+    let out_channels = if is_gray && background[0] == background[1] && background[1] == background[2] { 1 } else { 3 };
+    Some((background, out_channels))
+}
+
+// Blends the alpha channel away
+fn composite(png_image: &mut PNGImage, over: CompositeOver) {
+    let Some((background, out_channels)) = composite_params(png_image, over) else {
+        return;
+    };
+    let channels = png_image.image.channels as usize;
+    let max_val = (1u64 << png_image.image.depth) - 1;
+    let is_gray = channels == 2;
+    let bpc = (png_image.image.depth / 8) as usize; // bytes per channel
+    let image = &mut png_image.image;
+    let mut buf = Vec::with_capacity(image.w as usize * image.h as usize * out_channels * bpc);
+    for pixel in image.buf.chunks_exact(channels * bpc) {
+        let sample = |c: usize| if bpc == 1 { pixel[c] as u64 } else { u16::from_le_bytes([pixel[2 * c], pixel[2 * c + 1]]) as u64 };
+        let alpha = sample(channels - 1);
+        for (c, background) in background.iter().enumerate().take(out_channels) {
+            let val = (sample(if is_gray { 0 } else { c }) * alpha + background * (max_val - alpha) + max_val / 2) / max_val;
+            buf.extend_from_slice(&(val as u16).to_le_bytes()[..bpc]);
+        }
+    }
+    image.buf = buf;
+    image.channels = out_channels as u32;
+    // describe the flattened pixels
+    png_image.color_type = if out_channels == 1 { 0 } else { 2 };
+    png_image.trns_alpha = None;
+    if let Some(bits) = &mut png_image.significant_bits {
+        *bits = if is_gray { vec![bits[0]; out_channels] } else { bits[..3].to_vec() };
+    }
+}
+
+fn latin1_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| *byte as char).collect()
+}
+
+// Keywords are 1-79 printable Latin-1 characters without leading, trailing or consecutive spaces
+fn decode_keyword(bytes: &[u8]) -> Result<String, DecodingError> {
+    let printable = |byte: &u8| (32..=126).contains(byte) || *byte >= 161;
+    if bytes.is_empty() || bytes.len() > 79 || !bytes.iter().all(printable)
+        || bytes[0] == b' ' || bytes[bytes.len() - 1] == b' ' || bytes.windows(2).any(|pair| pair == b"  ") {
+        return Err(ErrorKind::InvalidChunkData.into());
+    }
+    Ok(latin1_to_string(bytes))
+}
+
+// How many more bytes of text `limits` allow
+fn text_bytes_left(png_image: &PNGImage, limits: &Limits) -> usize {
+    let used: usize = png_image.text.iter().map(|entry| entry.text.len()).sum();
+    limits.max_text_bytes.saturating_sub(used)
+}
+
+fn decode_text(stream: &mut PNGDatastream, png_image: &mut PNGImage, limits: &Limits) -> Result<(), DecodingError> {
+    let keyword = decode_keyword(stream.read_null_terminated()?)?;
+    let text = stream.read_to_end();
+    if text.len() > text_bytes_left(png_image, limits) {
+        return Err(ErrorKind::LimitsExceeded.into());
+    }
+    let text = latin1_to_string(text);
+    png_image.text.push(TextEntry { keyword, text, compressed: false, language_tag: None, translated_keyword: None });
+    Ok(())
+}
+
+fn decode_ztxt(stream: &mut PNGDatastream, png_image: &mut PNGImage, limits: &Limits) -> Result<(), DecodingError> {
+    let keyword = decode_keyword(stream.read_null_terminated()?)?;
+    if stream.read_u8()? != 0 { // compression method
+        return Err(ErrorKind::InvalidChunkData.into());
+    }
+    let text = latin1_to_string(&decompress_limited(stream.read_to_end(), Format::Zlib, text_bytes_left(png_image, limits))?);
+    png_image.text.push(TextEntry { keyword, text, compressed: true, language_tag: None, translated_keyword: None });
+    Ok(())
+}
+
+fn decode_itxt(stream: &mut PNGDatastream, png_image: &mut PNGImage, limits: &Limits) -> Result<(), DecodingError> {
+    let utf8 = |bytes: Vec<u8>| String::from_utf8(bytes).map_err(|_| ErrorKind::InvalidChunkData);
+    let keyword = decode_keyword(stream.read_null_terminated()?)?;
+    let compressed = match stream.read_u8()? {
+        0 => false,
+        1 => true,
+        _ => return Err(ErrorKind::InvalidChunkData.into()),
+    };
+    if stream.read_u8()? != 0 { // compression method
+        return Err(ErrorKind::InvalidChunkData.into());
+    }
+    let language_tag = stream.read_null_terminated()?;
+    // RFC 3066 tag: alphanumeric words separated by hyphens
+    if !language_tag.iter().all(|byte| byte.is_ascii_alphanumeric() || *byte == b'-') {
+        return Err(ErrorKind::InvalidChunkData.into());
+    }
+    let translated_keyword = utf8(stream.read_null_terminated()?.to_vec())?;
+    let text = stream.read_to_end();
+    let bytes_left = text_bytes_left(png_image, limits);
+    if !compressed && text.len() > bytes_left {
+        return Err(ErrorKind::LimitsExceeded.into());
+    }
+    let text = utf8(if compressed { decompress_limited(text, Format::Zlib, bytes_left)? } else { text.to_vec() })?;
+    png_image.text.push(TextEntry {
+        keyword,
+        text,
+        compressed,
+        language_tag: Some(latin1_to_string(language_tag)),
+        translated_keyword: Some(translated_keyword),
+    });
+    Ok(())
+}
+
+fn decode_iccp(stream: &mut PNGDatastream, png_image: &mut PNGImage, limits: &Limits) -> Result<(), DecodingError> {
+    if png_image.icc_profile.is_some() || png_image.idat_seen {
+        return Err(ErrorKind::ChunkOrder.into()); // only one iCCP is allowed, before IDAT
+    }
+    let name = decode_keyword(stream.read_null_terminated()?)?;
+    if stream.read_u8()? != 0 { // compression method
+        return Err(ErrorKind::InvalidChunkData.into());
+    }
+    let data = decompress_limited(stream.read_to_end(), Format::Zlib, limits.max_alloc_bytes)?;
+    png_image.icc_profile = Some(IccProfile { name, data });
+    Ok(())
+}
+
+fn decode_chrm(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(), DecodingError> {
+    let mut xy = [(0., 0.); 4];
+    for point in xy.iter_mut() {
+        let x = stream.read_u32()? as f64 / 100000.;
+        let y = stream.read_u32()? as f64 / 100000.;
+        if y == 0. {
+            return Err(ErrorKind::InvalidChunkData.into());
+        }
+        *point = (x, y);
+    }
+    png_image.chromaticities = Some(Chromaticities { white: xy[0], red: xy[1], green: xy[2], blue: xy[3] });
+    Ok(())
+}
+
+fn decode_srgb(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(), DecodingError> {
+    if stream.len() != 1 {
+        return Err(ErrorKind::InvalidChunkLength.into());
+    }
+    png_image.srgb_intent = Some(match stream.read_u8()? {
+        0 => RenderingIntent::Perceptual,
+        1 => RenderingIntent::RelativeColorimetric,
+        2 => RenderingIntent::Saturation,
+        3 => RenderingIntent::AbsoluteColorimetric,
+        _ => return Err(ErrorKind::InvalidChunkData.into()),
+    });
+    Ok(())
+}
+
+fn decode_cicp(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(), DecodingError> {
+    if stream.len() != 4 {
+        return Err(ErrorKind::InvalidChunkLength.into());
+    }
+    let color_primaries = stream.read_u8()?;
+    let transfer_function = stream.read_u8()?;
+    let matrix_coefficients = stream.read_u8()?;
+    let full_range = match stream.read_u8()? {
+        0 => false,
+        1 => true,
+        _ => return Err(ErrorKind::InvalidChunkData.into()),
+    };
+    png_image.cicp = Some(Cicp { color_primaries, transfer_function, matrix_coefficients, full_range });
+    Ok(())
+}
+
+fn decode_exif(stream: &mut PNGDatastream, png_image: &mut PNGImage) -> Result<(), DecodingError> {
+    if png_image.exif.is_some() {
+        return Err(ErrorKind::ChunkOrder.into()); // only one eXIf is allowed
+    }
+    png_image.exif = Some(Exif::parse(stream.read_to_end())?);
+    Ok(())
+}
+
+// Decodes zlib compressed, filtered image data that is split into `pieces` as a separate w x h image
+// with the pixel format of `png_image`. Used for APNG frames. With `lenient` errors end up in `warnings`
+// of the result, the rows after them are 0.
+pub(crate) fn decode_subimage(png_image: &PNGImage, w: u32, h: u32, pieces: &[&[u8]], verify_checksum: bool, lenient: bool) -> Result<PNGImage, DecodingError> {
+    let mut sub_image = PNGImage {
         image: Image {
-            w: 0,
-            h: 0,
-            channels: 0,
+            w,
+            h,
+            channels: png_image.image.channels,
             buf: vec![],
-            depth: 0,
+            depth: png_image.image.depth,
+        },
+        depth: png_image.depth,
+        palette: png_image.palette.clone(),
+        trns_alpha: png_image.trns_alpha,
+        color_type: png_image.color_type,
+        interlaced: png_image.interlaced,
+        ..PNGImage::new()
+    };
+    let mut reconstructor = PNGReconstructor::new(&mut sub_image, None, Destination::Image, 7);
+    if verify_checksum {
+        reconstructor.adler = Some(1);
+    }
+    let mut core = InflateCore::new(Format::Zlib);
+    let mut leftover = Leftover::default();
+    let mut result = pieces.iter().try_for_each(|piece| {
+        let mut output = ScanlineOutput { reconstructor: &mut reconstructor, png_image: &mut sub_image, on_row: None };
+        inflate_slice(&mut core, &mut leftover, piece, &mut output).map(|_| ())
+    });
+    if result.is_ok() && !core.is_done() {
+        result = Err(ErrorKind::Truncated.into());
+    }
+    match result {
+        // the rows reconstructed before the error are kept
+        Err(err) if lenient => sub_image.warnings.push(err),
+        result => result?,
+    }
+    reconstructor.finish(&mut sub_image);
+    if sub_image.rows_valid < h && sub_image.warnings.is_empty() {
+        // the zlib stream ended before the last scanline
+        if !lenient {
+            return Err(ErrorKind::Truncated.into());
+        }
+        sub_image.warnings.push(ErrorKind::Truncated.into());
+    }
+    Ok(sub_image)
+}
+
+// Decodes any chunk except IDAT and IEND, which need special handling from the caller. `data_start` is the offset
+// of the chunk data in the file. Broken ancillary chunks are left out of `png_image` with a warning.
+pub(crate) fn decode_chunk(chunk_name: &[u8; 4], chunk_stream: &mut PNGDatastream, png_image: &mut PNGImage, limits: &Limits, data_start: usize) -> Result<(), DecodingError> {
+    let result = match chunk_name {
+        b"IHDR" => decode_ihdr(chunk_stream, png_image, limits),
+        b"PLTE" => decode_plte(chunk_stream, png_image),
+        b"tRNS" => decode_trns(chunk_stream, png_image),
+        b"pHYs" => decode_phys(chunk_stream),
+        b"gAMA" => decode_gama(chunk_stream, png_image),
+        b"cHRM" => decode_chrm(chunk_stream, png_image),
+        b"sRGB" => decode_srgb(chunk_stream, png_image),
+        b"cICP" => decode_cicp(chunk_stream, png_image),
+        b"tEXt" => decode_text(chunk_stream, png_image, limits),
+        b"zTXt" => decode_ztxt(chunk_stream, png_image, limits),
+        b"iTXt" => decode_itxt(chunk_stream, png_image, limits),
+        b"iCCP" => decode_iccp(chunk_stream, png_image, limits),
+        b"eXIf" => decode_exif(chunk_stream, png_image),
+        b"sBIT" => decode_sbit(chunk_stream, png_image),
+        b"bKGD" => decode_bkgd(chunk_stream, png_image),
+        _ => {
+            // println!("skipping {}", String::from_utf8_lossy(chunk_name));
+            chunk_stream.skip(chunk_stream.len())
+        }
+    };
+
+    let result = result
+        .and_then(|()| if chunk_stream.eof() { Ok(()) } else { Err(ErrorKind::InvalidChunkLength.into()) })
+        .map_err(|err| err.at(Some(*chunk_name), data_start + chunk_stream.cursor));
+    match result {
+        Err(err) if err.kind != ErrorKind::LimitsExceeded && matches!(chunk_name, b"tEXt" | b"zTXt" | b"iTXt" | b"iCCP" | b"sRGB" | b"cICP" | b"eXIf" | b"sBIT" | b"bKGD") => {
+            png_image.warnings.push(err);
+            Ok(())
         },
-        depth: 0,
-        palette: None,
-        trns_alpha: None,
-        color_type: 0,
-        gama: None,
-        interlaced: false,
+        result => result,
+    }
+}
+
+pub fn decode(buf: &[u8]) -> Result<PNGImage, DecodingError> {
+    decode_with_options(buf, &DecodeOptions::default())
+}
+
+pub fn decode_with_options(buf: &[u8], options: &DecodeOptions) -> Result<PNGImage, DecodingError> {
+    decode_impl(buf, options, Destination::Image, false, None)
+}
+
+/// Decodes into `dst` with rows `stride` bytes apart, in the layout reported by `buffer_layout`.
+/// Bytes between the rows are left untouched. `image.buf` of the result is empty.
+pub fn decode_into(buf: &[u8], dst: &mut [u8], stride: usize) -> Result<PNGImage, DecodingError> {
+    decode_into_with_options(buf, dst, stride, &DecodeOptions::default())
+}
+
+pub fn decode_into_with_options(buf: &[u8], dst: &mut [u8], stride: usize, options: &DecodeOptions) -> Result<PNGImage, DecodingError> {
+    decode_impl(buf, options, Destination::Buffer(dst, stride), false, None)
+}
+
+/// Decodes row by row, handing each row to `sink` in the layout reported by `buffer_layout`.
+/// Only a single row is held for non-interlaced images, interlaced ones are decoded whole
+/// and their rows go to `sink` once the last pass is done. `image.buf` of the result is empty.
+pub fn decode_rows(buf: &[u8], sink: &mut dyn RowSink) -> Result<PNGImage, DecodingError> {
+    decode_rows_with_options(buf, sink, &DecodeOptions::default())
+}
+
+/// Like `decode_rows`. Options other than `output_format` need the whole image, so with them
+/// the rows go to `sink` after decoding, like for interlaced images.
+pub fn decode_rows_with_options(buf: &[u8], sink: &mut dyn RowSink, options: &DecodeOptions) -> Result<PNGImage, DecodingError> {
+    decode_impl(buf, options, Destination::Rows(sink), false, None)
+}
+
+/// Layout of the image that `decode_with_options` or `decode_into_with_options` would produce.
+/// Only the chunks before IDAT are decoded.
+pub fn buffer_layout(buf: &[u8], options: &DecodeOptions) -> Result<BufferLayout, DecodingError> {
+    let png_image = decode_impl(buf, options, Destination::Image, true, None)?;
+    Ok(output_layout(&png_image, options))
+}
+
+/// Decodes like `decode_with_options`, calling `on_pass` with the pass number and a full-size preview after
+/// each of the 7 passes of an interlaced image. Pixels that aren't decoded yet are filled in from the decoded
+/// pixel above and to the left of them. Previews are in the layout of the file, or of `output_format` if it's
+/// set, the other options only apply to the final image. Non-interlaced images have no previews.
+pub fn decode_progressive(buf: &[u8], options: &DecodeOptions, on_pass: &mut dyn FnMut(usize, &Image)) -> Result<PNGImage, DecodingError> {
+    decode_impl(buf, options, Destination::Image, false, Some(on_pass))
+}
+
+/// Dimensions, color type and palette from the chunks before IDAT, see `Image::probe`
+pub fn probe(buf: &[u8]) -> Result<ImageInfo, DecodingError> {
+    let png_image = decode_impl(buf, &DecodeOptions::default(), Destination::Image, true, None)?;
+    let image = &png_image.image;
+    Ok(ImageInfo {
+        w: image.w,
+        h: image.h,
+        channels: image.channels,
+        depth: png_image.depth,
+        interlaced: png_image.interlaced,
+        palette_size: png_image.palette_size(),
+        has_transparency: image.channels == 2 || image.channels == 4,
+    })
+}
+
+fn output_layout(png_image: &PNGImage, options: &DecodeOptions) -> BufferLayout {
+    let image = &png_image.image;
+    let composited_channels = options.composite_over.and_then(|over| composite_params(png_image, over)).map(|(_, channels)| channels as u32);
+    let (channels, depth) = match options.output_format {
+        Some(format) => (format.channels(), format.depth()),
+        None => (composited_channels.unwrap_or(image.channels), image.depth),
     };
+    let (w, h) = options.adam7_passes.map_or((image.w, image.h), |passes| reduced_size(image, passes));
+    BufferLayout { w, h, channels, depth }
+}
+
+// The image buffers are allocated at the output size in the output layout, and in the layout of the file
+// unless the scanlines are converted as they are reconstructed
+fn check_image_size(png_image: &PNGImage, options: &DecodeOptions) -> Result<(), DecodingError> {
+    let image = &png_image.image;
+    let layout = output_layout(png_image, options);
+    let pixels = layout.w as u64 * layout.h as u64;
+    let fused_format = options.output_format.is_some() && !needs_postprocessing(options);
+    let native_bits = if fused_format { 0 } else { image.channels * image.depth as u32 };
+    let bytes_per_pixel = native_bits.max(layout.channels * layout.depth as u32) / 8;
+    options.limits.check_alloc(pixels.saturating_mul(bytes_per_pixel as u64))
+}
+
+// The pixels of the first `passes` Adam7 passes form a grid of every BLOCK_W-th column of every BLOCK_H-th row
+fn reduced_size(image: &Image, passes: usize) -> (u32, u32) {
+    (image.w.div_ceil(BLOCK_W[passes] as u32), image.h.div_ceil(BLOCK_H[passes] as u32))
+}
+
+// Whether options other than `output_format` are set, they work on the whole image after decoding
+fn needs_postprocessing(options: &DecodeOptions) -> bool {
+    options.significant_bits.is_some() || options.composite_over.is_some() || options.color_space.is_some()
+}
+
+// Applies the options that work on the whole decoded image
+fn postprocess(png_image: &mut PNGImage, options: &DecodeOptions) -> Result<(), DecodingError> {
+    // rescaled samples don't use the full range of any pixel format
+    if options.significant_bits == Some(SignificantBits::Rescale) && options.output_format.is_some() {
+        return Err(ErrorKind::NotImplemented.into());
+    }
+    // replicated samples are still normalized and can be color converted, rescaled ones can't
+    if options.significant_bits == Some(SignificantBits::Replicate) {
+        apply_significant_bits(png_image, SignificantBits::Replicate);
+    }
+    if let Some(over) = options.composite_over {
+        composite(png_image, over);
+    }
+    if let Some(target) = options.color_space {
+        // without the sRGB or cICP chunk that was left out the source color space isn't known
+        let broken = png_image.warnings.iter().find(|warning| warning.chunk == Some(*b"sRGB") || warning.chunk == Some(*b"cICP"));
+        if let Some(err) = broken {
+            return Err(err.clone());
+        }
+        color::convert(png_image, target)?;
+    }
+    if options.significant_bits == Some(SignificantBits::Rescale) {
+        apply_significant_bits(png_image, SignificantBits::Rescale);
+    }
+    if needs_postprocessing(options) && let Some(format) = options.output_format {
+        pixel_format::convert(&mut png_image.image, format);
+    }
+    Ok(())
+}
+
+// Decodes the whole datastream, or only the chunks before IDAT if `header_only` is set.
+// The image goes to `destination`. Previews of interlaced images go to `on_pass` if it's set.
+fn decode_impl<'a>(buf: &[u8], options: &DecodeOptions, mut destination: Destination<'a>, header_only: bool, on_pass: Option<PassCallback<'a>>) -> Result<PNGImage, DecodingError> {
+    let mut stream = PNGDatastream::new(buf);
+    stream.check_crc = options.verify_checksums;
+    let mut chunk = None;
+    let mut png_image = PNGImage::new();
+    let result = decode_chunks(&mut stream, &mut chunk, &mut png_image, options, &mut destination, header_only, on_pass)
+        .map_err(|err| err.at(chunk, stream.cursor));
+    match result {
+        // decoding stops at the error, the image has what was reconstructed before it
+        Err(err) if options.lenient && png_image.idat_seen => png_image.warnings.push(err),
+        result => result?,
+    }
+    if header_only {
+        return Ok(png_image);
+    }
+
+    postprocess(&mut png_image, options)?;
+    match destination {
+        Destination::Image => {},
+        Destination::Buffer(dst, stride) => {
+            png_image.image.copy_into(dst, stride)?;
+            png_image.image.buf = vec![];
+        },
+        Destination::Rows(sink) => png_image.image.send_rows(sink),
+    }
+    Ok(png_image)
+}
+
+// Decodes the chunks up to IEND into `png_image`, or up to the first IDAT if `header_only` is set. `chunk` is
+// the chunk being decoded, errors that don't know their offset happened at the cursor of `stream`.
+fn decode_chunks<'a>(stream: &mut PNGDatastream, chunk: &mut Option<[u8; 4]>, png_image: &mut PNGImage, options: &DecodeOptions, destination: &mut Destination<'a>, header_only: bool, mut on_pass: Option<PassCallback<'a>>) -> Result<(), DecodingError> {
+    let buf = stream.buf;
+    stream.consume(PNG_SIGNATURE)?;
+
+    // the other options work on the layout of the file, so the output format is applied after them
+    let postprocessing = needs_postprocessing(options);
 
     loop {
-        let len = stream.read_u32()? as usize;
+        *chunk = None;
+        let mut len = stream.read_u32()? as usize;
         stream.reset_crc();
         let chunk_name = stream.read_chunk_name()?;
+        *chunk = Some(chunk_name);
+        let chunk_start = stream.cursor - 8;
+        if png_image.misplaced_ihdr(&chunk_name) {
+            return Err(ErrorKind::ChunkOrder.into());
+        }
+        if header_only && chunk_name == *b"IDAT" {
+            return Ok(());
+        }
+        if len > options.limits.max_chunk_len {
+            return Err(ErrorKind::LimitsExceeded.into());
+        }
         if stream.cursor + len >= buf.len() {
-            println!("Chunk length is out of bounds");
-            return Err(DecodingError::MalformedImage);
+            // the lenient mode decodes what there is of a cut off IDAT chunk
+            if !options.lenient || chunk_name != *b"IDAT" || png_image.idat_seen {
+                return Err(ErrorKind::InvalidChunkLength.into());
+            }
+            len = buf.len() - stream.cursor;
         }
         let mut chunk_stream = PNGDatastream::new(&buf[stream.cursor..stream.cursor + len]);
 
@@ -1384,38 +2348,300 @@ pub fn decode(buf: &[u8]) -> Result<PNGImage, DecodingError> {
         }
 
         match &chunk_name {
-            b"IHDR" => decode_ihdr(&mut chunk_stream, &mut png_image)?,
-            b"PLTE" => decode_plte(&mut chunk_stream, &mut png_image)?,
-            b"tRNS" => decode_trns(&mut chunk_stream, &mut png_image)?,
-            b"pHYs" => decode_phys(&mut chunk_stream)?,
-            b"gAMA" => decode_gama(&mut chunk_stream, &mut png_image)?,
-            b"IDAT" => unsafe { decode_idat(&mut stream, len as u32, &mut png_image) }?,
+            b"IDAT" if png_image.idat_seen => return Err(ErrorKind::ChunkOrder.into()), // IDAT chunks must be consecutive
+            b"IDAT" if png_image.palette_missing() => return Err(ErrorKind::ChunkOrder.into()),
+            b"IDAT" => {
+                check_image_size(png_image, options)?;
+                let fused_destination = if postprocessing || options.adam7_passes.is_some() { Destination::Image } else { std::mem::take(destination) };
+                if let Destination::Buffer(dst, stride) = &fused_destination {
+                    output_layout(png_image, options).check_buffer(dst, *stride)?;
+                }
+                let mut fused_destination = fused_destination;
+                let segments = png_image.segments.take().filter(|segments| segments[0].offset == chunk_start);
+                if let Some(segments) = segments && decode_segments(buf, &segments, png_image, options, &mut fused_destination)? {
+                    IdatInput::new(stream, len as u32).skip_idat_chunks()?;
+                } else {
+                    decode_idat(stream, len as u32, png_image, options, fused_destination, on_pass.take())?
+                }
+            },
+            b"iDOT" => png_image.segments = decode_idot(&mut chunk_stream, chunk_start, png_image),
             b"IEND" => break,
-            _ => {
-                // println!("skipping {}", String::from_utf8_lossy(&chunk_name));
-                chunk_stream.skip(len)?;
-            }
+            _ => decode_chunk(&chunk_name, &mut chunk_stream, png_image, &options.limits, chunk_start + 8)?,
         }
+    }
 
-        if chunk_name != *b"IDAT" && !chunk_stream.eof() {
-            return Err(DecodingError::MalformedImage);
+    *chunk = None;
+    if !png_image.idat_seen {
+        return Err(ErrorKind::ChunkOrder.into()); // missing IDAT chunk?
+    }
+    if !stream.eof() {
+        return Err(ErrorKind::TooMuchData.into());
+    }
+    Ok(())
+}
+
+// Compares the CRC register of a chunk with the stored big-endian CRC
+fn verify_crc(enabled: bool, crc: u32, stored: &[u8]) -> Result<(), DecodingError> {
+    if enabled && (crc ^ 0xffffffff).to_be_bytes() != stored {
+        Err(ErrorKind::CrcMismatch.into())
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+enum StreamState {
+    Signature,
+    ChunkHeader,
+    Chunk { name: [u8; 4], len: usize },
+    ImageData { bytes_left: usize },
+    ImageDataCrc,
+    End,
+    Stopped, // the lenient mode got past an error, the rest of the data is ignored
+}
+
+impl StreamState {
+    fn chunk(&self) -> Option<[u8; 4]> {
+        match self {
+            StreamState::Chunk { name, .. } => Some(*name),
+            StreamState::ImageData { .. } | StreamState::ImageDataCrc => Some(*b"IDAT"),
+            _ => None,
         }
     }
+}
 
-    if stream.eof() {
-        if png_image.image.buf.len() != 0 {
-            Ok(png_image)
-        } else {
-            Err(DecodingError::MalformedImage) // missing IDAT chunk?
+/// Push-style PNG decoder for data that arrives in pieces, e.g. over a slow pipe.
+///
+/// Bytes can be pushed in slices of any size. The inflate and scanline reconstruction state is kept
+/// between `push` calls, so every scanline is reported as soon as enough compressed data for it has arrived.
+pub struct PngStreamDecoder {
+    png_image: PNGImage,
+    state: StreamState,
+    pending: Vec<u8>,
+    core: Option<InflateCore>, // inflates the image data into `reconstructor`
+    leftover: Leftover, // image data of the last push that is inflated again with the next one
+    reconstructor: Option<PNGReconstructor<'static>>,
+    idat_finished: bool,
+    options: DecodeOptions,
+    crc: u32, // CRC register of the current chunk
+    consumed: usize, // bytes of the datastream before `pending`
+}
+
+impl Default for PngStreamDecoder {
+    fn default() -> PngStreamDecoder {
+        PngStreamDecoder::new()
+    }
+}
+
+impl PngStreamDecoder {
+    pub fn new() -> PngStreamDecoder {
+        PngStreamDecoder::with_options(DecodeOptions::default())
+    }
+
+    /// Decodes with the same `options` as `decode_with_options`. The options that work on the whole image
+    /// (`color_space`, `significant_bits` and `composite_over`) are applied in `finish`.
+    pub fn with_options(options: DecodeOptions) -> PngStreamDecoder {
+        PngStreamDecoder {
+            png_image: PNGImage::new(),
+            state: StreamState::Signature,
+            pending: vec![],
+            core: None,
+            leftover: Leftover::default(),
+            reconstructor: None,
+            idat_finished: false,
+            options,
+            crc: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Decodes as much of the datastream as possible.
+    ///
+    /// `on_scanline` is called with the row index and the row's pixels (in the final `Image` layout) after each
+    /// reconstructed scanline. For interlaced images it's called once per Adam7 pass that touches the row,
+    /// and the row only has the pixels of the passes decoded so far. With `adam7_passes` the rows are those
+    /// of the reduced image. The options that `finish` applies aren't applied to these rows yet.
+    pub fn push<F>(&mut self, data: &[u8], mut on_scanline: F) -> Result<(), DecodingError>
+        where F: FnMut(u32, &[u8])
+    {
+        self.pending.extend_from_slice(data);
+        let mut cursor = 0;
+        let result = self.decode_pending(&mut cursor, &mut on_scanline);
+        let offset = self.consumed + cursor;
+        self.pending.drain(..cursor);
+        self.consumed = offset;
+        let result = result.map_err(|err| err.at(self.state.chunk(), offset));
+        match result {
+            Err(err) if self.options.lenient && self.reconstructor.is_some() => {
+                self.png_image.warnings.push(err);
+                self.state = StreamState::Stopped;
+                Ok(())
+            },
+            result => result,
+        }
+    }
+
+    // Decodes `pending` from `cursor` on, `cursor` is left after the bytes that were used up
+    fn decode_pending<F>(&mut self, cursor: &mut usize, on_scanline: &mut F) -> Result<(), DecodingError>
+        where F: FnMut(u32, &[u8])
+    {
+        let PngStreamDecoder { png_image, state, pending, core, leftover, reconstructor, idat_finished, options, crc, consumed } = self;
+        let verify = options.verify_checksums;
+        loop {
+            let available = &pending[*cursor..];
+            match *state {
+                StreamState::Signature => {
+                    if available.len() < PNG_SIGNATURE.len() {
+                        break;
+                    }
+                    if !available.starts_with(PNG_SIGNATURE) {
+                        return Err(ErrorKind::InvalidSignature.into());
+                    }
+                    *cursor += PNG_SIGNATURE.len();
+                    *state = StreamState::ChunkHeader;
+                },
+                StreamState::ChunkHeader => {
+                    if available.len() < 8 {
+                        break;
+                    }
+                    let len = PNGDatastream::new(&available[..4]).read_u32()? as usize;
+                    let name = [available[4], available[5], available[6], available[7]];
+                    if len > options.limits.max_chunk_len {
+                        return Err(DecodingError::from(ErrorKind::LimitsExceeded).at(Some(name), *consumed + *cursor));
+                    }
+                    if png_image.misplaced_ihdr(&name) {
+                        return Err(DecodingError::from(ErrorKind::ChunkOrder).at(Some(name), *consumed + *cursor));
+                    }
+                    *crc = checksum::crc32_update(0xffffffff, &name);
+                    *cursor += 8;
+                    if &name == b"IDAT" {
+                        if *idat_finished || png_image.palette_missing() {
+                            return Err(DecodingError::from(ErrorKind::ChunkOrder).at(Some(name), *consumed + *cursor - 8));
+                        }
+                        if reconstructor.is_none() {
+                            check_image_size(png_image, options)?;
+                            let fused_format = if needs_postprocessing(options) { None } else { options.output_format };
+                            let mut new_reconstructor = PNGReconstructor::new(png_image, fused_format, Destination::Image, options.adam7_passes.unwrap_or(7));
+                            new_reconstructor.cpu = options.cpu_features;
+                            if verify {
+                                new_reconstructor.adler = Some(1);
+                            }
+                            *reconstructor = Some(new_reconstructor);
+                            *core = Some(InflateCore::new(Format::Zlib));
+                        }
+                        *state = StreamState::ImageData { bytes_left: len };
+                    } else {
+                        if let (Some(core), Some(reconstructor)) = (core.as_ref(), reconstructor.as_ref()) {
+                            // the rest of the image data isn't inflated once the reconstructor stopped
+                            if !core.is_done() && !reconstructor.stopped {
+                                return Err(DecodingError::from(ErrorKind::ChunkOrder).at(Some(name), *consumed + *cursor - 8));
+                            }
+                            *idat_finished = true;
+                        }
+                        *state = StreamState::Chunk { name, len };
+                    }
+                },
+                StreamState::Chunk { name, len } => {
+                    if available.len() < len + 4 {
+                        break;
+                    }
+                    verify_crc(verify, checksum::crc32_update(*crc, &available[..len]), &available[len..len + 4])?;
+                    if &name == b"IEND" {
+                        *state = StreamState::End;
+                    } else {
+                        let mut chunk_stream = PNGDatastream::new(&available[..len]);
+                        decode_chunk(&name, &mut chunk_stream, png_image, &options.limits, *consumed + *cursor)?;
+                        *state = StreamState::ChunkHeader;
+                    }
+                    *cursor += len + 4;
+                },
+                StreamState::ImageData { bytes_left } => {
+                    if bytes_left == 0 {
+                        *state = StreamState::ImageDataCrc;
+                        continue;
+                    }
+                    if available.is_empty() {
+                        break;
+                    }
+                    let cnt = std::cmp::min(bytes_left, available.len());
+                    let (Some(core), Some(reconstructor)) = (core.as_mut(), reconstructor.as_mut()) else {
+                        return Err(ErrorKind::ChunkOrder.into());
+                    };
+                    if verify {
+                        *crc = checksum::crc32_update(*crc, &available[..cnt]);
+                    }
+                    if !reconstructor.stopped {
+                        let mut output = ScanlineOutput { reconstructor, png_image, on_row: Some(on_scanline) };
+                        inflate_slice(core, leftover, &available[..cnt], &mut output)?;
+                    }
+                    *cursor += cnt;
+                    *state = StreamState::ImageData { bytes_left: bytes_left - cnt };
+                },
+                StreamState::ImageDataCrc => {
+                    if available.len() < 4 {
+                        break;
+                    }
+                    verify_crc(verify, *crc, &available[..4])?;
+                    *cursor += 4;
+                    *state = StreamState::ChunkHeader;
+                },
+                StreamState::End => {
+                    if !available.is_empty() {
+                        return Err(ErrorKind::TooMuchData.into()); // data after IEND
+                    }
+                    break;
+                },
+                StreamState::Stopped => {
+                    *cursor = pending.len();
+                    break;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// True once IEND has been reached
+    pub fn is_finished(&self) -> bool {
+        self.state == StreamState::End
+    }
+
+    pub fn finish(self) -> Result<PNGImage, DecodingError> {
+        let mut png_image = self.png_image;
+        match (self.state, self.core, self.reconstructor) {
+            (StreamState::End, Some(core), Some(mut reconstructor)) if core.is_done() || reconstructor.stopped => {
+                reconstructor.finish(&mut png_image);
+                if png_image.rows_valid < png_image.image.h {
+                    // the zlib stream ended before the last scanline
+                    if !self.options.lenient {
+                        return Err(ErrorKind::Truncated.into());
+                    }
+                    png_image.warnings.push(ErrorKind::Truncated.into());
+                }
+                postprocess(&mut png_image, &self.options)?;
+                Ok(png_image)
+            },
+            // the lenient mode returns the rows decoded so far
+            (state, _, Some(mut reconstructor)) if self.options.lenient => {
+                if state != StreamState::Stopped {
+                    png_image.warnings.push(DecodingError::from(ErrorKind::Truncated).at(state.chunk(), self.consumed + self.pending.len()));
+                }
+                reconstructor.finish(&mut png_image);
+                postprocess(&mut png_image, &self.options)?;
+                Ok(png_image)
+            },
+            _ => Err(ErrorKind::Truncated.into()), // truncated datastream or missing IDAT chunk
         }
-    } else {
-        Err(DecodingError::MalformedImage)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::deflate;
     use crate::png_decoder::CRC_TABLE;
+    use crate::png_decoder::Segment;
+    use crate::png_decoder::first_filter;
 
     #[test]
     fn test_crc_table() {
@@ -1429,6 +2655,20 @@ mod tests {
         assert_eq!(CRC_TABLE, expected_table);
     }
 
+    #[test]
+    fn test_first_filter() {
+        let scanlines: Vec<u8> = (0..1000).map(|i| if i % 101 == 0 { 2 } else { (i * 7) as u8 }).collect();
+        let data = deflate::compress(&scanlines, 6);
+        let mut buf = (data.len() as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(b"IDAT");
+        buf.extend_from_slice(&data);
+        buf.extend_from_slice(&[0; 4]); // CRC
+        let segment = Segment { first_row: 1, rows: 10, offset: 0 };
+        assert_eq!(first_filter(&buf, &segment), Some(2));
+        buf[4] = b'i';
+        assert_eq!(first_filter(&buf, &segment), None);
+    }
+
     #[test]
     fn test_avg() {
         for ai in 0..256 {
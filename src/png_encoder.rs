@@ -0,0 +1,525 @@
+use crate::EncodingError;
+use crate::Image;
+use crate::checksum;
+use crate::deflate;
+use crate::png_decoder::Filter;
+use crate::png_decoder::PNG_SIGNATURE;
+use crate::png_decoder::START_X;
+use crate::png_decoder::START_Y;
+use crate::png_decoder::STEP_X;
+use crate::png_decoder::STEP_Y;
+use crate::png_decoder::paeth_predictor;
+use std::collections::HashMap;
+
+const IDAT_SIZE: usize = 1 << 16;
+const FILTERS: [Filter; 5] = [Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth];
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum ColorType {
+    Gray,
+    Rgb,
+    Indexed,
+    GrayAlpha,
+    Rgba,
+}
+
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+pub enum FilterStrategy {
+    Fixed(Filter),
+    MinSum, // minimum sum of absolute differences, the heuristic recommended by the PNG spec
+    BruteForce, // compresses the scanline with every filter and keeps the smallest result
+}
+
+/// PNG writer. The input `Image` uses the same layout that `png_decoder::decode` produces for the chosen format,
+/// so `decode(encode(image))` gives back `image`:
+/// - `Gray`/`Rgb`: 1/3 channels, or 2/4 channels if a transparent color is set. The extra alpha channel must be
+///   fully transparent exactly for the pixels of the transparent color and fully opaque elsewhere.
+///   Gray samples are quantized to the nearest level for depths below 8.
+/// - `GrayAlpha`/`Rgba`: 2/4 channels.
+/// - `Indexed`: 1 channel of palette indices, or 3/4 channels of colors that are present in the palette.
+///   4 channels are required if the palette has transparent entries.
+///
+/// `Image::depth` must be 16 for 16-bit output and 8 otherwise, 16-bit samples are little-endian.
+pub struct PngEncoder<'a> {
+    color_type: ColorType,
+    depth: u8,
+    palette: Option<&'a [[u8; 4]]>,
+    transparent_color: Option<[u16; 3]>,
+    interlaced: bool,
+    filter_strategy: FilterStrategy,
+    compression_level: u8,
+}
+
+impl ColorType {
+    fn code(self) -> u8 {
+        match self {
+            ColorType::Gray => 0,
+            ColorType::Rgb => 2,
+            ColorType::Indexed => 3,
+            ColorType::GrayAlpha => 4,
+            ColorType::Rgba => 6,
+        }
+    }
+
+    // channels stored in the file
+    fn channels(self) -> usize {
+        match self {
+            ColorType::Gray | ColorType::Indexed => 1,
+            ColorType::GrayAlpha => 2,
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+        }
+    }
+}
+
+fn crc32(name: &[u8; 4], data: &[u8]) -> u32 {
+    checksum::crc32_update(checksum::crc32_update(0xffffffff, name), data) ^ 0xffffffff
+}
+
+fn write_chunk(out: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(name);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc32(name, data).to_be_bytes());
+}
+
+fn filter_scanline(filter: Filter, bpp: usize, prev: &[u8], cur: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+    out.push(filter as u8);
+    for i in 0..cur.len() {
+        let a = if i >= bpp { cur[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+        let pred = match filter {
+            Filter::None => 0,
+            Filter::Sub => a,
+            Filter::Up => b,
+            Filter::Average => ((a as u16 + b as u16) / 2) as u8,
+            Filter::Paeth => paeth_predictor(a, b, c),
+        };
+        out.push(cur[i].wrapping_sub(pred));
+    }
+}
+
+fn sum_of_abs_differences(filtered: &[u8]) -> usize {
+    filtered[1..].iter().map(|byte| (*byte as i8).unsigned_abs() as usize).sum()
+}
+
+// Size of the scanline compressed on its own
+fn compressed_size(filtered: &[u8], level: u8) -> usize {
+    deflate::compress(&filtered[1..], level).len()
+}
+
+impl<'a> PngEncoder<'a> {
+    /// `depth` is the bit depth of the file, which must be allowed for `color_type`
+    pub fn new(color_type: ColorType, depth: u8) -> PngEncoder<'a> {
+        PngEncoder {
+            color_type,
+            depth,
+            palette: None,
+            transparent_color: None,
+            interlaced: false,
+            filter_strategy: FilterStrategy::MinSum,
+            compression_level: deflate::DEFAULT_COMPRESSION,
+        }
+    }
+
+    /// RGBA palette entries, required for `ColorType::Indexed`. tRNS is written if any entry isn't opaque.
+    pub fn palette(mut self, palette: &'a [[u8; 4]]) -> PngEncoder<'a> {
+        self.palette = Some(palette);
+        self
+    }
+
+    /// Color key for `ColorType::Gray` (first value is used) and `ColorType::Rgb`, in file sample values
+    pub fn transparent_color(mut self, color: [u16; 3]) -> PngEncoder<'a> {
+        self.transparent_color = Some(color);
+        self
+    }
+
+    /// Adam7 interlacing
+    pub fn interlaced(mut self, interlaced: bool) -> PngEncoder<'a> {
+        self.interlaced = interlaced;
+        self
+    }
+
+    pub fn filter_strategy(mut self, filter_strategy: FilterStrategy) -> PngEncoder<'a> {
+        self.filter_strategy = filter_strategy;
+        self
+    }
+
+    /// Deflate level from `deflate::NO_COMPRESSION` to `deflate::BEST_COMPRESSION`
+    pub fn compression_level(mut self, level: u8) -> PngEncoder<'a> {
+        self.compression_level = level;
+        self
+    }
+
+    fn validate(&self, image: &Image) -> Result<(), EncodingError> {
+        let allowed_depths: &[u8] = match self.color_type {
+            ColorType::Gray => &[1, 2, 4, 8, 16],
+            ColorType::Indexed => &[1, 2, 4, 8],
+            _ => &[8, 16],
+        };
+        if !allowed_depths.contains(&self.depth) {
+            return Err(EncodingError::InvalidParameters);
+        }
+        match (self.color_type, self.palette) {
+            (ColorType::Indexed, Some(palette)) if !palette.is_empty() && palette.len() <= 1 << self.depth => {},
+            (ColorType::Indexed, _) | (_, Some(_)) => return Err(EncodingError::InvalidParameters),
+            _ => {},
+        }
+        if let Some(color) = self.transparent_color {
+            let n = match self.color_type {
+                ColorType::Gray => 1,
+                ColorType::Rgb => 3,
+                _ => return Err(EncodingError::InvalidParameters),
+            };
+            if color[..n].iter().any(|val| *val as u32 >= 1 << self.depth) {
+                return Err(EncodingError::InvalidParameters);
+            }
+        }
+
+        if image.w == 0 || image.h == 0 || image.w >= 0x80000000 || image.h >= 0x80000000 {
+            return Err(EncodingError::InvalidImage);
+        }
+        let expected_channels: &[u32] = match self.color_type {
+            ColorType::Gray if self.transparent_color.is_some() => &[2],
+            ColorType::Rgb if self.transparent_color.is_some() => &[4],
+            ColorType::Indexed if self.palette_has_alpha() => &[1, 4],
+            ColorType::Indexed => &[1, 3, 4],
+            _ => &[self.color_type.channels() as u32],
+        };
+        let expected_depth = if self.depth == 16 { 16 } else { 8 };
+        if !expected_channels.contains(&image.channels) || image.depth != expected_depth {
+            return Err(EncodingError::InvalidImage);
+        }
+        let sz = image.w as usize * image.h as usize * image.channels as usize * (image.depth / 8) as usize;
+        if image.buf.len() != sz {
+            return Err(EncodingError::InvalidImage);
+        }
+        Ok(())
+    }
+
+    fn palette_has_alpha(&self) -> bool {
+        self.palette.is_some_and(|palette| palette.iter().any(|entry| entry[3] != 255))
+    }
+
+    fn write_trns(&self, image: &Image) -> bool {
+        match self.color_type {
+            ColorType::Indexed => self.palette_has_alpha() || image.channels == 4,
+            _ => self.transparent_color.is_some(),
+        }
+    }
+
+    // Converts the image to the sample values stored in the file, `color_type.channels()` per pixel
+    fn samples(&self, image: &Image) -> Result<Vec<u16>, EncodingError> {
+        let values: Vec<u16> =
+            if image.depth == 16 {
+                image.buf.chunks_exact(2).map(|s| u16::from_le_bytes([s[0], s[1]])).collect()
+            } else {
+                image.buf.iter().map(|s| *s as u16).collect()
+            };
+        let in_channels = image.channels as usize;
+        let out_channels = self.color_type.channels();
+        let max = ((1u32 << self.depth) - 1) as u16;
+        let mut samples = Vec::with_capacity(image.w as usize * image.h as usize * out_channels);
+
+        match self.color_type {
+            ColorType::Indexed if in_channels == 1 => {
+                let palette_len = self.palette.map_or(0, |palette| palette.len());
+                if values.iter().any(|idx| *idx as usize >= palette_len) {
+                    return Err(EncodingError::InvalidImage);
+                }
+                samples = values;
+            },
+            ColorType::Indexed => {
+                let mut lookup = HashMap::new();
+                for (i, entry) in self.palette.unwrap_or_default().iter().enumerate().rev() {
+                    lookup.insert(&entry[..in_channels], i as u16); // the first matching entry wins
+                }
+                for pix in values.chunks_exact(in_channels) {
+                    let color: Vec<u8> = pix.iter().map(|s| *s as u8).collect();
+                    match lookup.get(&color[..]) {
+                        Some(idx) => samples.push(*idx),
+                        None => return Err(EncodingError::InvalidImage),
+                    }
+                }
+            },
+            _ => {
+                let scale = if self.depth < 8 { 255 / max } else { 1 };
+                for pix in values.chunks_exact(in_channels) {
+                    let color: Vec<u16> = pix[..out_channels].iter().map(|s| (s + scale / 2) / scale).collect();
+                    if let Some(key) = self.transparent_color {
+                        let expected_alpha = if color[..] == key[..out_channels] { 0 } else { (1u32 << image.depth) - 1 };
+                        if pix[out_channels] as u32 != expected_alpha {
+                            return Err(EncodingError::InvalidImage);
+                        }
+                    }
+                    samples.extend_from_slice(&color);
+                }
+            },
+        }
+        Ok(samples)
+    }
+
+    // Packs samples of the pixels with the given x coordinates of row y into a raw scanline
+    fn pack_scanline(&self, samples: &[u16], w: usize, y: usize, xs: impl Iterator<Item = usize>, out: &mut Vec<u8>) {
+        let channels = self.color_type.channels();
+        out.clear();
+        let mut bits = 0u32;
+        let mut n_bits = 0;
+        for x in xs {
+            let base = (y * w + x) * channels;
+            for sample in &samples[base..base + channels] {
+                match self.depth {
+                    16 => out.extend_from_slice(&sample.to_be_bytes()),
+                    8 => out.push(*sample as u8),
+                    _ => {
+                        bits = bits << self.depth | *sample as u32;
+                        n_bits += self.depth;
+                        if n_bits == 8 {
+                            out.push(bits as u8);
+                            bits = 0;
+                            n_bits = 0;
+                        }
+                    },
+                }
+            }
+        }
+        if n_bits != 0 {
+            out.push((bits << (8 - n_bits)) as u8);
+        }
+    }
+
+    fn choose_filter(&self, bpp: usize, prev: &[u8], cur: &[u8], candidate: &mut Vec<u8>, best: &mut Vec<u8>) {
+        match self.filter_strategy {
+            FilterStrategy::Fixed(filter) => filter_scanline(filter, bpp, prev, cur, best),
+            FilterStrategy::MinSum | FilterStrategy::BruteForce => {
+                let mut best_cost = usize::MAX;
+                for filter in FILTERS {
+                    filter_scanline(filter, bpp, prev, cur, candidate);
+                    let cost =
+                        if self.filter_strategy == FilterStrategy::MinSum {
+                            sum_of_abs_differences(candidate)
+                        } else {
+                            compressed_size(candidate, self.compression_level)
+                        };
+                    if cost < best_cost {
+                        best_cost = cost;
+                        std::mem::swap(candidate, best);
+                    }
+                }
+            },
+        }
+    }
+
+    // Filtered scanlines of all passes, i.e. the uncompressed zlib payload
+    fn filtered_data(&self, image: &Image, samples: &[u16]) -> Vec<u8> {
+        let w = image.w as usize;
+        let h = image.h as usize;
+        let bits_per_pixel = self.color_type.channels() * self.depth as usize;
+        let bpp = std::cmp::max(bits_per_pixel / 8, 1);
+        let passes = if self.interlaced { 1..8 } else { 0..1 };
+
+        let mut data = vec![];
+        let mut cur = vec![];
+        let mut candidate = vec![];
+        let mut best = vec![];
+        for pass_id in passes {
+            let (start_x, start_y) = (START_X[pass_id] as usize, START_Y[pass_id] as usize);
+            let (step_x, step_y) = (STEP_X[pass_id] as usize, STEP_Y[pass_id] as usize);
+            if start_x >= w || start_y >= h {
+                continue;
+            }
+            let pass_w = (w - start_x).div_ceil(step_x);
+            let mut prev = vec![0; (pass_w * bits_per_pixel).div_ceil(8)];
+            for y in (start_y..h).step_by(step_y) {
+                self.pack_scanline(samples, w, y, (start_x..w).step_by(step_x), &mut cur);
+                self.choose_filter(bpp, &prev, &cur, &mut candidate, &mut best);
+                data.extend_from_slice(&best);
+                std::mem::swap(&mut prev, &mut cur);
+            }
+        }
+        data
+    }
+
+    pub fn encode(&self, image: &Image) -> Result<Vec<u8>, EncodingError> {
+        self.validate(image)?;
+        let samples = self.samples(image)?;
+
+        let mut out = PNG_SIGNATURE.to_vec();
+
+        let mut ihdr = vec![];
+        ihdr.extend_from_slice(&image.w.to_be_bytes());
+        ihdr.extend_from_slice(&image.h.to_be_bytes());
+        ihdr.extend_from_slice(&[self.depth, self.color_type.code(), 0, 0, self.interlaced as u8]);
+        write_chunk(&mut out, b"IHDR", &ihdr);
+
+        if let Some(palette) = self.palette {
+            let plte: Vec<u8> = palette.iter().flat_map(|entry| entry[..3].iter().copied()).collect();
+            write_chunk(&mut out, b"PLTE", &plte);
+        }
+        if self.write_trns(image) {
+            let trns: Vec<u8> = match (self.palette, self.transparent_color) {
+                (Some(palette), _) => {
+                    // trailing opaque entries can be omitted, but keep at least one so the chunk isn't empty
+                    let len = palette.iter().rposition(|entry| entry[3] != 255).unwrap_or(0) + 1;
+                    palette[..len].iter().map(|entry| entry[3]).collect()
+                },
+                (None, Some(color)) => {
+                    color[..self.color_type.channels()].iter().flat_map(|val| val.to_be_bytes()).collect()
+                },
+                (None, None) => vec![],
+            };
+            write_chunk(&mut out, b"tRNS", &trns);
+        }
+
+        let zlib_data = deflate::zlib_compress(&self.filtered_data(image, &samples), self.compression_level);
+        for idat in zlib_data.chunks(IDAT_SIZE) {
+            write_chunk(&mut out, b"IDAT", idat);
+        }
+        write_chunk(&mut out, b"IEND", &[]);
+        Ok(out)
+    }
+}
+
+/// Encodes the image with the color type implied by its channel count (gray, gray + alpha, RGB or RGBA)
+pub fn encode(image: &Image) -> Result<Vec<u8>, EncodingError> {
+    let color_type = match image.channels {
+        1 => ColorType::Gray,
+        2 => ColorType::GrayAlpha,
+        3 => ColorType::Rgb,
+        4 => ColorType::Rgba,
+        _ => return Err(EncodingError::InvalidImage),
+    };
+    PngEncoder::new(color_type, image.depth).encode(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Image;
+    use crate::png_decoder;
+    use crate::png_decoder::Filter;
+    use crate::png_encoder::ColorType;
+    use crate::png_encoder::FilterStrategy;
+    use crate::png_encoder::PngEncoder;
+
+    // xorshift, deterministic noise with some structure for the filters
+    fn test_image(w: u32, h: u32, channels: u32, depth: u8, levels: u32) -> Image {
+        let mut state = 0x2545f491u32;
+        let n = (w * h * channels) as usize;
+        let values: Vec<u32> = (0..n).map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            if i % 3 == 0 { state % levels } else { (i as u32 / channels) % levels }
+        }).collect();
+        let buf = if depth == 16 {
+            values.iter().flat_map(|v| (*v as u16).to_le_bytes()).collect()
+        } else {
+            values.iter().map(|v| *v as u8).collect()
+        };
+        Image { w, h, channels, buf, depth }
+    }
+
+    fn assert_round_trip(encoder: &PngEncoder, image: &Image) {
+        let bytes = encoder.encode(image).unwrap();
+        let decoded = png_decoder::decode(&bytes).unwrap().image;
+        assert_eq!((decoded.w, decoded.h, decoded.channels, decoded.depth), (image.w, image.h, image.channels, image.depth));
+        assert!(decoded.buf == image.buf);
+    }
+
+    #[test]
+    fn test_round_trip_all_formats() {
+        let strategies = [FilterStrategy::Fixed(Filter::Paeth), FilterStrategy::MinSum, FilterStrategy::BruteForce];
+        for (w, h) in [(1, 1), (3, 5), (17, 9), (33, 2)] {
+            for interlaced in [false, true] {
+                for strategy in strategies {
+                    for (color_type, channels) in [(ColorType::Gray, 1), (ColorType::GrayAlpha, 2), (ColorType::Rgb, 3), (ColorType::Rgba, 4)] {
+                        for depth in [1, 2, 4, 8, 16] {
+                            let encoder = PngEncoder::new(color_type, depth).interlaced(interlaced).filter_strategy(strategy);
+                            if depth < 8 && color_type != ColorType::Gray {
+                                assert!(encoder.encode(&test_image(w, h, channels, 8, 256)).is_err());
+                                continue;
+                            }
+                            let image = test_image(w, h, channels, std::cmp::max(depth, 8), 1 << depth);
+                            // low bit depth gray is scaled to 0..255
+                            let image = if depth < 8 {
+                                let scale = 255 / ((1 << depth) - 1);
+                                Image { buf: image.buf.iter().map(|v| v * scale).collect(), ..image }
+                            } else {
+                                image
+                            };
+                            assert_round_trip(&encoder, &image);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // incompressible rows go to stored blocks, the repeats after them are copied from those
+    #[test]
+    fn test_round_trip_stored_block_matches() {
+        let (w, h) = (128, 200);
+        let mut state = 0x9e3779b9u32;
+        let mut buf: Vec<u8> = (0..w * 130).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+        for y in 130..h as usize {
+            let src = (y * 7 % 130) * w as usize;
+            buf.extend_from_within(src..src + w as usize);
+        }
+        let image = Image { w, h, channels: 1, buf, depth: 8 };
+        let encoder = PngEncoder::new(ColorType::Gray, 8).filter_strategy(FilterStrategy::Fixed(Filter::None));
+        assert_round_trip(&encoder, &image);
+    }
+
+    #[test]
+    fn test_round_trip_palette_and_transparency() {
+        let palette: Vec<[u8; 4]> = (0..16).map(|i| [i * 16, 255 - i * 16, i, if i % 5 == 0 { 128 } else { 255 }]).collect();
+        for interlaced in [false, true] {
+            for depth in [4, 8] {
+                let indices = test_image(11, 7, 1, 8, 16);
+                let rgba = Image {
+                    buf: indices.buf.iter().flat_map(|idx| palette[*idx as usize]).collect(),
+                    channels: 4,
+                    ..test_image(11, 7, 1, 8, 16)
+                };
+                let encoder = PngEncoder::new(ColorType::Indexed, depth).palette(&palette).interlaced(interlaced);
+                assert_round_trip(&encoder, &rgba);
+                let decoded = png_decoder::decode(&encoder.encode(&indices).unwrap()).unwrap().image;
+                assert!(decoded.buf == rgba.buf);
+            }
+
+            for (color_type, channels, depth) in [(ColorType::Gray, 1, 2), (ColorType::Gray, 1, 16), (ColorType::Rgb, 3, 8), (ColorType::Rgb, 3, 16)] {
+                let max = (1u32 << depth) - 1;
+                let key = [1, max as u16, 0];
+                let opaque = test_image(9, 6, channels, std::cmp::max(depth, 8), 1 << depth);
+                let (sample_size, scale) = if depth == 16 { (2, 1) } else { (1, 255 / max) };
+                let mut buf = vec![];
+                for pix in opaque.buf.chunks_exact(sample_size * channels as usize) {
+                    let color: Vec<u32> = pix.chunks_exact(sample_size)
+                        .map(|s| if depth == 16 { u16::from_le_bytes([s[0], s[1]]) as u32 } else { s[0] as u32 })
+                        .collect();
+                    let is_key = color.iter().zip(key).all(|(val, key)| *val == key as u32);
+                    let alpha = if is_key { 0 } else { u32::MAX };
+                    for v in color.iter().map(|val| val * scale).chain([alpha]) {
+                        buf.extend_from_slice(&v.to_le_bytes()[..sample_size]);
+                    }
+                }
+                let image = Image { buf, channels: channels + 1, ..opaque };
+                let encoder = PngEncoder::new(color_type, depth).transparent_color(key).interlaced(interlaced);
+                assert_round_trip(&encoder, &image);
+            }
+        }
+    }
+}
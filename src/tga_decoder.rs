@@ -1,12 +1,23 @@
 
+use crate::BufferLayout;
+use crate::DecodeOptions;
 use crate::DecodingError;
+use crate::ErrorKind;
+use crate::Destination;
 use crate::Image;
+use crate::ImageInfo;
+use crate::RowSink;
+use crate::pixel_format::PixelFormat;
+use crate::pixel_format::convert_row;
 
 pub struct TGAImage {
     pub image: Image,
     _image_type: u8,
     color_map_len: u16,
     color_map_entry_size: u8,
+    pub top_down: bool, // rows are stored from the top, bottom-up otherwise
+    pub warnings: Vec<DecodingError>, // errors the lenient mode got past
+    pub rows_valid: u32, // rows that are decoded, in the order they are stored
 }
 
 struct TGADatastream<'a> {
@@ -28,7 +39,7 @@ impl<'a> TGADatastream<'a> {
             self.cursor += 1;
             Ok(val)
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::Truncated.into())
         }
     }
 
@@ -40,7 +51,7 @@ impl<'a> TGADatastream<'a> {
             self.cursor += 2;
             Ok(val)
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::Truncated.into())
         }
     }
 
@@ -52,7 +63,7 @@ impl<'a> TGADatastream<'a> {
             self.cursor += 4;
             Ok(val)
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::Truncated.into())
         }
     }
 
@@ -61,7 +72,7 @@ impl<'a> TGADatastream<'a> {
             self.cursor += count;
             Ok(())
         } else {
-            Err(DecodingError::MalformedImage)
+            Err(ErrorKind::Truncated.into())
         }
     }
 }
@@ -72,31 +83,23 @@ pub fn is_tga(_buf: &[u8]) -> bool {
 
 fn decode_header(stream: &mut TGADatastream) -> Result<TGAImage, DecodingError> {
     let id_len = stream.read_u8()?;
-    if id_len != 0 {
-        println!("Non-empty identification field");
-    }
 
     let color_map_type = stream.read_u8()?;
     if color_map_type != 0 && color_map_type != 1 {
-        println!("color map type: {color_map_type}");
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidHeader.into());
     }
     if color_map_type != 0 {
-        println!("color map type 1, Not implemented");
-        return Err(DecodingError::NotImplemented);
+        return Err(ErrorKind::NotImplemented.into()); // color mapped images
     }
 
     let image_type = stream.read_u8()?;
-    if image_type != 2 && image_type != 3 {
-        println!("image type: {image_type}");
-    }
 
     // color map specification
     let _first_entry_idx = stream.read_u16()?;
     let color_map_len = stream.read_u16()?;
     let color_map_entry_size = stream.read_u8()?;
     if !([0, 16, 24, 32] as [u8; 4]).contains(&color_map_entry_size) {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidHeader.into());
     }
 
     // image specification
@@ -106,16 +109,18 @@ fn decode_header(stream: &mut TGADatastream) -> Result<TGAImage, DecodingError>
     let h = stream.read_u16()?;
     let depth = stream.read_u8()?;
     let image_descriptor = stream.read_u8()?;
-    if image_descriptor != 0 {
-        println!("image descriptor: {image_descriptor}");
+    if image_descriptor & 0x10 != 0 {
+        return Err(ErrorKind::NotImplemented.into()); // pixels from right to left
     }
-    // println!("{w} x {h}, depth: {depth}");
     if !([8, 16, 24, 32] as [u8; 4]).contains(&depth) {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidHeader.into());
+    }
+    if depth == 16 {
+        return Err(ErrorKind::NotImplemented.into()); // 16-bit pixels, they would need 4 channels in the image
     }
 
     if image_type != 2 && image_type != 3 {
-        return Err(DecodingError::NotImplemented);
+        return Err(ErrorKind::NotImplemented.into());
     }
 
     stream.skip(id_len as usize)?;
@@ -131,6 +136,9 @@ fn decode_header(stream: &mut TGADatastream) -> Result<TGAImage, DecodingError>
         _image_type: image_type,
         color_map_len,
         color_map_entry_size,
+        top_down: image_descriptor & 0x20 != 0,
+        warnings: vec![],
+        rows_valid: 0,
     })
 }
 
@@ -139,79 +147,95 @@ fn decode_color_map(stream: &mut TGADatastream, tga_image: &TGAImage) -> Result<
     Ok(())
 }
 
-fn decode_1_channel(input: &[u8], output: &mut Image) {
-    let scanline_size = output.w as usize * output.channels as usize;
-    for i in 0..output.h as usize {
-        let out_base = scanline_size as usize * (output.h as usize - i - 1) as usize;
-        let in_base = i * scanline_size;
-        output.buf[out_base..out_base + output.w as usize].copy_from_slice(&input[in_base..in_base + output.w as usize]);
-    }
+// Row writers, `input` is one row of the file and `output` the same row in the layout of the image
+
+fn decode_1_channel(input: &[u8], output: &mut [u8]) {
+    output.copy_from_slice(&input[..output.len()]);
 }
 
-fn decode_2_channels(input: &[u8], output: &mut Image) {
-    assert_eq!(output.channels, 4);
-    let scanline_size = output.w as usize * 2;
-    for i in 0..output.h as usize {
-        let out_base = scanline_size as usize * (output.h as usize - i - 1) as usize;
-        let in_base = i * scanline_size;
-        for j in 0..output.w as usize {
-            let out_pix_base = out_base + j * output.channels as usize;
-            let in_pix_base = in_base + j * 2;
-            let lo = input[in_pix_base + 0];
-            let hi = input[in_pix_base + 1];
-            output.buf[out_pix_base + 0] = (hi >> 2) & 0x1f; // r
-            output.buf[out_pix_base + 1] = ((hi & 3) << 3) | (lo >> 5); // g
-            output.buf[out_pix_base + 2] = lo & 0x1f; // b
-            output.buf[out_pix_base + 3] = hi >> 7; // a
-        }
+fn decode_3_channels(input: &[u8], output: &mut [u8]) {
+    for (in_pix, out_pix) in input.chunks_exact(3).zip(output.chunks_exact_mut(3)) {
+        out_pix[0] = in_pix[2]; // r
+        out_pix[1] = in_pix[1]; // g
+        out_pix[2] = in_pix[0]; // b
     }
 }
 
-fn decode_3_channels(input: &[u8], output: &mut Image) {
-    let scanline_size = output.w as usize * output.channels as usize;
-    for i in 0..output.h as usize {
-        let out_base = scanline_size as usize * (output.h as usize - i - 1) as usize;
-        let in_base = i * scanline_size;
-        for j in 0..output.w as usize {
-            let out_pix_base = out_base + j * output.channels as usize;
-            let in_pix_base = in_base + j * output.channels as usize;
-            output.buf[out_pix_base + 0] = input[in_pix_base + 2]; // r
-            output.buf[out_pix_base + 1] = input[in_pix_base + 1]; // g
-            output.buf[out_pix_base + 2] = input[in_pix_base + 0]; // b
-        }
+fn decode_4_channels(input: &[u8], output: &mut [u8]) {
+    for (in_pix, out_pix) in input.chunks_exact(4).zip(output.chunks_exact_mut(4)) {
+        out_pix[0] = in_pix[2]; // r
+        out_pix[1] = in_pix[1]; // g
+        out_pix[2] = in_pix[0]; // b
+        out_pix[3] = in_pix[3]; // a
     }
 }
 
-fn decode_4_channels(input: &[u8], output: &mut Image) {
-    let scanline_size = output.w as usize * output.channels as usize;
-    for i in 0..output.h as usize {
-        let out_base = scanline_size as usize * (output.h as usize - i - 1) as usize;
-        let in_base = i * scanline_size;
-        for j in 0..output.w as usize {
-            let out_pix_base = out_base + j * output.channels as usize;
-            let in_pix_base = in_base + j * output.channels as usize;
-            output.buf[out_pix_base + 0] = input[in_pix_base + 2]; // r
-            output.buf[out_pix_base + 1] = input[in_pix_base + 1]; // g
-            output.buf[out_pix_base + 2] = input[in_pix_base + 0]; // b
-            output.buf[out_pix_base + 3] = input[in_pix_base + 3]; // a
+// Writes the image to `destination`, the buffer of the image is only allocated if the image goes there.
+// In the lenient mode truncated data is decoded up to the last complete row.
+fn decode_image_data(stream: &mut TGADatastream, tga_image: &mut TGAImage, options: &DecodeOptions, destination: Destination) -> Result<(), DecodingError> {
+    let output_format = options.output_format;
+    let layout = output_layout(tga_image, output_format);
+    let image = &mut tga_image.image;
+    let in_row_size = image.w as usize * image.channels as usize;
+    let mut rows = image.h as usize;
+    if stream.cursor + in_row_size * rows > stream.buf.len() {
+        if !options.lenient {
+            return Err(ErrorKind::Truncated.into());
         }
+        tga_image.warnings.push(DecodingError::from(ErrorKind::Truncated).at(None, stream.buf.len()));
+        rows = (stream.buf.len() - stream.cursor) / in_row_size.max(1);
     }
-}
+    let len = in_row_size * rows;
 
-fn decode_image_data(stream: &mut TGADatastream, tga_image: &mut TGAImage) -> Result<(), DecodingError> {
-    let len = tga_image.image.w as usize * tga_image.image.h as usize * tga_image.image.channels as usize;
-    if stream.cursor + len > stream.buf.len() {
-        return Err(DecodingError::MalformedImage);
+    let decode_row: fn(&[u8], &mut [u8]) = match image.channels {
+        1 => decode_1_channel,
+        3 => decode_3_channels,
+        _ => decode_4_channels,
+    };
+    let native_row_size = image.w as usize * image.channels as usize;
+    let out_row_size = layout.row_bytes();
+    let mut native_row = if output_format.is_some() { vec![0; native_row_size] } else { vec![] };
+    let mut sink: Option<&mut dyn RowSink> = None;
+    let mut sink_row = vec![];
+    let (out, stride) = match destination {
+        Destination::Buffer(dst, stride) => {
+            layout.check_buffer(dst, stride)?;
+            image.buf = vec![];
+            (dst, stride)
+        },
+        Destination::Rows(rows_sink) => {
+            // every row is written to the same buffer and handed over right away
+            image.buf = vec![];
+            sink = Some(rows_sink);
+            sink_row = vec![0; out_row_size];
+            (&mut sink_row[..], 0)
+        },
+        Destination::Image => {
+            image.buf = vec![0; out_row_size * image.h as usize];
+            (&mut image.buf[..], out_row_size)
+        },
+    };
+    for i in 0..rows {
+        let input = &stream.buf[stream.cursor + i * in_row_size..stream.cursor + (i + 1) * in_row_size];
+        let y = if tga_image.top_down { i } else { image.h as usize - i - 1 };
+        let output = &mut out[stride * y..stride * y + out_row_size];
+        match output_format {
+            Some(format) => {
+                decode_row(input, &mut native_row);
+                convert_row(&native_row, image.channels, 8, false, format, output, 1);
+            },
+            None => decode_row(input, output),
+        }
+        if let Some(sink) = sink.as_deref_mut() {
+            sink.row(y as u32, output);
+        }
     }
-
-    tga_image.image.buf = vec![0; len];
-    match tga_image.image.channels {
-        1 => decode_1_channel(&stream.buf[stream.cursor..stream.cursor + len], &mut tga_image.image),
-        2 => decode_2_channels(&stream.buf[stream.cursor..stream.cursor + len], &mut tga_image.image),
-        3 => decode_3_channels(&stream.buf[stream.cursor..stream.cursor + len], &mut tga_image.image),
-        _ => decode_4_channels(&stream.buf[stream.cursor..stream.cursor + len], &mut tga_image.image),
+    if let Some(format) = output_format {
+        image.channels = format.channels();
+        image.depth = format.depth();
     }
 
+    tga_image.rows_valid = rows as u32;
     stream.cursor += len;
     Ok(())
 }
@@ -219,27 +243,16 @@ fn decode_image_data(stream: &mut TGADatastream, tga_image: &mut TGAImage) -> Re
 fn decode_footer(stream: &mut TGADatastream, _tga_image: &mut TGAImage) -> Result<(), DecodingError> {
     const FOOTER_SIZE: usize = 26;
     if stream.cursor + FOOTER_SIZE > stream.buf.len() {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::Truncated.into());
     }
-    let gap = stream.buf.len() - FOOTER_SIZE - stream.cursor;
-    if gap != 0 {
-        println!("Gap between image data and footer: {gap} bytes");
-    }
-
-    let _prev_cursor = stream.cursor;
+    // there may be a gap between the image data and the footer
     stream.cursor = stream.buf.len() - FOOTER_SIZE;
 
-    let extension_area_offset = stream.read_u32()?;
-    let developer_directory_offset = stream.read_u32()?;
-    if extension_area_offset != 0 {
-        println!("has extension area");
-    }
-    if developer_directory_offset != 0 {
-        println!("has developer directory");
-    }
+    let _extension_area_offset = stream.read_u32()?;
+    let _developer_directory_offset = stream.read_u32()?;
     const SIGNATURE: &[u8] = b"\x54\x52\x55\x45\x56\x49\x53\x49\x4F\x4E\x2D\x58\x46\x49\x4C\x45\x2E\x00";
     if &stream.buf[stream.cursor..stream.cursor + SIGNATURE.len()] != SIGNATURE {
-        return Err(DecodingError::MalformedImage);
+        return Err(ErrorKind::InvalidSignature.into());
     }
 
     // TODO
@@ -248,12 +261,92 @@ fn decode_footer(stream: &mut TGADatastream, _tga_image: &mut TGAImage) -> Resul
 }
 
 pub fn decode(buf: &[u8]) -> Result<TGAImage, DecodingError> {
+    decode_with_options(buf, &DecodeOptions::default())
+}
+
+pub fn decode_with_options(buf: &[u8], options: &DecodeOptions) -> Result<TGAImage, DecodingError> {
+    decode_impl(buf, options, Destination::Image)
+}
+
+/// Decodes into `dst` with rows `stride` bytes apart, in the layout reported by `buffer_layout`.
+/// Bytes between the rows are left untouched. `image.buf` of the result is empty.
+pub fn decode_into(buf: &[u8], dst: &mut [u8], stride: usize) -> Result<TGAImage, DecodingError> {
+    decode_into_with_options(buf, dst, stride, &DecodeOptions::default())
+}
+
+pub fn decode_into_with_options(buf: &[u8], dst: &mut [u8], stride: usize, options: &DecodeOptions) -> Result<TGAImage, DecodingError> {
+    decode_impl(buf, options, Destination::Buffer(dst, stride))
+}
+
+/// Decodes row by row, handing each row to `sink` in the layout reported by `buffer_layout`.
+/// Rows go bottom-up, as they are stored. `image.buf` of the result is empty.
+pub fn decode_rows(buf: &[u8], sink: &mut dyn RowSink) -> Result<TGAImage, DecodingError> {
+    decode_rows_with_options(buf, sink, &DecodeOptions::default())
+}
+
+pub fn decode_rows_with_options(buf: &[u8], sink: &mut dyn RowSink, options: &DecodeOptions) -> Result<TGAImage, DecodingError> {
+    decode_impl(buf, options, Destination::Rows(sink))
+}
+
+/// Layout of the image that `decode_with_options` or `decode_into_with_options` would produce, only reads the header
+pub fn buffer_layout(buf: &[u8], options: &DecodeOptions) -> Result<BufferLayout, DecodingError> {
+    let mut stream = TGADatastream::new(buf);
+    let tga_image = decode_header(&mut stream).map_err(|err| err.at(None, stream.cursor))?;
+    Ok(output_layout(&tga_image, options.output_format))
+}
+
+/// Dimensions and color type from the header, see `Image::probe`. The footer is checked if there is one.
+pub fn probe(buf: &[u8]) -> Result<ImageInfo, DecodingError> {
     let mut stream = TGADatastream::new(buf);
-    let mut tga_image = decode_header(&mut stream)?;
-    decode_color_map(&mut stream, &tga_image)?;
-    decode_image_data(&mut stream, &mut tga_image)?;
-    if stream.cursor != stream.buf.len() {
-        decode_footer(&mut stream, &mut tga_image)?;
+    probe_impl(&mut stream).map_err(|err| err.at(None, stream.cursor))
+}
+
+fn probe_impl(stream: &mut TGADatastream) -> Result<ImageInfo, DecodingError> {
+    let buf = stream.buf;
+    let mut tga_image = decode_header(stream)?;
+    decode_color_map(stream, &tga_image)?;
+    let image = &tga_image.image;
+    let data_end = stream.cursor + image.w as usize * image.h as usize * image.channels as usize;
+    if data_end < buf.len() {
+        stream.cursor = data_end;
+        decode_footer(stream, &mut tga_image)?;
+    }
+    let image = &tga_image.image;
+    Ok(ImageInfo {
+        w: image.w,
+        h: image.h,
+        channels: image.channels,
+        depth: image.depth,
+        interlaced: false,
+        palette_size: None, // color mapped images aren't supported
+        has_transparency: image.channels == 2 || image.channels == 4,
+    })
+}
+
+fn output_layout(tga_image: &TGAImage, output_format: Option<PixelFormat>) -> BufferLayout {
+    let image = &tga_image.image;
+    let (channels, depth) = output_format.map_or((image.channels, image.depth), |format| (format.channels(), format.depth()));
+    BufferLayout { w: image.w, h: image.h, channels, depth }
+}
+
+fn decode_impl(buf: &[u8], options: &DecodeOptions, destination: Destination) -> Result<TGAImage, DecodingError> {
+    let mut stream = TGADatastream::new(buf);
+    decode_datastream(&mut stream, options, destination).map_err(|err| err.at(None, stream.cursor))
+}
+
+fn decode_datastream(stream: &mut TGADatastream, options: &DecodeOptions, destination: Destination) -> Result<TGAImage, DecodingError> {
+    let mut tga_image = decode_header(stream)?;
+    let layout = output_layout(&tga_image, options.output_format);
+    options.limits.check_size(layout.w, layout.h)?;
+    options.limits.check_alloc(layout.h as u64 * layout.row_bytes() as u64)?;
+    decode_color_map(stream, &tga_image)?;
+    decode_image_data(stream, &mut tga_image, options, destination)?;
+    // truncated data has no footer
+    if tga_image.warnings.is_empty() && stream.cursor != stream.buf.len() {
+        match decode_footer(stream, &mut tga_image).map_err(|err| err.at(None, stream.cursor)) {
+            Err(err) if options.lenient => tga_image.warnings.push(err),
+            result => result?,
+        }
     }
     Ok(tga_image)
 }
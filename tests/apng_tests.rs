@@ -0,0 +1,219 @@
+mod common;
+
+use std::io::Cursor;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::apng_decoder;
+use image_codecs::deflate;
+use image_codecs::pixel_format::PixelFormat;
+
+fn encode_test_animation() -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut encoder = png::Encoder::new(Cursor::new(&mut bytes), 4, 4);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(3, 0).unwrap();
+    let mut writer = encoder.write_header().unwrap();
+
+    // frame 0: opaque red canvas
+    writer.set_frame_delay(1, 10).unwrap();
+    writer.write_image_data(&[255, 0, 0, 255].repeat(16)).unwrap();
+
+    // frame 1: half transparent blue square blended over the red, cleared afterwards
+    writer.set_frame_dimension(2, 2).unwrap();
+    writer.set_frame_position(1, 1).unwrap();
+    writer.set_blend_op(png::BlendOp::Over).unwrap();
+    writer.set_dispose_op(png::DisposeOp::Background).unwrap();
+    writer.set_frame_delay(2, 10).unwrap();
+    writer.write_image_data(&[0, 0, 255, 128].repeat(4)).unwrap();
+
+    // frame 2: green pixel in the corner
+    writer.set_frame_dimension(1, 1).unwrap();
+    writer.set_frame_position(0, 0).unwrap();
+    writer.set_blend_op(png::BlendOp::Source).unwrap();
+    writer.set_dispose_op(png::DisposeOp::None).unwrap();
+    writer.set_frame_delay(3, 0).unwrap();
+    writer.write_image_data(&[0, 255, 0, 255]).unwrap();
+
+    writer.finish().unwrap();
+    bytes
+}
+
+fn pixel(buf: &[u8], x: usize, y: usize) -> &[u8] {
+    &buf[(y * 4 + x) * 4..(y * 4 + x + 1) * 4]
+}
+
+#[test]
+fn test_apng_compositing() {
+    let bytes = encode_test_animation();
+    let decoder = apng_decoder::decode(&bytes).unwrap();
+    assert_eq!(decoder.num_frames(), 3);
+    assert_eq!(decoder.num_plays(), 0);
+    let frames: Vec<_> = decoder.map(|frame| frame.unwrap()).collect();
+    assert_eq!(frames.len(), 3);
+
+    for frame in &frames {
+        assert_eq!((frame.image.w, frame.image.h, frame.image.channels, frame.image.depth), (4, 4, 4, 8));
+    }
+    assert_eq!(frames[0].image.buf, [255, 0, 0, 255].repeat(16));
+
+    assert_eq!(pixel(&frames[1].image.buf, 0, 0), [255, 0, 0, 255]);
+    assert_eq!(pixel(&frames[1].image.buf, 1, 1), [127, 0, 128, 255]);
+    assert_eq!(pixel(&frames[1].image.buf, 2, 2), [127, 0, 128, 255]);
+    assert_eq!(pixel(&frames[1].image.buf, 3, 3), [255, 0, 0, 255]);
+
+    assert_eq!(pixel(&frames[2].image.buf, 0, 0), [0, 255, 0, 255]);
+    assert_eq!(pixel(&frames[2].image.buf, 1, 0), [255, 0, 0, 255]);
+    assert_eq!(pixel(&frames[2].image.buf, 1, 1), [0, 0, 0, 0]);
+    assert_eq!(pixel(&frames[2].image.buf, 2, 2), [0, 0, 0, 0]);
+    assert_eq!(pixel(&frames[2].image.buf, 3, 3), [255, 0, 0, 255]);
+
+    assert_eq!(frames[0].delay(), 0.1);
+    assert_eq!(frames[1].delay(), 0.2);
+    assert_eq!(frames[2].delay(), 0.03); // zero denominator means 1/100 s
+}
+
+#[test]
+fn test_frame_with_missing_rows() {
+    // frame 1 gets one of its two rows
+    let mut bytes = encode_test_animation();
+    let fdat_start = bytes.windows(4).position(|window| window == b"fdAT").unwrap() - 4;
+    let len = u32::from_be_bytes(bytes[fdat_start..fdat_start + 4].try_into().unwrap()) as usize;
+    let data = [&bytes[fdat_start + 8..fdat_start + 12], &deflate::zlib_compress(&[0; 9], 6)].concat();
+    bytes.splice(fdat_start..fdat_start + 12 + len, common::encode_chunk(b"fdAT", &data));
+
+    let mut frames = apng_decoder::decode(&bytes).unwrap();
+    assert!(frames.next().unwrap().is_ok());
+    assert_eq!(frames.next().unwrap().err().map(|err| err.kind), Some(ErrorKind::Truncated));
+}
+
+#[test]
+fn test_checksums() {
+    for name in [b"IHDR", b"acTL", b"fcTL", b"IDAT", b"fdAT", b"IEND"] {
+        let mut bytes = encode_test_animation();
+        let start = bytes.windows(4).position(|window| window == name).unwrap() - 4;
+        let len = u32::from_be_bytes(bytes[start..start + 4].try_into().unwrap()) as usize;
+        bytes[start + 8 + len] ^= 1;
+        assert_eq!(apng_decoder::decode(&bytes).err().map(|err| (err.kind, err.chunk)), Some((ErrorKind::CrcMismatch, Some(*name))));
+        let frames: Vec<_> = apng_decoder::decode_with_options(&bytes, &DecodeOptions::new().verify_checksums(false)).unwrap().map(|frame| frame.unwrap()).collect();
+        assert_eq!(frames.len(), 3);
+    }
+
+    // the Adler-32 of a frame
+    let mut bytes = encode_test_animation();
+    let fdat_start = bytes.windows(4).position(|window| window == b"fdAT").unwrap() - 4;
+    let len = u32::from_be_bytes(bytes[fdat_start..fdat_start + 4].try_into().unwrap()) as usize;
+    let mut data = bytes[fdat_start + 8..fdat_start + 8 + len].to_vec();
+    *data.last_mut().unwrap() ^= 1;
+    bytes.splice(fdat_start..fdat_start + 12 + len, common::encode_chunk(b"fdAT", &data));
+    let mut frames = apng_decoder::decode(&bytes).unwrap();
+    assert!(frames.next().unwrap().is_ok());
+    assert_eq!(frames.next().unwrap().err().map(|err| err.kind), Some(ErrorKind::AdlerMismatch));
+    let frames: Vec<_> = apng_decoder::decode_with_options(&bytes, &DecodeOptions::new().verify_checksums(false)).unwrap().map(|frame| frame.unwrap()).collect();
+    assert_eq!(frames.len(), 3);
+}
+
+#[test]
+fn test_static_png_is_single_frame() {
+    let mut bytes = vec![];
+    let mut encoder = png::Encoder::new(Cursor::new(&mut bytes), 3, 2);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&[0, 50, 100, 150, 200, 250]).unwrap();
+    writer.finish().unwrap();
+
+    let frames: Vec<_> = apng_decoder::decode(&bytes).unwrap().map(|frame| frame.unwrap()).collect();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].image.buf, [0, 0, 0, 255, 50, 50, 50, 255, 100, 100, 100, 255, 150, 150, 150, 255, 200, 200, 200, 255, 250, 250, 250, 255]);
+}
+
+#[test]
+fn test_fctl_without_frame_data() {
+    // a second fcTL follows the one of the default image before IDAT, the chunks after it are renumbered
+    let animation = encode_test_animation();
+    let mut bytes = animation[..8].to_vec();
+    let mut pos = 8;
+    let mut sequence_number = 0u32;
+    while pos < animation.len() {
+        let len = u32::from_be_bytes(animation[pos..pos + 4].try_into().unwrap()) as usize;
+        let name: [u8; 4] = animation[pos + 4..pos + 8].try_into().unwrap();
+        let mut data = animation[pos + 8..pos + 8 + len].to_vec();
+        if &name == b"fcTL" || &name == b"fdAT" {
+            data[..4].copy_from_slice(&sequence_number.to_be_bytes());
+            sequence_number += 1;
+        }
+        bytes.extend(common::encode_chunk(&name, &data));
+        if sequence_number == 1 {
+            data[..4].copy_from_slice(&sequence_number.to_be_bytes());
+            sequence_number += 1;
+            bytes.extend(common::encode_chunk(b"fcTL", &data));
+        }
+        pos += 12 + len;
+    }
+    assert_eq!(apng_decoder::decode(&bytes).err().map(|err| (err.kind, err.chunk)), Some((ErrorKind::ChunkOrder, Some(*b"fcTL"))));
+}
+
+#[test]
+fn test_broken_ancillary_chunk() {
+    let mut bytes = encode_test_animation();
+    common::insert_chunk_before_idat(&mut bytes, b"sRGB", &[0, 0]);
+    let decoder = apng_decoder::decode(&bytes).unwrap();
+    assert_eq!(decoder.warnings().iter().map(|warning| (warning.kind, warning.chunk)).collect::<Vec<_>>(), [(ErrorKind::InvalidChunkLength, Some(*b"sRGB"))]);
+    assert_eq!(decoder.map(|frame| frame.unwrap()).count(), 3);
+
+    assert!(apng_decoder::decode(&encode_test_animation()).unwrap().warnings().is_empty());
+}
+
+#[test]
+fn test_output_format() {
+    let bytes = encode_test_animation();
+    let expected: Vec<_> = apng_decoder::decode(&bytes).unwrap().map(|frame| frame.unwrap().image.buf).collect();
+
+    let options = DecodeOptions::new().output_format(PixelFormat::Rgba8);
+    let frames: Vec<_> = apng_decoder::decode_with_options(&bytes, &options).unwrap().map(|frame| frame.unwrap().image).collect();
+    assert_eq!(frames.iter().map(|image| image.buf.clone()).collect::<Vec<_>>(), expected);
+
+    let options = DecodeOptions::new().output_format(PixelFormat::Bgra8);
+    let frames: Vec<_> = apng_decoder::decode_with_options(&bytes, &options).unwrap().map(|frame| frame.unwrap().image).collect();
+    for (image, expected) in frames.iter().zip(&expected) {
+        assert_eq!((image.channels, image.depth), (4, 8));
+        let bgra: Vec<u8> = expected.chunks(4).flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]]).collect();
+        assert_eq!(image.buf, bgra);
+    }
+
+    let options = DecodeOptions::new().output_format(PixelFormat::Rgb16);
+    let frame = apng_decoder::decode_with_options(&bytes, &options).unwrap().next().unwrap().unwrap();
+    assert_eq!((frame.image.channels, frame.image.depth, &frame.image.buf[..6]), (3, 16, &[255, 255, 0, 0, 0, 0][..]));
+}
+
+#[test]
+fn test_lenient() {
+    let options = DecodeOptions::new().lenient(true);
+
+    // frame 1 gets one of its two rows, the other one is transparent and blended over the red canvas
+    let mut bytes = encode_test_animation();
+    let fdat_start = bytes.windows(4).position(|window| window == b"fdAT").unwrap() - 4;
+    let len = u32::from_be_bytes(bytes[fdat_start..fdat_start + 4].try_into().unwrap()) as usize;
+    let data = [&bytes[fdat_start + 8..fdat_start + 12], &deflate::zlib_compress(&[0, 0, 0, 255, 128, 0, 0, 255, 128], 6)].concat();
+    bytes.splice(fdat_start..fdat_start + 12 + len, common::encode_chunk(b"fdAT", &data));
+    let mut decoder = apng_decoder::decode_with_options(&bytes, &options).unwrap();
+    let frames: Vec<_> = decoder.by_ref().map(|frame| frame.unwrap()).collect();
+    assert_eq!(frames.len(), 3);
+    assert_eq!(pixel(&frames[1].image.buf, 1, 1), [127, 0, 128, 255]);
+    assert_eq!(pixel(&frames[1].image.buf, 1, 2), [255, 0, 0, 255]);
+    assert_eq!(decoder.warnings().iter().map(|warning| warning.kind).collect::<Vec<_>>(), [ErrorKind::Truncated]);
+
+    // the file ends before the last frame
+    let bytes = encode_test_animation();
+    let fctl_start = bytes.windows(4).rposition(|window| window == b"fcTL").unwrap() - 4;
+    let cut = &bytes[..fctl_start];
+    assert!(apng_decoder::decode(cut).is_err());
+    let decoder = apng_decoder::decode_with_options(cut, &options).unwrap();
+    assert_eq!((decoder.num_frames(), decoder.warnings().len()), (2, 1));
+    assert_eq!(decoder.map(|frame| frame.unwrap()).count(), 2);
+
+    // errors before the first frame data still fail
+    assert!(apng_decoder::decode_with_options(&bytes[..40], &options).is_err());
+}
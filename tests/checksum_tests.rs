@@ -0,0 +1,54 @@
+mod common;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::png_decoder;
+
+// Position of the first chunk with the given name
+fn find_chunk(png: &[u8], name: &[u8; 4]) -> (usize, usize) {
+    let mut pos = 8;
+    loop {
+        let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+        if &png[pos + 4..pos + 8] == name {
+            return (pos, len);
+        }
+        pos += len + 12;
+    }
+}
+
+fn assert_checksum_mismatch(png: &[u8], kind: ErrorKind) {
+    assert_eq!(png_decoder::decode(png).err().map(|err| err.kind), Some(kind));
+    assert_eq!(common::decode_streaming(png, DecodeOptions::new()).err().map(|err| err.kind), Some(kind));
+
+    let options = DecodeOptions::new().verify_checksums(false);
+    assert!(png_decoder::decode_with_options(png, &options).is_ok());
+    assert!(common::decode_streaming(png, options).is_ok());
+}
+
+#[test]
+fn test_valid_checksums() {
+    let png = common::rgb_png(32, 16);
+    assert!(png_decoder::decode(&png).is_ok());
+    assert!(common::decode_streaming(&png, DecodeOptions::new()).is_ok());
+}
+
+#[test]
+fn test_chunk_crc_mismatch() {
+    let png = common::rgb_png(32, 16);
+    for name in [b"IHDR", b"IDAT", b"IEND"] {
+        let mut bad = png.clone();
+        let (pos, len) = find_chunk(&png, name);
+        bad[pos + 8 + len] ^= 0x10;
+        assert_checksum_mismatch(&bad, ErrorKind::CrcMismatch);
+    }
+}
+
+#[test]
+fn test_adler32_mismatch() {
+    let mut png = common::rgb_png(32, 16);
+    let (pos, len) = find_chunk(&png, b"IDAT");
+    png[pos + 8 + len - 1] ^= 0x01; // last byte of the zlib stream
+    let chunk = common::encode_chunk(b"IDAT", &png[pos + 8..pos + 8 + len]);
+    png.splice(pos..pos + 12 + len, chunk);
+    assert_checksum_mismatch(&png, ErrorKind::AdlerMismatch);
+}
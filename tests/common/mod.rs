@@ -15,8 +15,13 @@ use std::io::Cursor;
 use std::env;
 use std::fs;
 
+use image_codecs::DecodeOptions;
 use image_codecs::DecodingError;
+use image_codecs::ErrorKind;
 use image_codecs::Image;
+use image_codecs::png_decoder::PNGImage;
+use image_codecs::png_decoder::PngStreamDecoder;
+use image_codecs::png_encoder;
 
 pub fn read_all_images(root: &str, extension: &str) -> Vec<(PathBuf, Vec<u8>)> {
     WalkDir::new(root)
@@ -33,6 +38,54 @@ pub fn read_all_images(root: &str, extension: &str) -> Vec<(PathBuf, Vec<u8>)> {
     .collect()
 }
 
+// Chunk with a valid CRC
+#[allow(dead_code)]
+pub fn encode_chunk(name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(name);
+    chunk.extend_from_slice(data);
+    let mut crc = 0xffffffffu32;
+    for byte in &chunk[4..] {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    chunk.extend_from_slice(&(crc ^ 0xffffffff).to_be_bytes());
+    chunk
+}
+
+// Inserts a chunk right after IHDR
+#[allow(dead_code)]
+pub fn insert_chunk(png: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    let ihdr_end = 8 + 8 + 13 + 4;
+    png.splice(ihdr_end..ihdr_end, encode_chunk(name, data));
+}
+
+// For chunks that have to follow PLTE
+#[allow(dead_code)]
+pub fn insert_chunk_before_idat(png: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    let idat_start = png.windows(4).position(|window| window == b"IDAT").unwrap() - 4;
+    png.splice(idat_start..idat_start, encode_chunk(name, data));
+}
+
+// w x h RGB image with varied pixel values
+#[allow(dead_code)]
+pub fn rgb_png(w: u32, h: u32) -> Vec<u8> {
+    let buf = (0..w * h * 3).map(|i| (i * 7 % 251) as u8).collect();
+    png_encoder::encode(&Image { w, h, channels: 3, buf, depth: 8 }).unwrap()
+}
+
+// Pushes the file to a stream decoder a few bytes at a time
+#[allow(dead_code)]
+pub fn decode_streaming(png: &[u8], options: DecodeOptions) -> Result<PNGImage, DecodingError> {
+    let mut decoder = PngStreamDecoder::with_options(options);
+    for piece in png.chunks(5) {
+        decoder.push(piece, |_, _| {})?;
+    }
+    decoder.finish()
+}
+
 #[allow(dead_code)]
 fn reinterpret(v: Vec<u16>) -> Vec<u8> {
     let len = v.len() * 2;
@@ -45,7 +98,7 @@ fn reinterpret(v: Vec<u16>) -> Vec<u8> {
 }
 
 #[allow(dead_code)]
-fn decode_with_image_rs(bytes: &[u8], image_type: &str) -> Result<Vec<u8>, image::error::ImageError> {
+pub fn decode_with_image_rs(bytes: &[u8], image_type: &str) -> Result<Vec<u8>, image::error::ImageError> {
     let mut image_reader = ImageReader::new(Cursor::new(bytes));
     let image_reader =
         if image_type == "tga" {
@@ -81,9 +134,14 @@ fn decode_with_image_rs(bytes: &[u8], image_type: &str) -> Result<Vec<u8>, image
 }
 
 #[allow(dead_code)]
-fn decode_with_png_rs(bytes: &[u8]) -> Result<Vec<u8>, png::DecodingError> {
+pub fn decode_with_png_rs(bytes: &[u8]) -> Result<Vec<u8>, png::DecodingError> {
+    decode_with_png_rs_checksums(bytes, true)
+}
+
+#[allow(dead_code)]
+pub fn decode_with_png_rs_checksums(bytes: &[u8], verify_checksums: bool) -> Result<Vec<u8>, png::DecodingError> {
     let mut options = png::DecodeOptions::default();
-    options.set_ignore_checksums(false);
+    options.set_ignore_checksums(!verify_checksums);
     options.set_ignore_iccp_chunk(false);
     options.set_ignore_text_chunk(false);
 
@@ -106,7 +164,7 @@ fn decode_with_png_rs(bytes: &[u8]) -> Result<Vec<u8>, png::DecodingError> {
 }
 
 #[allow(dead_code)]
-fn decode_with_zune_png(bytes: &[u8]) -> Result<Vec<u8>, zune_png::error::PngDecodeErrors> {
+pub fn decode_with_zune_png(bytes: &[u8]) -> Result<Vec<u8>, zune_png::error::PngDecodeErrors> {
     let cursor = ZCursor::new(bytes);
     let options = DecoderOptions::new_fast();
     let res = PngDecoder::new_with_options(cursor, options).decode()?;
@@ -121,7 +179,7 @@ fn decode_with_zune_png(bytes: &[u8]) -> Result<Vec<u8>, zune_png::error::PngDec
 }
 
 #[allow(dead_code)]
-fn decode_with_spng(bytes: &[u8]) -> Result<Vec<u8>, spng::Error> {
+pub fn decode_with_spng(bytes: &[u8]) -> Result<Vec<u8>, spng::Error> {
     // let (_out_info, data) = spng::decode(Cursor::new(bytes), spng::Format::Rgba8)?;
     // Ok(data)
 
@@ -175,7 +233,7 @@ fn decode_with_spng(bytes: &[u8]) -> Result<Vec<u8>, spng::Error> {
 
 #[allow(dead_code)]
 #[allow(deprecated)]
-fn decode_with_lodepng(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+pub fn decode_with_lodepng(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut state = lodepng::State::new();
     state.decoder.ignore_crc = true;
     state.read_text_chunks(false);
@@ -207,6 +265,7 @@ fn decode_with_lodepng(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Erro
     Ok(decoded_bytes)
 }
 
+#[allow(dead_code)]
 pub fn test_decoder(image_type: &str) {
     let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
     println!("Scanning {root} for {image_type}s");
@@ -234,7 +293,7 @@ pub fn test_decoder(image_type: &str) {
         let elapsed = start.elapsed();
 
         let image = match image {
-            Err(DecodingError::NotImplemented) => {
+            Err(err) if err.kind == ErrorKind::NotImplemented => {
                 println!("Decoding failed: not implemented. Skipping");
                 n_skipped += 1;
                 continue;
@@ -0,0 +1,45 @@
+mod common;
+
+use std::env;
+
+use image_codecs::CpuFeatures;
+use image_codecs::DecodeOptions;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::PngStreamDecoder;
+
+#[test]
+fn test_png_code_paths() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    // extensions the CPU lacks are skipped, so on such a CPU some of these are the scalar path again
+    let feature_sets = [
+        CpuFeatures { sse41: true, bmi2: false },
+        CpuFeatures { sse41: false, bmi2: true },
+        CpuFeatures { sse41: true, bmi2: true },
+    ];
+    for (path, bytes) in imgs {
+        for format in [None, Some(PixelFormat::Rgba8)] {
+            let options = DecodeOptions::new().cpu_features(CpuFeatures::default());
+            let options = if let Some(format) = format { options.output_format(format) } else { options };
+            let scalar = png_decoder::decode_with_options(&bytes, &options);
+            println!("decoding {} with each code path", path.strip_prefix(&root).unwrap().display());
+            for features in feature_sets {
+                let result = png_decoder::decode_with_options(&bytes, &options.clone().cpu_features(features));
+                match (&scalar, result) {
+                    (Ok(expected), Ok(png_image)) => {
+                        assert_eq!((png_image.image.w, png_image.image.h), (expected.image.w, expected.image.h));
+                        assert!(png_image.image.buf == expected.image.buf);
+                        let mut decoder = PngStreamDecoder::with_options(options.clone().cpu_features(features));
+                        decoder.push(&bytes, |_, _| {}).unwrap();
+                        assert!(decoder.finish().unwrap().image.buf == expected.image.buf);
+                    },
+                    (Err(expected), Err(err)) => assert_eq!(err, *expected),
+                    _ => panic!("{features:?} and the scalar path disagree about {}", path.display()),
+                }
+            }
+        }
+    }
+}
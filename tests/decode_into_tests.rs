@@ -0,0 +1,85 @@
+mod common;
+
+use std::env;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::CompositeOver;
+use image_codecs::tga_decoder;
+
+const PADDING: u8 = 0xa5;
+
+// Rows of `dst`, checking that the padding after each row is untouched
+fn unpadded(dst: &[u8], stride: usize, row_bytes: usize, h: usize) -> Vec<u8> {
+    let mut rows = vec![];
+    for (y, row) in dst.chunks(stride).enumerate() {
+        if y < h {
+            rows.extend_from_slice(&row[..row_bytes]);
+        }
+        assert!(row[row_bytes.min(row.len())..].iter().all(|byte| *byte == PADDING));
+    }
+    rows
+}
+
+#[test]
+fn test_png_decode_into() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    let option_sets = [
+        DecodeOptions::new(),
+        DecodeOptions::new().output_format(PixelFormat::Bgra8),
+        DecodeOptions::new().composite_over(CompositeOver::Color([0, 128, 255])),
+    ];
+    for (path, bytes) in imgs {
+        for options in &option_sets {
+            let Ok(expected) = png_decoder::decode_with_options(&bytes, options) else {
+                continue;
+            };
+            println!("decoding {} into a buffer", path.strip_prefix(&root).unwrap().display());
+            let layout = png_decoder::buffer_layout(&bytes, options).unwrap();
+            assert_eq!((layout.w, layout.h, layout.channels, layout.depth), (expected.image.w, expected.image.h, expected.image.channels, expected.image.depth));
+
+            let stride = layout.row_bytes().next_multiple_of(256);
+            let mut dst = vec![PADDING; layout.required_size(stride) + 100];
+            let png_image = png_decoder::decode_into_with_options(&bytes, &mut dst, stride, options).unwrap();
+            assert!(png_image.image.buf.is_empty());
+            assert!(unpadded(&dst, stride, layout.row_bytes(), layout.h as usize) == expected.image.buf);
+        }
+    }
+}
+
+#[test]
+fn test_buffer_too_small() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let (_, bytes) = common::read_all_images(&root, "png").into_iter().find(|(path, _)| path.ends_with("basn2c08.png")).unwrap();
+    let layout = png_decoder::buffer_layout(&bytes, &DecodeOptions::new()).unwrap();
+    assert_eq!(layout.required_size(layout.row_bytes()), 32 * 32 * 3);
+
+    let mut dst = vec![0; layout.required_size(layout.row_bytes()) - 1];
+    assert_eq!(png_decoder::decode_into(&bytes, &mut dst, layout.row_bytes()).err().map(|err| err.kind), Some(ErrorKind::BufferTooSmall));
+    let mut dst = vec![0; 32 * 32 * 4];
+    assert_eq!(png_decoder::decode_into(&bytes, &mut dst, layout.row_bytes() - 1).err().map(|err| err.kind), Some(ErrorKind::BufferTooSmall));
+}
+
+#[test]
+fn test_tga_decode_into() {
+    // 2 x 2 bottom-up BGRA image
+    let mut tga = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 32, 0];
+    tga.extend((1..=16).collect::<Vec<u8>>());
+
+    for options in [DecodeOptions::new(), DecodeOptions::new().output_format(PixelFormat::Rgb16)] {
+        let expected = tga_decoder::decode_with_options(&tga, &options).unwrap().image;
+        let layout = tga_decoder::buffer_layout(&tga, &options).unwrap();
+        let stride = layout.row_bytes() + 3;
+        let mut dst = vec![PADDING; layout.required_size(stride)];
+        tga_decoder::decode_into_with_options(&tga, &mut dst, stride, &options).unwrap();
+        assert_eq!(unpadded(&dst, stride, layout.row_bytes(), 2), expected.buf);
+
+        let mut small = vec![0; layout.required_size(stride) - 1];
+        assert_eq!(tga_decoder::decode_into_with_options(&tga, &mut small, stride, &options).err().map(|err| err.kind), Some(ErrorKind::BufferTooSmall));
+    }
+}
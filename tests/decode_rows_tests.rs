@@ -0,0 +1,103 @@
+mod common;
+
+use std::env;
+
+use image_codecs::DecodeOptions;
+use image_codecs::RowSink;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::CompositeOver;
+use image_codecs::tga_decoder;
+
+// Sink that keeps only a running checksum of the rows, like a caller hashing a huge image would
+struct RowHasher {
+    next_y: u32,
+    sum: u64,
+}
+
+impl RowSink for RowHasher {
+    fn row(&mut self, y: u32, row: &[u8]) {
+        assert_eq!(y, self.next_y);
+        self.next_y += 1;
+        self.sum = row.iter().fold(self.sum, |sum, byte| sum.wrapping_mul(31).wrapping_add(*byte as u64));
+    }
+}
+
+#[test]
+fn test_png_decode_rows() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    let option_sets = [
+        DecodeOptions::new(),
+        DecodeOptions::new().output_format(PixelFormat::Rgba16),
+        DecodeOptions::new().composite_over(CompositeOver::Color([0, 128, 255])),
+    ];
+    for (path, bytes) in imgs {
+        for options in &option_sets {
+            let Ok(expected) = png_decoder::decode_with_options(&bytes, options) else {
+                continue;
+            };
+            println!("decoding {} row by row", path.strip_prefix(&root).unwrap().display());
+            let mut rows = vec![];
+            let png_image = png_decoder::decode_rows_with_options(&bytes, &mut |y: u32, row: &[u8]| {
+                assert_eq!(y as usize, rows.len());
+                rows.push(row.to_vec());
+            }, options).unwrap();
+            assert!(png_image.image.buf.is_empty());
+            assert_eq!((png_image.image.channels, png_image.image.depth), (expected.image.channels, expected.image.depth));
+            assert_eq!(rows.len(), expected.image.h as usize);
+            assert!(rows.concat() == expected.image.buf);
+        }
+    }
+}
+
+#[test]
+fn test_row_sink() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let (_, bytes) = common::read_all_images(&root, "png").into_iter().find(|(path, _)| path.ends_with("basn6a16.png")).unwrap();
+    let mut hasher = RowHasher { next_y: 0, sum: 0 };
+    png_decoder::decode_rows(&bytes, &mut hasher).unwrap();
+    assert_eq!(hasher.next_y, 32);
+
+    let image = png_decoder::decode(&bytes).unwrap().image;
+    let mut expected = RowHasher { next_y: 0, sum: 0 };
+    for (y, row) in image.buf.chunks(image.buf.len() / 32).enumerate() {
+        expected.row(y as u32, row);
+    }
+    assert_eq!(hasher.sum, expected.sum);
+}
+
+#[test]
+fn test_tga_decode_rows() {
+    // 2 x 2 bottom-up BGRA image
+    let mut tga = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 32, 0];
+    tga.extend((1..=16).collect::<Vec<u8>>());
+
+    for options in [DecodeOptions::new(), DecodeOptions::new().output_format(PixelFormat::Rgb16)] {
+        let expected = tga_decoder::decode_with_options(&tga, &options).unwrap().image;
+        let row_bytes = expected.buf.len() / 2;
+        let mut ys = vec![];
+        tga_decoder::decode_rows_with_options(&tga, &mut |y: u32, row: &[u8]| {
+            assert_eq!(row, &expected.buf[y as usize * row_bytes..][..row_bytes]);
+            ys.push(y);
+        }, &options).unwrap();
+        assert_eq!(ys, vec![1, 0]);
+    }
+}
+
+#[test]
+fn test_tga_top_down_rows() {
+    let mut tga = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 32, 0];
+    tga.extend((1..=16).collect::<Vec<u8>>());
+    let bottom_up = tga_decoder::decode(&tga).unwrap().image;
+    tga[17] = 0x20; // origin in the top left corner
+    let top_down = tga_decoder::decode(&tga).unwrap().image;
+    assert_eq!(top_down.buf[..8], bottom_up.buf[8..]);
+    assert_eq!(top_down.buf[8..], bottom_up.buf[..8]);
+
+    let mut ys = vec![];
+    tga_decoder::decode_rows(&tga, &mut |y: u32, _: &[u8]| ys.push(y)).unwrap();
+    assert_eq!(ys, vec![0, 1]);
+}
@@ -0,0 +1,43 @@
+mod common;
+
+use image_codecs::Image;
+use image_codecs::deflate;
+use image_codecs::png_decoder;
+use image_codecs::png_encoder::ColorType;
+use image_codecs::png_encoder::PngEncoder;
+
+// smooth gradients with noise, so that all block types and match lengths show up
+fn test_image(w: u32, h: u32) -> Image {
+    let mut state = 0x2545f491u32;
+    let buf = (0..w * h * 4).map(|i| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let (x, y, c) = ((i / 4) % w, (i / 4) / w, i % 4);
+        match c {
+            0 => (x * 255 / w) as u8,
+            1 => (y * 255 / h) as u8,
+            2 => if (x / 16 + y / 16) % 2 == 0 { 0 } else { (state % 8) as u8 },
+            _ => 255,
+        }
+    }).collect();
+    Image { w, h, channels: 4, buf, depth: 8 }
+}
+
+#[test]
+fn test_deflate_with_reference_decoders() {
+    let image = test_image(300, 200);
+    let mut prev_size = usize::MAX;
+    for level in [deflate::NO_COMPRESSION, deflate::BEST_SPEED, 4, deflate::DEFAULT_COMPRESSION, deflate::BEST_COMPRESSION] {
+        let bytes = PngEncoder::new(ColorType::Rgba, 8).compression_level(level).encode(&image).unwrap();
+        println!("level {level}: {} bytes", bytes.len());
+        assert!(bytes.len() <= prev_size);
+        prev_size = bytes.len();
+
+        assert!(png_decoder::decode(&bytes).unwrap().image.buf == image.buf);
+        assert!(common::decode_with_png_rs(&bytes).unwrap() == image.buf);
+        assert!(common::decode_with_zune_png(&bytes).unwrap() == image.buf);
+        assert!(common::decode_with_spng(&bytes).unwrap() == image.buf);
+        assert!(common::decode_with_lodepng(&bytes).unwrap() == image.buf);
+    }
+}
@@ -0,0 +1,184 @@
+mod common;
+
+use std::env;
+
+use image_codecs::CpuFeatures;
+use image_codecs::DecodeOptions;
+use image_codecs::Image;
+use image_codecs::Limits;
+use image_codecs::apng_decoder;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::PngStreamDecoder;
+use image_codecs::tga_decoder;
+
+// Differential fuzzing against the reference decoders. The corpus files are mutated with a fixed seed,
+// so failures reproduce. Hostile input may only end in a DecodingError, and whenever both decoders
+// accept a file they have to agree on the pixels. FUZZ_ITERATIONS sets the number of mutations per file.
+
+fn iterations() -> usize {
+    env::var("FUZZ_ITERATIONS").ok().and_then(|n| n.parse().ok()).unwrap_or(20)
+}
+
+// xorshift64*
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+}
+
+fn mutate(rng: &mut Rng, bytes: &[u8], skip: usize) -> Vec<u8> {
+    let mut bytes = bytes.to_vec();
+    for _ in 0..1 + rng.below(4) {
+        let len = bytes.len();
+        if len <= skip {
+            break;
+        }
+        let pos = skip + rng.below(len - skip);
+        match rng.below(6) {
+            0 => bytes[pos] ^= 1 << rng.below(8),
+            1 => bytes[pos] = [0, 1, 0x7f, 0x80, 0xff][rng.below(5)],
+            2 => bytes.truncate(pos),
+            3 => {
+                let end = (pos + 1 + rng.below(64)).min(len);
+                bytes.drain(pos..end);
+            },
+            4 => {
+                let end = (pos + 1 + rng.below(64)).min(len);
+                let copy = bytes[pos..end].to_vec();
+                let at = skip + rng.below(len - skip);
+                bytes.splice(at..at, copy);
+            },
+            _ => {
+                // a plausible big-endian length or dimension
+                let val = [0u32, 1, 0x7fff_ffff, 0xffff_ffff, rng.below(1 << 16) as u32][rng.below(5)];
+                let end = (pos + 4).min(len);
+                bytes[pos..end].copy_from_slice(&val.to_be_bytes()[..end - pos]);
+            },
+        }
+    }
+    bytes
+}
+
+// Recomputes the CRCs of the chunks that are complete, so that mutations get past the CRC checks
+fn fix_crcs(png: &mut [u8]) {
+    let mut pos = 8;
+    while pos + 12 <= png.len() {
+        let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+        if len > png.len() - pos - 12 {
+            break;
+        }
+        let crc_pos = pos + 8 + len;
+        let chunk = common::encode_chunk(png[pos + 4..pos + 8].try_into().unwrap(), &png[pos + 8..crc_pos]);
+        png[crc_pos..crc_pos + 4].copy_from_slice(&chunk[chunk.len() - 4..]);
+        pos = crc_pos + 4;
+    }
+}
+
+// Huge dimensions would only exhaust the memory
+fn limited_options() -> DecodeOptions {
+    DecodeOptions::new().limits(Limits { max_alloc_bytes: 1 << 24, ..Limits::default() })
+}
+
+// The other entry points only have to fail gracefully
+fn decode_png_every_way(bytes: &[u8]) {
+    let _ = png_decoder::probe(bytes);
+    let _ = png_decoder::decode_with_options(bytes, &limited_options().cpu_features(CpuFeatures::default()));
+    let _ = png_decoder::decode_with_options(bytes, &limited_options().adam7_passes(3).output_format(PixelFormat::Rgba16));
+    let _ = png_decoder::decode_with_options(bytes, &limited_options().lenient(true));
+    let _ = png_decoder::decode_progressive(bytes, &limited_options(), &mut |_, _: &Image| {});
+    let _ = png_decoder::decode_rows_with_options(bytes, &mut |_, _: &[u8]| {}, &limited_options());
+    if let Ok(layout) = png_decoder::buffer_layout(bytes, &limited_options()) {
+        // the destination isn't covered by the limits
+        if layout.required_size(layout.row_bytes()) < 1 << 24 {
+            let mut dst = vec![0; layout.required_size(layout.row_bytes())];
+            let _ = png_decoder::decode_into_with_options(bytes, &mut dst, layout.row_bytes(), &limited_options());
+        }
+    }
+    let mut stream_decoder = PngStreamDecoder::with_options(limited_options());
+    for chunk in bytes.chunks(97) {
+        if stream_decoder.push(chunk, |_, _| {}).is_err() {
+            break;
+        }
+    }
+    let _ = stream_decoder.finish();
+    let mut stream_decoder = PngStreamDecoder::with_options(limited_options().lenient(true));
+    for chunk in bytes.chunks(97) {
+        let _ = stream_decoder.push(chunk, |_, _| {});
+    }
+    let _ = stream_decoder.finish();
+    if let Ok(frames) = apng_decoder::decode_with_options(bytes, &limited_options()) {
+        frames.take(4).for_each(drop);
+    }
+}
+
+#[test]
+fn fuzz_png_differential() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    let options = limited_options().verify_checksums(false);
+    let mut n_compared = 0;
+    for (i, (path, bytes)) in imgs.iter().enumerate() {
+        println!("fuzzing {}", path.strip_prefix(&root).unwrap().display());
+        let mut rng = Rng(0x9e3779b97f4a7c15 ^ i as u64);
+        for _ in 0..iterations() {
+            let mut mutated = mutate(&mut rng, bytes, 8);
+            if rng.below(4) != 0 {
+                fix_crcs(&mut mutated);
+            }
+            let ours = png_decoder::decode_with_options(&mutated, &options);
+            decode_png_every_way(&mutated);
+            let Ok(ours) = ours else {
+                continue;
+            };
+            if let Ok(expected) = common::decode_with_png_rs_checksums(&mutated, false) {
+                assert!(ours.image.buf == expected, "decoders disagree about a mutation of {}", path.display());
+                n_compared += 1;
+            }
+        }
+    }
+    println!("{n_compared} mutations decoded by both");
+}
+
+#[test]
+fn fuzz_tga_differential() {
+    // 5 x 3 BGR, BGRA and grayscale images, bottom-up and top-down
+    let mut seeds = vec![];
+    for (image_type, depth) in [(2, 24), (2, 32), (3, 8)] {
+        for descriptor in [0, 0x20] {
+            let mut tga = vec![0, 0, image_type, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 3, 0, depth, descriptor];
+            tga.extend((0..5 * 3 * depth as usize / 8).map(|i| (i * 37) as u8));
+            seeds.push(tga);
+        }
+    }
+
+    let mut n_compared = 0;
+    for (i, seed) in seeds.iter().enumerate() {
+        let mut rng = Rng(0x2545f4914f6cdd1d ^ i as u64);
+        for _ in 0..iterations() * 20 {
+            let mutated = mutate(&mut rng, seed, 0);
+            let _ = Image::probe(&mutated);
+            let _ = tga_decoder::decode_rows(&mutated, &mut |_, _: &[u8]| {});
+            let _ = tga_decoder::decode_with_options(&mutated, &DecodeOptions::new().lenient(true));
+            let Ok(ours) = tga_decoder::decode(&mutated) else {
+                continue;
+            };
+            if let Ok(expected) = common::decode_with_image_rs(&mutated, "tga") {
+                assert!(ours.image.buf == expected, "decoders disagree about {mutated:?}");
+                n_compared += 1;
+            }
+        }
+    }
+    println!("{n_compared} mutations decoded by both");
+}
@@ -0,0 +1,223 @@
+mod common;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::Image;
+use image_codecs::deflate;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::PngStreamDecoder;
+use image_codecs::png_encoder::ColorType;
+use image_codecs::png_encoder::PngEncoder;
+use image_codecs::tga_decoder;
+
+const W: usize = 16;
+const H: usize = 10;
+const ROW: usize = 1 + W * 3; // filter byte and RGB pixels
+
+fn pixels() -> Vec<u8> {
+    (0..W * H * 3).map(|i| (i * 13 + 1) as u8).collect()
+}
+
+fn lenient() -> DecodeOptions {
+    DecodeOptions::new().lenient(true)
+}
+
+// Scanlines with filter None in a stored block, the image data is split into IDAT chunks at `splits`
+fn stored_png(pixels: &[u8], splits: &[usize]) -> Vec<u8> {
+    let scanlines: Vec<u8> = pixels.chunks(W * 3).flat_map(|row| [&[0], row].concat()).collect();
+    let mut data = vec![0x78, 0x01, 1];
+    data.extend_from_slice(&(scanlines.len() as u16).to_le_bytes());
+    data.extend_from_slice(&(!(scanlines.len() as u16)).to_le_bytes());
+    data.extend_from_slice(&scanlines);
+    data.extend_from_slice(&deflate::adler32(&scanlines).to_be_bytes());
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut ihdr = vec![];
+    ihdr.extend_from_slice(&(W as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(H as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    png.extend(common::encode_chunk(b"IHDR", &ihdr));
+    let mut start = 0;
+    for end in splits.iter().copied().chain([data.len()]) {
+        png.extend(common::encode_chunk(b"IDAT", &data[start..end]));
+        start = end;
+    }
+    png.extend(common::encode_chunk(b"IEND", &[]));
+    png
+}
+
+// Offset of the scanline byte `pos` in a file from `stored_png`
+fn scanline_offset(png: &[u8], splits: &[usize], pos: usize) -> usize {
+    let first_idat = png.windows(4).position(|window| window == b"IDAT").unwrap() + 4;
+    let data_pos = 7 + pos; // after the zlib and the stored block header
+    // headers and CRCs of the IDAT chunks before it
+    first_idat + data_pos + 12 * splits.iter().filter(|split| **split <= data_pos).count()
+}
+
+// The rows after the valid ones are 0, except for the one that was partly decoded
+fn assert_partial(image: &Image, pixels: &[u8], rows_valid: usize) {
+    let row_size = W * 3;
+    assert!(image.buf[..rows_valid * row_size] == pixels[..rows_valid * row_size]);
+    assert!(image.buf[(rows_valid + 1) * row_size..].iter().all(|val| *val == 0));
+}
+
+#[test]
+fn test_truncated_idat() {
+    let pixels = pixels();
+    for splits in [&[][..], &[100, 300]] {
+        let png = stored_png(&pixels, splits);
+        for rows in [0, 1, 4, 6, H - 1] {
+            let cut = &png[..scanline_offset(&png, splits, rows * ROW + 20)];
+            assert!(png_decoder::decode(cut).is_err());
+
+            let decoded = png_decoder::decode_with_options(cut, &lenient()).unwrap();
+            assert_eq!(decoded.rows_valid, rows as u32);
+            assert_partial(&decoded.image, &pixels, rows);
+            assert_eq!(decoded.warnings.iter().map(|warning| warning.kind).collect::<Vec<_>>(), [ErrorKind::Truncated]);
+            assert_eq!(decoded.warnings[0].chunk, Some(*b"IDAT"));
+        }
+    }
+}
+
+#[test]
+fn test_trailing_garbage() {
+    let pixels = pixels();
+    let mut png = stored_png(&pixels, &[]);
+    png.extend_from_slice(b"garbage");
+    assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::TooMuchData));
+
+    let decoded = png_decoder::decode_with_options(&png, &lenient()).unwrap();
+    assert!(decoded.image.buf == pixels);
+    assert_eq!(decoded.rows_valid, H as u32);
+    assert_eq!(decoded.warnings.iter().map(|warning| warning.kind).collect::<Vec<_>>(), [ErrorKind::TooMuchData]);
+
+    // a complete file has no warnings
+    let decoded = png_decoder::decode_with_options(&stored_png(&pixels, &[]), &lenient()).unwrap();
+    assert_eq!((decoded.rows_valid, decoded.warnings.len()), (H as u32, 0));
+}
+
+#[test]
+fn test_invalid_filter() {
+    let pixels = pixels();
+    let mut png = stored_png(&pixels, &[]);
+    let offset = scanline_offset(&png, &[], 4 * ROW);
+    png[offset] = 7;
+    let options = lenient().verify_checksums(false);
+    assert_eq!(png_decoder::decode_with_options(&png, &options.clone().lenient(false)).err().map(|err| err.kind), Some(ErrorKind::InvalidFilter));
+
+    let decoded = png_decoder::decode_with_options(&png, &options).unwrap();
+    assert_eq!(decoded.rows_valid, 4);
+    assert_partial(&decoded.image, &pixels, 4);
+    assert_eq!(decoded.warnings[0].kind, ErrorKind::InvalidFilter);
+}
+
+#[test]
+fn test_errors_before_image_data() {
+    // the header is needed to return anything
+    let mut png = stored_png(&pixels(), &[]);
+    png[20] ^= 1; // height
+    assert_eq!(png_decoder::decode_with_options(&png, &lenient()).err().map(|err| err.kind), Some(ErrorKind::CrcMismatch));
+    assert!(png_decoder::decode_with_options(&png[..40], &lenient()).is_err());
+}
+
+#[test]
+fn test_truncated_interlaced() {
+    let pixels = pixels();
+    let image = Image { w: W as u32, h: H as u32, channels: 3, buf: pixels.clone(), depth: 8 };
+    let png = PngEncoder::new(ColorType::Rgb, 8).interlaced(true).compression_level(deflate::NO_COMPRESSION).encode(&image).unwrap();
+    let iend = png.len() - 12;
+    for cut in [iend - 100, iend - 200] {
+        let decoded = png_decoder::decode_with_options(&png[..cut], &lenient()).unwrap();
+        let rows_valid = decoded.rows_valid as usize;
+        assert!(rows_valid > 0 && rows_valid < H);
+        assert!(decoded.image.buf[..rows_valid * W * 3] == pixels[..rows_valid * W * 3]);
+        assert_eq!(decoded.warnings[0].kind, ErrorKind::Truncated);
+    }
+}
+
+#[test]
+fn test_lenient_rows_and_stream() {
+    let pixels = pixels();
+    let png = stored_png(&pixels, &[200]);
+    let cut = &png[..scanline_offset(&png, &[200], 6 * ROW + 20)];
+
+    let mut rows = vec![];
+    let decoded = png_decoder::decode_rows_with_options(cut, &mut |y, row: &[u8]| rows.push((y, row.to_vec())), &lenient()).unwrap();
+    assert_eq!(decoded.rows_valid, 6);
+    assert_eq!(rows.len(), 6);
+    for (y, row) in rows {
+        assert!(row == pixels[y as usize * W * 3..][..W * 3]);
+    }
+
+    let mut decoder = PngStreamDecoder::with_options(lenient());
+    decoder.push(cut, |_, _| {}).unwrap();
+    let decoded = decoder.finish().unwrap();
+    assert_eq!(decoded.rows_valid, 6);
+    assert_partial(&decoded.image, &pixels, 6);
+    assert_eq!(decoded.warnings[0].kind, ErrorKind::Truncated);
+
+    let mut decoder = PngStreamDecoder::with_options(lenient());
+    decoder.push(&[&png[..], b"garbage"].concat(), |_, _| {}).unwrap();
+    let decoded = decoder.finish().unwrap();
+    assert_eq!((decoded.rows_valid, decoded.warnings[0].kind), (H as u32, ErrorKind::TooMuchData));
+
+    let mut decoder = PngStreamDecoder::new();
+    decoder.push(cut, |_, _| {}).unwrap();
+    assert_eq!(decoder.finish().err().map(|err| err.kind), Some(ErrorKind::Truncated));
+}
+
+// 5 x 3 BGR, bottom-up
+fn tga() -> Vec<u8> {
+    let mut tga = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 3, 0, 24, 0];
+    tga.extend((0..45).map(|i| i as u8 + 1));
+    tga
+}
+
+#[test]
+fn test_truncated_tga() {
+    let tga = tga();
+    let cut = &tga[..18 + 2 * 15 + 7];
+    assert_eq!(tga_decoder::decode(cut).err().map(|err| err.kind), Some(ErrorKind::Truncated));
+
+    let decoded = tga_decoder::decode_with_options(cut, &lenient()).unwrap();
+    assert_eq!(decoded.rows_valid, 2);
+    assert!(!decoded.top_down);
+    let expected = tga_decoder::decode(&tga).unwrap().image.buf;
+    assert!(decoded.image.buf[..15].iter().all(|val| *val == 0));
+    assert!(decoded.image.buf[15..] == expected[15..]);
+    assert_eq!(decoded.warnings.iter().map(|warning| (warning.kind, warning.offset)).collect::<Vec<_>>(), [(ErrorKind::Truncated, Some(cut.len()))]);
+}
+
+#[test]
+fn test_tga_bad_footer() {
+    let mut tga = tga();
+    tga.extend_from_slice(&[0; 8]);
+    tga.extend_from_slice(b"TRUEVISION-YFILE.\0");
+    assert_eq!(tga_decoder::decode(&tga).err().map(|err| err.kind), Some(ErrorKind::InvalidSignature));
+
+    let decoded = tga_decoder::decode_with_options(&tga, &lenient()).unwrap();
+    assert_eq!(decoded.rows_valid, 3);
+    assert!(decoded.image.buf == tga_decoder::decode(&tga[..18 + 45]).unwrap().image.buf);
+    assert_eq!(decoded.warnings[0].kind, ErrorKind::InvalidSignature);
+}
+
+#[test]
+fn test_missing_scanlines() {
+    // a complete zlib stream with 2 of the 4 rows
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png.extend(common::encode_chunk(b"IHDR", &[0, 0, 0, 4, 0, 0, 0, 4, 8, 0, 0, 0, 0]));
+    png.extend(common::encode_chunk(b"IDAT", &deflate::zlib_compress(&[0, 1, 2, 3, 4, 0, 5, 6, 7, 8], 6)));
+    png.extend(common::encode_chunk(b"IEND", &[]));
+    assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::Truncated));
+    let mut decoder = PngStreamDecoder::new();
+    decoder.push(&png, |_, _| {}).unwrap();
+    assert_eq!(decoder.finish().err().map(|err| err.kind), Some(ErrorKind::Truncated));
+
+    let decoded = png_decoder::decode_with_options(&png, &lenient()).unwrap();
+    assert_eq!((decoded.rows_valid, decoded.warnings[0].kind), (2, ErrorKind::Truncated));
+    assert_eq!(decoded.image.buf, [1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut decoder = PngStreamDecoder::with_options(lenient());
+    decoder.push(&png, |_, _| {}).unwrap();
+    let decoded = decoder.finish().unwrap();
+    assert_eq!((decoded.rows_valid, decoded.warnings[0].kind), (2, ErrorKind::Truncated));
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::Limits;
+use image_codecs::apng_decoder;
+use image_codecs::deflate;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+use image_codecs::tga_decoder;
+
+fn decode_with_limits(png: &[u8], limits: Limits) -> Result<(), ErrorKind> {
+    let options = DecodeOptions::new().limits(limits);
+    png_decoder::decode_with_options(png, &options).map(|_| ()).map_err(|err| err.kind)
+}
+
+fn decode_streaming(png: &[u8], limits: Limits) -> Result<(), ErrorKind> {
+    common::decode_streaming(png, DecodeOptions::new().limits(limits)).map(|_| ()).map_err(|err| err.kind)
+}
+
+#[test]
+fn test_huge_dimensions() {
+    // IHDR of the largest allowed RGBA image followed by a few bytes of image data
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png.extend(common::encode_chunk(b"IHDR", &[0x7f, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff, 8, 6, 0, 0, 0]));
+    png.extend(common::encode_chunk(b"IDAT", &deflate::zlib_compress(&[0; 64], 6)));
+    png.extend(common::encode_chunk(b"IEND", &[]));
+
+    let err = png_decoder::decode(&png).err().unwrap();
+    assert_eq!((err.kind, err.chunk), (ErrorKind::LimitsExceeded, Some(*b"IHDR")));
+    assert_eq!(decode_streaming(&png, Limits::default()), Err(ErrorKind::LimitsExceeded));
+    assert_eq!(apng_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::LimitsExceeded));
+
+    // without limits on the dimensions the allocation is still checked
+    let limits = Limits { max_width: u32::MAX, max_height: u32::MAX, max_pixels: u64::MAX, ..Limits::default() };
+    assert_eq!(decode_with_limits(&png, limits), Err(ErrorKind::LimitsExceeded));
+    assert_eq!(decode_streaming(&png, limits), Err(ErrorKind::LimitsExceeded));
+    let options = DecodeOptions::new().limits(limits);
+    assert_eq!(apng_decoder::decode_with_options(&png, &options).err().map(|err| err.kind), Some(ErrorKind::LimitsExceeded));
+}
+
+#[test]
+fn test_dimension_limits() {
+    let png = common::rgb_png(16, 8);
+    assert_eq!(decode_with_limits(&png, Limits { max_width: 16, max_height: 8, max_pixels: 128, ..Limits::default() }), Ok(()));
+    assert_eq!(decode_with_limits(&png, Limits { max_width: 15, ..Limits::default() }), Err(ErrorKind::LimitsExceeded));
+    assert_eq!(decode_with_limits(&png, Limits { max_height: 7, ..Limits::default() }), Err(ErrorKind::LimitsExceeded));
+    assert_eq!(decode_with_limits(&png, Limits { max_pixels: 127, ..Limits::default() }), Err(ErrorKind::LimitsExceeded));
+    assert_eq!(decode_streaming(&png, Limits { max_pixels: 127, ..Limits::default() }), Err(ErrorKind::LimitsExceeded));
+}
+
+#[test]
+fn test_alloc_limit() {
+    let png = common::rgb_png(16, 8);
+    let limits = Limits { max_alloc_bytes: 384, ..Limits::default() };
+    assert_eq!(decode_with_limits(&png, limits), Ok(()));
+    assert_eq!(decode_streaming(&png, limits), Ok(()));
+    assert_eq!(decode_with_limits(&png, Limits { max_alloc_bytes: 383, ..Limits::default() }), Err(ErrorKind::LimitsExceeded));
+
+    // the output layout needs 16 x 8 x 8 bytes
+    let options = DecodeOptions::new().limits(limits).output_format(PixelFormat::Rgba16);
+    assert_eq!(png_decoder::decode_with_options(&png, &options).err().map(|err| err.kind), Some(ErrorKind::LimitsExceeded));
+    let options = options.limits(Limits { max_alloc_bytes: 1024, ..Limits::default() });
+    assert!(png_decoder::decode_with_options(&png, &options).is_ok());
+
+    // converted scanlines only need a row in the layout of the file
+    let options = DecodeOptions::new().limits(Limits { max_alloc_bytes: 128, ..Limits::default() }).output_format(PixelFormat::L8);
+    assert_eq!(png_decoder::decode_with_options(&png, &options).map(|png_image| png_image.image.buf.len()), Ok(128));
+    let mut dst = vec![0; 128];
+    assert!(png_decoder::decode_into_with_options(&png, &mut dst, 16, &options).is_ok());
+    assert!(common::decode_streaming(&png, options).is_ok());
+}
+
+#[test]
+fn test_chunk_len_limit() {
+    let mut png = common::rgb_png(16, 8);
+    common::insert_chunk(&mut png, b"abCd", &[0; 100]);
+    assert_eq!(decode_with_limits(&png, Limits { max_chunk_len: 100, ..Limits::default() }), Ok(()));
+    let limits = Limits { max_chunk_len: 99, ..Limits::default() };
+    let err = png_decoder::decode_with_options(&png, &DecodeOptions::new().limits(limits)).err().unwrap();
+    assert_eq!((err.kind, err.chunk), (ErrorKind::LimitsExceeded, Some(*b"abCd")));
+    assert_eq!(decode_streaming(&png, limits), Err(ErrorKind::LimitsExceeded));
+}
+
+#[test]
+fn test_text_limit() {
+    let text_chunk = |text: &[u8]| [b"Comment\0".as_slice(), text].concat();
+    let ztxt_chunk = |text: &[u8]| [b"Comment\0\0".as_slice(), &deflate::zlib_compress(text, 6)].concat();
+    let limits = Limits { max_text_bytes: 1000, ..Limits::default() };
+
+    let mut png = common::rgb_png(16, 8);
+    common::insert_chunk(&mut png, b"tEXt", &text_chunk(&[b'a'; 600]));
+    assert_eq!(decode_with_limits(&png, limits), Ok(()));
+    // the limit is for the text of all chunks together
+    common::insert_chunk(&mut png, b"zTXt", &ztxt_chunk(&[b'a'; 400]));
+    assert_eq!(decode_with_limits(&png, limits), Ok(()));
+    common::insert_chunk(&mut png, b"tEXt", &text_chunk(b"a"));
+    assert_eq!(decode_with_limits(&png, limits), Err(ErrorKind::LimitsExceeded));
+
+    // a small chunk that inflates to 16 MiB
+    let mut bomb = common::rgb_png(16, 8);
+    common::insert_chunk(&mut bomb, b"zTXt", &ztxt_chunk(&vec![b'a'; 1 << 24]));
+    assert_eq!(decode_with_limits(&bomb, limits), Err(ErrorKind::LimitsExceeded));
+    assert_eq!(decode_streaming(&bomb, limits), Err(ErrorKind::LimitsExceeded));
+}
+
+#[test]
+fn test_iccp_limit() {
+    let mut png = common::rgb_png(16, 8);
+    let profile = vec![0; 1 << 20];
+    common::insert_chunk(&mut png, b"iCCP", &[b"icc\0\0".as_slice(), &deflate::zlib_compress(&profile, 6)].concat());
+    assert_eq!(decode_with_limits(&png, Limits { max_alloc_bytes: 1 << 20, ..Limits::default() }), Ok(()));
+    assert_eq!(decode_with_limits(&png, Limits { max_alloc_bytes: (1 << 20) - 1, ..Limits::default() }), Err(ErrorKind::LimitsExceeded));
+}
+
+#[test]
+fn test_tga_limits() {
+    let mut tga = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 3, 0, 24, 0];
+    tga.extend([0; 5 * 3 * 3]);
+    let decode = |limits: Limits| tga_decoder::decode_with_options(&tga, &DecodeOptions::new().limits(limits)).map(|_| ()).map_err(|err| err.kind);
+    assert_eq!(decode(Limits { max_width: 5, max_height: 3, max_pixels: 15, max_alloc_bytes: 45, ..Limits::default() }), Ok(()));
+    assert_eq!(decode(Limits { max_width: 4, ..Limits::default() }), Err(ErrorKind::LimitsExceeded));
+    assert_eq!(decode(Limits { max_height: 2, ..Limits::default() }), Err(ErrorKind::LimitsExceeded));
+    assert_eq!(decode(Limits { max_pixels: 14, ..Limits::default() }), Err(ErrorKind::LimitsExceeded));
+    assert_eq!(decode(Limits { max_alloc_bytes: 44, ..Limits::default() }), Err(ErrorKind::LimitsExceeded));
+}
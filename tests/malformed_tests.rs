@@ -0,0 +1,255 @@
+mod common;
+
+use std::io::Cursor;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::Image;
+use image_codecs::apng_decoder;
+use image_codecs::deflate;
+use image_codecs::inflate;
+use image_codecs::png_decoder;
+use image_codecs::png_encoder;
+use image_codecs::tga_decoder;
+
+// Inputs that used to panic
+
+fn indexed_png() -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut encoder = png::Encoder::new(Cursor::new(&mut bytes), 2, 1);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(vec![0, 0, 0, 255, 255, 255]);
+    encoder.write_header().unwrap().write_image_data(&[0, 1]).unwrap();
+    bytes
+}
+
+// Replaces the data of the only IDAT chunk
+fn replace_idat(png: &mut Vec<u8>, data: impl FnOnce(&[u8]) -> Vec<u8>) {
+    let idat_start = png.windows(4).position(|window| window == b"IDAT").unwrap() - 4;
+    let len = u32::from_be_bytes(png[idat_start..idat_start + 4].try_into().unwrap()) as usize;
+    let data = data(&png[idat_start + 8..idat_start + 8 + len]);
+    png.splice(idat_start..idat_start + 12 + len, common::encode_chunk(b"IDAT", &data));
+}
+
+#[test]
+fn test_trns_before_plte() {
+    let mut png = indexed_png();
+    common::insert_chunk(&mut png, b"tRNS", &[0]);
+    assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+}
+
+#[test]
+fn test_repeated_trns() {
+    let mut png = common::rgb_png(2, 1);
+    common::insert_chunk(&mut png, b"tRNS", &[0, 1, 0, 2, 0, 3]);
+    assert_eq!(png_decoder::decode(&png).unwrap().image.channels, 4);
+    common::insert_chunk(&mut png, b"tRNS", &[0, 1, 0, 2, 0, 3]);
+    assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+
+    let mut png = indexed_png();
+    common::insert_chunk_before_idat(&mut png, b"tRNS", &[0]);
+    common::insert_chunk_before_idat(&mut png, b"tRNS", &[0]);
+    assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+}
+
+// Inserts a chunk right before IEND
+fn insert_chunk_after_idat(png: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    let iend_start = png.len() - 12;
+    png.splice(iend_start..iend_start, common::encode_chunk(name, data));
+}
+
+fn assert_chunk_order_error(png: &[u8]) {
+    assert_eq!(png_decoder::decode(png).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+    assert_eq!(common::decode_streaming(png, DecodeOptions::new()).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+    assert_eq!(apng_decoder::decode(png).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+}
+
+#[test]
+fn test_trns_after_idat() {
+    let gray = png_encoder::encode(&Image { w: 4, h: 3, channels: 1, buf: (0..12).collect(), depth: 8 }).unwrap();
+    for (mut png, trns) in [(gray, &[0, 1][..]), (common::rgb_png(2, 1), &[0, 1, 0, 2, 0, 3]), (indexed_png(), &[0])] {
+        insert_chunk_after_idat(&mut png, b"tRNS", trns);
+        assert_chunk_order_error(&png);
+    }
+}
+
+#[test]
+fn test_misplaced_plte() {
+    let mut png = indexed_png();
+    insert_chunk_after_idat(&mut png, b"PLTE", &[255, 0, 0, 0, 255, 0]);
+    assert_chunk_order_error(&png);
+
+    let mut png = indexed_png();
+    common::insert_chunk_before_idat(&mut png, b"PLTE", &[255, 0, 0, 0, 255, 0]);
+    assert_chunk_order_error(&png);
+}
+
+#[test]
+fn test_missing_plte() {
+    let mut png = indexed_png();
+    let plte_start = png.windows(4).position(|window| window == b"PLTE").unwrap() - 4;
+    png.drain(plte_start..plte_start + 12 + 6);
+    assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+    let mut dst = [0; 2];
+    assert_eq!(png_decoder::decode_into(&png, &mut dst, 2).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+    assert_eq!(common::decode_streaming(&png, DecodeOptions::new()).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+    assert_eq!(apng_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+}
+
+#[test]
+fn test_repeated_ihdr() {
+    let ihdr = |w: u32, color_type: u8, depth: u8| common::encode_chunk(b"IHDR", &[&w.to_be_bytes()[..], &[0, 0, 0, 1, depth, color_type, 0, 0, 0]].concat());
+    let plte = common::encode_chunk(b"PLTE", &[0, 0, 0, 255, 255, 255]);
+    let idat = common::encode_chunk(b"IDAT", &deflate::zlib_compress(&[0; 7], 6));
+    let iend = common::encode_chunk(b"IEND", &[]);
+    let pngs = [
+        [&ihdr(2, 3, 1)[..], &plte, &ihdr(2, 2, 8), &idat, &iend].concat(),
+        [&ihdr(2, 3, 1)[..], &plte, &ihdr(2, 2, 16), &idat, &iend].concat(),
+        [&ihdr(2, 2, 8)[..], &idat, &ihdr(100, 2, 8), &iend].concat(),
+        [&plte[..], &ihdr(2, 3, 8), &idat, &iend].concat(), // IHDR isn't the first chunk
+    ];
+    for chunks in pngs {
+        let png = [b"\x89PNG\r\n\x1a\n".as_slice(), &chunks].concat();
+        assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+        assert_eq!(common::decode_streaming(&png, DecodeOptions::new()).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+        assert_eq!(apng_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::ChunkOrder));
+    }
+}
+
+#[test]
+fn test_error_location() {
+    let mut png = common::rgb_png(2, 1);
+    common::insert_chunk(&mut png, b"tRNS", &[0, 1, 0, 2, 0, 3]);
+    common::insert_chunk(&mut png, b"tRNS", &[0, 1, 0, 2, 0, 3]);
+    let data_start = png.windows(4).rposition(|window| window == b"tRNS").unwrap() + 4;
+    let err = png_decoder::decode(&png).err().unwrap();
+    assert_eq!((err.chunk, err.offset), (Some(*b"tRNS"), Some(data_start)));
+    assert_eq!(err.to_string(), format!("missing, repeated or misplaced chunk in tRNS chunk at byte {data_start}"));
+
+    let mut png = common::rgb_png(2, 1);
+    png[33 - 1] ^= 1; // CRC of IHDR
+    let err = png_decoder::decode(&png).err().unwrap();
+    assert_eq!((err.kind, err.chunk, err.offset), (ErrorKind::CrcMismatch, Some(*b"IHDR"), Some(33)));
+
+    let mut png = common::rgb_png(2, 1);
+    replace_idat(&mut png, |data| [&data[..2], &[0x07], &data[3..]].concat()); // reserved block type
+    let err = png_decoder::decode(&png).err().unwrap();
+    assert_eq!((err.kind, err.chunk), (ErrorKind::InvalidDeflateData, Some(*b"IDAT")));
+
+    let tga = [0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1, 0, 24, 0, 1, 2, 3];
+    let err = tga_decoder::decode(&tga).err().unwrap();
+    assert_eq!((err.kind, err.chunk, err.offset), (ErrorKind::Truncated, None, Some(18)));
+    assert_eq!(err.to_string(), "truncated data at byte 18");
+}
+
+#[test]
+fn test_zlib_header_check() {
+    // FCHECK makes the header a multiple of 31, preset dictionaries aren't allowed
+    for (flg, valid) in [(0x01, true), (0xda, true), (0x02, false), (0x1f, false), (0x20, false)] {
+        let mut png = common::rgb_png(2, 1);
+        replace_idat(&mut png, |data| [&[0x78, flg], &data[2..]].concat());
+        let kind = (!valid).then_some(ErrorKind::InvalidZlibHeader);
+        assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), kind);
+        assert_eq!(common::decode_streaming(&png, DecodeOptions::new()).err().map(|err| err.kind), kind);
+    }
+}
+
+#[test]
+fn test_data_after_zlib_stream() {
+    let mut png = common::rgb_png(2, 1);
+    replace_idat(&mut png, |data| [data, &[0; 4]].concat());
+    assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::TooMuchData));
+}
+
+#[test]
+fn test_oversubscribed_code() {
+    // a dynamic block with 19 code length codes of length 1
+    let mut bits: Vec<u8> = vec![1, 0, 1]; // final block, dynamic Huffman codes
+    bits.extend([0; 10]); // 257 literal/length and 1 distance code
+    bits.extend([1, 1, 1, 1]); // 19 code length codes
+    for _ in 0..19 {
+        bits.extend([1, 0, 0]);
+    }
+    let mut deflate = vec![0; bits.len().div_ceil(8)];
+    for (i, bit) in bits.iter().enumerate() {
+        deflate[i / 8] |= bit << (i % 8);
+    }
+    assert_eq!(inflate::inflate(&deflate).err().map(|err| err.kind), Some(ErrorKind::InvalidHuffmanTable));
+
+    let mut png = common::rgb_png(2, 1);
+    replace_idat(&mut png, |_| [&[0x78, 0x01], &deflate[..], &[0; 4]].concat());
+    assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::InvalidHuffmanTable));
+}
+
+#[test]
+fn test_code_not_in_table() {
+    // a dynamic block that only has codes for literal 0 and the end of block, then an unassigned code
+    let mut bits: Vec<u8> = vec![];
+    let mut push = |val: u32, count: u32| bits.extend((0..count).map(|i| (val >> i & 1) as u8));
+    push(0b101, 3); // final block, dynamic Huffman codes
+    push(0, 5); // 257 literal/length codes
+    push(0, 5); // 1 distance code
+    push(14, 4); // 18 code length codes
+    for len in [0, 0, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2] {
+        push(len, 3); // code lengths 0, 1, 2 and 18 get the codes 00, 01, 10 and 11
+    }
+    // the codes go out starting with their first bit
+    push(0b01, 2); // literal 0 has length 2
+    push(0b11, 2); // 138 zeros
+    push(127, 7);
+    push(0b11, 2); // 117 zeros
+    push(106, 7);
+    push(0b01, 2); // end of block has length 2
+    push(0b10, 2); // the distance code has length 1
+    push(0b00, 2); // literal 0
+    push(0b11, 2); // code 11 isn't assigned
+
+    // the same after a fixed Huffman block that only has the end of block code, whose table has every code
+    let after_fixed_block = [&[0, 1, 0][..], &[0; 7], &bits].concat();
+    for bits in [bits, after_fixed_block] {
+        let mut deflate = vec![0; bits.len().div_ceil(8)];
+        for (i, bit) in bits.iter().enumerate() {
+            deflate[i / 8] |= bit << (i % 8);
+        }
+        assert_eq!(inflate::inflate(&deflate).err().map(|err| err.kind), Some(ErrorKind::InvalidDeflateData));
+
+        let mut png = common::rgb_png(2, 1);
+        replace_idat(&mut png, |_| [&[0x78, 0x01], &deflate[..], &[0; 4]].concat());
+        assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::InvalidDeflateData));
+        assert_eq!(common::decode_streaming(&png, DecodeOptions::new()).err().map(|err| err.kind), Some(ErrorKind::InvalidDeflateData));
+    }
+}
+
+#[test]
+fn test_distance_before_start() {
+    // a fixed Huffman block that starts with a match of the 7 bytes of the scanline at distance 1
+    let mut bits: Vec<u8> = vec![1, 1, 0]; // final block, fixed Huffman codes
+    bits.extend([0, 0, 0, 0, 1, 0, 1]); // length 7
+    bits.extend([0, 0, 0, 0, 0]); // distance 1
+    bits.extend([0; 7]); // end of block
+    let mut deflate = vec![0; bits.len().div_ceil(8)];
+    for (i, bit) in bits.iter().enumerate() {
+        deflate[i / 8] |= bit << (i % 8);
+    }
+    assert_eq!(inflate::inflate(&deflate).err().map(|err| err.kind), Some(ErrorKind::InvalidDeflateData));
+
+    // the Adler-32 of the zeros the window starts with
+    let mut png = common::rgb_png(2, 1);
+    replace_idat(&mut png, |_| [&[0x78, 0x01], &deflate[..], &deflate::adler32(&[0; 7]).to_be_bytes()].concat());
+    for verify_checksums in [true, false] {
+        let options = DecodeOptions::new().verify_checksums(verify_checksums);
+        assert_eq!(png_decoder::decode_with_options(&png, &options).err().map(|err| err.kind), Some(ErrorKind::InvalidDeflateData));
+        assert_eq!(common::decode_streaming(&png, options).err().map(|err| err.kind), Some(ErrorKind::InvalidDeflateData));
+    }
+}
+
+#[test]
+fn test_16_bit_tga() {
+    let mut tga = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1, 0, 16, 0];
+    tga.extend([0x1f, 0x80, 0xe0, 0x03]);
+    assert_eq!(tga_decoder::decode(&tga).err().map(|err| err.kind), Some(ErrorKind::NotImplemented));
+    // nothing is reported for an image that can't be decoded
+    assert_eq!(tga_decoder::probe(&tga).err().map(|err| err.kind), Some(ErrorKind::NotImplemented));
+    assert_eq!(tga_decoder::buffer_layout(&tga, &DecodeOptions::new()).err().map(|err| err.kind), Some(ErrorKind::NotImplemented));
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use std::env;
+
+use image_codecs::DecodeOptions;
+use image_codecs::Image;
+use image_codecs::color::TargetSpace;
+use image_codecs::pixel_format;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::PngStreamDecoder;
+use image_codecs::png_encoder;
+use image_codecs::tga_decoder;
+
+const FORMATS: [PixelFormat; 10] = [
+    PixelFormat::L8, PixelFormat::La8, PixelFormat::Rgb8, PixelFormat::Rgba8, PixelFormat::Bgr8,
+    PixelFormat::Bgra8, PixelFormat::L16, PixelFormat::La16, PixelFormat::Rgb16, PixelFormat::Rgba16,
+];
+
+#[test]
+fn test_png_output_formats() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    for (path, bytes) in imgs {
+        let Ok(native) = png_decoder::decode(&bytes) else {
+            continue;
+        };
+        println!("converting {}", path.strip_prefix(&root).unwrap().display());
+        for format in FORMATS {
+            let options = DecodeOptions::new().output_format(format);
+            let mut expected = Image { buf: native.image.buf.clone(), ..native.image };
+            pixel_format::convert(&mut expected, format);
+
+            let image = png_decoder::decode_with_options(&bytes, &options).unwrap().image;
+            assert_eq!((image.channels, image.depth), (format.channels(), format.depth()));
+            assert!(image.buf == expected.buf);
+
+            let mut decoder = PngStreamDecoder::with_options(options);
+            let mut last_rows = vec![vec![]; image.h as usize];
+            decoder.push(&bytes, |y, row| last_rows[y as usize] = row.to_vec()).unwrap();
+            assert!(decoder.finish().unwrap().image.buf == expected.buf);
+            assert!(last_rows.concat() == expected.buf);
+        }
+    }
+}
+
+#[test]
+fn test_output_format_with_color_conversion() {
+    let image = Image { w: 2, h: 1, channels: 1, buf: vec![0, 255], depth: 8 };
+    let mut png = png_encoder::encode(&image).unwrap();
+    common::insert_chunk(&mut png, b"gAMA", &100000u32.to_be_bytes());
+    let options = DecodeOptions::new().color_space(TargetSpace::Srgb).output_format(PixelFormat::Rgba16);
+    let decoded = png_decoder::decode_with_options(&png, &options).unwrap().image;
+    assert_eq!(decoded.buf, [[0u16, 0, 0, 65535], [65535; 4]].concat().iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>());
+}
+
+#[test]
+fn test_tga_output_formats() {
+    // 2 x 2 bottom-up BGR image
+    let mut tga = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 24, 0];
+    let pixels: [[u8; 3]; 4] = [[1, 2, 3], [4, 5, 6], [7, 8, 9], [10, 11, 12]];
+    tga.extend(pixels.concat());
+
+    let rgb = tga_decoder::decode(&tga).unwrap().image;
+    assert_eq!(rgb.buf, vec![9, 8, 7, 12, 11, 10, 3, 2, 1, 6, 5, 4]);
+
+    let decode_to = |format| tga_decoder::decode_with_options(&tga, &DecodeOptions::new().output_format(format)).unwrap().image;
+    assert_eq!(decode_to(PixelFormat::Bgr8).buf, [&pixels[2..], &pixels[..2]].concat().concat());
+    let rgba = decode_to(PixelFormat::Rgba8);
+    assert_eq!((rgba.channels, rgba.depth), (4, 8));
+    assert_eq!(rgba.buf, vec![9, 8, 7, 255, 12, 11, 10, 255, 3, 2, 1, 255, 6, 5, 4, 255]);
+    assert_eq!(Image::new_with_options(&tga, &DecodeOptions::new().output_format(PixelFormat::Rgba8)).unwrap().buf, rgba.buf);
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::Image;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::Background;
+use image_codecs::png_decoder::CompositeOver;
+use image_codecs::png_encoder;
+use image_codecs::png_encoder::ColorType;
+use image_codecs::png_encoder::PngEncoder;
+
+fn decode_over(png: &[u8], over: CompositeOver) -> Image {
+    png_decoder::decode_with_options(png, &DecodeOptions::new().composite_over(over)).unwrap().image
+}
+
+#[test]
+fn test_rgba() {
+    // opaque, transparent and half transparent red
+    let image = Image { w: 3, h: 1, channels: 4, buf: vec![10, 20, 30, 255, 10, 20, 30, 0, 255, 0, 0, 128], depth: 8 };
+    let mut png = png_encoder::encode(&image).unwrap();
+    common::insert_chunk(&mut png, b"bKGD", &[0, 0, 0, 0, 0, 255]);
+    assert_eq!(png_decoder::decode(&png).unwrap().background, Some(Background::Rgb([0, 0, 255])));
+
+    let flattened = decode_over(&png, CompositeOver::Bkgd);
+    assert_eq!(flattened.channels, 3);
+    assert_eq!(flattened.buf, vec![10, 20, 30, 0, 0, 255, 128, 0, 127]);
+    assert_eq!(decode_over(&png, CompositeOver::Color([255, 255, 255])).buf, vec![10, 20, 30, 255, 255, 255, 255, 127, 127]);
+
+    // without bKGD the alpha channel is kept
+    assert_eq!(decode_over(&png_encoder::encode(&image).unwrap(), CompositeOver::Bkgd).buf, image.buf);
+}
+
+#[test]
+fn test_gray_alpha_16_bit() {
+    let buf = [[0x1234u16, 65535], [0x1234, 0]].concat().iter().flat_map(|v| v.to_le_bytes()).collect();
+    let image = Image { w: 2, h: 1, channels: 2, buf, depth: 16 };
+    let mut png = png_encoder::encode(&image).unwrap();
+    common::insert_chunk(&mut png, b"bKGD", &[0xab, 0xcd]);
+
+    let gray = decode_over(&png, CompositeOver::Bkgd);
+    assert_eq!(gray.channels, 1);
+    assert_eq!(gray.buf, vec![0x34, 0x12, 0xcd, 0xab]);
+
+    // a colored background makes the result RGB
+    let rgb = decode_over(&png, CompositeOver::Color([255, 0, 0]));
+    assert_eq!(rgb.channels, 3);
+    assert_eq!(rgb.buf, [[0x1234u16; 3], [0xffff, 0, 0]].concat().iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>());
+}
+
+#[test]
+fn test_palette_and_trns() {
+    let palette = [[255, 0, 0, 255], [0, 255, 0, 0], [0, 0, 255, 255]];
+    let image = Image { w: 2, h: 1, channels: 1, buf: vec![0, 1], depth: 8 };
+    let mut png = PngEncoder::new(ColorType::Indexed, 2).palette(&palette).encode(&image).unwrap();
+    common::insert_chunk_before_idat(&mut png, b"bKGD", &[2]);
+    assert_eq!(png_decoder::decode(&png).unwrap().background, Some(Background::PaletteIndex(2)));
+    assert_eq!(decode_over(&png, CompositeOver::Bkgd).buf, vec![255, 0, 0, 0, 0, 255]);
+
+    // gray with a tRNS color at 4 bits, bKGD gray 5 becomes 5 * 17
+    let image = Image { w: 2, h: 1, channels: 2, buf: vec![3 * 17, 255, 7 * 17, 0], depth: 8 };
+    let mut png = PngEncoder::new(ColorType::Gray, 4).transparent_color([7, 0, 0]).encode(&image).unwrap();
+    common::insert_chunk(&mut png, b"bKGD", &[0, 5]);
+    assert_eq!(decode_over(&png, CompositeOver::Bkgd).buf, vec![3 * 17, 5 * 17]);
+}
+
+#[test]
+fn test_malformed_bkgd() {
+    let rgb = png_encoder::encode(&Image { w: 1, h: 1, channels: 3, buf: vec![1, 2, 3], depth: 8 }).unwrap();
+    let palette = [[255, 0, 0, 255]];
+    let indexed = PngEncoder::new(ColorType::Indexed, 8).palette(&palette).encode(&Image { w: 1, h: 1, channels: 1, buf: vec![0], depth: 8 }).unwrap();
+    let cases: [(&Vec<u8>, &[u8], ErrorKind); 4] = [
+        (&rgb, &[0, 1, 0, 2], ErrorKind::Truncated), // too short
+        (&rgb, &[0, 1, 1, 0, 0, 3], ErrorKind::InvalidChunkData), // 256 doesn't fit into 8 bits
+        (&indexed, &[1], ErrorKind::InvalidChunkData), // out of palette
+        (&indexed, &[0, 0], ErrorKind::InvalidChunkLength),
+    ];
+    // like libpng the chunk is ignored
+    let assert_ignored = |png: &[u8], kind| {
+        let png_image = png_decoder::decode(png).unwrap();
+        assert_eq!(png_image.background, None);
+        assert_eq!(png_image.warnings.iter().map(|warning| (warning.kind, warning.chunk)).collect::<Vec<_>>(), [(kind, Some(*b"bKGD"))]);
+    };
+    for (png, bkgd, kind) in cases {
+        let mut png = png.clone();
+        common::insert_chunk_before_idat(&mut png, b"bKGD", bkgd);
+        assert_ignored(&png, kind);
+    }
+    let mut before_plte = indexed.clone();
+    common::insert_chunk(&mut before_plte, b"bKGD", &[0]);
+    assert_ignored(&before_plte, ErrorKind::ChunkOrder);
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::Image;
+use image_codecs::color;
+use image_codecs::color::TargetSpace;
+use image_codecs::png_decoder;
+use image_codecs::png_encoder;
+
+fn srgb_encode(linear: f64) -> f64 {
+    if linear <= 0.0031308 { linear * 12.92 } else { 1.055 * linear.powf(1.0 / 2.4) - 0.055 }
+}
+
+fn encode_with_chunks(image: &Image, chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let mut png = png_encoder::encode(image).unwrap();
+    for (name, data) in chunks.iter().rev() {
+        common::insert_chunk(&mut png, name, data);
+    }
+    png
+}
+
+fn decode_to(png: &[u8], target: TargetSpace) -> Image {
+    png_decoder::decode_with_options(png, &DecodeOptions::new().color_space(target)).unwrap().image
+}
+
+fn assert_close(actual: &[u8], expected: &[u8], tolerance: u8) {
+    assert_eq!(actual.len(), expected.len());
+    for (a, e) in actual.iter().zip(expected) {
+        assert!(a.abs_diff(*e) <= tolerance, "{:?} != {:?}", actual, expected);
+    }
+}
+
+#[test]
+fn test_srgb_is_unchanged() {
+    let image = Image { w: 4, h: 1, channels: 4, buf: vec![0, 10, 128, 255, 50, 100, 150, 7, 255, 255, 255, 255, 1, 2, 3, 4], depth: 8 };
+    let png = encode_with_chunks(&image, &[(b"sRGB", &[0])]);
+    assert_eq!(decode_to(&png, TargetSpace::Srgb).buf, image.buf);
+    // untagged images are assumed to be sRGB
+    assert_eq!(decode_to(&encode_with_chunks(&image, &[]), TargetSpace::Srgb).buf, image.buf);
+}
+
+#[test]
+fn test_gamma() {
+    let values: Vec<u8> = (0..=255).collect();
+    let image = Image { w: 256, h: 1, channels: 1, buf: values.clone(), depth: 8 };
+
+    // linear data
+    let png = encode_with_chunks(&image, &[(b"gAMA", &100000u32.to_be_bytes())]);
+    let expected: Vec<u8> = values.iter().map(|v| (srgb_encode(*v as f64 / 255.0) * 255.0).round() as u8).collect();
+    assert_eq!(decode_to(&png, TargetSpace::Srgb).buf, expected);
+    assert_eq!(decode_to(&png, TargetSpace::LinearSrgb).buf, values);
+
+    // gamma 1/2.2 is close to the sRGB curve except for the darkest values
+    let png = encode_with_chunks(&image, &[(b"gAMA", &45455u32.to_be_bytes())]);
+    assert_close(&decode_to(&png, TargetSpace::Srgb).buf[64..], &values[64..], 3);
+
+    let png_image = png_decoder::decode(&png).unwrap();
+    assert_eq!(png_image.gamma(), Some(0.45455));
+    assert_eq!(png_image.image.buf, values); // no conversion without the option
+}
+
+#[test]
+fn test_gamma_16_bit() {
+    let values: Vec<u16> = (0..=16).map(|i| (i * 4096).min(65535) as u16).collect();
+    // gray + alpha, the alpha channel holds the pixel index
+    let buf = values.iter().enumerate().flat_map(|(i, v)| [v.to_le_bytes(), [i as u8, 0xff]].concat()).collect();
+    let image = Image { w: values.len() as u32, h: 1, channels: 2, buf, depth: 16 };
+    let png = encode_with_chunks(&image, &[(b"gAMA", &100000u32.to_be_bytes())]);
+    let decoded = decode_to(&png, TargetSpace::Srgb);
+    for (i, pixel) in decoded.buf.chunks(4).enumerate() {
+        let gray = u16::from_le_bytes([pixel[0], pixel[1]]);
+        let expected = (srgb_encode(values[i] as f64 / 65535.0) * 65535.0).round() as u16;
+        assert_eq!(gray, expected);
+        assert_eq!(&pixel[2..], &[i as u8, 0xff]); // alpha is untouched
+    }
+}
+
+#[test]
+fn test_primaries() {
+    // white and gray stay neutral, red is outside of sRGB and gets clipped
+    let image = Image { w: 3, h: 1, channels: 3, buf: vec![255, 255, 255, 128, 128, 128, 255, 0, 0], depth: 8 };
+
+    let display_p3 = encode_with_chunks(&image, &[(b"cICP", &[12, 13, 0, 1])]);
+    let decoded = decode_to(&display_p3, TargetSpace::Srgb);
+    assert_close(&decoded.buf[..6], &image.buf[..6], 1);
+    assert_eq!(decoded.buf[6], 255);
+    assert!(decoded.buf[7] == 0 && decoded.buf[8] == 0);
+
+    let bt2020: Vec<u8> = [31270u32, 32900, 70800, 29200, 17000, 79700, 13100, 4600].iter().flat_map(|v| v.to_be_bytes()).collect();
+    let chrm = encode_with_chunks(&image, &[(b"gAMA", &45455u32.to_be_bytes()), (b"cHRM", &bt2020)]);
+    let decoded = decode_to(&chrm, TargetSpace::Srgb);
+    assert_close(&decoded.buf[..6], &[255, 255, 255, 128, 128, 128], 3);
+    assert!(decoded.buf[6] == 255 && decoded.buf[7] == 0);
+
+    // converting again doesn't change anything
+    let mut png_image = png_decoder::decode_with_options(&chrm, &DecodeOptions::new().color_space(TargetSpace::Srgb)).unwrap();
+    color::convert(&mut png_image, TargetSpace::Srgb).unwrap();
+    assert_eq!(png_image.image.buf, decoded.buf);
+}
+
+#[test]
+fn test_narrow_range() {
+    let image = Image { w: 2, h: 1, channels: 3, buf: vec![16, 16, 16, 235, 235, 235], depth: 8 };
+    let png = encode_with_chunks(&image, &[(b"cICP", &[1, 13, 0, 0])]);
+    assert_eq!(decode_to(&png, TargetSpace::Srgb).buf, vec![0, 0, 0, 255, 255, 255]);
+}
+
+#[test]
+fn test_invalid_color_chunks() {
+    // the chunks are only needed for the color conversion
+    let image = Image { w: 1, h: 1, channels: 3, buf: vec![1, 2, 3], depth: 8 };
+    let cases = [
+        (b"sRGB", &[4][..], ErrorKind::InvalidChunkData),
+        (b"sRGB", &[0, 0], ErrorKind::InvalidChunkLength),
+        (b"cICP", &[1, 13, 0, 2], ErrorKind::InvalidChunkData),
+        (b"cICP", &[1, 13, 0, 1, 0], ErrorKind::InvalidChunkLength),
+    ];
+    for (name, data, kind) in cases {
+        let png = encode_with_chunks(&image, &[(name, data)]);
+        let decoded = png_decoder::decode(&png).unwrap();
+        assert_eq!(decoded.image.buf, image.buf);
+        assert_eq!((decoded.srgb_intent, decoded.cicp), (None, None));
+        assert_eq!(decoded.warnings.iter().map(|warning| (warning.kind, warning.chunk)).collect::<Vec<_>>(), [(kind, Some(*name))]);
+
+        let err = png_decoder::decode_with_options(&png, &DecodeOptions::new().color_space(TargetSpace::Srgb)).err().unwrap();
+        assert_eq!((err.kind, err.chunk), (kind, Some(*name)));
+    }
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use image_codecs::ErrorKind;
+use image_codecs::Image;
+use image_codecs::exif;
+use image_codecs::exif::ByteOrder;
+use image_codecs::exif::Ifd;
+use image_codecs::png_decoder;
+use image_codecs::png_encoder;
+
+fn encode_test_image() -> Vec<u8> {
+    png_encoder::encode(&Image { w: 2, h: 2, channels: 3, buf: vec![7; 12], depth: 8 }).unwrap()
+}
+
+// Little-endian TIFF with Orientation and Model in IFD0
+fn exif_chunk() -> Vec<u8> {
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend_from_slice(&8u32.to_le_bytes());
+    tiff.extend_from_slice(&2u16.to_le_bytes());
+    tiff.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 8, 0, 0, 0]); // Orientation = 8
+    tiff.extend_from_slice(&[0x10, 0x01, 2, 0, 8, 0, 0, 0, 38, 0, 0, 0]); // Model at offset 38
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    tiff.extend_from_slice(b"Pixel 9\0");
+    tiff
+}
+
+#[test]
+fn test_exif() {
+    let mut png = encode_test_image();
+    common::insert_chunk(&mut png, b"eXIf", &exif_chunk());
+    let exif = png_decoder::decode(&png).unwrap().exif.unwrap();
+    assert_eq!(exif.byte_order, ByteOrder::LittleEndian);
+    assert_eq!(exif.orientation(), Some(8));
+    assert_eq!(exif.get(Ifd::Primary, exif::MODEL).unwrap().as_str(), Some("Pixel 9"));
+
+    assert_eq!(png_decoder::decode(&encode_test_image()).unwrap().exif, None);
+}
+
+#[test]
+fn test_malformed_exif() {
+    let mut duplicate = encode_test_image();
+    common::insert_chunk(&mut duplicate, b"eXIf", &exif_chunk());
+    common::insert_chunk(&mut duplicate, b"eXIf", &exif_chunk());
+
+    let mut truncated = encode_test_image();
+    let chunk = exif_chunk();
+    common::insert_chunk(&mut truncated, b"eXIf", &chunk[..chunk.len() - 4]);
+
+    let mut junk = encode_test_image();
+    common::insert_chunk(&mut junk, b"eXIf", b"JUNKJUNK");
+
+    let mut bad_offset = encode_test_image();
+    let mut chunk = exif_chunk();
+    chunk[4..8].copy_from_slice(&1000u32.to_le_bytes()); // IFD0 past the end
+    common::insert_chunk(&mut bad_offset, b"eXIf", &chunk);
+
+    // the image decodes without the broken EXIF data, the first of two is kept
+    let cases = [
+        (duplicate, ErrorKind::ChunkOrder, true),
+        (truncated, ErrorKind::InvalidChunkData, false),
+        (junk, ErrorKind::InvalidChunkData, false),
+        (bad_offset, ErrorKind::InvalidChunkData, false),
+    ];
+    for (png, kind, has_exif) in cases {
+        let png_image = png_decoder::decode(&png).unwrap();
+        assert_eq!(png_image.exif.is_some(), has_exif);
+        assert_eq!(png_image.warnings.iter().map(|warning| (warning.kind, warning.chunk)).collect::<Vec<_>>(), [(kind, Some(*b"eXIf"))]);
+    }
+}
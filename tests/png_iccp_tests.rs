@@ -0,0 +1,110 @@
+mod common;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::Image;
+use image_codecs::color::TargetSpace;
+use image_codecs::deflate;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::IccProfile;
+use image_codecs::png_encoder;
+
+fn encode_test_image() -> Vec<u8> {
+    png_encoder::encode(&Image { w: 2, h: 2, channels: 3, buf: vec![7; 12], depth: 8 }).unwrap()
+}
+
+fn iccp_chunk(name: &[u8], profile: &[u8]) -> Vec<u8> {
+    [name, &[0, 0], &deflate::zlib_compress(profile, deflate::DEFAULT_COMPRESSION)].concat()
+}
+
+#[test]
+fn test_iccp() {
+    let profile: Vec<u8> = (0..3000u32).map(|i| (i % 97) as u8).collect();
+    let mut png = encode_test_image();
+    common::insert_chunk(&mut png, b"iCCP", &iccp_chunk(b"Display P3", &profile));
+    let png_image = png_decoder::decode(&png).unwrap();
+    assert_eq!(png_image.icc_profile, Some(IccProfile { name: "Display P3".to_string(), data: profile }));
+
+    assert_eq!(png_decoder::decode(&encode_test_image()).unwrap().icc_profile, None);
+}
+
+#[test]
+fn test_malformed_iccp() {
+    let mut duplicate = encode_test_image();
+    common::insert_chunk(&mut duplicate, b"iCCP", &iccp_chunk(b"a", b"profile"));
+    common::insert_chunk(&mut duplicate, b"iCCP", &iccp_chunk(b"b", b"profile"));
+
+    let mut bad_method = encode_test_image();
+    let mut chunk = iccp_chunk(b"a", b"profile");
+    chunk[2] = 1;
+    common::insert_chunk(&mut bad_method, b"iCCP", &chunk);
+
+    let mut truncated = encode_test_image();
+    let chunk = iccp_chunk(b"a", b"profile");
+    common::insert_chunk(&mut truncated, b"iCCP", &chunk[..chunk.len() - 5]);
+
+    let mut bad_name = encode_test_image();
+    common::insert_chunk(&mut bad_name, b"iCCP", &iccp_chunk(b" a", b"profile"));
+
+    let mut bad_zlib = encode_test_image();
+    let mut chunk = iccp_chunk(b"a", b"profile");
+    chunk[3] = 0x79; // CMF
+    common::insert_chunk(&mut bad_zlib, b"iCCP", &chunk);
+
+    // a broken profile is treated as absent, the first of two is kept
+    let cases = [
+        (duplicate, ErrorKind::ChunkOrder, Some("b")),
+        (bad_method, ErrorKind::InvalidChunkData, None),
+        (truncated, ErrorKind::Truncated, None),
+        (bad_name, ErrorKind::InvalidChunkData, None),
+        (bad_zlib, ErrorKind::InvalidZlibHeader, None),
+    ];
+    for (png, kind, name) in cases {
+        let png_image = png_decoder::decode(&png).unwrap();
+        assert_eq!(png_image.icc_profile.map(|profile| profile.name), name.map(str::to_string));
+        assert_eq!(png_image.warnings.iter().map(|warning| (warning.kind, warning.chunk)).collect::<Vec<_>>(), [(kind, Some(*b"iCCP"))]);
+    }
+}
+
+// Gray profile with a single kTRC gamma curve
+fn gray_profile(gamma: f64) -> Vec<u8> {
+    let mut profile = vec![0; 128];
+    profile[8] = 2;
+    profile[12..16].copy_from_slice(b"mntr");
+    profile[16..20].copy_from_slice(b"GRAY");
+    profile[20..24].copy_from_slice(b"XYZ ");
+    profile[36..40].copy_from_slice(b"acsp");
+    profile.extend_from_slice(&1u32.to_be_bytes());
+    profile.extend_from_slice(b"kTRC");
+    profile.extend_from_slice(&144u32.to_be_bytes());
+    profile.extend_from_slice(&14u32.to_be_bytes());
+    profile.extend_from_slice(b"curv\0\0\0\0");
+    profile.extend_from_slice(&1u32.to_be_bytes());
+    profile.extend_from_slice(&((gamma * 256.0) as u16).to_be_bytes());
+    let size = profile.len() as u32;
+    profile[..4].copy_from_slice(&size.to_be_bytes());
+    profile
+}
+
+#[test]
+fn test_iccp_color_conversion() {
+    let values: Vec<u8> = (0..=255).collect();
+    let image = Image { w: 256, h: 1, channels: 1, buf: values.clone(), depth: 8 };
+    let options = DecodeOptions::new().color_space(TargetSpace::Srgb);
+
+    // a linear kTRC matches gAMA 1.0
+    let mut iccp = png_encoder::encode(&image).unwrap();
+    common::insert_chunk(&mut iccp, b"iCCP", &iccp_chunk(b"linear", &gray_profile(1.0)));
+    let mut gama = png_encoder::encode(&image).unwrap();
+    common::insert_chunk(&mut gama, b"gAMA", &100000u32.to_be_bytes());
+    let png_image = png_decoder::decode_with_options(&iccp, &options).unwrap();
+    assert_eq!(png_image.image.buf, png_decoder::decode_with_options(&gama, &options).unwrap().image.buf);
+    assert_eq!(png_image.icc_profile, None); // the profile no longer describes the pixels
+
+    // profiles that can't be used fall back to the other chunks
+    let mut rgb_profile_for_gray = png_encoder::encode(&image).unwrap();
+    let mut profile = gray_profile(1.0);
+    profile[16..20].copy_from_slice(b"RGB ");
+    common::insert_chunk(&mut rgb_profile_for_gray, b"iCCP", &iccp_chunk(b"rgb", &profile));
+    assert_eq!(png_decoder::decode_with_options(&rgb_profile_for_gray, &options).unwrap().image.buf, values);
+}
@@ -0,0 +1,183 @@
+mod common;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::deflate;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+
+const W: usize = 40;
+const H: usize = 30;
+const SPLIT: usize = 13; // first row of the second segment
+
+fn pixels() -> Vec<u8> {
+    (0..W * H * 3).map(|i| ((i * 7) ^ (i / 97)) as u8).collect()
+}
+
+// Scanlines with filter None, except for Up on `up_rows`
+fn scanlines(pixels: &[u8], up_rows: &[usize]) -> Vec<u8> {
+    let mut data = vec![];
+    for (y, row) in pixels.chunks(W * 3).enumerate() {
+        if up_rows.contains(&y) {
+            data.push(2);
+            data.extend(row.iter().zip(&pixels[(y - 1) * W * 3..]).map(|(cur, above)| cur.wrapping_sub(*above)));
+        } else {
+            data.push(0);
+            data.extend_from_slice(row);
+        }
+    }
+    data
+}
+
+// Image data in two segments like Apple writes them: the first one ends with an empty stored block to
+// flush to a byte boundary, the second one doesn't refer back to the first. The second segment is split
+// into two IDAT chunks. `gap` goes between the segments.
+fn idot_png(scanlines: &[u8], gap: &[u8], with_idot: bool) -> Vec<u8> {
+    let split = SPLIT * (1 + W * 3);
+    idot_png_with_second(scanlines, gap, deflate::compress(&scanlines[split..], 6), with_idot)
+}
+
+// Same with the deflate data of the second segment given
+fn idot_png_with_second(scanlines: &[u8], gap: &[u8], mut second: Vec<u8>, with_idot: bool) -> Vec<u8> {
+    let split = SPLIT * (1 + W * 3);
+    let mut first = vec![0x78, 0x01];
+    for block in scanlines[..split].chunks(65535) {
+        first.push(0); // stored, not final
+        first.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        first.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        first.extend_from_slice(block);
+    }
+    first.extend_from_slice(gap);
+    second.extend_from_slice(&deflate::adler32(scanlines).to_be_bytes());
+    let (second_a, second_b) = second.split_at(second.len() / 2);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut ihdr = vec![];
+    ihdr.extend_from_slice(&(W as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(H as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    png.extend(common::encode_chunk(b"IHDR", &ihdr));
+    if with_idot {
+        let idot_size = 12 + 28;
+        let mut idot = vec![];
+        for val in [2, 0, SPLIT, idot_size, SPLIT, H - SPLIT, idot_size + 12 + first.len()] {
+            idot.extend_from_slice(&(val as u32).to_be_bytes());
+        }
+        png.extend(common::encode_chunk(b"iDOT", &idot));
+    }
+    png.extend(common::encode_chunk(b"IDAT", &first));
+    png.extend(common::encode_chunk(b"IDAT", second_a));
+    png.extend(common::encode_chunk(b"IDAT", second_b));
+    png.extend(common::encode_chunk(b"IEND", &[]));
+    png
+}
+
+const FLUSH: [u8; 5] = [0, 0, 0, 0xff, 0xff];
+
+#[test]
+fn test_idot() {
+    let pixels = pixels();
+    let scanlines = scanlines(&pixels, &[5, 6, SPLIT + 1, H - 1]);
+    for with_idot in [true, false] {
+        let png = idot_png(&scanlines, &FLUSH, with_idot);
+        let image = png_decoder::decode(&png).unwrap().image;
+        assert_eq!((image.w, image.h, image.channels, image.depth), (W as u32, H as u32, 3, 8));
+        assert!(image.buf == pixels);
+
+        let options = DecodeOptions::new().output_format(PixelFormat::Bgra8);
+        let expected: Vec<u8> = pixels.chunks(3).flat_map(|pixel| [pixel[2], pixel[1], pixel[0], 255]).collect();
+        let stride = W * 4 + 5;
+        let mut dst = vec![0; stride * H];
+        png_decoder::decode_into_with_options(&png, &mut dst, stride, &options).unwrap();
+        assert!(dst.chunks(stride).flat_map(|row| &row[..W * 4]).copied().collect::<Vec<u8>>() == expected);
+    }
+}
+
+#[test]
+fn test_idot_segments_depend_on_each_other() {
+    // the second row of the second segment repeats the last row of the first one and its pixels are copied
+    // from it with a match, the segments are split with a sync flush instead of a full flush
+    let row_len = 1 + W * 3;
+    let mut pixels = pixels();
+    pixels.copy_within((SPLIT - 1) * W * 3..SPLIT * W * 3, (SPLIT + 1) * W * 3);
+    let scanlines = scanlines(&pixels, &[]);
+    // the first row is stored, so the first filter is found before the match
+    let mut second = vec![0];
+    second.extend_from_slice(&(row_len as u16).to_le_bytes());
+    second.extend_from_slice(&(!(row_len as u16)).to_le_bytes());
+    second.extend_from_slice(&scanlines[SPLIT * row_len..(SPLIT + 1) * row_len]);
+    let mut bits: Vec<u8> = vec![0, 1, 0]; // fixed Huffman codes
+    bits.extend([0, 0, 1, 1, 0, 0, 0, 0]); // filter None as a literal
+    bits.extend([1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0]); // length 115 + 5
+    bits.extend([0, 1, 1, 1, 1, 1, 0, 0, 0, 1, 1]); // distance 193 + 49
+    bits.extend([0; 7]); // end of block
+    bits.extend([0; 3]); // stored
+    let start = second.len();
+    second.resize(start + bits.len().div_ceil(8), 0);
+    for (i, bit) in bits.iter().enumerate() {
+        second[start + i / 8] |= bit << (i % 8);
+    }
+    second.extend_from_slice(&[0, 0, 0xff, 0xff]);
+    second.extend(deflate::compress(&scanlines[(SPLIT + 2) * row_len..], 6));
+
+    let png = idot_png_with_second(&scanlines, &FLUSH, second, true);
+    for verify_checksums in [true, false] {
+        let options = DecodeOptions::new().verify_checksums(verify_checksums);
+        assert!(png_decoder::decode_with_options(&png, &options).unwrap().image.buf == pixels);
+    }
+}
+
+#[test]
+fn test_idot_fallback() {
+    // the first row of the second segment needs the row above it, so the segments are decoded sequentially
+    let pixels = pixels();
+    let png = idot_png(&scanlines(&pixels, &[SPLIT]), &FLUSH, true);
+    assert!(png_decoder::decode(&png).unwrap().image.buf == pixels);
+
+    // a broken Adler-32 is still reported
+    let mut png = idot_png(&scanlines(&pixels, &[]), &FLUSH, true);
+    let idat_start = png.windows(4).rposition(|window| window == b"IDAT").unwrap() - 4;
+    let len = u32::from_be_bytes(png[idat_start..idat_start + 4].try_into().unwrap()) as usize;
+    let mut data = png[idat_start + 8..idat_start + 8 + len].to_vec();
+    data[len - 1] ^= 0xff;
+    png.splice(idat_start..idat_start + 12 + len, common::encode_chunk(b"IDAT", &data));
+    assert_eq!(png_decoder::decode(&png).err().map(|err| err.kind), Some(ErrorKind::AdlerMismatch));
+}
+
+#[test]
+fn test_idot_many_segments() {
+    // a segment per row, more than there are threads
+    let pixels = pixels();
+    let scanlines = scanlines(&pixels, &[]);
+    let mut idats = vec![];
+    for (y, row) in scanlines.chunks(1 + W * 3).enumerate() {
+        let mut data = if y == 0 { vec![0x78, 0x01] } else { vec![] };
+        data.push((y == H - 1) as u8);
+        data.extend_from_slice(&(row.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(!(row.len() as u16)).to_le_bytes());
+        data.extend_from_slice(row);
+        if y == H - 1 {
+            data.extend_from_slice(&deflate::adler32(&scanlines).to_be_bytes());
+        }
+        idats.push(common::encode_chunk(b"IDAT", &data));
+    }
+
+    let mut idot = (H as u32).to_be_bytes().to_vec();
+    let mut offset = 12 + 4 + H * 12;
+    for (y, idat) in idats.iter().enumerate() {
+        for val in [y, 1, offset] {
+            idot.extend_from_slice(&(val as u32).to_be_bytes());
+        }
+        offset += idat.len();
+    }
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut ihdr = vec![];
+    ihdr.extend_from_slice(&(W as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(H as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    png.extend(common::encode_chunk(b"IHDR", &ihdr));
+    png.extend(common::encode_chunk(b"iDOT", &idot));
+    idats.iter().for_each(|idat| png.extend_from_slice(idat));
+    png.extend(common::encode_chunk(b"IEND", &[]));
+    assert!(png_decoder::decode(&png).unwrap().image.buf == pixels);
+}
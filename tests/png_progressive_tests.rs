@@ -0,0 +1,78 @@
+mod common;
+
+use std::env;
+
+use image_codecs::DecodeOptions;
+use image_codecs::Image;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::CompositeOver;
+
+// size of the blocks covered by each decoded pixel after a pass
+const BLOCK_W: [usize; 8] = [1, 8, 4, 4, 2, 2, 1, 1];
+const BLOCK_H: [usize; 8] = [1, 8, 8, 4, 4, 2, 2, 1];
+
+fn pixel(image: &Image, x: usize, y: usize) -> &[u8] {
+    let bpp = image.channels as usize * image.depth as usize / 8;
+    &image.buf[(y * image.w as usize + x) * bpp..][..bpp]
+}
+
+#[test]
+fn test_png_progressive() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    let option_sets = [
+        DecodeOptions::new(),
+        DecodeOptions::new().output_format(PixelFormat::Rgba8),
+        DecodeOptions::new().composite_over(CompositeOver::Color([0, 128, 255])).output_format(PixelFormat::Rgb16),
+    ];
+    for (path, bytes) in imgs {
+        for (i, options) in option_sets.iter().enumerate() {
+            let Ok(expected) = png_decoder::decode_with_options(&bytes, options) else {
+                continue;
+            };
+            println!("decoding {} progressively", path.strip_prefix(&root).unwrap().display());
+            let interlaced = png_decoder::probe(&bytes).unwrap().interlaced;
+            let mut passes = vec![];
+            let png_image = png_decoder::decode_progressive(&bytes, options, &mut |pass: usize, preview: &Image| {
+                assert_eq!((preview.w, preview.h), (expected.image.w, expected.image.h));
+                assert_eq!((preview.channels, preview.depth), (expected.image.channels, expected.image.depth));
+                for y in (0..preview.h as usize).step_by(BLOCK_H[pass]) {
+                    for x in (0..preview.w as usize).step_by(BLOCK_W[pass]) {
+                        for dy in 0..BLOCK_H[pass].min(preview.h as usize - y) {
+                            for dx in 0..BLOCK_W[pass].min(preview.w as usize - x) {
+                                assert_eq!(pixel(preview, x + dx, y + dy), pixel(preview, x, y));
+                            }
+                        }
+                    }
+                }
+                // compositing only applies to the final image
+                if pass == 7 && i < 2 {
+                    assert!(preview.buf == expected.image.buf);
+                }
+                passes.push(pass);
+            }).unwrap();
+            assert!(png_image.image.buf == expected.image.buf);
+            assert_eq!(passes, if interlaced { (1..=7).collect() } else { vec![] });
+        }
+    }
+}
+
+#[test]
+fn test_png_progressive_first_pass() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let (_, bytes) = common::read_all_images(&root, "png").into_iter().find(|(path, _)| path.ends_with("basi2c08.png")).unwrap();
+    let expected = png_decoder::decode(&bytes).unwrap().image;
+    let mut previews = vec![];
+    png_decoder::decode_progressive(&bytes, &DecodeOptions::new(), &mut |_, preview: &Image| previews.push(preview.buf.clone())).unwrap();
+
+    // after the first pass every 8 x 8 block has the color of its top left pixel
+    let first = Image { w: 32, h: 32, channels: 3, buf: previews[0].clone(), depth: 8 };
+    for y in 0..32 {
+        for x in 0..32 {
+            assert_eq!(pixel(&first, x, y), pixel(&expected, x & !7, y & !7));
+        }
+    }
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use std::env;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::Image;
+use image_codecs::Limits;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::PngStreamDecoder;
+
+// size of the pixel grid after each number of passes
+const BLOCK_W: [usize; 8] = [1, 8, 4, 4, 2, 2, 1, 1];
+const BLOCK_H: [usize; 8] = [1, 8, 8, 4, 4, 2, 2, 1];
+
+fn subsampled(image: &Image, passes: usize) -> Vec<u8> {
+    let bpp = image.channels as usize * image.depth as usize / 8;
+    let mut buf = vec![];
+    for row in image.buf.chunks(image.w as usize * bpp).step_by(BLOCK_H[passes]) {
+        for pixel in row.chunks(bpp).step_by(BLOCK_W[passes]) {
+            buf.extend_from_slice(pixel);
+        }
+    }
+    buf
+}
+
+#[test]
+fn test_png_adam7_passes() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    for (path, bytes) in imgs {
+        let Ok(full) = png_decoder::decode(&bytes) else {
+            continue;
+        };
+        println!("decoding the first passes of {}", path.strip_prefix(&root).unwrap().display());
+        let rgba16 = png_decoder::decode_with_options(&bytes, &DecodeOptions::new().output_format(PixelFormat::Rgba16)).unwrap();
+        for passes in 1..=7 {
+            for (options, expected) in [(DecodeOptions::new(), &full.image), (DecodeOptions::new().output_format(PixelFormat::Rgba16), &rgba16.image)] {
+                let options = options.adam7_passes(passes as u8);
+                let png_image = png_decoder::decode_with_options(&bytes, &options).unwrap();
+                let image = &png_image.image;
+                assert_eq!(image.w as usize, (full.image.w as usize).div_ceil(BLOCK_W[passes]));
+                assert_eq!(image.h as usize, (full.image.h as usize).div_ceil(BLOCK_H[passes]));
+                assert!(image.buf == subsampled(expected, passes));
+
+                let layout = png_decoder::buffer_layout(&bytes, &options).unwrap();
+                assert_eq!((layout.w, layout.h), (image.w, image.h));
+                let mut dst = vec![0; layout.required_size(layout.row_bytes())];
+                png_decoder::decode_into_with_options(&bytes, &mut dst, layout.row_bytes(), &options).unwrap();
+                assert!(dst == image.buf);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_png_adam7_passes_stop_inflate() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let (_, mut bytes) = common::read_all_images(&root, "png").into_iter().find(|(path, _)| path.ends_with("basi2c08.png")).unwrap();
+
+    // break the Adam7-32 at the end of the zlib stream, the first passes don't get that far
+    let idat_start = bytes.windows(4).position(|window| window == b"IDAT").unwrap() - 4;
+    let len = u32::from_be_bytes(bytes[idat_start..idat_start + 4].try_into().unwrap()) as usize;
+    let mut data = bytes[idat_start + 8..idat_start + 8 + len].to_vec();
+    data[len - 1] ^= 0xff;
+    bytes.splice(idat_start..idat_start + 12 + len, common::encode_chunk(b"IDAT", &data));
+
+    assert_eq!(png_decoder::decode(&bytes).err().map(|err| err.kind), Some(ErrorKind::AdlerMismatch));
+    let image = png_decoder::decode_with_options(&bytes, &DecodeOptions::new().adam7_passes(5)).unwrap().image;
+    assert_eq!((image.w, image.h), (16, 16));
+    assert_eq!(common::decode_streaming(&bytes, DecodeOptions::new()).err().map(|err| err.kind), Some(ErrorKind::AdlerMismatch));
+    let streamed = common::decode_streaming(&bytes, DecodeOptions::new().adam7_passes(5)).unwrap().image;
+    assert!(streamed.buf == image.buf);
+}
+
+#[test]
+fn test_png_adam7_passes_limits() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let (_, bytes) = common::read_all_images(&root, "png").into_iter().find(|(path, _)| path.ends_with("basi2c08.png")).unwrap();
+
+    // the 32 x 32 x 3 bytes of the whole image don't fit, the 4 x 4 pixels of the first pass do
+    let options = DecodeOptions::new().limits(Limits { max_alloc_bytes: 1024, ..Limits::default() });
+    assert_eq!(png_decoder::decode_with_options(&bytes, &options).err().map(|err| err.kind), Some(ErrorKind::LimitsExceeded));
+    let expected = png_decoder::decode_with_options(&bytes, &DecodeOptions::new().adam7_passes(1)).unwrap().image;
+    let image = png_decoder::decode_with_options(&bytes, &options.clone().adam7_passes(1)).unwrap().image;
+    assert_eq!((image.w, image.h), (4, 4));
+    assert!(image.buf == expected.buf);
+    let image = common::decode_streaming(&bytes, options.adam7_passes(1)).unwrap().image;
+    assert!(image.buf == expected.buf);
+}
+
+#[test]
+fn test_png_adam7_passes_stream_rows() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    // the rows that push reports are those of the reduced image
+    for (path, bytes) in imgs {
+        for passes in [1, 4, 7] {
+            let options = DecodeOptions::new().adam7_passes(passes);
+            let Ok(expected) = png_decoder::decode_with_options(&bytes, &options) else {
+                continue;
+            };
+            println!("stream decoding the first passes of {}", path.strip_prefix(&root).unwrap().display());
+            let mut decoder = PngStreamDecoder::with_options(options);
+            let mut last_rows = vec![vec![]; expected.image.h as usize];
+            decoder.push(&bytes, |y, row| last_rows[y as usize] = row.to_vec()).unwrap();
+            assert!(decoder.finish().unwrap().image.buf == expected.image.buf);
+            assert!(last_rows.concat() == expected.image.buf);
+        }
+    }
+}
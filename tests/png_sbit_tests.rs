@@ -0,0 +1,87 @@
+mod common;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::Image;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::SignificantBits;
+use image_codecs::png_encoder;
+
+fn encode_with_sbit(image: &Image, sbit: &[u8]) -> Vec<u8> {
+    let mut png = png_encoder::encode(image).unwrap();
+    common::insert_chunk(&mut png, b"sBIT", sbit);
+    png
+}
+
+fn decode_with(png: &[u8], mode: SignificantBits) -> Vec<u8> {
+    png_decoder::decode_with_options(png, &DecodeOptions::new().significant_bits(mode)).unwrap().image.buf
+}
+
+#[test]
+fn test_rgb565() {
+    // 5-6-5 bit samples shifted to the top of 8-bit samples
+    let pixels: [[u8; 3]; 3] = [[0, 0, 0], [31, 63, 31], [16, 1, 30]];
+    let buf = pixels.iter().flat_map(|[r, g, b]| [r << 3, g << 2, b << 3]).collect();
+    let image = Image { w: 3, h: 1, channels: 3, buf, depth: 8 };
+    let png = encode_with_sbit(&image, &[5, 6, 5]);
+
+    let png_image = png_decoder::decode(&png).unwrap();
+    assert_eq!(png_image.significant_bits, Some(vec![5, 6, 5]));
+    assert_eq!(png_image.image.buf, image.buf); // unchanged by default
+
+    assert_eq!(decode_with(&png, SignificantBits::Rescale), pixels.concat());
+    let replicate = |v: u8, bits: u32| ((v as u32 * 255 * 2 + (1 << bits) - 1) / (2 * ((1 << bits) - 1))) as u8;
+    let expected: Vec<u8> = pixels.iter().flat_map(|[r, g, b]| [replicate(*r, 5), replicate(*g, 6), replicate(*b, 5)]).collect();
+    assert_eq!(decode_with(&png, SignificantBits::Replicate), expected);
+}
+
+#[test]
+fn test_16_bit_gray_alpha() {
+    // 10-bit gray, full precision alpha
+    let samples: [(u16, u16); 3] = [(0, 0), (1023, 65535), (512, 1234)];
+    let buf = samples.iter().flat_map(|(gray, alpha)| [(gray << 6).to_le_bytes(), alpha.to_le_bytes()].concat()).collect();
+    let image = Image { w: 3, h: 1, channels: 2, buf, depth: 16 };
+    let png = encode_with_sbit(&image, &[10, 16]);
+
+    let rescaled = decode_with(&png, SignificantBits::Rescale);
+    let replicated = decode_with(&png, SignificantBits::Replicate);
+    for (i, (gray, alpha)) in samples.iter().enumerate() {
+        let sample = |buf: &[u8], c: usize| u16::from_le_bytes([buf[4 * i + 2 * c], buf[4 * i + 2 * c + 1]]);
+        assert_eq!(sample(&rescaled, 0), *gray);
+        assert_eq!(sample(&replicated, 0), gray << 6 | gray >> 4);
+        assert_eq!(sample(&rescaled, 1), *alpha);
+        assert_eq!(sample(&replicated, 1), *alpha);
+    }
+}
+
+#[test]
+fn test_output_format() {
+    let image = Image { w: 1, h: 1, channels: 3, buf: vec![31 << 3, 63 << 2, 16 << 3], depth: 8 };
+    let png = encode_with_sbit(&image, &[5, 6, 5]);
+    // replicated samples are converted like any other, rescaled ones would be taken as full range values
+    let options = DecodeOptions::new().significant_bits(SignificantBits::Replicate).output_format(PixelFormat::Rgba16);
+    let expected: Vec<u8> = decode_with(&png, SignificantBits::Replicate).iter().flat_map(|v| [*v, *v]).chain([255, 255]).collect();
+    assert_eq!(png_decoder::decode_with_options(&png, &options).unwrap().image.buf, expected);
+    let options = options.significant_bits(SignificantBits::Rescale);
+    assert_eq!(png_decoder::decode_with_options(&png, &options).err().map(|err| err.kind), Some(ErrorKind::NotImplemented));
+    assert_eq!(common::decode_streaming(&png, options).err().map(|err| err.kind), Some(ErrorKind::NotImplemented));
+}
+
+#[test]
+fn test_malformed_sbit() {
+    let image = Image { w: 1, h: 1, channels: 3, buf: vec![1, 2, 3], depth: 8 };
+    for (sbit, kind) in [
+        (&[5, 6][..], ErrorKind::InvalidChunkLength),
+        (&[5, 6, 5, 8], ErrorKind::InvalidChunkLength),
+        (&[0, 6, 5], ErrorKind::InvalidChunkData),
+        (&[5, 9, 5], ErrorKind::InvalidChunkData),
+    ] {
+        // an invalid sBIT is ignored
+        let png = encode_with_sbit(&image, sbit);
+        let png_image = png_decoder::decode(&png).unwrap();
+        assert_eq!(png_image.significant_bits, None);
+        assert_eq!(png_image.warnings.iter().map(|warning| (warning.kind, warning.chunk)).collect::<Vec<_>>(), [(kind, Some(*b"sBIT"))]);
+        assert_eq!(decode_with(&png, SignificantBits::Rescale), image.buf);
+    }
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use std::env;
+
+use image_codecs::DecodeOptions;
+use image_codecs::color::TargetSpace;
+use image_codecs::pixel_format::PixelFormat;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::CompositeOver;
+use image_codecs::png_decoder::PngStreamDecoder;
+use image_codecs::png_decoder::SignificantBits;
+
+#[test]
+fn test_png_stream_decoder() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    let mut n_ok = 0;
+    for (path, bytes) in imgs {
+        let Ok(expected) = png_decoder::decode(&bytes) else {
+            continue;
+        };
+        println!("stream decoding {}", path.strip_prefix(&root).unwrap().display());
+
+        // odd piece sizes to split chunk headers, zlib symbols and scanlines at arbitrary places
+        for piece_size in [1, 7, 4096] {
+            let mut decoder = PngStreamDecoder::new();
+            // interlaced rows are reported once per pass, the last report must have the final pixels
+            let mut last_rows = vec![vec![]; expected.image.h as usize];
+            for piece in bytes.chunks(piece_size) {
+                decoder.push(piece, |y, row| last_rows[y as usize] = row.to_vec()).unwrap();
+            }
+            assert!(decoder.is_finished());
+            let image = decoder.finish().unwrap().image;
+            assert_eq!((image.w, image.h, image.channels, image.depth), (expected.image.w, expected.image.h, expected.image.channels, expected.image.depth));
+            assert!(image.buf == expected.image.buf);
+            assert!(last_rows.concat() == expected.image.buf);
+        }
+        n_ok += 1;
+    }
+    assert_ne!(n_ok, 0);
+}
+
+#[test]
+fn test_png_stream_decoder_options() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    // the options that work on the whole image give the same pixels as decode_with_options
+    let option_sets = [
+        DecodeOptions::new().color_space(TargetSpace::LinearSrgb).output_format(PixelFormat::Rgba16),
+        DecodeOptions::new().significant_bits(SignificantBits::Rescale),
+        DecodeOptions::new().composite_over(CompositeOver::Color([0, 128, 255])),
+        DecodeOptions::new().adam7_passes(3).output_format(PixelFormat::Rgba8),
+    ];
+    for (path, bytes) in imgs {
+        for options in &option_sets {
+            let Ok(expected) = png_decoder::decode_with_options(&bytes, options) else {
+                continue;
+            };
+            println!("stream decoding {} with {options:?}", path.strip_prefix(&root).unwrap().display());
+            let mut decoder = PngStreamDecoder::with_options(options.clone());
+            decoder.push(&bytes, |_, _| {}).unwrap();
+            let image = decoder.finish().unwrap().image;
+            assert_eq!((image.w, image.h, image.channels, image.depth), (expected.image.w, expected.image.h, expected.image.channels, expected.image.depth));
+            assert!(image.buf == expected.image.buf);
+        }
+    }
+}
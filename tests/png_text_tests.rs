@@ -0,0 +1,96 @@
+mod common;
+
+use std::io::Cursor;
+
+use image_codecs::DecodeOptions;
+use image_codecs::ErrorKind;
+use image_codecs::png_decoder;
+use image_codecs::png_decoder::TextEntry;
+use png::text_metadata::ITXtChunk;
+
+fn encode_with_text(add_text: impl FnOnce(&mut png::Writer<Cursor<&mut Vec<u8>>>)) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut encoder = png::Encoder::new(Cursor::new(&mut bytes), 2, 2);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.add_text_chunk("Author".to_string(), "Jos\u{e9} Dupont".to_string()).unwrap();
+    encoder.add_ztxt_chunk("Copyright".to_string(), "public domain ".repeat(20)).unwrap();
+    let mut writer = encoder.write_header().unwrap();
+    add_text(&mut writer);
+    writer.write_image_data(&[0, 64, 128, 255]).unwrap();
+    writer.finish().unwrap();
+    bytes
+}
+
+#[test]
+fn test_text_chunks() {
+    let bytes = encode_with_text(|writer| {
+        let mut itxt = ITXtChunk::new("Software", "\u{43a}\u{43e}\u{434}\u{435}\u{43a} 1.0");
+        itxt.language_tag = "ru-RU".to_string();
+        itxt.translated_keyword = "\u{41f}\u{440}\u{43e}\u{433}\u{440}\u{430}\u{43c}\u{43c}\u{430}".to_string();
+        writer.write_text_chunk(&itxt).unwrap();
+        let mut compressed = ITXtChunk::new("Comment", "\u{1f600} ".repeat(50));
+        compressed.compressed = true;
+        writer.write_text_chunk(&compressed).unwrap();
+    });
+
+    let png_image = png_decoder::decode(&bytes).unwrap();
+    assert_eq!(png_image.text, vec![
+        TextEntry {
+            keyword: "Author".to_string(),
+            text: "Jos\u{e9} Dupont".to_string(),
+            compressed: false,
+            language_tag: None,
+            translated_keyword: None,
+        },
+        TextEntry {
+            keyword: "Copyright".to_string(),
+            text: "public domain ".repeat(20),
+            compressed: true,
+            language_tag: None,
+            translated_keyword: None,
+        },
+        TextEntry {
+            keyword: "Software".to_string(),
+            text: "\u{43a}\u{43e}\u{434}\u{435}\u{43a} 1.0".to_string(),
+            compressed: false,
+            language_tag: Some("ru-RU".to_string()),
+            translated_keyword: Some("\u{41f}\u{440}\u{43e}\u{433}\u{440}\u{430}\u{43c}\u{43c}\u{430}".to_string()),
+        },
+        TextEntry {
+            keyword: "Comment".to_string(),
+            text: "\u{1f600} ".repeat(50),
+            compressed: true,
+            language_tag: Some(String::new()),
+            translated_keyword: Some(String::new()),
+        },
+    ]);
+}
+
+fn with_raw_text_chunk(data: &[u8]) -> Vec<u8> {
+    let mut bytes = encode_with_text(|_| {});
+    common::insert_chunk(&mut bytes, b"tEXt", data);
+    bytes
+}
+
+#[test]
+fn test_keyword_validation() {
+    assert!(png_decoder::decode(&with_raw_text_chunk(b"Title\0ok")).is_ok());
+    let long_keyword = [b"a".repeat(80), b"\0text".to_vec()].concat();
+    for data in [&b"\0empty keyword"[..], b" leading\0", b"trailing \0", b"two  spaces\0", b"tab\there\0", b"no terminator", &long_keyword] {
+        // the broken chunk is left out, the image and the other text still decode
+        let decoded = png_decoder::decode(&with_raw_text_chunk(data)).unwrap();
+        assert_eq!(decoded.text.iter().map(|entry| entry.keyword.as_str()).collect::<Vec<_>>(), ["Author", "Copyright"]);
+        assert_eq!(decoded.warnings.iter().map(|warning| (warning.kind, warning.chunk)).collect::<Vec<_>>(), [(ErrorKind::InvalidChunkData, Some(*b"tEXt"))]);
+    }
+}
+
+#[test]
+fn test_broken_ztxt() {
+    let mut bytes = encode_with_text(|_| {});
+    common::insert_chunk(&mut bytes, b"zTXt", b"Comment\0\0not zlib");
+    for decoded in [png_decoder::decode(&bytes).unwrap(), common::decode_streaming(&bytes, DecodeOptions::new()).unwrap()] {
+        assert_eq!(decoded.text.len(), 2);
+        assert_eq!(decoded.warnings.iter().map(|warning| (warning.kind, warning.chunk)).collect::<Vec<_>>(), [(ErrorKind::InvalidZlibHeader, Some(*b"zTXt"))]);
+    }
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use std::env;
+
+use image_codecs::ErrorKind;
+use image_codecs::Image;
+use image_codecs::png_decoder;
+
+#[test]
+fn test_png_probe() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let imgs = common::read_all_images(&root, "png");
+    assert_ne!(imgs.len(), 0);
+
+    for (path, bytes) in imgs {
+        let Ok(expected) = png_decoder::decode(&bytes) else {
+            continue;
+        };
+        println!("probing {}", path.strip_prefix(&root).unwrap().display());
+        let info = Image::probe(&bytes).unwrap();
+        assert_eq!((info.w, info.h, info.channels), (expected.image.w, expected.image.h, expected.image.channels));
+        assert_eq!(info.depth, expected.depth);
+        assert_eq!(info.interlaced, expected.interlaced());
+        assert_eq!(info.palette_size, expected.palette_size());
+        assert_eq!(info.palette_size.is_some(), expected.color_type() == 3);
+        assert_eq!(info.has_transparency, expected.image.channels == 2 || expected.image.channels == 4);
+
+        // the pixel data isn't needed
+        let idat = bytes.windows(4).position(|window| window == b"IDAT").unwrap();
+        assert_eq!(Image::probe(&bytes[..idat + 4]).unwrap(), info);
+    }
+}
+
+#[test]
+fn test_png_probe_palette() {
+    let root = env::var("TEST_IMAGES_ROOT").expect("TEST_IMAGES_ROOT env var not found");
+    let (_, bytes) = common::read_all_images(&root, "png").into_iter().find(|(path, _)| path.ends_with("tbbn3p08.png")).unwrap();
+    let info = Image::probe(&bytes).unwrap();
+    assert_eq!(info.palette_size, Some(246));
+    assert_eq!(info.channels, 4);
+    assert!(info.has_transparency);
+}
+
+#[test]
+fn test_tga_probe() {
+    // 2 x 2 bottom-up BGRA image
+    let mut tga = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 32, 0];
+    let info = Image::probe(&tga).unwrap();
+    assert_eq!((info.w, info.h, info.channels, info.depth), (2, 2, 4, 8));
+    assert!(info.has_transparency);
+    assert_eq!(info.palette_size, None);
+
+    // a broken footer is reported
+    tga.extend((1..=16).collect::<Vec<u8>>());
+    tga.extend([0; 30]);
+    assert_eq!(Image::probe(&tga).err().map(|err| err.kind), Some(ErrorKind::InvalidSignature));
+}